@@ -0,0 +1,62 @@
+/*
+ * C bindings for kvs, a key-value store written in Rust.
+ *
+ * Mirrors ffi/src/lib.rs; see the documentation there for the details of
+ * each function. Strings are passed as a pointer and a length and are never
+ * NUL-terminated. Keys and values must be valid UTF-8.
+ */
+#ifndef KVS_H
+#define KVS_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef int kvs_status;
+
+#define KVS_OK 0
+#define KVS_NOT_FOUND 1
+#define KVS_INVALID_ARGUMENT 2
+#define KVS_IO 3
+#define KVS_CORRUPTION 4
+#define KVS_ERROR 5
+#define KVS_PANIC 6
+
+typedef struct kvs_store kvs_store;
+
+/* Returning anything but zero stops the scan. */
+typedef int (*kvs_scan_fn)(void *ctx,
+                           const uint8_t *key, size_t key_len,
+                           const uint8_t *value, size_t value_len);
+
+kvs_status kvs_open(const uint8_t *path, size_t path_len, kvs_store **out);
+
+/* On KVS_OK, *value must be released with kvs_free. */
+kvs_status kvs_get(kvs_store *store,
+                   const uint8_t *key, size_t key_len,
+                   uint8_t **value, size_t *value_len);
+
+kvs_status kvs_set(kvs_store *store,
+                   const uint8_t *key, size_t key_len,
+                   const uint8_t *value, size_t value_len);
+
+kvs_status kvs_remove(kvs_store *store, const uint8_t *key, size_t key_len);
+
+kvs_status kvs_scan(kvs_store *store,
+                    const uint8_t *prefix, size_t prefix_len,
+                    kvs_scan_fn callback, void *ctx);
+
+void kvs_close(kvs_store *store);
+
+void kvs_free(uint8_t *value, size_t value_len);
+
+const char *kvs_status_message(kvs_status status);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* KVS_H */
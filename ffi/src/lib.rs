@@ -0,0 +1,322 @@
+#![warn(missing_docs)]
+//! C bindings for [`kvs`](../kvs/index.html).
+//!
+//! Every function takes its strings as a pointer and a length, and returns a
+//! [`kvs_status`]. Keys and values must be valid UTF-8. The declarations are
+//! in `include/kvs.h`, which has to be kept in step with this file.
+//!
+//! No function unwinds into its caller: a panic is caught and reported as
+//! `KVS_PANIC`.
+//!
+//! [`kvs_status`]: type.kvs_status.html
+#![allow(non_camel_case_types)]
+use std::os::raw::{c_char, c_int, c_void};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::PathBuf;
+use std::ptr;
+use std::slice;
+use std::str;
+
+use kvs::{KvStore, KvsError, Result};
+
+/// The status every function returns.
+pub type kvs_status = c_int;
+
+/// The call succeeded.
+pub const KVS_OK: kvs_status = 0;
+/// The key does not exist.
+pub const KVS_NOT_FOUND: kvs_status = 1;
+/// A pointer was null, or a key or value was not UTF-8.
+pub const KVS_INVALID_ARGUMENT: kvs_status = 2;
+/// An I/O error occurred.
+pub const KVS_IO: kvs_status = 3;
+/// The store's files are damaged.
+pub const KVS_CORRUPTION: kvs_status = 4;
+/// Any other error reported by the store.
+pub const KVS_ERROR: kvs_status = 5;
+/// The store panicked. The store should be closed.
+pub const KVS_PANIC: kvs_status = 6;
+
+/// Called by [`kvs_scan`] once per entry. Returning anything but zero stops
+/// the scan.
+///
+/// [`kvs_scan`]: fn.kvs_scan.html
+pub type kvs_scan_fn = extern "C" fn(
+    ctx: *mut c_void,
+    key: *const u8,
+    key_len: usize,
+    value: *const u8,
+    value_len: usize,
+) -> c_int;
+
+/// An open store. Only ever handled through a pointer.
+pub struct kvs_store {
+    store: KvStore,
+}
+
+/// Opens the store in the directory at `path`, creating it if it is missing,
+/// and writes the handle to `*out`. The handle is released by [`kvs_close`].
+///
+/// # Safety
+///
+/// `path` must point to `path_len` readable bytes and `out` must be valid
+/// for writes.
+///
+/// [`kvs_close`]: fn.kvs_close.html
+#[no_mangle]
+pub unsafe extern "C" fn kvs_open(
+    path: *const u8,
+    path_len: usize,
+    out: *mut *mut kvs_store,
+) -> kvs_status {
+    if out.is_null() {
+        return KVS_INVALID_ARGUMENT;
+    }
+    let path = match bytes(path, path_len).and_then(to_path) {
+        Some(path) => path,
+        None => return KVS_INVALID_ARGUMENT,
+    };
+    guard(|| {
+        let store = KvStore::open(path)?;
+        *out = Box::into_raw(Box::new(kvs_store { store }));
+        Ok(KVS_OK)
+    })
+}
+
+/// Looks up `key`. If it exists, its value is written to `*value` and
+/// `*value_len`, and has to be released by [`kvs_free`]. Otherwise, this
+/// returns `KVS_NOT_FOUND`.
+///
+/// # Safety
+///
+/// `store` must come from [`kvs_open`], `key` must point to `key_len`
+/// readable bytes and `value` and `value_len` must be valid for writes.
+///
+/// [`kvs_free`]: fn.kvs_free.html
+/// [`kvs_open`]: fn.kvs_open.html
+#[no_mangle]
+pub unsafe extern "C" fn kvs_get(
+    store: *mut kvs_store,
+    key: *const u8,
+    key_len: usize,
+    value: *mut *mut u8,
+    value_len: *mut usize,
+) -> kvs_status {
+    if store.is_null() || value.is_null() || value_len.is_null() {
+        return KVS_INVALID_ARGUMENT;
+    }
+    let key = match text(key, key_len) {
+        Some(key) => key,
+        None => return KVS_INVALID_ARGUMENT,
+    };
+    guard(|| match (*store).store.get(key)? {
+        Some(found) => {
+            let found = found.into_bytes().into_boxed_slice();
+            *value_len = found.len();
+            *value = Box::into_raw(found) as *mut u8;
+            Ok(KVS_OK)
+        }
+        None => Ok(KVS_NOT_FOUND),
+    })
+}
+
+/// Sets `key` to `value`.
+///
+/// # Safety
+///
+/// `store` must come from [`kvs_open`], and `key` and `value` must point to
+/// `key_len` and `value_len` readable bytes.
+///
+/// [`kvs_open`]: fn.kvs_open.html
+#[no_mangle]
+pub unsafe extern "C" fn kvs_set(
+    store: *mut kvs_store,
+    key: *const u8,
+    key_len: usize,
+    value: *const u8,
+    value_len: usize,
+) -> kvs_status {
+    if store.is_null() {
+        return KVS_INVALID_ARGUMENT;
+    }
+    let (key, value) = match (text(key, key_len), text(value, value_len)) {
+        (Some(key), Some(value)) => (key, value),
+        _ => return KVS_INVALID_ARGUMENT,
+    };
+    guard(|| {
+        (*store).store.set(key, value)?;
+        Ok(KVS_OK)
+    })
+}
+
+/// Removes `key`, returning `KVS_NOT_FOUND` if it does not exist.
+///
+/// # Safety
+///
+/// `store` must come from [`kvs_open`] and `key` must point to `key_len`
+/// readable bytes.
+///
+/// [`kvs_open`]: fn.kvs_open.html
+#[no_mangle]
+pub unsafe extern "C" fn kvs_remove(
+    store: *mut kvs_store,
+    key: *const u8,
+    key_len: usize,
+) -> kvs_status {
+    if store.is_null() {
+        return KVS_INVALID_ARGUMENT;
+    }
+    let key = match text(key, key_len) {
+        Some(key) => key,
+        None => return KVS_INVALID_ARGUMENT,
+    };
+    guard(|| {
+        (*store).store.remove(key)?;
+        Ok(KVS_OK)
+    })
+}
+
+/// Calls `callback`, in key order, with every key that starts with `prefix`
+/// and its value. The pointers handed to `callback` are only valid for the
+/// duration of that call.
+///
+/// # Safety
+///
+/// `store` must come from [`kvs_open`] and `prefix` must point to
+/// `prefix_len` readable bytes. `ctx` is passed to `callback` untouched.
+///
+/// [`kvs_open`]: fn.kvs_open.html
+#[no_mangle]
+pub unsafe extern "C" fn kvs_scan(
+    store: *mut kvs_store,
+    prefix: *const u8,
+    prefix_len: usize,
+    callback: kvs_scan_fn,
+    ctx: *mut c_void,
+) -> kvs_status {
+    if store.is_null() {
+        return KVS_INVALID_ARGUMENT;
+    }
+    let prefix = match text(prefix, prefix_len) {
+        Some(prefix) => prefix,
+        None => return KVS_INVALID_ARGUMENT,
+    };
+    guard(|| {
+        for (key, value) in (*store).store.scan(&prefix)? {
+            if callback(ctx, key.as_ptr(), key.len(), value.as_ptr(), value.len()) != 0 {
+                break;
+            }
+        }
+        Ok(KVS_OK)
+    })
+}
+
+/// Closes a store opened by [`kvs_open`]. Closing a null pointer does
+/// nothing.
+///
+/// # Safety
+///
+/// `store` must come from [`kvs_open`] and must not be used again.
+///
+/// [`kvs_open`]: fn.kvs_open.html
+#[no_mangle]
+pub unsafe extern "C" fn kvs_close(store: *mut kvs_store) {
+    if !store.is_null() {
+        let _ = panic::catch_unwind(AssertUnwindSafe(|| drop(Box::from_raw(store))));
+    }
+}
+
+/// Releases a value returned by [`kvs_get`]. Freeing a null pointer does
+/// nothing.
+///
+/// # Safety
+///
+/// `value` and `value_len` must be exactly as [`kvs_get`] returned them, and
+/// `value` must not be used again.
+///
+/// [`kvs_get`]: fn.kvs_get.html
+#[no_mangle]
+pub unsafe extern "C" fn kvs_free(value: *mut u8, value_len: usize) {
+    if !value.is_null() {
+        drop(Box::from_raw(ptr::slice_from_raw_parts_mut(
+            value, value_len,
+        )));
+    }
+}
+
+/// Returns a static, NUL-terminated description of `status`.
+#[no_mangle]
+pub extern "C" fn kvs_status_message(status: kvs_status) -> *const c_char {
+    let message: &'static [u8] = match status {
+        KVS_OK => b"ok\0",
+        KVS_NOT_FOUND => b"key not found\0",
+        KVS_INVALID_ARGUMENT => b"invalid argument\0",
+        KVS_IO => b"I/O error\0",
+        KVS_CORRUPTION => b"store is corrupted\0",
+        KVS_ERROR => b"error\0",
+        KVS_PANIC => b"panic\0",
+        _ => b"unknown status\0",
+    };
+    message.as_ptr() as *const c_char
+}
+
+/// Runs `f`, turning any error or panic into a status.
+fn guard<F: FnOnce() -> Result<kvs_status>>(f: F) -> kvs_status {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(status)) => status,
+        Ok(Err(err)) => status(&err),
+        Err(_) => KVS_PANIC,
+    }
+}
+
+fn status(err: &KvsError) -> kvs_status {
+    match err {
+        KvsError::Io(_) => KVS_IO,
+        KvsError::KeyNotFound(_) => KVS_NOT_FOUND,
+        KvsError::Serde(_) | KvsError::UnexpectedCommandType(_) | KvsError::Corruption(_) => {
+            KVS_CORRUPTION
+        }
+        KvsError::KeyCodecMismatch(_)
+        | KvsError::IndexNotFound(_)
+        | KvsError::UnexpectedFile(_)
+        | KvsError::SegmentLayoutMismatch(_)
+        | KvsError::StoreLocked(_)
+        | KvsError::WrongEngine(_)
+        | KvsError::WriteOnce(_)
+        | KvsError::HashCollision(_)
+        | KvsError::VersionMismatch(_)
+        | KvsError::UnsupportedFormat(_)
+        | KvsError::ReadOnly(_)
+        | KvsError::InvalidInput(_) => KVS_ERROR,
+    }
+}
+
+/// Borrows `len` bytes at `ptr`. A null pointer is only allowed for an empty
+/// slice.
+unsafe fn bytes<'a>(ptr: *const u8, len: usize) -> Option<&'a [u8]> {
+    if ptr.is_null() {
+        if len == 0 {
+            Some(&[])
+        } else {
+            None
+        }
+    } else {
+        Some(slice::from_raw_parts(ptr, len))
+    }
+}
+
+unsafe fn text(ptr: *const u8, len: usize) -> Option<String> {
+    bytes(ptr, len).and_then(|bytes| str::from_utf8(bytes).ok().map(str::to_owned))
+}
+
+#[cfg(unix)]
+fn to_path(bytes: &[u8]) -> Option<PathBuf> {
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+    Some(PathBuf::from(OsStr::from_bytes(bytes)))
+}
+
+#[cfg(not(unix))]
+fn to_path(bytes: &[u8]) -> Option<PathBuf> {
+    str::from_utf8(bytes).ok().map(PathBuf::from)
+}
@@ -0,0 +1,105 @@
+use kvs_ffi::*;
+use std::os::raw::{c_int, c_void};
+use std::ptr;
+use std::slice;
+use tempfile::TempDir;
+
+fn open(temp_dir: &TempDir) -> *mut kvs_store {
+    let path = temp_dir.path().to_str().unwrap();
+    let mut store = ptr::null_mut();
+    assert_eq!(
+        unsafe { kvs_open(path.as_ptr(), path.len(), &mut store) },
+        KVS_OK
+    );
+    store
+}
+
+unsafe fn get(store: *mut kvs_store, key: &str) -> (kvs_status, Option<String>) {
+    let mut value = ptr::null_mut();
+    let mut value_len = 0;
+    let status = kvs_get(store, key.as_ptr(), key.len(), &mut value, &mut value_len);
+    if status != KVS_OK {
+        return (status, None);
+    }
+    let found = String::from_utf8(slice::from_raw_parts(value, value_len).to_vec()).unwrap();
+    kvs_free(value, value_len);
+    (status, Some(found))
+}
+
+unsafe fn set(store: *mut kvs_store, key: &str, value: &str) -> kvs_status {
+    kvs_set(store, key.as_ptr(), key.len(), value.as_ptr(), value.len())
+}
+
+extern "C" fn collect(
+    ctx: *mut c_void,
+    key: *const u8,
+    key_len: usize,
+    value: *const u8,
+    value_len: usize,
+) -> c_int {
+    let entries = unsafe { &mut *(ctx as *mut Vec<(Vec<u8>, Vec<u8>)>) };
+    unsafe {
+        entries.push((
+            slice::from_raw_parts(key, key_len).to_vec(),
+            slice::from_raw_parts(value, value_len).to_vec(),
+        ));
+    }
+    (entries.len() == 2) as c_int
+}
+
+// Should get, set, remove and scan through the C API, and report status
+// codes rather than panicking on bad input.
+#[test]
+fn c_api() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open(&temp_dir);
+    unsafe {
+        assert_eq!(get(store, "key1"), (KVS_NOT_FOUND, None));
+        assert_eq!(set(store, "key1", "value1"), KVS_OK);
+        assert_eq!(set(store, "key2", "value2"), KVS_OK);
+        assert_eq!(set(store, "key3", "value3"), KVS_OK);
+        assert_eq!(set(store, "other", "value"), KVS_OK);
+        assert_eq!(get(store, "key1"), (KVS_OK, Some("value1".to_owned())));
+
+        assert_eq!(kvs_remove(store, b"key3".as_ptr(), 4), KVS_OK);
+        assert_eq!(kvs_remove(store, b"key3".as_ptr(), 4), KVS_NOT_FOUND);
+
+        let invalid = [0xff, 0xfe];
+        assert_eq!(
+            kvs_set(store, invalid.as_ptr(), invalid.len(), b"v".as_ptr(), 1),
+            KVS_INVALID_ARGUMENT
+        );
+        assert_eq!(
+            kvs_set(store, ptr::null(), 1, ptr::null(), 0),
+            KVS_INVALID_ARGUMENT
+        );
+
+        // The callback stops the scan after two entries.
+        assert_eq!(set(store, "key4", "value4"), KVS_OK);
+        let mut entries: Vec<(Vec<u8>, Vec<u8>)> = Vec::new();
+        let status = kvs_scan(
+            store,
+            b"key".as_ptr(),
+            3,
+            collect,
+            &mut entries as *mut _ as *mut c_void,
+        );
+        assert_eq!(status, KVS_OK);
+        assert_eq!(
+            entries,
+            vec![
+                (b"key1".to_vec(), b"value1".to_vec()),
+                (b"key2".to_vec(), b"value2".to_vec()),
+            ]
+        );
+        kvs_close(store);
+    }
+
+    // Open again to make sure the writes were persisted.
+    let store = open(&temp_dir);
+    unsafe {
+        assert_eq!(get(store, "key2"), (KVS_OK, Some("value2".to_owned())));
+        assert_eq!(get(store, "key3"), (KVS_NOT_FOUND, None));
+        kvs_close(store);
+    }
+}
@@ -2,6 +2,14 @@
 use crate::commands;
 use kvs::command_prelude::*;
 
+/// The exit codes every command sticks to.
+const EXIT_CODES: &str = "EXIT CODES:
+    0    Success, including `get` and `ttl` of a key that does not exist
+    1    Any other failure, such as an I/O error, invalid arguments or malformed input
+    2    A key that `rm`, `expire` or `persist` needs does not exist
+    3    The store is corrupted, or, with --strict, damaged blocks were skipped
+    4    The store is open in another process, which holds its lock";
+
 /// Builds an `App`. This `App` is comprised of information read from cargo
 /// environment variables, a list of settings, and a list of a list of all
 /// supported sub-commands.
@@ -17,5 +25,32 @@ pub fn app() -> App {
             AppSettings::AllowExternalSubcommands,
             AppSettings::SubcommandRequiredElseHelp,
         ])
+        .arg(
+            Arg::with_name("quiet")
+                .long("quiet")
+                .short("q")
+                .global(true)
+                .help("Print no status messages or progress bars"),
+        )
+        .arg(
+            Arg::with_name("verbose")
+                .long("verbose")
+                .short("v")
+                .global(true)
+                .help("Print a status message for every key a command changes"),
+        )
+        .arg(
+            Arg::with_name("strict")
+                .long("strict")
+                .global(true)
+                .help("Fail, rather than warn, when damaged blocks are skipped"),
+        )
+        .arg(
+            Arg::with_name("no-color")
+                .long("no-color")
+                .global(true)
+                .help("Print status messages without color (also set by NO_COLOR)"),
+        )
+        .after_help(EXIT_CODES)
         .subcommands(commands::all_sub_commands())
 }
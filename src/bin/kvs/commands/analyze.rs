@@ -0,0 +1,22 @@
+use kvs::command_prelude::{App, Arg, SubCommand};
+use kvs::{Analysis, Result};
+
+/// The number of keys sampled unless told otherwise.
+pub const DEFAULT_SAMPLE: &str = "1000";
+
+pub fn cli() -> App {
+    SubCommand::with_name("analyze")
+        .about("Summarize a random sample of the store's keys")
+        .arg(
+            Arg::with_name("sample")
+                .long("sample")
+                .value_name("KEYS")
+                .help("The number of keys to sample")
+                .default_value(DEFAULT_SAMPLE)
+                .validator(|s| s.parse::<usize>().map(|_| ()).map_err(|e| e.to_string())),
+        )
+}
+
+pub fn exec(sample: usize, strict: bool) -> Result<Analysis> {
+    super::open(strict)?.analyze(sample)
+}
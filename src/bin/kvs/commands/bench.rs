@@ -0,0 +1,133 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::{Duration, Instant};
+
+use kvs::command_prelude::{App, Arg, SubCommand};
+use kvs::{KvOpts, KvStore, MemFs, Result};
+
+/// The engines a store can run on, by the names `--engine` takes.
+pub const ENGINES: &[&str] = &["native", "memory"];
+
+pub fn cli() -> App {
+    SubCommand::with_name("bench")
+        .about("Time sets and gets against scratch stores")
+        .arg(
+            Arg::with_name("engine")
+                .long("engine")
+                .value_name("ENGINE")
+                .help("The engine to benchmark")
+                .possible_values(ENGINES)
+                .default_value("native"),
+        )
+        .arg(
+            Arg::with_name("compare")
+                .long("compare")
+                .help("Benchmark every engine and print them side by side")
+                .conflicts_with("engine"),
+        )
+        .arg(
+            Arg::with_name("keys")
+                .long("keys")
+                .value_name("KEYS")
+                .help("The number of keys to set and get")
+                .default_value("10000")
+                .validator(|s| s.parse::<u64>().map(|_| ()).map_err(|e| e.to_string())),
+        )
+        .arg(
+            Arg::with_name("value-size")
+                .long("value-size")
+                .value_name("BYTES")
+                .help("The size of every value; give several, separated by commas, to compare them")
+                .default_value("100")
+                .use_delimiter(true)
+                .validator(|s| s.parse::<usize>().map(|_| ()).map_err(|e| e.to_string())),
+        )
+}
+
+/// The timings of one engine at one value size.
+pub struct Row {
+    pub engine: &'static str,
+    pub value_size: usize,
+    pub keys: u64,
+    /// The time taken to set every key.
+    pub set: Duration,
+    /// The time taken to get every key back, in an order unrelated to the
+    /// one they were set in.
+    pub get: Duration,
+}
+
+impl Row {
+    /// Returns the operations per second done in `elapsed`.
+    pub fn rate(&self, elapsed: Duration) -> f64 {
+        self.keys as f64 / elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+}
+
+/// Benchmarks every engine in `engines` at every value size, each against a
+/// fresh store that is removed again afterwards.
+pub fn exec(engines: &[&'static str], keys: u64, value_sizes: &[usize]) -> Result<Vec<Row>> {
+    let mut rows = Vec::new();
+    for &engine in engines {
+        for &value_size in value_sizes {
+            rows.push(run(engine, keys, value_size)?);
+        }
+    }
+    Ok(rows)
+}
+
+fn run(engine: &'static str, keys: u64, value_size: usize) -> Result<Row> {
+    let dir = scratch_dir(engine);
+    let opts = match engine {
+        "memory" => KvOpts::new().fs(MemFs::new()),
+        _ => {
+            fs::create_dir_all(&dir)?;
+            KvOpts::new()
+        }
+    };
+    let result = time(&dir, opts, keys, value_size);
+    if engine != "memory" {
+        fs::remove_dir_all(&dir)?;
+    }
+    let (set, get) = result?;
+    Ok(Row {
+        engine,
+        value_size,
+        keys,
+        set,
+        get,
+    })
+}
+
+fn time(dir: &Path, opts: KvOpts, keys: u64, value_size: usize) -> Result<(Duration, Duration)> {
+    let mut store = KvStore::open_with_opts(dir, opts)?;
+    let value = "x".repeat(value_size);
+
+    let start = Instant::now();
+    for i in 0..keys {
+        store.set(format!("key{}", i), value.clone())?;
+    }
+    let set = start.elapsed();
+
+    // Stepping by a prime that does not divide the number of keys visits
+    // every key once, without reading them back in the order they were set.
+    let step = [7919, 7907, 7901]
+        .iter()
+        .copied()
+        .find(|step| !keys.is_multiple_of(*step))
+        .unwrap_or(1);
+    let mut got = String::new();
+    let start = Instant::now();
+    for i in 0..keys {
+        let key = format!("key{}", i * step % keys);
+        store.get_into(&key, &mut got)?;
+    }
+    let get = start.elapsed();
+    Ok((set, get))
+}
+
+/// Returns a directory for a scratch store that no other process uses.
+fn scratch_dir(engine: &str) -> PathBuf {
+    env::temp_dir().join(format!("kvs-bench-{}-{}", process::id(), engine))
+}
@@ -0,0 +1,47 @@
+use kvs::command_prelude::{App, Arg, SubCommand};
+use kvs::{KvStore, Progress, Result, Stats};
+
+pub fn cli() -> App {
+    SubCommand::with_name("compact")
+        .about("Reclaim the space taken up by stale commands")
+        .arg(
+            Arg::with_name("dry-run")
+                .long("dry-run")
+                .help("Report what compacting would write and reclaim, without compacting"),
+        )
+        .arg(
+            Arg::with_name("offline")
+                .long("offline")
+                .value_name("DIR")
+                .help(
+                    "Compact the store in DIR, such as a backup copy, instead of the current one",
+                ),
+        )
+}
+
+/// Compacts the store in `offline`, or else in the current directory,
+/// reporting progress to `progress`, unless `dry_run` is set, and returns
+/// its statistics from before the compaction.
+///
+/// A store is only ever compacted with its lock held, so a store that is
+/// open anywhere else is never compacted from under it. An offline store
+/// has to exist already: a mistyped path must not create an empty one.
+pub fn exec<F: FnMut(Progress)>(
+    offline: Option<&str>,
+    progress: F,
+    dry_run: bool,
+    strict: bool,
+) -> Result<Stats> {
+    let mut store = match offline {
+        Some(dir) => {
+            KvStore::validate(dir)?;
+            super::open_at(dir, strict)?
+        }
+        None => super::open(strict)?,
+    };
+    let stats = store.stats();
+    if !dry_run {
+        store.compact_with_progress(progress)?;
+    }
+    Ok(stats)
+}
@@ -0,0 +1,32 @@
+use kvs::command_prelude::{App, Arg, SubCommand};
+use kvs::{KvStore, Progress, Result, Stats};
+
+pub fn cli() -> App {
+    SubCommand::with_name("copy")
+        .about("Copy a store that is not open into another directory")
+        .arg(
+            Arg::with_name("SRC")
+                .help("The directory of the store to copy")
+                .required(true),
+        )
+        .arg(
+            Arg::with_name("DST")
+                .help("The directory to copy the store into")
+                .required(true),
+        )
+        .arg(
+            Arg::with_name("compact")
+                .long("compact")
+                .help("Compact the copy, leaving only live commands in fresh segments"),
+        )
+}
+
+/// Copies the store at `src` into `dst`, compacting the copy with `compact`
+/// and reporting progress to `progress`, and returns the copy's statistics.
+pub fn exec<F: FnMut(Progress)>(src: &str, dst: &str, compact: bool, progress: F) -> Result<Stats> {
+    let mut store = KvStore::copy(src, dst)?;
+    if compact {
+        store.compact_with_progress(progress)?;
+    }
+    Ok(store.stats())
+}
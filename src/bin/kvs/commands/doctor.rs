@@ -0,0 +1,13 @@
+use std::env;
+
+use kvs::command_prelude::{App, SubCommand};
+use kvs::{Check, KvStore, Result};
+
+pub fn cli() -> App {
+    SubCommand::with_name("doctor")
+        .about("Check the store's directory and environment for problems")
+}
+
+pub fn exec() -> Result<Vec<Check>> {
+    Ok(KvStore::diagnose(env::current_dir()?))
+}
@@ -0,0 +1,20 @@
+use std::time::Duration;
+
+use kvs::command_prelude::{App, Arg, SubCommand};
+use kvs::Result;
+
+pub fn cli() -> App {
+    SubCommand::with_name("expire")
+        .about("Set a given key to expire after a number of seconds")
+        .arg(Arg::with_name("KEY").help("A string key").required(true))
+        .arg(
+            Arg::with_name("SECONDS")
+                .help("The number of seconds until the key expires")
+                .required(true)
+                .validator(|s| s.parse::<u64>().map(|_| ()).map_err(|e| e.to_string())),
+        )
+}
+
+pub fn exec(key: String, seconds: u64, strict: bool) -> Result<()> {
+    super::open(strict)?.expire(key, Duration::from_secs(seconds))
+}
@@ -0,0 +1,82 @@
+use std::io::Write;
+
+use kvs::command_prelude::{App, Arg, ArgMatches, SubCommand};
+use kvs::{Result, ScanOpts};
+use serde::Serialize;
+
+pub fn cli() -> App {
+    SubCommand::with_name("export")
+        .about("Print keys as JSON lines of the form {\"key\": ..., \"value\": ...}")
+        .arg(
+            Arg::with_name("prefix")
+                .long("prefix")
+                .value_name("PREFIX")
+                .help("Only export keys starting with PREFIX"),
+        )
+        .arg(
+            Arg::with_name("range")
+                .long("range")
+                .value_name("FROM..TO")
+                .help("Only export keys at or after FROM and before TO; either may be left out")
+                .validator(|s| parse_range(&s).map(|_| ())),
+        )
+}
+
+/// A single exported record, as `kvs import` reads it.
+#[derive(Serialize)]
+pub struct Record<'a> {
+    pub key: &'a str,
+    pub value: &'a str,
+}
+
+/// Parses `FROM..TO`, either side of which may be empty.
+fn parse_range(range: &str) -> std::result::Result<(Option<&str>, Option<&str>), String> {
+    let (from, to) = range
+        .split_once("..")
+        .ok_or_else(|| format!("expected FROM..TO, not {}", range))?;
+    Ok((bound(from), bound(to)))
+}
+
+/// An empty side of a range leaves it open.
+fn bound(side: &str) -> Option<&str> {
+    Some(side).filter(|side| !side.is_empty())
+}
+
+/// Reads which keys to export from the command line.
+pub fn scan_opts(arg_matches: &ArgMatches) -> ScanOpts {
+    let mut opts = ScanOpts::new();
+    if let Some(prefix) = arg_matches.value_of("prefix") {
+        opts = opts.prefix(prefix);
+    }
+    if let Some(range) = arg_matches.value_of("range") {
+        let (from, to) = parse_range(range).expect("range was validated");
+        if let Some(from) = from {
+            opts = opts.from(from);
+        }
+        if let Some(to) = to {
+            opts = opts.to(to);
+        }
+    }
+    opts
+}
+
+/// Writes the keys picked out by `opts` to `out`, one value at a time, and
+/// returns how many were written.
+pub fn exec<W: Write>(opts: ScanOpts, out: &mut W, strict: bool) -> Result<u64> {
+    let mut store = super::open(strict)?;
+    let mut exported = 0;
+    for entry in store.scan_iter(opts) {
+        let (key, value) = entry?;
+        serde_json::to_writer(
+            &mut *out,
+            &Record {
+                key: &key,
+                value: &value,
+            },
+        )?;
+        out.write_all(b"\n")?;
+        exported += 1;
+    }
+    out.flush()?;
+    Ok(exported)
+}
@@ -1,7 +1,5 @@
-use std::env;
-
 use kvs::command_prelude::{App, Arg, SubCommand};
-use kvs::{KvOpts, KvStore, Result};
+use kvs::Result;
 
 pub fn cli() -> App {
     SubCommand::with_name("get")
@@ -9,6 +7,6 @@ pub fn cli() -> App {
         .arg(Arg::with_name("KEY").help("A string key").required(true))
 }
 
-pub fn exec(key: String) -> Result<Option<String>> {
-    KvStore::open_with_opts(env::current_dir()?, KvOpts {})?.get(key)
+pub fn exec(key: String, strict: bool) -> Result<Option<String>> {
+    super::open(strict)?.get(key)
 }
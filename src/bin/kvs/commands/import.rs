@@ -0,0 +1,202 @@
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+
+use kvs::command_prelude::{App, Arg, ArgMatches, SubCommand};
+use kvs::{KvsError, Result};
+use regex::Regex;
+use serde::de::{Deserializer, Error};
+use serde::Deserialize;
+
+pub fn cli() -> App {
+    SubCommand::with_name("import")
+        .about("Set keys from JSON lines of the form {\"key\": ..., \"value\": ...}")
+        .arg(
+            Arg::with_name("FILE")
+                .help("The file to read records from, or - for stdin")
+                .default_value("-"),
+        )
+        .arg(
+            Arg::with_name("overwrite")
+                .long("overwrite")
+                .help("Replace keys that already exist, rather than skip them"),
+        )
+        .arg(
+            Arg::with_name("dry-run")
+                .long("dry-run")
+                .help("Report what would be imported, without changing the store"),
+        )
+        .arg(
+            Arg::with_name("prefix")
+                .long("prefix")
+                .value_name("PREFIX")
+                .help("Prepend PREFIX to every key"),
+        )
+        .arg(
+            Arg::with_name("value-template")
+                .long("value-template")
+                .value_name("TEMPLATE")
+                .help(
+                    "Replace every value with TEMPLATE, in which {key} and {value} are filled in",
+                ),
+        )
+        .arg(
+            Arg::with_name("filter")
+                .long("filter")
+                .value_name("REGEX")
+                .help("Only import records whose key matches REGEX")
+                .validator(|s| Regex::new(&s).map(|_| ()).map_err(|e| e.to_string())),
+        )
+        .arg(
+            Arg::with_name("mapping")
+                .long("mapping")
+                .value_name("FILE")
+                .help("Read prefix, value_template and filter from a JSON file"),
+        )
+}
+
+/// A single record to import.
+#[derive(Deserialize)]
+struct Record {
+    key: String,
+    value: String,
+}
+
+/// How every record is changed on its way into the store.
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Transform {
+    prefix: Option<String>,
+    value_template: Option<String>,
+    #[serde(deserialize_with = "deserialize_filter", default)]
+    filter: Option<Regex>,
+}
+
+fn deserialize_filter<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<Option<Regex>, D::Error> {
+    match Option::<String>::deserialize(deserializer)? {
+        Some(filter) => Regex::new(&filter).map(Some).map_err(D::Error::custom),
+        None => Ok(None),
+    }
+}
+
+impl Transform {
+    /// Reads the transforms from the mapping file, if one is given, and the
+    /// flags, which win over it.
+    pub fn from_args(arg_matches: &ArgMatches) -> Result<Transform> {
+        let mut transform = match arg_matches.value_of("mapping") {
+            Some(path) => serde_json::from_reader(BufReader::new(File::open(path)?))
+                .map_err(|err| invalid_input(path, err))?,
+            None => Transform::default(),
+        };
+        if let Some(prefix) = arg_matches.value_of("prefix") {
+            transform.prefix = Some(prefix.to_owned());
+        }
+        if let Some(template) = arg_matches.value_of("value-template") {
+            transform.value_template = Some(template.to_owned());
+        }
+        if let Some(filter) = arg_matches.value_of("filter") {
+            transform.filter = Some(Regex::new(filter).expect("filter was validated"));
+        }
+        Ok(transform)
+    }
+
+    /// Applies the transforms to a record, or returns `None` if the record
+    /// is filtered out. The filter and the template see the record as it was
+    /// read.
+    fn apply(&self, record: Record) -> Option<(String, String)> {
+        if let Some(filter) = &self.filter {
+            if !filter.is_match(&record.key) {
+                return None;
+            }
+        }
+        let value = match &self.value_template {
+            Some(template) => template
+                .replace("{key}", &record.key)
+                .replace("{value}", &record.value),
+            None => record.value,
+        };
+        let key = match &self.prefix {
+            Some(prefix) => format!("{}{}", prefix, record.key),
+            None => record.key,
+        };
+        Some((key, value))
+    }
+}
+
+/// Reports a malformed file handed to `import`. Failing to read it is an
+/// I/O error like any other.
+fn invalid_input(file: &str, err: serde_json::Error) -> KvsError {
+    if err.is_io() {
+        return KvsError::Io(err.into());
+    }
+    let file = if file == "-" { "stdin" } else { file };
+    KvsError::InvalidInput(format!("{}: {}", file, err))
+}
+
+/// What an import did.
+pub struct Imported {
+    pub set: u64,
+    /// The bytes of the keys and values that were set.
+    pub bytes: u64,
+    /// Records whose key already existed, without `--overwrite`.
+    pub skipped: u64,
+    pub filtered: u64,
+}
+
+/// Imports the records in `file`, or with `dry_run` only works out what
+/// importing them would do, and reports what happens to every record's key
+/// to `item`.
+pub fn exec<F: FnMut(&str, &str)>(
+    file: &str,
+    transform: &Transform,
+    overwrite: bool,
+    dry_run: bool,
+    mut item: F,
+    strict: bool,
+) -> Result<Imported> {
+    let input: Box<dyn Read> = if file == "-" {
+        Box::new(io::stdin())
+    } else {
+        Box::new(File::open(file)?)
+    };
+    let mut store = super::open(strict)?;
+    let mut imported = Imported {
+        set: 0,
+        bytes: 0,
+        skipped: 0,
+        filtered: 0,
+    };
+    // A dry run leaves the store alone, so the keys it would have set have
+    // to be remembered to tell which later records would be skipped.
+    let mut would_set = HashSet::new();
+    let records = serde_json::Deserializer::from_reader(BufReader::new(input)).into_iter();
+    for record in records {
+        let record: Record = record.map_err(|err| invalid_input(file, err))?;
+        let original = record.key.clone();
+        let (key, value) = match transform.apply(record) {
+            Some(pair) => pair,
+            None => {
+                item("filtered out", &original);
+                imported.filtered += 1;
+                continue;
+            }
+        };
+        // A key has a TTL, or is persistent, exactly when it exists.
+        if !overwrite && (would_set.contains(&key) || store.ttl(key.clone())?.is_some()) {
+            item("skipped", &key);
+            imported.skipped += 1;
+            continue;
+        }
+        item(if dry_run { "would set" } else { "set" }, &key);
+        imported.set += 1;
+        imported.bytes += (key.len() + value.len()) as u64;
+        if dry_run {
+            would_set.insert(key);
+        } else {
+            store.set(key, value)?;
+        }
+    }
+    Ok(imported)
+}
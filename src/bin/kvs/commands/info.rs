@@ -0,0 +1,10 @@
+use kvs::command_prelude::{App, SubCommand};
+use kvs::{Result, StoreMeta};
+
+pub fn cli() -> App {
+    SubCommand::with_name("info").about("Show the store's metadata")
+}
+
+pub fn exec(strict: bool) -> Result<StoreMeta> {
+    Ok(super::open(strict)?.info().clone())
+}
@@ -0,0 +1,18 @@
+use std::path::PathBuf;
+
+use kvs::command_prelude::{App, Arg, SubCommand};
+use kvs::{KvStore, Result};
+
+pub fn cli() -> App {
+    SubCommand::with_name("list-stores")
+        .about("List every store at or beneath a directory")
+        .arg(
+            Arg::with_name("ROOT")
+                .help("The directory to search")
+                .default_value("."),
+        )
+}
+
+pub fn exec(root: &str) -> Result<Vec<PathBuf>> {
+    KvStore::discover(root)
+}
@@ -1,9 +1,110 @@
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
 use kvs::command_prelude::*;
+use kvs::{KvOpts, KvStore, KvsError, Result, Watchdog};
+
+/// How often a store opened by a command publishes its operation counts,
+/// for `kvs top` to read.
+const PUBLISH_STATS_EVERY: Duration = Duration::from_secs(1);
 
 pub fn all_sub_commands() -> Vec<App> {
-    vec![get::cli(), set::cli(), remove::cli()]
+    vec![
+        get::cli(),
+        set::cli(),
+        remove::cli(),
+        info::cli(),
+        expire::cli(),
+        persist::cli(),
+        ttl::cli(),
+        analyze::cli(),
+        top::cli(),
+        compact::cli(),
+        doctor::cli(),
+        import::cli(),
+        export::cli(),
+        undelete::cli(),
+        list_stores::cli(),
+        copy::cli(),
+        bench::cli(),
+        upgrade::cli(),
+        sample::cli(),
+    ]
+}
+
+/// Opens the store in the current directory, as [`open_at`] does.
+///
+/// [`open_at`]: fn.open_at.html
+pub fn open(strict: bool) -> Result<KvStore> {
+    open_at(env::current_dir()?, strict)
+}
+
+/// Opens the store in `path`, creating it if it does not exist, as
+/// [`open_with`] does.
+///
+/// [`open_with`]: fn.open_with.html
+pub fn open_at<P: AsRef<Path>>(path: P, strict: bool) -> Result<KvStore> {
+    fs::create_dir_all(path.as_ref())?;
+    open_with(
+        path,
+        KvOpts::new().publish_stats(PUBLISH_STATS_EVERY),
+        strict,
+    )
+}
+
+/// Opens the store in `path` with `opts`, warning of syncs and compactions
+/// that stall.
+///
+/// Damaged blocks skipped while opening the store, and unexpected files in
+/// its directory, are warnings, or errors if `strict` is set. Leftovers of
+/// an interrupted compaction that were removed are always just warnings.
+pub fn open_with<P: AsRef<Path>>(path: P, opts: KvOpts, strict: bool) -> Result<KvStore> {
+    let watchdog = Watchdog::new().on_stall(|stall| eprintln!("warning: {}", stall));
+    let store = KvStore::open_with_opts(path, opts.watchdog(watchdog))?;
+    let damaged = store.damaged_blocks();
+    if damaged > 0 {
+        let message = format!(
+            "skipped {} damaged block(s) while opening the store",
+            damaged
+        );
+        if strict {
+            return Err(KvsError::Corruption(message));
+        }
+        eprintln!("warning: {}", message);
+    }
+    for file in store.removed_orphans() {
+        eprintln!(
+            "warning: removed the leftovers of an interrupted compaction: {}",
+            file.display()
+        );
+    }
+    for file in store.unexpected_files() {
+        if strict {
+            return Err(KvsError::UnexpectedFile(file.display().to_string()));
+        }
+        eprintln!("warning: unexpected file in the store: {}", file.display());
+    }
+    Ok(store)
 }
 
+pub mod analyze;
+pub mod bench;
+pub mod compact;
+pub mod copy;
+pub mod doctor;
+pub mod expire;
+pub mod export;
 pub mod get;
+pub mod import;
+pub mod info;
+pub mod list_stores;
+pub mod persist;
 pub mod remove;
+pub mod sample;
 pub mod set;
+pub mod top;
+pub mod ttl;
+pub mod undelete;
+pub mod upgrade;
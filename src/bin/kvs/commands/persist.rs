@@ -0,0 +1,12 @@
+use kvs::command_prelude::{App, Arg, SubCommand};
+use kvs::Result;
+
+pub fn cli() -> App {
+    SubCommand::with_name("persist")
+        .about("Clear the expiry of a given key")
+        .arg(Arg::with_name("KEY").help("A string key").required(true))
+}
+
+pub fn exec(key: String, strict: bool) -> Result<()> {
+    super::open(strict)?.persist(key)
+}
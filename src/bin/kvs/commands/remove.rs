@@ -1,14 +1,40 @@
-use std::env;
-
 use kvs::command_prelude::{App, Arg, SubCommand};
-use kvs::{KvStore, Result};
+use kvs::{KvsError, Result};
 
 pub fn cli() -> App {
     SubCommand::with_name("rm")
         .about("Remove a given key")
         .arg(Arg::with_name("KEY").help("A string key").required(true))
+        .arg(
+            Arg::with_name("force")
+                .long("force")
+                .short("f")
+                .help("Succeed even if the key does not exist"),
+        )
+        .arg(
+            Arg::with_name("dry-run")
+                .long("dry-run")
+                .help("Report whether the key would be removed, without removing it"),
+        )
 }
 
-pub fn exec(key: String) -> Result<()> {
-    KvStore::open(env::current_dir()?)?.remove(key)
+/// Removes the key, or with `dry_run` only checks that it exists, and
+/// returns whether it was, or would have been, removed.
+pub fn exec(key: String, force: bool, dry_run: bool, strict: bool) -> Result<bool> {
+    let mut store = super::open(strict)?;
+    if dry_run {
+        // A key has a TTL, or is persistent, exactly when it exists.
+        let exists = store.ttl(key.clone())?.is_some();
+        if !exists && !force {
+            return Err(KvsError::KeyNotFound(format!(
+                "could not find key: {}",
+                key
+            )));
+        }
+        Ok(exists)
+    } else if force {
+        store.remove_if_exists(key)
+    } else {
+        store.remove(key).map(|()| true)
+    }
 }
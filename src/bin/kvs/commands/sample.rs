@@ -0,0 +1,40 @@
+use std::io::Write;
+
+use kvs::command_prelude::{App, Arg, SubCommand};
+use kvs::Result;
+
+use super::export::Record;
+
+pub fn cli() -> App {
+    SubCommand::with_name("sample")
+        .about("Print a random sample of keys as JSON lines, as `kvs export` does")
+        .arg(
+            Arg::with_name("count")
+                .long("count")
+                .value_name("N")
+                .help("The number of keys to sample")
+                .required(true)
+                .validator(|s| s.parse::<usize>().map(|_| ()).map_err(|e| e.to_string())),
+        )
+        .arg(
+            Arg::with_name("seed")
+                .long("seed")
+                .value_name("S")
+                .help("Picks the keys; the same seed gives the same sample of the same store")
+                .default_value("0")
+                .validator(|s| s.parse::<u64>().map(|_| ()).map_err(|e| e.to_string())),
+        )
+}
+
+/// Writes a sample of up to `count` keys to `out`, in key order, and returns
+/// how many were written.
+pub fn exec<W: Write>(count: usize, seed: u64, out: &mut W, strict: bool) -> Result<u64> {
+    let mut store = super::open(strict)?;
+    let sample = store.sample(count, seed)?;
+    for (key, value) in &sample {
+        serde_json::to_writer(&mut *out, &Record { key, value })?;
+        out.write_all(b"\n")?;
+    }
+    out.flush()?;
+    Ok(sample.len() as u64)
+}
@@ -1,7 +1,5 @@
-use std::env;
-
 use kvs::command_prelude::{App, Arg, SubCommand};
-use kvs::{KvStore, Result};
+use kvs::Result;
 
 pub fn cli() -> App {
     SubCommand::with_name("set")
@@ -14,6 +12,6 @@ pub fn cli() -> App {
         )
 }
 
-pub fn exec(key: String, value: String) -> Result<()> {
-    KvStore::open(env::current_dir()?)?.set(key, value)
+pub fn exec(key: String, value: String, strict: bool) -> Result<()> {
+    super::open(strict)?.set(key, value)
 }
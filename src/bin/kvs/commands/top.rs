@@ -0,0 +1,185 @@
+use std::env;
+use std::fmt::Write;
+use std::num::ParseFloatError;
+use std::time::{Duration, Instant};
+
+use kvs::command_prelude::{App, Arg, SubCommand};
+use kvs::{KvOpts, KvStore, OpCounts, Prefix, Result, Stats};
+
+use super::analyze::DEFAULT_SAMPLE;
+
+/// The number of seconds between refreshes unless told otherwise.
+pub const DEFAULT_INTERVAL: &str = "1";
+
+/// The number of prefixes shown.
+const PREFIX_ROWS: usize = 10;
+
+pub fn cli() -> App {
+    SubCommand::with_name("top")
+        .about("Watch the store's statistics as they change")
+        .arg(
+            Arg::with_name("interval")
+                .long("interval")
+                .value_name("SECONDS")
+                .help("The number of seconds between refreshes")
+                .default_value(DEFAULT_INTERVAL)
+                .validator(|s| parse_interval(&s).map(|_| ())),
+        )
+        .arg(
+            Arg::with_name("iterations")
+                .long("iterations")
+                .value_name("N")
+                .help("Stop after N refreshes")
+                .validator(|s| s.parse::<u64>().map(|_| ()).map_err(|e| e.to_string())),
+        )
+        .arg(
+            Arg::with_name("sample")
+                .long("sample")
+                .value_name("KEYS")
+                .help("The number of keys to sample for the hottest prefixes")
+                .default_value(DEFAULT_SAMPLE)
+                .validator(|s| s.parse::<usize>().map(|_| ()).map_err(|e| e.to_string())),
+        )
+}
+
+/// Parses a number of seconds between refreshes, which has to be finite and
+/// greater than zero.
+pub fn parse_interval(secs: &str) -> std::result::Result<Duration, String> {
+    let secs: f64 = secs.parse().map_err(|e: ParseFloatError| e.to_string())?;
+    match Duration::try_from_secs_f64(secs) {
+        Ok(interval) if secs > 0.0 => Ok(interval),
+        _ => Err(format!(
+            "expected a number of seconds greater than zero, not {}",
+            secs
+        )),
+    }
+}
+
+/// The state of the store at one refresh.
+pub struct Snapshot {
+    taken: Instant,
+    stats: Stats,
+    prefixes: Vec<Prefix>,
+    segments: u64,
+    disk_bytes: u64,
+    ops: Option<OpCounts>,
+}
+
+/// Reads the state of the store in the current directory. The store is
+/// opened read-only, so it can be watched while another process has it
+/// open, and is left exactly as it was.
+pub fn exec(sample: usize, strict: bool) -> Result<Snapshot> {
+    let dir = env::current_dir()?;
+    let info = KvStore::validate(&dir)?;
+    let mut store = super::open_with(&dir, KvOpts::new().read_only(true), strict)?;
+    let stats = store.stats();
+    let prefixes = store.analyze(sample)?.prefixes;
+
+    Ok(Snapshot {
+        taken: Instant::now(),
+        stats,
+        prefixes,
+        segments: info.segments.len() as u64,
+        disk_bytes: info.size,
+        ops: info.ops,
+    })
+}
+
+/// Renders a snapshot, with rates of change since the previous one.
+pub fn render(snapshot: &Snapshot, previous: Option<&Snapshot>, compactions: u64) -> String {
+    let secs = previous.map(|previous| {
+        let secs = snapshot.taken.duration_since(previous.taken).as_secs_f64();
+        secs.max(1e-3)
+    });
+    let rate = |now: u64, then: Option<u64>| match (secs, then) {
+        (Some(secs), Some(then)) => format!("  {:+.1}/s", (now as f64 - then as f64) / secs),
+        _ => String::new(),
+    };
+
+    let stats = &snapshot.stats;
+    let mut frame = String::new();
+    // Only a store that publishes its operation counts has any to show.
+    let by_op = |ops: &OpCounts| [ops.sets, ops.gets, ops.removes];
+    let now = snapshot.ops.as_ref().map(by_op);
+    let then = previous
+        .and_then(|previous| previous.ops.as_ref())
+        .map(by_op);
+    for (i, name) in ["sets", "gets", "removes"].iter().enumerate() {
+        match now {
+            Some(now) => {
+                let _ = writeln!(
+                    frame,
+                    "{:<16}{:>14}{}",
+                    name,
+                    now[i],
+                    rate(now[i], then.map(|then| then[i]))
+                );
+            }
+            None => {
+                let _ = writeln!(frame, "{:<16}{:>14}", name, "-");
+            }
+        }
+    }
+    let _ = writeln!(
+        frame,
+        "{:<16}{:>14}{}",
+        "keys",
+        stats.keys,
+        rate(stats.keys, previous.map(|s| s.stats.keys))
+    );
+    let _ = writeln!(
+        frame,
+        "{:<16}{:>14}{}",
+        "live bytes",
+        stats.live_bytes,
+        rate(stats.live_bytes, previous.map(|s| s.stats.live_bytes))
+    );
+    let _ = writeln!(
+        frame,
+        "{:<16}{:>14}{}",
+        "stale bytes",
+        stats.stale_bytes,
+        rate(stats.stale_bytes, previous.map(|s| s.stats.stale_bytes))
+    );
+    let _ = writeln!(
+        frame,
+        "{:<16}{:>14}",
+        "stale in 1h", stats.reclaim_forecast.in_an_hour
+    );
+    let _ = writeln!(
+        frame,
+        "{:<16}{:>14}",
+        "stale in 1d", stats.reclaim_forecast.in_a_day
+    );
+    let _ = writeln!(
+        frame,
+        "{:<16}{:>14}{}",
+        "bytes on disk",
+        snapshot.disk_bytes,
+        rate(snapshot.disk_bytes, previous.map(|s| s.disk_bytes))
+    );
+    let _ = writeln!(frame, "{:<16}{:>14}", "segments", snapshot.segments);
+    let _ = writeln!(frame, "{:<16}{:>14}", "compactions", compactions);
+    let _ = writeln!(
+        frame,
+        "{:<16}{:>14}",
+        "damaged blocks", stats.damaged_blocks
+    );
+
+    let _ = writeln!(frame);
+    let _ = writeln!(frame, "{:<30}{:>10}{:>14}", "prefix", "keys", "bytes");
+    for prefix in snapshot.prefixes.iter().take(PREFIX_ROWS) {
+        let _ = writeln!(
+            frame,
+            "{:<30}{:>10}{:>14}",
+            prefix.prefix, prefix.count, prefix.bytes
+        );
+    }
+    frame
+}
+
+/// Returns whether the store was compacted between two snapshots. Compacting
+/// is the only thing that makes stale bytes go down.
+pub fn compacted(snapshot: &Snapshot, previous: &Snapshot) -> bool {
+    snapshot.stats.stale_bytes < previous.stats.stale_bytes
+}
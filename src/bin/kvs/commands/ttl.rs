@@ -0,0 +1,12 @@
+use kvs::command_prelude::{App, Arg, SubCommand};
+use kvs::{Result, Ttl};
+
+pub fn cli() -> App {
+    SubCommand::with_name("ttl")
+        .about("Get the number of seconds until a given key expires")
+        .arg(Arg::with_name("KEY").help("A string key").required(true))
+}
+
+pub fn exec(key: String, strict: bool) -> Result<Option<Ttl>> {
+    super::open(strict)?.ttl(key)
+}
@@ -0,0 +1,27 @@
+use std::env;
+use std::path::PathBuf;
+
+use kvs::command_prelude::{App, Arg, SubCommand};
+use kvs::{KvStore, Result};
+
+pub fn cli() -> App {
+    SubCommand::with_name("upgrade")
+        .about("Rewrite a store that is not open in the newest on-disk format")
+        .arg(
+            Arg::with_name("dir")
+                .long("dir")
+                .value_name("DIR")
+                .help("Upgrade the store in DIR instead of the current one"),
+        )
+}
+
+/// Upgrades the store in `dir`, or else in the current directory, and
+/// returns its directory along with whether it needed upgrading.
+pub fn exec(dir: Option<&str>) -> Result<(PathBuf, bool)> {
+    let dir = match dir {
+        Some(dir) => PathBuf::from(dir),
+        None => env::current_dir()?,
+    };
+    let upgraded = KvStore::upgrade(&dir)?;
+    Ok((dir, upgraded))
+}
@@ -1,14 +1,54 @@
-use std::io::{self, Write};
+use std::io::{self, IsTerminal, Write};
 use std::process::exit;
+use std::thread;
 
-use kvs::Result;
+use kvs::{CheckStatus, KvsError, Result, Ttl};
+
+use output::{human_bytes, Output};
 
 mod cli;
 mod commands;
+mod output;
+
+/// The exit code for any failure without a code of its own.
+const EXIT_FAILURE: i32 = 1;
+/// The exit code for a command that needs a key that does not exist.
+const EXIT_KEY_NOT_FOUND: i32 = 2;
+/// The exit code for a store that is corrupted, or, with `--strict`, had
+/// damaged blocks skipped.
+const EXIT_CORRUPTION: i32 = 3;
+/// The exit code for a store that is open in another process.
+const EXIT_LOCKED: i32 = 4;
 
-fn main() -> Result<()> {
+fn main() {
     // run the cli app
-    Ok(run(cli::app())?)
+    if let Err(err) = run(cli::app()) {
+        eprintln!("Error: {}", err);
+        exit(exit_code(&err));
+    }
+}
+
+fn exit_code(err: &KvsError) -> i32 {
+    match err {
+        KvsError::KeyNotFound(_) => EXIT_KEY_NOT_FOUND,
+        // Anything malformed that was read from the store's own files is
+        // corruption; malformed input is a failure like any other.
+        KvsError::Corruption(_) | KvsError::Serde(_) | KvsError::UnexpectedCommandType(_) => {
+            EXIT_CORRUPTION
+        }
+        KvsError::StoreLocked(_) => EXIT_LOCKED,
+        _ => EXIT_FAILURE,
+    }
+}
+
+/// Reports a missing key on stderr and exits.
+fn key_not_found() -> ! {
+    eprintln!("Key not found");
+    exit(EXIT_KEY_NOT_FOUND);
+}
+
+fn strict(arg_matches: &clap::ArgMatches) -> bool {
+    arg_matches.is_present("strict")
 }
 
 /// Executes a cli app. This function parses the command line arguments and
@@ -18,8 +58,24 @@ fn run(app: clap::App<'static, 'static>) -> Result<()> {
         ("get", Some(args)) => get(args),
         ("rm", Some(args)) => remove(args),
         ("set", Some(args)) => set(args),
+        ("info", Some(args)) => info(args),
+        ("expire", Some(args)) => expire(args),
+        ("persist", Some(args)) => persist(args),
+        ("ttl", Some(args)) => ttl(args),
+        ("analyze", Some(args)) => analyze(args),
+        ("top", Some(args)) => top(args),
+        ("compact", Some(args)) => compact(args),
+        ("doctor", Some(_)) => doctor(),
+        ("import", Some(args)) => import(args),
+        ("export", Some(args)) => export(args),
+        ("undelete", Some(args)) => undelete(args),
+        ("list-stores", Some(args)) => list_stores(args),
+        ("copy", Some(args)) => copy(args),
+        ("bench", Some(args)) => bench(args),
+        ("upgrade", Some(args)) => upgrade(args),
+        ("sample", Some(args)) => sample(args),
         _ => {
-            exit(1);
+            exit(EXIT_FAILURE);
         }
     }
 }
@@ -30,10 +86,10 @@ fn get(arg_matches: &clap::ArgMatches) -> Result<()> {
         .map(String::from)
         .expect("KEY argument missing");
 
-    if let Some(value) = commands::get::exec(key)? {
+    if let Some(value) = commands::get::exec(key, strict(arg_matches))? {
         io::stdout().write_fmt(format_args!("{}", value))?;
     } else {
-        io::stdout().write(b"Key not found")?;
+        io::stdout().write_all(b"Key not found")?;
     }
     Ok(())
 }
@@ -49,7 +105,7 @@ fn set(arg_matches: &clap::ArgMatches) -> Result<()> {
         .map(String::from)
         .expect("VALUE argument missing");
 
-    commands::set::exec(key, value)
+    commands::set::exec(key, value, strict(arg_matches))
 }
 
 fn remove(arg_matches: &clap::ArgMatches) -> Result<()> {
@@ -58,12 +114,346 @@ fn remove(arg_matches: &clap::ArgMatches) -> Result<()> {
         .map(String::from)
         .expect("KEY argument missing");
 
-    match commands::remove::exec(key) {
-        Ok(()) => {}
-        Err(_) => {
-            io::stdout().write(b"Key not found")?;
-            exit(2);
+    let force = arg_matches.is_present("force");
+    let dry_run = arg_matches.is_present("dry-run");
+    let removed = match commands::remove::exec(key.clone(), force, dry_run, strict(arg_matches)) {
+        Err(KvsError::KeyNotFound(_)) => key_not_found(),
+        result => result?,
+    };
+    let output = Output::new(arg_matches);
+    if removed {
+        output.item(if dry_run { "would remove" } else { "removed" }, &key);
+    }
+    if dry_run {
+        output.status("Would remove", &format!("{} key(s)", removed as u64));
+    }
+    Ok(())
+}
+
+fn info(arg_matches: &clap::ArgMatches) -> Result<()> {
+    let meta = commands::info::exec(strict(arg_matches))?;
+    serde_json::to_writer_pretty(io::stdout(), &meta)?;
+    io::stdout().write_all(b"\n")?;
+    Ok(())
+}
+
+fn expire(arg_matches: &clap::ArgMatches) -> Result<()> {
+    let key = arg_matches
+        .value_of("KEY")
+        .map(String::from)
+        .expect("KEY argument missing");
+
+    let seconds = arg_matches
+        .value_of("SECONDS")
+        .and_then(|seconds| seconds.parse().ok())
+        .expect("SECONDS argument missing");
+
+    match commands::expire::exec(key, seconds, strict(arg_matches)) {
+        Err(KvsError::KeyNotFound(_)) => key_not_found(),
+        result => result,
+    }
+}
+
+fn persist(arg_matches: &clap::ArgMatches) -> Result<()> {
+    let key = arg_matches
+        .value_of("KEY")
+        .map(String::from)
+        .expect("KEY argument missing");
+
+    match commands::persist::exec(key, strict(arg_matches)) {
+        Err(KvsError::KeyNotFound(_)) => key_not_found(),
+        result => result,
+    }
+}
+
+fn undelete(arg_matches: &clap::ArgMatches) -> Result<()> {
+    let key = arg_matches
+        .value_of("KEY")
+        .map(String::from)
+        .expect("KEY argument missing");
+
+    match commands::undelete::exec(key, strict(arg_matches)) {
+        Err(KvsError::KeyNotFound(_)) => key_not_found(),
+        result => result,
+    }
+}
+
+fn list_stores(arg_matches: &clap::ArgMatches) -> Result<()> {
+    let root = arg_matches.value_of("ROOT").expect("ROOT argument missing");
+    let mut stdout = io::stdout();
+    for store in commands::list_stores::exec(root)? {
+        writeln!(stdout, "{}", store.display())?;
+    }
+    Ok(())
+}
+
+fn ttl(arg_matches: &clap::ArgMatches) -> Result<()> {
+    let key = arg_matches
+        .value_of("KEY")
+        .map(String::from)
+        .expect("KEY argument missing");
+
+    match commands::ttl::exec(key, strict(arg_matches))? {
+        Some(Ttl::Expires(remaining)) => {
+            io::stdout().write_fmt(format_args!("{}", remaining.as_secs()))?;
+        }
+        Some(Ttl::Persistent) => io::stdout().write_all(b"No expiry")?,
+        None => io::stdout().write_all(b"Key not found")?,
+    }
+    Ok(())
+}
+
+fn analyze(arg_matches: &clap::ArgMatches) -> Result<()> {
+    let sample = arg_matches
+        .value_of("sample")
+        .and_then(|sample| sample.parse().ok())
+        .expect("sample argument missing");
+
+    let analysis = commands::analyze::exec(sample, strict(arg_matches))?;
+    serde_json::to_writer_pretty(io::stdout(), &analysis)?;
+    io::stdout().write_all(b"\n")?;
+    Ok(())
+}
+
+fn top(arg_matches: &clap::ArgMatches) -> Result<()> {
+    let interval = arg_matches
+        .value_of("interval")
+        .map(|interval| commands::top::parse_interval(interval).expect("interval was validated"))
+        .expect("interval argument missing");
+
+    let iterations: Option<u64> = arg_matches
+        .value_of("iterations")
+        .and_then(|iterations| iterations.parse().ok());
+
+    let sample = arg_matches
+        .value_of("sample")
+        .and_then(|sample| sample.parse().ok())
+        .expect("sample argument missing");
+
+    // Only redraw in place when a person is watching.
+    let clear = io::stdout().is_terminal();
+    let mut previous = None;
+    let mut compactions = 0;
+    let mut refreshes = 0;
+    loop {
+        let snapshot = commands::top::exec(sample, strict(arg_matches))?;
+        if let Some(previous) = &previous {
+            compactions += commands::top::compacted(&snapshot, previous) as u64;
+        }
+
+        let mut stdout = io::stdout();
+        if clear {
+            stdout.write_all(b"\x1b[H\x1b[2J")?;
+        }
+        stdout.write_all(
+            commands::top::render(&snapshot, previous.as_ref(), compactions).as_bytes(),
+        )?;
+        stdout.flush()?;
+
+        refreshes += 1;
+        if Some(refreshes) == iterations {
+            return Ok(());
+        }
+        if !clear {
+            stdout.write_all(b"\n")?;
+        }
+        previous = Some(snapshot);
+        thread::sleep(interval);
+    }
+}
+
+fn compact(arg_matches: &clap::ArgMatches) -> Result<()> {
+    let output = Output::new(arg_matches);
+    let mut bar = output.progress("Compacting");
+    let dry_run = arg_matches.is_present("dry-run");
+    let stats = commands::compact::exec(
+        arg_matches.value_of("offline"),
+        |progress| bar.update(progress),
+        dry_run,
+        strict(arg_matches),
+    )?;
+    bar.finish();
+    if dry_run {
+        output.status(
+            "Would compact",
+            &format!(
+                "{} live, reclaiming {}",
+                human_bytes(stats.live_bytes),
+                human_bytes(stats.stale_bytes)
+            ),
+        );
+        return Ok(());
+    }
+    output.status(
+        "Compacted",
+        &format!(
+            "{} live, reclaimed {}",
+            human_bytes(stats.live_bytes),
+            human_bytes(stats.stale_bytes)
+        ),
+    );
+    Ok(())
+}
+
+fn copy(arg_matches: &clap::ArgMatches) -> Result<()> {
+    let src = arg_matches.value_of("SRC").expect("SRC argument missing");
+    let dst = arg_matches.value_of("DST").expect("DST argument missing");
+    let output = Output::new(arg_matches);
+    let mut bar = output.progress("Compacting");
+    let compact = arg_matches.is_present("compact");
+    let stats = commands::copy::exec(src, dst, compact, |progress| bar.update(progress))?;
+    bar.finish();
+    output.status(
+        "Copied",
+        &format!(
+            "{} key(s), {} live, {} stale",
+            stats.keys,
+            human_bytes(stats.live_bytes),
+            human_bytes(stats.stale_bytes)
+        ),
+    );
+    Ok(())
+}
+
+fn upgrade(arg_matches: &clap::ArgMatches) -> Result<()> {
+    let output = Output::new(arg_matches);
+    let (dir, upgraded) = commands::upgrade::exec(arg_matches.value_of("dir"))?;
+    if upgraded {
+        output.status("Upgraded", &dir.display().to_string());
+    } else {
+        output.status(
+            "Unchanged",
+            &format!("{} is in the newest format already", dir.display()),
+        );
+    }
+    Ok(())
+}
+
+fn bench(arg_matches: &clap::ArgMatches) -> Result<()> {
+    let engines = if arg_matches.is_present("compare") {
+        commands::bench::ENGINES.to_vec()
+    } else {
+        let engine = arg_matches
+            .value_of("engine")
+            .expect("engine argument missing");
+        commands::bench::ENGINES
+            .iter()
+            .copied()
+            .filter(|&name| name == engine)
+            .collect()
+    };
+    let keys = arg_matches
+        .value_of("keys")
+        .and_then(|keys| keys.parse().ok())
+        .expect("keys argument missing");
+    let value_sizes: Vec<usize> = arg_matches
+        .values_of("value-size")
+        .expect("value-size argument missing")
+        .map(|size| size.parse().expect("value size was validated"))
+        .collect();
+
+    let rows = commands::bench::exec(&engines, keys, &value_sizes)?;
+    let mut stdout = io::stdout();
+    writeln!(
+        stdout,
+        "{:<8}{:>12}{:>10}{:>14}{:>14}",
+        "engine", "value size", "keys", "sets/s", "gets/s"
+    )?;
+    for row in &rows {
+        writeln!(
+            stdout,
+            "{:<8}{:>12}{:>10}{:>14.0}{:>14.0}",
+            row.engine,
+            human_bytes(row.value_size as u64),
+            row.keys,
+            row.rate(row.set),
+            row.rate(row.get)
+        )?;
+    }
+    Ok(())
+}
+
+fn doctor() -> Result<()> {
+    let checks = commands::doctor::exec()?;
+    let mut stdout = io::stdout();
+    for check in &checks {
+        let status = match check.status {
+            CheckStatus::Ok => "ok",
+            CheckStatus::Warning => "warning",
+            CheckStatus::Error => "error",
+            CheckStatus::Skipped => "skipped",
+        };
+        writeln!(stdout, "{:<9}{}: {}", status, check.name, check.detail)?;
+        if let Some(advice) = &check.advice {
+            writeln!(stdout, "{:<9}-> {}", "", advice)?;
         }
     }
+    if checks
+        .iter()
+        .any(|check| check.status == CheckStatus::Error)
+    {
+        exit(EXIT_FAILURE);
+    }
+    Ok(())
+}
+
+fn import(arg_matches: &clap::ArgMatches) -> Result<()> {
+    let file = arg_matches.value_of("FILE").expect("FILE argument missing");
+    let transform = commands::import::Transform::from_args(arg_matches)?;
+    let overwrite = arg_matches.is_present("overwrite");
+    let dry_run = arg_matches.is_present("dry-run");
+    let output = Output::new(arg_matches);
+    let imported = commands::import::exec(
+        file,
+        &transform,
+        overwrite,
+        dry_run,
+        |action, key| output.item(action, key),
+        strict(arg_matches),
+    )?;
+    if dry_run {
+        output.status(
+            "Would import",
+            &format!(
+                "{} key(s) ({}), skip {} existing, filter out {}",
+                imported.set,
+                human_bytes(imported.bytes),
+                imported.skipped,
+                imported.filtered
+            ),
+        );
+        return Ok(());
+    }
+    output.status(
+        "Imported",
+        &format!(
+            "{} key(s), skipped {} existing, filtered out {}",
+            imported.set, imported.skipped, imported.filtered
+        ),
+    );
+    Ok(())
+}
+
+fn sample(arg_matches: &clap::ArgMatches) -> Result<()> {
+    let count = arg_matches
+        .value_of("count")
+        .and_then(|count| count.parse().ok())
+        .expect("count argument missing");
+    let seed = arg_matches
+        .value_of("seed")
+        .and_then(|seed| seed.parse().ok())
+        .expect("seed argument missing");
+
+    let mut stdout = io::BufWriter::new(io::stdout().lock());
+    let sampled = commands::sample::exec(count, seed, &mut stdout, strict(arg_matches))?;
+    Output::new(arg_matches).status("Sampled", &format!("{} key(s)", sampled));
+    Ok(())
+}
+
+fn export(arg_matches: &clap::ArgMatches) -> Result<()> {
+    let opts = commands::export::scan_opts(arg_matches);
+    let mut stdout = io::BufWriter::new(io::stdout().lock());
+    let exported = commands::export::exec(opts, &mut stdout, strict(arg_matches))?;
+    Output::new(arg_matches).status("Exported", &format!("{} key(s)", exported));
     Ok(())
 }
@@ -0,0 +1,149 @@
+//! # Status and progress output.
+//!
+//! Everything here goes to stderr, so it never mixes with a command's
+//! results on stdout.
+use std::env;
+use std::io::{self, IsTerminal, Write};
+use std::time::{Duration, Instant};
+
+use kvs::command_prelude::ArgMatches;
+use kvs::Progress;
+
+/// The shortest time between two redraws of a progress bar.
+const REDRAW_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The number of cells in a progress bar.
+const BAR_WIDTH: usize = 30;
+
+/// How to talk to the person running a command.
+#[derive(Debug, Clone, Copy)]
+pub struct Output {
+    quiet: bool,
+    verbose: bool,
+    color: bool,
+    interactive: bool,
+}
+
+impl Output {
+    /// Reads `--quiet`, `--verbose` and `--no-color` from the command line.
+    /// Color is also off when `NO_COLOR` is set, and both color and progress
+    /// bars are off when stderr is not a terminal.
+    pub fn new(arg_matches: &ArgMatches) -> Output {
+        let interactive = io::stderr().is_terminal();
+        Output {
+            quiet: arg_matches.is_present("quiet"),
+            verbose: arg_matches.is_present("verbose"),
+            color: interactive
+                && !arg_matches.is_present("no-color")
+                && env::var_os("NO_COLOR").is_none_or(|no_color| no_color.is_empty()),
+            interactive,
+        }
+    }
+
+    /// Prints a status line, such as `Compacted 12 KiB`, with its first word
+    /// in green.
+    pub fn status(&self, label: &str, message: &str) {
+        if self.quiet {
+            return;
+        }
+        if self.color {
+            eprintln!("\x1b[1;32m{}\x1b[0m {}", label, message);
+        } else {
+            eprintln!("{} {}", label, message);
+        }
+    }
+
+    /// Prints a status line for a single key, such as `  removed key1`, if
+    /// asked to be verbose.
+    pub fn item(&self, action: &str, key: &str) {
+        if self.verbose && !self.quiet {
+            eprintln!("  {} {}", action, key);
+        }
+    }
+
+    /// Starts a progress bar labelled `label`.
+    pub fn progress(&self, label: &'static str) -> ProgressBar {
+        ProgressBar {
+            label,
+            visible: self.interactive && !self.quiet,
+            started: Instant::now(),
+            drawn: None,
+        }
+    }
+}
+
+/// A progress bar with a byte count and an estimate of the time left.
+pub struct ProgressBar {
+    label: &'static str,
+    visible: bool,
+    started: Instant,
+    drawn: Option<Instant>,
+}
+
+impl ProgressBar {
+    /// Redraws the bar, at most every `REDRAW_INTERVAL` unless `progress` is
+    /// complete.
+    pub fn update(&mut self, progress: Progress) {
+        if !self.visible {
+            return;
+        }
+        let now = Instant::now();
+        let complete = progress.done >= progress.total;
+        if !complete
+            && self
+                .drawn
+                .is_some_and(|drawn| now - drawn < REDRAW_INTERVAL)
+        {
+            return;
+        }
+        self.drawn = Some(now);
+
+        let fraction = if progress.total == 0 {
+            1.0
+        } else {
+            progress.done as f64 / progress.total as f64
+        };
+        let filled = (fraction * BAR_WIDTH as f64) as usize;
+        let elapsed = now.duration_since(self.started).as_secs_f64();
+        let eta = if fraction > 0.0 {
+            format!("{:.0}s", elapsed / fraction - elapsed)
+        } else {
+            "?".to_owned()
+        };
+        let mut stderr = io::stderr();
+        let _ = write!(
+            stderr,
+            "\r{} [{}{}] {}/{} ETA {}\x1b[K",
+            self.label,
+            "=".repeat(filled),
+            " ".repeat(BAR_WIDTH - filled),
+            human_bytes(progress.done),
+            human_bytes(progress.total),
+            eta
+        );
+        let _ = stderr.flush();
+    }
+
+    /// Clears the bar, leaving the line free for a status message.
+    pub fn finish(self) {
+        if self.drawn.is_some() {
+            eprint!("\r\x1b[K");
+        }
+    }
+}
+
+/// Formats a byte count with a binary unit, such as `1.5 MiB`.
+pub fn human_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
@@ -0,0 +1,282 @@
+//! Key space analytics.
+//!
+//! [`KvStore::analyze`] reads a random sample of live keys and summarizes
+//! what it finds in an [`Analysis`]: how long keys are, how large values
+//! are, which key prefixes take up the most room and how soon keys expire.
+//! Every figure describes the sample; scale by `keys / sampled` to estimate
+//! the whole store.
+//!
+//! [`KvStore::estimate_size`] estimates how many keys, and how many bytes,
+//! fall within a [`KeySpan`] from the index alone, without reading values.
+//!
+//! [`KvStore::analyze`]: ../struct.KvStore.html#method.analyze
+//! [`Analysis`]: struct.Analysis.html
+//! [`KvStore::estimate_size`]: ../struct.KvStore.html#method.estimate_size
+//! [`KeySpan`]: enum.KeySpan.html
+use std::collections::HashMap;
+use std::ops::{Bound, RangeBounds};
+use std::time::Duration;
+
+use serde::Serialize;
+
+use crate::ScanOpts;
+
+/// The number of prefixes an [`Analysis`] reports.
+///
+/// [`Analysis`]: struct.Analysis.html
+const PREFIX_LIMIT: usize = 20;
+
+/// The characters that end a key's prefix.
+const PREFIX_DELIMITERS: &[char] = &[':', '/', '.', '_', '-'];
+
+/// The number of keys [`KvStore::estimate_size`] looks at.
+///
+/// [`KvStore::estimate_size`]: ../struct.KvStore.html#method.estimate_size
+pub(crate) const ESTIMATE_SAMPLE: usize = 1000;
+
+/// A summary of a sample of a store's keys.
+#[derive(Serialize, Debug, Clone, PartialEq, Default)]
+pub struct Analysis {
+    /// The number of live keys in the store.
+    pub keys: u64,
+    /// The number of keys that were sampled.
+    pub sampled: u64,
+    /// Sampled key lengths, in bytes.
+    pub key_lengths: Vec<Bucket>,
+    /// Sampled value sizes, in bytes.
+    pub value_sizes: Vec<Bucket>,
+    /// The most common prefixes among the sampled keys, most common first.
+    pub prefixes: Vec<Prefix>,
+    /// How soon the sampled keys expire.
+    pub ttls: TtlBuckets,
+}
+
+/// The number of sizes that fell in a power-of-two sized range.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Bucket {
+    /// The largest size in the range. The range starts just past the
+    /// previous bucket's `max`.
+    pub max: u64,
+    /// The number of sizes in the range.
+    pub count: u64,
+}
+
+/// The sampled keys sharing a prefix.
+///
+/// A key's prefix is everything up to, and including, the first `:`, `/`,
+/// `.`, `_` or `-` in it. Keys without any of those share the empty prefix.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Prefix {
+    /// The prefix itself.
+    pub prefix: String,
+    /// The number of keys with the prefix.
+    pub count: u64,
+    /// The number of live bytes taken up by keys with the prefix.
+    pub bytes: u64,
+}
+
+/// The number of sampled keys by how soon they expire.
+#[derive(Serialize, Debug, Clone, PartialEq, Default)]
+pub struct TtlBuckets {
+    /// Keys that never expire.
+    pub persistent: u64,
+    /// Keys that expire within a minute.
+    pub under_a_minute: u64,
+    /// Keys that expire within an hour, but not within a minute.
+    pub under_an_hour: u64,
+    /// Keys that expire within a day, but not within an hour.
+    pub under_a_day: u64,
+    /// Keys that expire a day or more from now.
+    pub longer: u64,
+}
+
+/// The keys [`KvStore::estimate_size`] estimates the size of.
+///
+/// A `&str` or `String` converts into the span of keys starting with it.
+///
+/// [`KvStore::estimate_size`]: ../struct.KvStore.html#method.estimate_size
+#[derive(Debug, Clone, PartialEq)]
+pub enum KeySpan {
+    /// Every key starting with the prefix.
+    Prefix(String),
+    /// Every key between the bounds, compared byte by byte.
+    Range(Bound<String>, Bound<String>),
+}
+
+impl KeySpan {
+    /// The span of every key within `range`, such as `"a".."n"`.
+    pub fn range<'a, R: RangeBounds<&'a str>>(range: R) -> KeySpan {
+        KeySpan::Range(
+            range.start_bound().map(|s| s.to_string()),
+            range.end_bound().map(|s| s.to_string()),
+        )
+    }
+
+    /// Returns the span with its prefix or bounds passed through `normalize`.
+    pub(crate) fn normalize<F: Fn(String) -> String>(self, normalize: F) -> KeySpan {
+        match self {
+            KeySpan::Prefix(prefix) => KeySpan::Prefix(normalize(prefix)),
+            KeySpan::Range(start, end) => {
+                KeySpan::Range(start.map(&normalize), end.map(&normalize))
+            }
+        }
+    }
+
+    /// Returns the range of the index that holds the span's keys, or `None`
+    /// if no key can be in it. See [`ScanOpts::key_range`].
+    ///
+    /// [`ScanOpts::key_range`]: ../struct.ScanOpts.html#method.key_range
+    pub(crate) fn key_range(&self) -> Option<(Bound<String>, Bound<String>)> {
+        match self {
+            KeySpan::Prefix(prefix) => ScanOpts::new().prefix(prefix.as_str()).key_range(),
+            KeySpan::Range(start, end) => ScanOpts::new()
+                .range((
+                    start.as_ref().map(String::as_str),
+                    end.as_ref().map(String::as_str),
+                ))
+                .key_range(),
+        }
+    }
+
+    /// Returns whether `key` is in the span.
+    pub(crate) fn contains(&self, key: &str) -> bool {
+        match self {
+            KeySpan::Prefix(prefix) => key.starts_with(prefix.as_str()),
+            KeySpan::Range(start, end) => {
+                let after_start = match start {
+                    Bound::Included(start) => key >= start.as_str(),
+                    Bound::Excluded(start) => key > start.as_str(),
+                    Bound::Unbounded => true,
+                };
+                let before_end = match end {
+                    Bound::Included(end) => key <= end.as_str(),
+                    Bound::Excluded(end) => key < end.as_str(),
+                    Bound::Unbounded => true,
+                };
+                after_start && before_end
+            }
+        }
+    }
+}
+
+impl From<&str> for KeySpan {
+    fn from(prefix: &str) -> KeySpan {
+        KeySpan::Prefix(prefix.to_owned())
+    }
+}
+
+impl From<String> for KeySpan {
+    fn from(prefix: String) -> KeySpan {
+        KeySpan::Prefix(prefix)
+    }
+}
+
+/// An estimate of the size of a [`KeySpan`].
+///
+/// [`KeySpan`]: enum.KeySpan.html
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Default)]
+pub struct SizeEstimate {
+    /// The estimated number of live keys in the span.
+    pub keys: u64,
+    /// The estimated number of live bytes the span's keys take up in the
+    /// store's logs.
+    pub bytes: u64,
+    /// The number of keys the estimate was made from.
+    pub sampled: u64,
+    /// Whether every key in the store was looked at, in which case the
+    /// estimate is exact.
+    pub exact: bool,
+}
+
+/// Builds an `Analysis` one sampled key at a time.
+pub(crate) struct Analyzer {
+    analysis: Analysis,
+    key_lengths: HashMap<u64, u64>,
+    value_sizes: HashMap<u64, u64>,
+    prefixes: HashMap<String, Prefix>,
+}
+
+impl Analyzer {
+    pub(crate) fn new(keys: u64) -> Analyzer {
+        Analyzer {
+            analysis: Analysis {
+                keys,
+                ..Analysis::default()
+            },
+            key_lengths: HashMap::new(),
+            value_sizes: HashMap::new(),
+            prefixes: HashMap::new(),
+        }
+    }
+
+    /// Records a sampled key, the size of its value, the live bytes it takes
+    /// up and how long it has left, if it expires.
+    pub(crate) fn observe(
+        &mut self,
+        key: &str,
+        value_size: u64,
+        bytes: u64,
+        ttl: Option<Duration>,
+    ) {
+        self.analysis.sampled += 1;
+        *self
+            .key_lengths
+            .entry(bucket_max(key.len() as u64))
+            .or_default() += 1;
+        *self.value_sizes.entry(bucket_max(value_size)).or_default() += 1;
+
+        let prefix = match key.find(PREFIX_DELIMITERS) {
+            Some(end) => &key[..=end],
+            None => "",
+        };
+        let stats = self
+            .prefixes
+            .entry(prefix.to_owned())
+            .or_insert_with(|| Prefix {
+                prefix: prefix.to_owned(),
+                count: 0,
+                bytes: 0,
+            });
+        stats.count += 1;
+        stats.bytes += bytes;
+
+        let ttls = &mut self.analysis.ttls;
+        match ttl.map(|ttl| ttl.as_secs()) {
+            None => ttls.persistent += 1,
+            Some(secs) if secs < 60 => ttls.under_a_minute += 1,
+            Some(secs) if secs < 60 * 60 => ttls.under_an_hour += 1,
+            Some(secs) if secs < 24 * 60 * 60 => ttls.under_a_day += 1,
+            Some(_) => ttls.longer += 1,
+        }
+    }
+
+    pub(crate) fn finish(mut self) -> Analysis {
+        self.analysis.key_lengths = buckets(self.key_lengths);
+        self.analysis.value_sizes = buckets(self.value_sizes);
+
+        let mut prefixes: Vec<Prefix> = self.prefixes.into_values().collect();
+        prefixes.sort_unstable_by(|a, b| {
+            b.count
+                .cmp(&a.count)
+                .then(b.bytes.cmp(&a.bytes))
+                .then_with(|| a.prefix.cmp(&b.prefix))
+        });
+        prefixes.truncate(PREFIX_LIMIT);
+        self.analysis.prefixes = prefixes;
+        self.analysis
+    }
+}
+
+/// Returns the largest size in the power-of-two sized range `size` falls in.
+fn bucket_max(size: u64) -> u64 {
+    size.checked_next_power_of_two().unwrap_or(u64::MAX)
+}
+
+fn buckets(counts: HashMap<u64, u64>) -> Vec<Bucket> {
+    let mut buckets: Vec<Bucket> = counts
+        .into_iter()
+        .map(|(max, count)| Bucket { max, count })
+        .collect();
+    buckets.sort_unstable_by_key(|bucket| bucket.max);
+    buckets
+}
@@ -0,0 +1,108 @@
+//! Atomic write batches.
+//!
+//! A [`WriteBatch`] collects sets and removes for [`KvStore::write`], which
+//! logs all of them to the write-ahead log in a single record before any of
+//! them is applied. A crash either recovers the whole record or, if it was
+//! torn, none of it, so after a crash either every write in a batch is
+//! visible or none is.
+//!
+//! [`WriteBatch`]: struct.WriteBatch.html
+//! [`KvStore::write`]: ../struct.KvStore.html#method.write
+use std::time::Duration;
+
+/// One write in a [`WriteBatch`].
+///
+/// [`WriteBatch`]: struct.WriteBatch.html
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum BatchOp {
+    Set {
+        key: String,
+        value: String,
+        ttl: Option<Duration>,
+    },
+    Remove {
+        key: String,
+    },
+}
+
+/// Sets and removes that [`KvStore::write`] applies as a unit, in order.
+///
+/// ```rust
+/// # use kvs::{KvStore, Result, WriteBatch};
+/// # fn main() -> Result<()> {
+/// # let dir = tempfile::TempDir::new()?;
+/// let mut store = KvStore::open(dir.path())?;
+/// store.set("from".to_owned(), "100".to_owned())?;
+///
+/// let mut batch = WriteBatch::new();
+/// batch
+///     .set("from".to_owned(), "60".to_owned())
+///     .set("to".to_owned(), "40".to_owned());
+/// store.write(batch)?;
+/// assert_eq!(store.get("to".to_owned())?, Some("40".to_owned()));
+/// # Ok(())
+/// # }
+/// ```
+///
+/// [`KvStore::write`]: struct.KvStore.html#method.write
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct WriteBatch {
+    pub(crate) ops: Vec<BatchOp>,
+}
+
+impl WriteBatch {
+    /// Creates an empty batch.
+    pub fn new() -> WriteBatch {
+        WriteBatch::default()
+    }
+
+    /// Sets a key, as [`KvStore::set`] does.
+    ///
+    /// [`KvStore::set`]: struct.KvStore.html#method.set
+    pub fn set(&mut self, key: String, value: String) -> &mut WriteBatch {
+        self.ops.push(BatchOp::Set {
+            key,
+            value,
+            ttl: None,
+        });
+        self
+    }
+
+    /// Sets a key that expires once `ttl` has passed, as
+    /// [`KvStore::set_with_ttl`] does.
+    ///
+    /// [`KvStore::set_with_ttl`]: struct.KvStore.html#method.set_with_ttl
+    pub fn set_with_ttl(&mut self, key: String, value: String, ttl: Duration) -> &mut WriteBatch {
+        self.ops.push(BatchOp::Set {
+            key,
+            value,
+            ttl: Some(ttl),
+        });
+        self
+    }
+
+    /// Removes a key, as [`KvStore::remove_if_exists`] does: a key that does
+    /// not exist by the time the batch gets to it is left alone, rather than
+    /// failing the batch.
+    ///
+    /// [`KvStore::remove_if_exists`]: struct.KvStore.html#method.remove_if_exists
+    pub fn remove(&mut self, key: String) -> &mut WriteBatch {
+        self.ops.push(BatchOp::Remove { key });
+        self
+    }
+
+    /// Returns the number of writes in the batch.
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    /// Returns whether the batch has no writes.
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    /// Drops every write, so that the batch can be filled again.
+    pub fn clear(&mut self) {
+        self.ops.clear();
+    }
+}
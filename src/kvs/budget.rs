@@ -0,0 +1,186 @@
+//! Resource limits shared by every store in a process.
+//!
+//! A [`Budget`] caps how many segment files all the stores opened with it
+//! keep open between them, and how many live bytes all of them keep in cache
+//! mode. Each store enforces the budget on its own resources, so no store
+//! ever closes another's files or evicts another's keys.
+//!
+//! [`Budget`]: struct.Budget.html
+use std::fmt;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// Limits shared by every store opened with a clone of the same `Budget`.
+///
+/// ```rust
+/// # use kvs::{Budget, KvOpts};
+/// // Dozens of stores between them keep at most 64 segment files open and
+/// // at most 64 MiB of live values.
+/// let budget = Budget::new()
+///     .max_open_files(64)
+///     .max_cache_bytes(64 << 20);
+/// let opts = KvOpts::new().budget(budget.clone());
+/// ```
+#[derive(Clone, Default)]
+pub struct Budget {
+    shared: Arc<Shared>,
+}
+
+#[derive(Default)]
+struct Shared {
+    /// The most segment files kept open, or 0 for no limit.
+    max_open_files: AtomicUsize,
+    /// The most live bytes kept in cache mode, or 0 for no limit.
+    max_cache_bytes: AtomicU64,
+    open_files: AtomicUsize,
+    cache_bytes: AtomicU64,
+}
+
+impl Budget {
+    /// Creates a budget without any limits.
+    pub fn new() -> Budget {
+        Budget::default()
+    }
+
+    /// Sets the most segment files that the stores keep open for reading
+    /// between them. A store that needs to open one more while the budget is
+    /// spent closes its own least recently read files first, and only goes
+    /// over budget if it has none left to close. Closed files are opened
+    /// again when they are next read.
+    ///
+    /// The limit applies to every clone of the budget, including the ones
+    /// stores are already open with.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max` is 0.
+    pub fn max_open_files(self, max: usize) -> Budget {
+        assert!(max > 0, "max_open_files is 0");
+        self.shared.max_open_files.store(max, Ordering::Relaxed);
+        self
+    }
+
+    /// Sets the most live bytes (see [`Stats::live_bytes`]) that the stores
+    /// keep between them. Every store opened with a budget that has this
+    /// limit is a cache, as if opened with [`KvOpts::cache`]: a write that
+    /// takes the stores over budget evicts the least recently used keys of
+    /// the store written to until they fit again, or until that store has
+    /// nothing left to evict.
+    ///
+    /// A store reports its live bytes to the budget whenever it is written
+    /// to, and withdraws them when it is dropped.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max` is 0.
+    ///
+    /// [`Stats::live_bytes`]: struct.Stats.html#structfield.live_bytes
+    /// [`KvOpts::cache`]: struct.KvOpts.html#method.cache
+    pub fn max_cache_bytes(self, max: u64) -> Budget {
+        assert!(max > 0, "max_cache_bytes is 0");
+        self.shared.max_cache_bytes.store(max, Ordering::Relaxed);
+        self
+    }
+
+    /// Returns the number of segment files the stores keep open.
+    pub fn open_files(&self) -> usize {
+        self.shared.open_files.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of live bytes the stores in cache mode last
+    /// reported.
+    pub fn cache_bytes(&self) -> u64 {
+        self.shared.cache_bytes.load(Ordering::Relaxed)
+    }
+
+    /// Returns whether the budget limits the stores' live bytes.
+    pub(crate) fn limits_cache(&self) -> bool {
+        self.shared.max_cache_bytes.load(Ordering::Relaxed) > 0
+    }
+
+    /// Takes up one open file if the budget allows it.
+    pub(crate) fn try_open_file(&self) -> bool {
+        let max = self.shared.max_open_files.load(Ordering::Relaxed);
+        self.shared
+            .open_files
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |open| {
+                if max == 0 || open < max {
+                    Some(open + 1)
+                } else {
+                    None
+                }
+            })
+            .is_ok()
+    }
+
+    /// Takes up one open file whether the budget allows it or not.
+    pub(crate) fn force_open_file(&self) {
+        self.shared.open_files.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Gives back one open file.
+    pub(crate) fn close_file(&self) {
+        self.shared.open_files.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// Replaces `old` live bytes with `new` ones, and returns whether the
+    /// stores are over budget afterwards.
+    pub(crate) fn update_cache_bytes(&self, old: u64, new: u64) -> bool {
+        // Adding the difference modulo 2^64 subtracts it when `new` is less.
+        let delta = new.wrapping_sub(old);
+        let total = self
+            .shared
+            .cache_bytes
+            .fetch_add(delta, Ordering::Relaxed)
+            .wrapping_add(delta);
+        let max = self.shared.max_cache_bytes.load(Ordering::Relaxed);
+        max > 0 && total > max
+    }
+}
+
+impl fmt::Debug for Budget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let limit = |max: u64| if max == 0 { None } else { Some(max) };
+        f.debug_struct("Budget")
+            .field(
+                "max_open_files",
+                &limit(self.shared.max_open_files.load(Ordering::Relaxed) as u64),
+            )
+            .field(
+                "max_cache_bytes",
+                &limit(self.shared.max_cache_bytes.load(Ordering::Relaxed)),
+            )
+            .field("open_files", &self.open_files())
+            .field("cache_bytes", &self.cache_bytes())
+            .finish()
+    }
+}
+
+/// The live bytes one store has reported to a [`Budget`], withdrawn again
+/// when it is dropped.
+///
+/// [`Budget`]: struct.Budget.html
+pub(crate) struct CacheCharge {
+    budget: Budget,
+    bytes: u64,
+}
+
+impl CacheCharge {
+    pub(crate) fn new(budget: Budget) -> CacheCharge {
+        CacheCharge { budget, bytes: 0 }
+    }
+
+    /// Reports `bytes` live bytes in place of the last report, and returns
+    /// whether the stores are over budget.
+    pub(crate) fn report(&mut self, bytes: u64) -> bool {
+        let over = self.budget.update_cache_bytes(self.bytes, bytes);
+        self.bytes = bytes;
+        over
+    }
+}
+
+impl Drop for CacheCharge {
+    fn drop(&mut self) {
+        self.budget.update_cache_bytes(self.bytes, 0);
+    }
+}
@@ -0,0 +1,49 @@
+//! Keys derived from the values they hold.
+//!
+//! [`KvStore::put_cas`] files every value under a key made of the name of a
+//! [`CasHash`] and the value's digest, so that identical values share a key.
+//!
+//! [`KvStore::put_cas`]: struct.KvStore.html#method.put_cas
+//! [`CasHash`]: enum.CasHash.html
+use crate::util::sha256::Sha256;
+
+/// The hash that [`KvStore::put_cas`] derives keys from.
+///
+/// [`KvStore::put_cas`]: struct.KvStore.html#method.put_cas
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CasHash {
+    /// SHA-256, under keys of the form `sha256:<64 hex digits>`. Two values
+    /// are never expected to share a digest. This is the default.
+    #[default]
+    Sha256,
+    /// 64-bit FNV-1a, under keys of the form `fnv1a64:<16 hex digits>`.
+    /// Much cheaper to compute than SHA-256, but different values do
+    /// collide once a store holds a few billion of them.
+    Fnv1a64,
+}
+
+impl CasHash {
+    /// Returns the key `value` is filed under.
+    pub(crate) fn key(self, value: &[u8]) -> String {
+        match self {
+            CasHash::Sha256 => {
+                let mut hasher = Sha256::new();
+                hasher.update(value);
+                format!("sha256:{}", hex(&hasher.finish()))
+            }
+            CasHash::Fnv1a64 => format!("fnv1a64:{}", hex(&fnv1a64(value).to_be_bytes())),
+        }
+    }
+}
+
+fn fnv1a64(value: &[u8]) -> u64 {
+    const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+    const PRIME: u64 = 0x0000_0100_0000_01b3;
+    value.iter().fold(OFFSET_BASIS, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(PRIME)
+    })
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
@@ -0,0 +1,76 @@
+//! Time.
+//!
+//! Everything a `KvStore` does that depends on time, such as expiring keys
+//! or recording when it was created, reads the time from a [`Clock`]. The
+//! default is [`SystemClock`]; a [`ManualClock`] only moves when it is told
+//! to, which lets tests skip ahead in time without waiting.
+//!
+//! [`Clock`]: trait.Clock.html
+//! [`SystemClock`]: struct.SystemClock.html
+//! [`ManualClock`]: struct.ManualClock.html
+use std::fmt::Debug;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// A source of the current time.
+pub trait Clock: Debug + Send + Sync {
+    /// Returns the time elapsed since the Unix epoch.
+    fn now(&self) -> Duration;
+}
+
+/// The [`Clock`] backed by the system's clock.
+///
+/// There is no system clock on wasm32-unknown-unknown, where reading this
+/// clock panics; use a [`ManualClock`], or a `Clock` of your own, there.
+///
+/// [`Clock`]: trait.Clock.html
+/// [`ManualClock`]: struct.ManualClock.html
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Duration {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+    }
+}
+
+/// A [`Clock`] that stands still until it is moved, with millisecond
+/// precision.
+///
+/// Clones share their time, so a clone can be handed to a store while the
+/// original stays behind to move it.
+///
+/// [`Clock`]: trait.Clock.html
+#[derive(Debug, Clone, Default)]
+pub struct ManualClock {
+    millis: Arc<AtomicU64>,
+}
+
+impl ManualClock {
+    /// Creates a clock that reads `now`, measured since the Unix epoch.
+    pub fn new(now: Duration) -> ManualClock {
+        ManualClock {
+            millis: Arc::new(AtomicU64::new(now.as_millis() as u64)),
+        }
+    }
+
+    /// Moves the clock forward by `by`.
+    pub fn advance(&self, by: Duration) {
+        self.millis
+            .fetch_add(by.as_millis() as u64, Ordering::SeqCst);
+    }
+
+    /// Sets the clock to `now`, measured since the Unix epoch.
+    pub fn set(&self, now: Duration) {
+        self.millis.store(now.as_millis() as u64, Ordering::SeqCst);
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Duration {
+        Duration::from_millis(self.millis.load(Ordering::SeqCst))
+    }
+}
@@ -0,0 +1,184 @@
+//! When a store compacts itself.
+//!
+//! After every write, a store checks its [`CompactionPolicy`]. The default
+//! compacts as soon as a fixed number of bytes could be reclaimed, which
+//! keeps small stores tidy but rewrites a large store over and over. An
+//! [`Adaptive`] policy instead targets a ratio of stale to live bytes, which
+//! it relaxes while the store is being rewritten quickly (the stale bytes
+//! would be back soon after a compaction) and drops once the disk runs low
+//! on space.
+//!
+//! [`CompactionPolicy`]: enum.CompactionPolicy.html
+//! [`Adaptive`]: enum.CompactionPolicy.html#variant.Adaptive
+
+/// The stale bytes past which a store compacts by default.
+const DEFAULT_STALE_BYTES: u64 = 512;
+
+/// The time over which the write rate is averaged, in milliseconds.
+const RATE_WINDOW_MS: f64 = 60_000.0;
+
+/// How often the free space on the disk is checked, in milliseconds.
+const HEADROOM_CHECK_MS: u64 = 1_000;
+
+/// When a store compacts itself, as set by [`KvOpts::compaction_policy`].
+///
+/// [`KvOpts::compaction_policy`]: struct.KvOpts.html#method.compaction_policy
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CompactionPolicy {
+    /// Compacts once more than this many bytes can be reclaimed. The default
+    /// is 512 bytes.
+    StaleBytes(u64),
+    /// Compacts once the stale bytes reach a ratio of the live bytes, taking
+    /// the write rate and the free space on the disk into account.
+    Adaptive(AdaptiveCompaction),
+}
+
+impl Default for CompactionPolicy {
+    fn default() -> CompactionPolicy {
+        CompactionPolicy::StaleBytes(DEFAULT_STALE_BYTES)
+    }
+}
+
+/// The settings of [`CompactionPolicy::Adaptive`].
+///
+/// A store compacts once its reclaimable bytes are past the floor and past
+/// the target ratio of its live bytes. The more of its live bytes a store
+/// has rewritten over the last minute or so, the further the target is
+/// stretched, up to twice the ratio. Once the disk has less free space than
+/// the minimum headroom, the ratio is dropped and only the floor is left.
+///
+/// ```rust
+/// # use kvs::{AdaptiveCompaction, CompactionPolicy, KvOpts};
+/// let policy = AdaptiveCompaction::new()
+///     .stale_ratio(0.25)
+///     .min_stale_bytes(4 << 20);
+/// let opts = KvOpts::new().compaction_policy(CompactionPolicy::Adaptive(policy));
+/// ```
+///
+/// [`CompactionPolicy::Adaptive`]: enum.CompactionPolicy.html#variant.Adaptive
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AdaptiveCompaction {
+    stale_ratio: f64,
+    min_stale_bytes: u64,
+    min_headroom: f64,
+}
+
+impl Default for AdaptiveCompaction {
+    fn default() -> AdaptiveCompaction {
+        AdaptiveCompaction {
+            stale_ratio: 0.5,
+            min_stale_bytes: 1 << 20,
+            min_headroom: 1.0,
+        }
+    }
+}
+
+impl AdaptiveCompaction {
+    /// Creates the default settings: a ratio of 0.5, a floor of 1 MiB and a
+    /// minimum headroom of 1.0.
+    pub fn new() -> AdaptiveCompaction {
+        AdaptiveCompaction::default()
+    }
+
+    /// Sets the ratio of stale to live bytes past which the store compacts.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `ratio` is negative or not a number.
+    pub fn stale_ratio(mut self, ratio: f64) -> AdaptiveCompaction {
+        assert!(ratio >= 0.0, "stale_ratio is negative");
+        self.stale_ratio = ratio;
+        self
+    }
+
+    /// Sets the reclaimable bytes below which the store never compacts, so
+    /// that small stores are not compacted for a few bytes at a time.
+    pub fn min_stale_bytes(mut self, bytes: u64) -> AdaptiveCompaction {
+        self.min_stale_bytes = bytes;
+        self
+    }
+
+    /// Sets the free space on the disk, as a multiple of the store's size,
+    /// below which the store compacts as soon as it is past the floor. Only
+    /// file systems that report their free space, such as [`StdFs`] on
+    /// Unix, have headroom to run out of.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `headroom` is negative or not a number.
+    ///
+    /// [`StdFs`]: struct.StdFs.html
+    pub fn min_headroom(mut self, headroom: f64) -> AdaptiveCompaction {
+        assert!(headroom >= 0.0, "min_headroom is negative");
+        self.min_headroom = headroom;
+        self
+    }
+}
+
+/// What a store needs to remember to apply its compaction policy.
+#[derive(Debug, Default)]
+pub(crate) struct CompactionState {
+    /// The bytes written per second, averaged over about `RATE_WINDOW_MS`.
+    rate: f64,
+    /// When the rate was last updated, in milliseconds since the Unix epoch.
+    rate_at: u64,
+    /// The free space on the disk, and when it was checked.
+    available: Option<(Option<u64>, u64)>,
+}
+
+impl CompactionState {
+    /// Counts `bytes` written at `now`.
+    pub(crate) fn record_write(&mut self, now: u64, bytes: u64) {
+        let elapsed = now.saturating_sub(self.rate_at) as f64;
+        self.rate =
+            self.rate * (-elapsed / RATE_WINDOW_MS).exp() + bytes as f64 * 1000.0 / RATE_WINDOW_MS;
+        self.rate_at = now;
+    }
+
+    /// Returns whether a store with `reclaimable` and `live` bytes should
+    /// compact now. `available` reports the free space on the disk, if the
+    /// store's file system knows it, and is only called every so often.
+    pub(crate) fn should_compact<F>(
+        &mut self,
+        policy: &CompactionPolicy,
+        now: u64,
+        reclaimable: u64,
+        live: u64,
+        available: F,
+    ) -> bool
+    where
+        F: FnOnce() -> Option<u64>,
+    {
+        let adaptive = match policy {
+            CompactionPolicy::StaleBytes(max) => return reclaimable > *max,
+            CompactionPolicy::Adaptive(adaptive) => adaptive,
+        };
+        if reclaimable < adaptive.min_stale_bytes {
+            return false;
+        }
+
+        let available = match self.available {
+            Some((available, at)) if now.saturating_sub(at) < HEADROOM_CHECK_MS => available,
+            _ => {
+                let available = available();
+                self.available = Some((available, now));
+                available
+            }
+        };
+        if let Some(available) = available {
+            let size = (live + reclaimable) as f64;
+            if (available as f64) < size * adaptive.min_headroom {
+                return true;
+            }
+        }
+
+        // A store that rewrites all of its live bytes every minute earns
+        // twice the ratio.
+        let churn = if live == 0 {
+            0.0
+        } else {
+            (self.rate * 60.0 / live as f64).min(1.0)
+        };
+        reclaimable as f64 > live as f64 * adaptive.stale_ratio * (1.0 + churn)
+    }
+}
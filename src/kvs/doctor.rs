@@ -0,0 +1,377 @@
+//! Environment checks for a store's directory.
+//!
+//! [`KvStore::diagnose`] looks at everything around a store that can keep it
+//! from opening or working well, without opening it, and says what to do
+//! about whatever it finds.
+//!
+//! [`KvStore::diagnose`]: struct.KvStore.html#method.diagnose
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::SystemTime;
+
+use crate::clock::{Clock, SystemClock};
+use crate::kvio::fs::{Fs, StdFs};
+use crate::meta::{CODEC, ENGINE, FORMAT_VERSION, META_FILE_NAME};
+use crate::{temporary_segments, SegmentLayout, StoreMeta, LOCK_FILE_NAME};
+
+/// The free space below which a store's file system is reported as nearly
+/// full.
+const LOW_DISK_BYTES: u64 = 256 * 1024 * 1024;
+
+/// The number of file descriptors a process needs beyond the ones its
+/// stores' segments take up.
+const SPARE_FDS: u64 = 64;
+
+/// How far in the future, in seconds, a store's files may seem to have been
+/// written before the clock is reported as wrong.
+const CLOCK_SLACK_SECS: u64 = 60;
+
+/// The outcome of a single [`Check`].
+///
+/// [`Check`]: struct.Check.html
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CheckStatus {
+    /// Nothing is wrong.
+    Ok,
+    /// The store works, but not as well as it could.
+    Warning,
+    /// The store cannot be opened, or cannot be written to.
+    Error,
+    /// The check could not be made on this platform or in this state.
+    Skipped,
+}
+
+/// One of the checks made by [`KvStore::diagnose`].
+///
+/// [`KvStore::diagnose`]: struct.KvStore.html#method.diagnose
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Check {
+    /// What was checked, such as `disk space`.
+    pub name: &'static str,
+    /// How the check went.
+    pub status: CheckStatus,
+    /// What was found.
+    pub detail: String,
+    /// What to do about it, unless nothing needs doing.
+    pub advice: Option<String>,
+}
+
+impl Check {
+    fn new(name: &'static str, status: CheckStatus, detail: String) -> Check {
+        Check {
+            name,
+            status,
+            detail,
+            advice: None,
+        }
+    }
+
+    fn advise<S: Into<String>>(mut self, advice: S) -> Check {
+        self.advice = Some(advice.into());
+        self
+    }
+}
+
+/// Runs every check on the store in `dir`.
+pub(crate) fn diagnose(dir: &Path) -> Vec<Check> {
+    let permissions = check_permissions(dir);
+    if permissions.status == CheckStatus::Error {
+        return vec![permissions];
+    }
+    let (format, meta) = check_format(dir);
+    let layout = meta
+        .as_ref()
+        .map(|meta| meta.segment_layout.clone())
+        .unwrap_or_default();
+    vec![
+        permissions,
+        format,
+        check_lock(dir, meta.is_some()),
+        check_disk_space(dir),
+        check_fd_limit(dir, &layout),
+        check_temporary_files(dir, &layout),
+        check_clock(dir, meta.as_ref()),
+    ]
+}
+
+fn check_permissions(dir: &Path) -> Check {
+    const NAME: &str = "permissions";
+    match fs::metadata(dir) {
+        Ok(metadata) if metadata.is_dir() => {}
+        Ok(_) => {
+            return Check::new(
+                NAME,
+                CheckStatus::Error,
+                format!("{} is not a directory", dir.display()),
+            )
+            .advise("point kvs at the store's directory");
+        }
+        Err(err) => {
+            return Check::new(
+                NAME,
+                CheckStatus::Error,
+                format!("cannot read {}: {}", dir.display(), err),
+            )
+            .advise("create the directory, or make it readable by this user");
+        }
+    }
+    // Nothing short of writing a file says whether writes are allowed.
+    let probe = dir.join("kvs.doctor");
+    match fs::write(&probe, b"") {
+        Ok(()) => {
+            let _ = fs::remove_file(&probe);
+            Check::new(
+                NAME,
+                CheckStatus::Ok,
+                "the directory is writable".to_owned(),
+            )
+        }
+        Err(err) => Check::new(
+            NAME,
+            CheckStatus::Error,
+            format!("cannot write to {}: {}", dir.display(), err),
+        )
+        .advise("make the directory writable by this user, or run kvs as its owner"),
+    }
+}
+
+fn check_format(dir: &Path) -> (Check, Option<StoreMeta>) {
+    const NAME: &str = "format";
+    let buf = match fs::read(dir.join(META_FILE_NAME)) {
+        Ok(buf) => buf,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            let check = Check::new(
+                NAME,
+                CheckStatus::Ok,
+                "there is no store here yet; one is created when it is first opened".to_owned(),
+            );
+            return (check, None);
+        }
+        Err(err) => {
+            let check = Check::new(
+                NAME,
+                CheckStatus::Error,
+                format!("cannot read {}: {}", META_FILE_NAME, err),
+            )
+            .advise("make the store's files readable by this user");
+            return (check, None);
+        }
+    };
+    let meta: StoreMeta = match serde_json::from_slice(&buf) {
+        Ok(meta) => meta,
+        Err(err) => {
+            let check = Check::new(
+                NAME,
+                CheckStatus::Error,
+                format!("{} is damaged: {}", META_FILE_NAME, err),
+            )
+            .advise("restore the store from a backup");
+            return (check, None);
+        }
+    };
+    let check = if meta.engine != ENGINE {
+        Check::new(
+            NAME,
+            CheckStatus::Error,
+            format!("the store was created by the {} engine", meta.engine),
+        )
+        .advise(format!("open it with {} instead", meta.engine))
+    } else if meta.format_version > FORMAT_VERSION {
+        Check::new(
+            NAME,
+            CheckStatus::Error,
+            format!(
+                "the store has format version {}, newer than this kvs's {}",
+                meta.format_version, FORMAT_VERSION
+            ),
+        )
+        .advise("upgrade kvs")
+    } else if !meta.is_current() {
+        Check::new(
+            NAME,
+            CheckStatus::Error,
+            format!(
+                "the store has format version {} with the {} codec, older than this kvs's {} with the {} codec",
+                meta.format_version, meta.codec, FORMAT_VERSION, CODEC
+            ),
+        )
+        .advise("rewrite it with `kvs upgrade`")
+    } else {
+        Check::new(
+            NAME,
+            CheckStatus::Ok,
+            format!("format version {}", meta.format_version),
+        )
+    };
+    (check, Some(meta))
+}
+
+fn check_lock(dir: &Path, exists: bool) -> Check {
+    const NAME: &str = "lock";
+    if !exists {
+        return Check::new(
+            NAME,
+            CheckStatus::Skipped,
+            "there is no store to lock".to_owned(),
+        );
+    }
+    match StdFs.lock(&dir.join(LOCK_FILE_NAME)) {
+        // The lock is given back as soon as it is dropped.
+        Ok(_) => Check::new(NAME, CheckStatus::Ok, "the store is not open".to_owned()),
+        Err(err) if err.kind() == io::ErrorKind::WouldBlock => Check::new(
+            NAME,
+            CheckStatus::Warning,
+            "the store is open, in this process or another one".to_owned(),
+        )
+        .advise("close it before compacting or repairing the store"),
+        Err(err) => Check::new(
+            NAME,
+            CheckStatus::Error,
+            format!("cannot take the lock: {}", err),
+        )
+        .advise(format!("make {} writable by this user", LOCK_FILE_NAME)),
+    }
+}
+
+fn check_disk_space(dir: &Path) -> Check {
+    const NAME: &str = "disk space";
+    match StdFs.available_space(dir) {
+        Some(Ok(free)) if free < LOW_DISK_BYTES => {
+            Check::new(NAME, CheckStatus::Warning, format!("{} bytes free", free))
+                .advise("free up space; compacting needs room for a copy of every live key")
+        }
+        Some(Ok(free)) => Check::new(NAME, CheckStatus::Ok, format!("{} bytes free", free)),
+        Some(Err(err)) => Check::new(NAME, CheckStatus::Skipped, format!("cannot tell: {}", err)),
+        None => Check::new(
+            NAME,
+            CheckStatus::Skipped,
+            "not supported on this platform".to_owned(),
+        ),
+    }
+}
+
+fn check_fd_limit(dir: &Path, layout: &SegmentLayout) -> Check {
+    const NAME: &str = "open files";
+    let segments = match layout.versions(&StdFs, dir) {
+        Ok(versions) => versions.len() as u64,
+        Err(err) => {
+            return Check::new(
+                NAME,
+                CheckStatus::Skipped,
+                format!("cannot list segments: {:?}", err),
+            )
+        }
+    };
+    match fd_limit() {
+        Some(Ok(limit)) if limit < segments + SPARE_FDS => Check::new(
+            NAME,
+            CheckStatus::Warning,
+            format!(
+                "{} segment(s) against a limit of {} open files",
+                segments, limit
+            ),
+        )
+        .advise("raise the limit with `ulimit -n`, compact the store, or open it with a Budget"),
+        Some(Ok(limit)) => Check::new(
+            NAME,
+            CheckStatus::Ok,
+            format!(
+                "{} segment(s) against a limit of {} open files",
+                segments, limit
+            ),
+        ),
+        Some(Err(err)) => Check::new(NAME, CheckStatus::Skipped, format!("cannot tell: {}", err)),
+        None => Check::new(
+            NAME,
+            CheckStatus::Skipped,
+            "not supported on this platform".to_owned(),
+        ),
+    }
+}
+
+fn check_temporary_files(dir: &Path, layout: &SegmentLayout) -> Check {
+    const NAME: &str = "temporary files";
+    let mut leftovers = match temporary_segments(&StdFs, layout, dir) {
+        Ok(leftovers) => leftovers,
+        Err(err) => {
+            return Check::new(
+                NAME,
+                CheckStatus::Skipped,
+                format!("cannot list them: {:?}", err),
+            )
+        }
+    };
+    let meta_tmp = dir.join(format!("{}.tmp", META_FILE_NAME));
+    if meta_tmp.exists() {
+        leftovers.push(meta_tmp);
+    }
+    if leftovers.is_empty() {
+        return Check::new(NAME, CheckStatus::Ok, "none".to_owned());
+    }
+    let names: Vec<_> = leftovers
+        .iter()
+        .map(|file| file.display().to_string())
+        .collect();
+    Check::new(
+        NAME,
+        CheckStatus::Warning,
+        format!("left behind by an interrupted write: {}", names.join(", ")),
+    )
+    .advise("opening the store removes them")
+}
+
+fn check_clock(dir: &Path, meta: Option<&StoreMeta>) -> Check {
+    const NAME: &str = "clock";
+    let now = SystemClock.now().as_secs();
+    let mut newest = meta.map_or(0, |meta| meta.created);
+    if let Ok(entries) = fs::read_dir(dir) {
+        for entry in entries.flatten() {
+            let modified = entry
+                .metadata()
+                .and_then(|metadata| metadata.modified())
+                .ok()
+                .and_then(|modified| modified.duration_since(SystemTime::UNIX_EPOCH).ok());
+            if let Some(modified) = modified {
+                newest = newest.max(modified.as_secs());
+            }
+        }
+    }
+    if newest > now + CLOCK_SLACK_SECS {
+        Check::new(
+            NAME,
+            CheckStatus::Warning,
+            format!(
+                "the store was written {} seconds in the future",
+                newest - now
+            ),
+        )
+        .advise("fix the system clock; keys with a TTL expire late until it catches up")
+    } else {
+        Check::new(
+            NAME,
+            CheckStatus::Ok,
+            "the store was not written in the future".to_owned(),
+        )
+    }
+}
+
+/// Returns the most files this process may have open, or `None` where that
+/// cannot be asked.
+#[cfg(target_os = "linux")]
+fn fd_limit() -> Option<io::Result<u64>> {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    // SAFETY: `limit` is a valid `rlimit` for `getrlimit` to write to.
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } != 0 {
+        return Some(Err(io::Error::last_os_error()));
+    }
+    Some(Ok(limit.rlim_cur))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn fd_limit() -> Option<io::Result<u64>> {
+    None
+}
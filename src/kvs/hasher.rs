@@ -0,0 +1,91 @@
+//! The hashers a store's secondary indexes can be built with.
+//!
+//! Every value filed in a secondary or token index, and every query of one,
+//! hashes the index keys pulled out of a value. The standard library's
+//! SipHash resists keys crafted to collide, which only matters when values
+//! come from someone who should not be trusted, and costs several times
+//! what FxHash does.
+use std::collections::hash_map::{DefaultHasher, RandomState};
+use std::hash::{BuildHasher, Hasher};
+
+/// The hasher a store's secondary indexes hash keys with, as set by
+/// [`KvOpts::index_hasher`].
+///
+/// [`KvOpts::index_hasher`]: struct.KvOpts.html#method.index_hasher
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum IndexHasher {
+    /// The standard library's randomly keyed SipHash, which keys crafted to
+    /// collide cannot slow down. This is the default.
+    #[default]
+    SipHash,
+    /// FxHash, the hasher `rustc` uses. Much faster on short keys, but
+    /// trivially made to collide, so only suited to trusted keys.
+    Fx,
+}
+
+/// Builds the hashers of one secondary index.
+#[derive(Debug, Clone)]
+pub(crate) struct IndexState {
+    hasher: IndexHasher,
+    sip: RandomState,
+}
+
+impl IndexState {
+    pub(crate) fn new(hasher: IndexHasher) -> IndexState {
+        IndexState {
+            hasher,
+            sip: RandomState::new(),
+        }
+    }
+}
+
+impl BuildHasher for IndexState {
+    type Hasher = KeyHasher;
+
+    fn build_hasher(&self) -> KeyHasher {
+        match self.hasher {
+            IndexHasher::SipHash => KeyHasher::Sip(self.sip.build_hasher()),
+            IndexHasher::Fx => KeyHasher::Fx(0),
+        }
+    }
+}
+
+/// A hasher of either kind.
+pub(crate) enum KeyHasher {
+    Sip(DefaultHasher),
+    /// The FxHash state.
+    Fx(u64),
+}
+
+/// The multiplier FxHash mixes every word in with.
+const FX_SEED: u64 = 0x517c_c1b7_2722_0a95;
+
+fn fx_add(hash: u64, word: u64) -> u64 {
+    (hash.rotate_left(5) ^ word).wrapping_mul(FX_SEED)
+}
+
+impl Hasher for KeyHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        match self {
+            KeyHasher::Sip(hasher) => hasher.write(bytes),
+            KeyHasher::Fx(hash) => {
+                let mut chunks = bytes.chunks_exact(8);
+                for chunk in &mut chunks {
+                    let mut word = [0u8; 8];
+                    word.copy_from_slice(chunk);
+                    *hash = fx_add(*hash, u64::from_le_bytes(word));
+                }
+                for &byte in chunks.remainder() {
+                    *hash = fx_add(*hash, u64::from(byte));
+                }
+            }
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        match self {
+            KeyHasher::Sip(hasher) => hasher.finish(),
+            KeyHasher::Fx(hash) => *hash,
+        }
+    }
+}
@@ -0,0 +1,136 @@
+//! Key normalization.
+//!
+//! A [`KeyCodec`] is applied to every key handed to a `KvStore` before the
+//! key reaches the index, so keys that normalize to the same string are the
+//! same key. The codec is chosen when a store is created and its name is
+//! recorded in the store's metadata; opening the store with a different codec
+//! is an error, so the behavior can never silently change between opens.
+//!
+//! [`KeyCodec`]: trait.KeyCodec.html
+use std::borrow::Cow;
+use std::fmt::Debug;
+use std::sync::Arc;
+
+use crate::util::errors::{KvsError, Result};
+
+/// Normalizes keys before they are used.
+pub trait KeyCodec: Debug + Send + Sync {
+    /// The name the codec is recorded under in a store's metadata. Two codecs
+    /// with the same name must normalize keys identically.
+    fn name(&self) -> &str;
+
+    /// Normalizes a key.
+    fn normalize(&self, key: String) -> String;
+
+    /// Normalizes a borrowed key. Codecs that leave some keys as they are can
+    /// borrow those back rather than copy them.
+    fn normalize_str<'a>(&self, key: &'a str) -> Cow<'a, str> {
+        Cow::Owned(self.normalize(key.to_owned()))
+    }
+}
+
+/// Uses keys exactly as given. This is the default.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Exact;
+
+impl KeyCodec for Exact {
+    fn name(&self) -> &str {
+        "exact"
+    }
+
+    fn normalize(&self, key: String) -> String {
+        key
+    }
+
+    fn normalize_str<'a>(&self, key: &'a str) -> Cow<'a, str> {
+        Cow::Borrowed(key)
+    }
+}
+
+/// Folds keys to lowercase, making them case-insensitive.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CaseInsensitive;
+
+impl KeyCodec for CaseInsensitive {
+    fn name(&self) -> &str {
+        "case-insensitive"
+    }
+
+    fn normalize(&self, key: String) -> String {
+        key.to_lowercase()
+    }
+}
+
+/// Strips leading and trailing whitespace from keys.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Trimmed;
+
+impl KeyCodec for Trimmed {
+    fn name(&self) -> &str {
+        "trimmed"
+    }
+
+    fn normalize(&self, key: String) -> String {
+        let trimmed = key.trim();
+        if trimmed.len() == key.len() {
+            key
+        } else {
+            trimmed.to_owned()
+        }
+    }
+}
+
+/// Leaves keys as they are, under the name of whichever codec a store was
+/// created with. Keys copied out of a store are normalized already, so this
+/// is all copying them needs, even for a custom codec.
+#[derive(Debug, Clone)]
+pub(crate) struct AsStored(pub(crate) String);
+
+impl KeyCodec for AsStored {
+    fn name(&self) -> &str {
+        &self.0
+    }
+
+    fn normalize(&self, key: String) -> String {
+        key
+    }
+
+    fn normalize_str<'a>(&self, key: &'a str) -> Cow<'a, str> {
+        Cow::Borrowed(key)
+    }
+}
+
+/// Returns the built-in codec recorded under `name`.
+fn builtin(name: &str) -> Option<Arc<dyn KeyCodec>> {
+    match name {
+        "exact" => Some(Arc::new(Exact)),
+        "case-insensitive" => Some(Arc::new(CaseInsensitive)),
+        "trimmed" => Some(Arc::new(Trimmed)),
+        _ => None,
+    }
+}
+
+/// Picks the codec to open a store with, given the name recorded in the
+/// store's metadata and the codec the caller asked for, if any.
+///
+/// A caller that did not ask for a codec gets the recorded one, as long as
+/// it is a built-in.
+pub(crate) fn resolve(
+    recorded: &str,
+    requested: Option<&Arc<dyn KeyCodec>>,
+) -> Result<Arc<dyn KeyCodec>> {
+    match requested {
+        Some(codec) if codec.name() == recorded => Ok(Arc::clone(codec)),
+        Some(codec) => Err(KvsError::KeyCodecMismatch(format!(
+            "store uses key codec '{}', not '{}'",
+            recorded,
+            codec.name()
+        ))),
+        None => builtin(recorded).ok_or_else(|| {
+            KvsError::KeyCodecMismatch(format!(
+                "store uses the custom key codec '{}', which has to be supplied through `KvOpts::key_codec`",
+                recorded
+            ))
+        }),
+    }
+}
@@ -0,0 +1,335 @@
+//! The block format of data segments.
+//!
+//! A data segment is a sequence of blocks. Every block spans a whole number
+//! of `BLOCK_SIZE` units and ends with a fixed-size [`Trailer`]:
+//!
+//! ```text
+//! +---------+---------+---------------------------------------------------+
+//! | payload | padding | len | count | units | compression | kind | crc     |
+//! +---------+---------+---------------------------------------------------+
+//! ```
+//!
+//! The payload of a data block is the concatenation of the block's serialized
+//! commands. Commands are never split across blocks; a command too large for
+//! a single unit gets a block of its own spanning as many units as it needs.
+//!
+//! A sealed segment ends with a footer block, whose payload describes the
+//! segment as a whole (see the `footer` module).
+//!
+//! Because blocks always start on a unit boundary, a reader that runs into a
+//! damaged block can step to the next unit and carry on from there.
+//!
+//! [`Trailer`]: struct.Trailer.html
+use std::convert::TryInto;
+use std::io::{Read, Seek, SeekFrom};
+
+use crate::util::crc::Crc32;
+use crate::util::errors::Result;
+
+/// The unit, in bytes, that every block is a multiple of.
+pub const BLOCK_SIZE: u64 = 4096;
+
+/// The encoded length of a [`Trailer`](struct.Trailer.html).
+pub const TRAILER_LEN: u64 = 18;
+
+/// The number of payload bytes that fit into a single unit.
+const UNIT_CAPACITY: usize = (BLOCK_SIZE - TRAILER_LEN) as usize;
+
+/// Block payloads are stored as they are. This is the only compression
+/// scheme supported so far; the trailer leaves room for others.
+pub const COMPRESSION_NONE: u8 = 0;
+
+/// A block holding serialized commands.
+pub const KIND_DATA: u8 = 0;
+
+/// The block that seals a segment.
+pub const KIND_FOOTER: u8 = 1;
+
+/// The fixed-size trailer found at the end of every block.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Trailer {
+    /// The length of the payload in bytes.
+    pub len: u32,
+    /// The number of commands in the payload.
+    pub count: u32,
+    /// The number of `BLOCK_SIZE` units the block spans.
+    pub units: u32,
+    /// How the payload is compressed.
+    pub compression: u8,
+    /// What the payload holds.
+    pub kind: u8,
+}
+
+impl Trailer {
+    fn encode(&self, payload: &[u8]) -> [u8; TRAILER_LEN as usize] {
+        let mut buf = [0u8; TRAILER_LEN as usize];
+        buf[0..4].copy_from_slice(&self.len.to_le_bytes());
+        buf[4..8].copy_from_slice(&self.count.to_le_bytes());
+        buf[8..12].copy_from_slice(&self.units.to_le_bytes());
+        buf[12] = self.compression;
+        buf[13] = self.kind;
+        let crc = checksum(payload, &buf[..14]);
+        buf[14..18].copy_from_slice(&crc.to_le_bytes());
+        buf
+    }
+
+    /// Decodes a trailer, returning it along with the checksum it carries.
+    fn decode(buf: &[u8]) -> (Trailer, u32) {
+        let u32_at = |i: usize| u32::from_le_bytes(buf[i..i + 4].try_into().unwrap());
+        let trailer = Trailer {
+            len: u32_at(0),
+            count: u32_at(4),
+            units: u32_at(8),
+            compression: buf[12],
+            kind: buf[13],
+        };
+        (trailer, u32_at(14))
+    }
+}
+
+/// The checksum covers the payload as well as the rest of the trailer, so a
+/// damaged trailer is caught just like a damaged payload.
+fn checksum(payload: &[u8], trailer: &[u8]) -> u32 {
+    let mut crc = Crc32::new();
+    crc.update(payload);
+    crc.update(trailer);
+    crc.finish()
+}
+
+/// Returns the number of units a block with a payload of `len` bytes spans.
+fn units_for(len: usize) -> u64 {
+    (len as u64 + TRAILER_LEN).div_ceil(BLOCK_SIZE).max(1)
+}
+
+/// Groups serialized commands into sealed blocks.
+///
+/// The builder's buffer holds any number of sealed blocks followed by the
+/// payload of the block currently being filled. Because a payload sits at
+/// the very start of its block, a command's offset within the buffer is also
+/// its offset once the buffer is written out.
+#[derive(Debug, Default)]
+pub struct BlockBuilder {
+    buf: Vec<u8>,
+    /// Where the open block starts within `buf`.
+    start: usize,
+    /// The number of commands in the open block.
+    count: u32,
+}
+
+impl BlockBuilder {
+    pub fn new() -> BlockBuilder {
+        BlockBuilder::default()
+    }
+
+    /// Adds a serialized command, returning its offset within the buffer.
+    ///
+    /// The open block is sealed first if the command does not fit, and again
+    /// afterwards if the command filled it up.
+    pub fn add(&mut self, record: &[u8]) -> usize {
+        if self.count > 0 && self.buf.len() - self.start + record.len() > UNIT_CAPACITY {
+            self.seal();
+        }
+        let offset = self.buf.len();
+        self.buf.extend_from_slice(record);
+        self.count += 1;
+        if self.buf.len() - self.start >= UNIT_CAPACITY {
+            self.seal();
+        }
+        offset
+    }
+
+    /// Pads the open block and appends its trailer. Does nothing if the open
+    /// block is empty.
+    pub fn seal(&mut self) {
+        if self.count == 0 {
+            return;
+        }
+        self.finish_block(KIND_DATA);
+    }
+
+    /// Seals the open block and appends a footer block holding `payload`.
+    pub fn add_footer(&mut self, payload: &[u8]) {
+        self.seal();
+        self.buf.extend_from_slice(payload);
+        self.finish_block(KIND_FOOTER);
+    }
+
+    fn finish_block(&mut self, kind: u8) {
+        let len = self.buf.len() - self.start;
+        let units = units_for(len);
+        let trailer = Trailer {
+            len: len as u32,
+            count: self.count,
+            units: units as u32,
+            compression: COMPRESSION_NONE,
+            kind,
+        };
+        let encoded = trailer.encode(&self.buf[self.start..]);
+        let end = self.start + (units * BLOCK_SIZE) as usize;
+        self.buf.resize(end - TRAILER_LEN as usize, 0);
+        self.buf.extend_from_slice(&encoded);
+        self.start = self.buf.len();
+        self.count = 0;
+    }
+
+    /// Returns `true` if there is a block that has not been sealed yet.
+    pub fn is_open(&self) -> bool {
+        self.count > 0
+    }
+
+    pub fn len(&self) -> usize {
+        self.buf.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        &self.buf
+    }
+
+    pub fn clear(&mut self) {
+        self.buf.clear();
+        self.start = 0;
+        self.count = 0;
+    }
+}
+
+/// A block whose checksum has been verified.
+#[derive(Debug)]
+pub struct Block {
+    /// The position of the block within its segment.
+    pub start: u64,
+    pub trailer: Trailer,
+    pub payload: Vec<u8>,
+}
+
+/// Walks the blocks of a segment from front to back, skipping any unit that
+/// does not start a valid block.
+pub struct BlockReader<'a, R: Read + Seek> {
+    reader: &'a mut R,
+    pos: u64,
+    end: u64,
+    damaged: u64,
+}
+
+impl<'a, R: Read + Seek> BlockReader<'a, R> {
+    pub fn new(reader: &'a mut R) -> Result<Self> {
+        let end = reader.seek(SeekFrom::End(0))?;
+        Ok(BlockReader {
+            reader,
+            pos: 0,
+            end,
+            damaged: 0,
+        })
+    }
+
+    /// The number of units skipped so far because they did not start a
+    /// valid block.
+    pub fn damaged(&self) -> u64 {
+        self.damaged
+    }
+
+    /// Returns the segment's footer block if the segment has been sealed.
+    ///
+    /// The footer's checksum is only checked if `verify` is set.
+    pub fn footer(&mut self, verify: bool) -> Result<Option<Block>> {
+        if self.end < BLOCK_SIZE || !self.end.is_multiple_of(BLOCK_SIZE) {
+            return Ok(None);
+        }
+        let mut buf = [0u8; TRAILER_LEN as usize];
+        self.reader.seek(SeekFrom::Start(self.end - TRAILER_LEN))?;
+        self.reader.read_exact(&mut buf)?;
+        let (trailer, _) = Trailer::decode(&buf);
+
+        let len = u64::from(trailer.units) * BLOCK_SIZE;
+        if trailer.kind != KIND_FOOTER
+            || len == 0
+            || len > self.end
+            || u64::from(trailer.len) > len - TRAILER_LEN
+        {
+            return Ok(None);
+        }
+        if !verify {
+            let start = self.end - len;
+            let mut payload = vec![0u8; trailer.len as usize];
+            self.reader.seek(SeekFrom::Start(start))?;
+            self.reader.read_exact(&mut payload)?;
+            return Ok(Some(Block {
+                start,
+                trailer,
+                payload,
+            }));
+        }
+        Ok(self
+            .block_at(self.end - len)?
+            .filter(|block| block.trailer.kind == KIND_FOOTER))
+    }
+
+    /// Returns the next valid data block, or `None` once the segment is
+    /// exhausted.
+    pub fn next_block(&mut self) -> Result<Option<Block>> {
+        while self.pos + BLOCK_SIZE <= self.end {
+            if let Some(block) = self.block_at(self.pos)? {
+                self.pos += u64::from(block.trailer.units) * BLOCK_SIZE;
+                if block.trailer.kind == KIND_FOOTER {
+                    continue;
+                }
+                return Ok(Some(block));
+            }
+            self.pos += BLOCK_SIZE;
+            self.damaged += 1;
+        }
+        // A partial unit at the end of the segment can only be a torn write.
+        if self.pos < self.end {
+            self.pos = self.end;
+            self.damaged += 1;
+        }
+        Ok(None)
+    }
+
+    /// Looks for a block starting at `start`. A block of `n` units has its
+    /// trailer at the end of its `n`th unit, so each unit boundary is tried
+    /// in turn until a trailer claims the right number of units and its
+    /// checksum holds.
+    ///
+    /// The search stops at the first intact block it runs into, wherever
+    /// that block starts: blocks never overlap, so no block starting at
+    /// `start` can reach past it. A damaged unit therefore costs a read up
+    /// to the next intact block, rather than to the end of the segment.
+    pub fn block_at(&mut self, start: u64) -> Result<Option<Block>> {
+        let mut buf = [0u8; TRAILER_LEN as usize];
+        let mut units = 1;
+        while start + units * BLOCK_SIZE <= self.end {
+            let end = start + units * BLOCK_SIZE;
+            self.reader.seek(SeekFrom::Start(end - TRAILER_LEN))?;
+            self.reader.read_exact(&mut buf)?;
+            let (trailer, crc) = Trailer::decode(&buf);
+
+            let claimed = u64::from(trailer.units);
+            if (1..=units).contains(&claimed)
+                && u64::from(trailer.len) <= claimed * BLOCK_SIZE - TRAILER_LEN
+                && trailer.compression == COMPRESSION_NONE
+                && (trailer.kind == KIND_DATA || trailer.kind == KIND_FOOTER)
+            {
+                let block_start = end - claimed * BLOCK_SIZE;
+                let mut payload = vec![0u8; trailer.len as usize];
+                self.reader.seek(SeekFrom::Start(block_start))?;
+                self.reader.read_exact(&mut payload)?;
+                if checksum(&payload, &buf[..14]) == crc {
+                    if block_start != start {
+                        return Ok(None);
+                    }
+                    return Ok(Some(Block {
+                        start,
+                        trailer,
+                        payload,
+                    }));
+                }
+            }
+            units += 1;
+        }
+        Ok(None)
+    }
+}
@@ -0,0 +1,202 @@
+//! I/O accounting.
+//!
+//! A [`CountedFs`] wraps the file system a store is opened on, and counts
+//! every byte read from and written to the store's files, and every sync,
+//! into the [`IoCounters`] it shares with the store. It also times every
+//! sync against the store's watchdog.
+//!
+//! [`CountedFs`]: struct.CountedFs.html
+//! [`IoCounters`]: struct.IoCounters.html
+use std::io::{self, IoSlice, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+use crate::kvio::fs::{Fs, FsFile, LockGuard, OpenMode};
+use crate::watchdog::Watchdog;
+
+/// What a store's files have seen since the store was opened.
+#[derive(Debug, Default)]
+pub struct IoCounters {
+    bytes_read: AtomicU64,
+    bytes_written: AtomicU64,
+    syncs: AtomicU64,
+    slow_syncs: AtomicU64,
+    slow_compactions: AtomicU64,
+}
+
+impl IoCounters {
+    pub fn bytes_read(&self) -> u64 {
+        self.bytes_read.load(Ordering::Relaxed)
+    }
+
+    pub fn bytes_written(&self) -> u64 {
+        self.bytes_written.load(Ordering::Relaxed)
+    }
+
+    pub fn syncs(&self) -> u64 {
+        self.syncs.load(Ordering::Relaxed)
+    }
+
+    pub fn slow_syncs(&self) -> u64 {
+        self.slow_syncs.load(Ordering::Relaxed)
+    }
+
+    pub fn slow_compactions(&self) -> u64 {
+        self.slow_compactions.load(Ordering::Relaxed)
+    }
+
+    pub fn compaction_stalled(&self) {
+        self.slow_compactions.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn read(&self, len: usize) {
+        self.bytes_read.fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    fn written(&self, len: usize) {
+        self.bytes_written.fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    /// Counts a sync that started at `start`, and reports it to `watchdog`
+    /// if it took too long.
+    fn synced(&self, start: Instant, watchdog: &Watchdog) {
+        self.syncs.fetch_add(1, Ordering::Relaxed);
+        if watchdog.check_sync(start.elapsed()) {
+            self.slow_syncs.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// An `Fs` that counts the I/O done through it.
+#[derive(Debug)]
+pub struct CountedFs {
+    inner: Arc<dyn Fs>,
+    counters: Arc<IoCounters>,
+    watchdog: Watchdog,
+}
+
+impl CountedFs {
+    pub fn new(inner: Arc<dyn Fs>, counters: Arc<IoCounters>, watchdog: Watchdog) -> CountedFs {
+        CountedFs {
+            inner,
+            counters,
+            watchdog,
+        }
+    }
+
+    fn wrap(&self, file: Box<dyn FsFile>) -> Box<dyn FsFile> {
+        Box::new(CountedFile {
+            inner: file,
+            counters: Arc::clone(&self.counters),
+            watchdog: self.watchdog.clone(),
+        })
+    }
+}
+
+impl Fs for CountedFs {
+    fn open(&self, path: &Path, mode: OpenMode) -> io::Result<Box<dyn FsFile>> {
+        self.inner.open(path, mode).map(|file| self.wrap(file))
+    }
+
+    fn read_dir(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        self.inner.read_dir(dir)
+    }
+
+    fn subdirs(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        self.inner.subdirs(dir)
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.inner.exists(path)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        self.inner.rename(from, to)
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        self.inner.remove_file(path)
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        self.inner.create_dir_all(path)
+    }
+
+    fn create_direct(&self, path: &Path) -> io::Result<Box<dyn FsFile>> {
+        self.inner.create_direct(path).map(|file| self.wrap(file))
+    }
+
+    fn sync_dir(&self, dir: &Path) -> io::Result<()> {
+        let start = Instant::now();
+        self.inner.sync_dir(dir)?;
+        self.counters.synced(start, &self.watchdog);
+        Ok(())
+    }
+
+    fn lock(&self, path: &Path) -> io::Result<Option<LockGuard>> {
+        self.inner.lock(path)
+    }
+
+    fn available_space(&self, dir: &Path) -> Option<io::Result<u64>> {
+        self.inner.available_space(dir)
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        let buf = self.inner.read(path)?;
+        self.counters.read(buf.len());
+        Ok(buf)
+    }
+}
+
+struct CountedFile {
+    inner: Box<dyn FsFile>,
+    counters: Arc<IoCounters>,
+    watchdog: Watchdog,
+}
+
+impl Read for CountedFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        self.counters.read(len);
+        Ok(len)
+    }
+}
+
+impl Seek for CountedFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+impl Write for CountedFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.inner.write(buf)?;
+        self.counters.written(len);
+        Ok(len)
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        let len = self.inner.write_vectored(bufs)?;
+        self.counters.written(len);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl FsFile for CountedFile {
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        self.inner.set_len(len)
+    }
+
+    fn sync_data(&mut self) -> io::Result<()> {
+        let start = Instant::now();
+        self.inner.sync_data()?;
+        self.counters.synced(start, &self.watchdog);
+        Ok(())
+    }
+}
@@ -0,0 +1,145 @@
+//! Writing files around the page cache, with `O_DIRECT`.
+//!
+//! Direct I/O moves data straight between the disk and the caller's buffer,
+//! which has to be aligned to the disk's blocks, as do the length and the
+//! file offset of every write. A [`DirectFile`] stages writes in an aligned
+//! buffer and only hands whole units of `ALIGN` bytes to the kernel.
+//!
+//! [`DirectFile`]: struct.DirectFile.html
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::io::AsRawFd;
+
+use crate::kvio::fs::FsFile;
+
+/// The alignment direct I/O needs. This covers the logical block size of
+/// just about every disk.
+const ALIGN: usize = 4096;
+
+/// The number of bytes staged before they are written out.
+const CAPACITY: usize = 256 * ALIGN;
+
+/// A file opened with `O_DIRECT` for writing.
+///
+/// A write that leaves a partial unit behind, such as the last one to a file
+/// whose length is not a multiple of `ALIGN`, is finished with direct I/O
+/// switched off, and the rest of the file is written through the page cache
+/// like any other.
+pub struct DirectFile {
+    file: File,
+    /// Oversized so that an aligned window of `CAPACITY` bytes fits in it.
+    buf: Vec<u8>,
+    /// Where the aligned window starts within `buf`.
+    start: usize,
+    /// The number of bytes staged in the window.
+    len: usize,
+    direct: bool,
+}
+
+impl DirectFile {
+    /// Wraps `file`, which must have been opened with `O_DIRECT` at an
+    /// aligned offset.
+    pub fn new(file: File) -> DirectFile {
+        let buf = vec![0u8; CAPACITY + ALIGN];
+        let start = buf.as_ptr().align_offset(ALIGN);
+        DirectFile {
+            file,
+            buf,
+            start,
+            len: 0,
+            direct: true,
+        }
+    }
+
+    /// Writes out every whole unit that is staged, keeping the rest.
+    fn write_units(&mut self) -> io::Result<()> {
+        let units = self.len - self.len % ALIGN;
+        if units == 0 {
+            return Ok(());
+        }
+        let window = self.start..self.start + self.len;
+        self.file
+            .write_all(&self.buf[self.start..self.start + units])?;
+        self.buf
+            .copy_within(window.start + units..window.end, self.start);
+        self.len -= units;
+        Ok(())
+    }
+
+    /// Writes out everything that is staged, switching direct I/O off for a
+    /// partial unit.
+    fn write_all_staged(&mut self) -> io::Result<()> {
+        if self.direct {
+            self.write_units()?;
+            if self.len == 0 {
+                return Ok(());
+            }
+            self.buffered()?;
+        }
+        self.file
+            .write_all(&self.buf[self.start..self.start + self.len])?;
+        self.len = 0;
+        Ok(())
+    }
+
+    /// Switches direct I/O off for the rest of the file.
+    fn buffered(&mut self) -> io::Result<()> {
+        let fd = self.file.as_raw_fd();
+        // SAFETY: `fd` is the open descriptor of `self.file`, and neither
+        // call touches memory.
+        let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+        if flags < 0 || unsafe { libc::fcntl(fd, libc::F_SETFL, flags & !libc::O_DIRECT) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        self.direct = false;
+        Ok(())
+    }
+}
+
+impl Write for DirectFile {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let n = data.len().min(CAPACITY - self.len);
+        let at = self.start + self.len;
+        self.buf[at..at + n].copy_from_slice(&data[..n]);
+        self.len += n;
+        if self.len == CAPACITY {
+            self.write_units()?;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_all_staged()
+    }
+}
+
+impl Read for DirectFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.file.read(buf)
+    }
+}
+
+impl Seek for DirectFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.write_all_staged()?;
+        self.file.seek(pos)
+    }
+}
+
+impl FsFile for DirectFile {
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        self.write_all_staged()?;
+        self.file.set_len(len)
+    }
+
+    fn sync_data(&mut self) -> io::Result<()> {
+        self.write_all_staged()?;
+        self.file.sync_data()
+    }
+}
+
+impl Drop for DirectFile {
+    fn drop(&mut self) {
+        let _ = self.write_all_staged();
+    }
+}
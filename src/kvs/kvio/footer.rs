@@ -0,0 +1,54 @@
+//! Segment footers.
+//!
+//! When a segment is sealed, a footer describing its final state is written
+//! as its last block. Loading a sealed segment means reading its footer
+//! instead of replaying it command by command.
+use serde::{Deserialize, Serialize};
+
+/// Where the latest `Set` command of a key lives within a segment.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct FooterEntry {
+    pub key: String,
+    pub pos: u64,
+    pub len: u64,
+    /// When the key expires, in milliseconds since the Unix epoch.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires: Option<u64>,
+    /// When the key was soft-deleted, in milliseconds since the Unix epoch,
+    /// if a later command in the segment soft-deleted it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deleted: Option<u64>,
+}
+
+/// A key whose latest command in a segment soft-deleted it, while its latest
+/// `Set` command is in an earlier segment.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct SoftRemoved {
+    pub key: String,
+    /// When the key was soft-deleted, in milliseconds since the Unix epoch.
+    pub deleted: u64,
+}
+
+/// The final state of a sealed segment.
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
+pub struct Footer {
+    /// Keys whose latest command in the segment is a `Set`, sorted by key.
+    pub entries: Vec<FooterEntry>,
+    /// Keys whose latest command in the segment is a `Remove`, sorted.
+    pub removed: Vec<String>,
+    /// Keys whose latest command in the segment is a soft `Remove`, sorted
+    /// by key.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub soft_removed: Vec<SoftRemoved>,
+    /// The number of bytes in the segment that are stale regardless of what
+    /// any other segment holds.
+    pub stale_bytes: u64,
+    /// Whether the segment was written by a compaction, in which case every
+    /// segment with a lower version was compacted into it.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub compaction: bool,
+}
+
+fn is_false(b: &bool) -> bool {
+    !*b
+}
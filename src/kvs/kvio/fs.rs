@@ -0,0 +1,515 @@
+//! The file system a store lives on.
+//!
+//! Every file a `KvStore` touches is opened through an [`Fs`]. The default,
+//! [`StdFs`], is a thin wrapper around `std::fs`, and [`MemFs`] keeps every
+//! file in memory. Other implementations can keep files elsewhere, such as
+//! in an object store, or misbehave on purpose to test how the store copes
+//! with failing I/O.
+//!
+//! [`Fs`]: trait.Fs.html
+//! [`StdFs`]: struct.StdFs.html
+//! [`MemFs`]: struct.MemFs.html
+use std::any::Any;
+use std::collections::{HashMap, HashSet};
+use std::fmt::{self, Debug};
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+#[cfg(target_os = "linux")]
+use crate::kvio::direct::DirectFile;
+#[cfg(windows)]
+use std::{thread, time::Duration};
+
+/// How an [`Fs`] opens a file.
+///
+/// [`Fs`]: trait.Fs.html
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OpenMode {
+    /// Opens an existing file for reading.
+    Read,
+    /// Opens a file for reading and writing, creating it if it is missing.
+    ReadWrite,
+    /// Opens a file for appending, creating it if it is missing.
+    Append,
+    /// Creates a file for writing, truncating it if it exists.
+    Create,
+}
+
+/// A file opened through an [`Fs`].
+///
+/// [`Fs`]: trait.Fs.html
+pub trait FsFile: Read + Write + Seek + Send {
+    /// Truncates or extends the file to `len` bytes.
+    fn set_len(&mut self, len: u64) -> io::Result<()>;
+
+    /// Asks for the file's contents to be persisted.
+    fn sync_data(&mut self) -> io::Result<()>;
+}
+
+/// The file operations a `KvStore` needs.
+pub trait Fs: Debug + Send + Sync {
+    /// Opens the file at `path`.
+    fn open(&self, path: &Path, mode: OpenMode) -> io::Result<Box<dyn FsFile>>;
+
+    /// Returns the paths of the files, but not the directories, in `dir`.
+    fn read_dir(&self, dir: &Path) -> io::Result<Vec<PathBuf>>;
+
+    /// Returns the paths of the directories in `dir`.
+    fn subdirs(&self, dir: &Path) -> io::Result<Vec<PathBuf>>;
+
+    /// Returns whether anything exists at `path`.
+    fn exists(&self, path: &Path) -> bool;
+
+    /// Renames `from` to `to`, replacing `to` if it exists.
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+
+    /// Removes the file at `path`.
+    fn remove_file(&self, path: &Path) -> io::Result<()>;
+
+    /// Creates the directory at `path`, along with any missing parents.
+    fn create_dir_all(&self, path: &Path) -> io::Result<()>;
+
+    /// Creates a file for writing, truncating it if it exists, like
+    /// `OpenMode::Create` does, but bypassing the OS's page cache if this
+    /// file system can. By default, this is just `OpenMode::Create`.
+    fn create_direct(&self, path: &Path) -> io::Result<Box<dyn FsFile>> {
+        self.open(path, OpenMode::Create)
+    }
+
+    /// Asks for the entries of the directory at `dir` to be persisted, so
+    /// that files created, renamed or removed in it stay that way after a
+    /// crash. By default, this does nothing.
+    fn sync_dir(&self, _dir: &Path) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Takes an exclusive lock on the file at `path`, creating the file if
+    /// it is missing. The lock is held until the returned guard is dropped.
+    ///
+    /// Fails with `io::ErrorKind::WouldBlock` if the lock is already held.
+    /// Returns `None` if this file system has no locks, which is the
+    /// default.
+    fn lock(&self, _path: &Path) -> io::Result<Option<LockGuard>> {
+        Ok(None)
+    }
+
+    /// Returns the number of bytes free for unprivileged users on the file
+    /// system `dir` is on, or `None` if this file system cannot tell, which
+    /// is the default.
+    fn available_space(&self, _dir: &Path) -> Option<io::Result<u64>> {
+        None
+    }
+
+    /// Reads the whole file at `path`.
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        self.open(path, OpenMode::Read)?.read_to_end(&mut buf)?;
+        Ok(buf)
+    }
+}
+
+/// A lock taken with [`Fs::lock`], which is held until this is dropped.
+///
+/// What holds the lock is up to the [`Fs`] that took it, such as the locked
+/// file for [`StdFs`].
+///
+/// [`Fs::lock`]: trait.Fs.html#method.lock
+/// [`Fs`]: trait.Fs.html
+/// [`StdFs`]: struct.StdFs.html
+pub struct LockGuard {
+    _held: Box<dyn Any + Send>,
+}
+
+impl LockGuard {
+    /// Creates a guard that holds the lock for as long as `held` lives.
+    pub fn new<T: Send + 'static>(held: T) -> LockGuard {
+        LockGuard {
+            _held: Box::new(held),
+        }
+    }
+}
+
+impl Debug for LockGuard {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("LockGuard")
+    }
+}
+
+/// The [`Fs`] backed by the local file system, through `std::fs`.
+///
+/// Files created with [`Fs::create_direct`] are opened with `O_DIRECT` on
+/// Linux, unless the file system refuses it, as tmpfs does. Everywhere else
+/// they are ordinary files.
+///
+/// Locks are the platform's own: `flock` on Unix, `LockFileEx` on Windows.
+/// Directories are synced with `fsync` on Unix; Windows has no way to sync
+/// a directory, and NTFS does not need one for its metadata to survive.
+///
+/// On Windows, a file cannot be removed or replaced while another process,
+/// such as a virus scanner or an indexer, holds it open without allowing
+/// that. Those processes rarely hold on for long, so removes and renames
+/// that are denied are retried a few times before they fail.
+///
+/// [`Fs`]: trait.Fs.html
+/// [`Fs::create_direct`]: trait.Fs.html#method.create_direct
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StdFs;
+
+impl Fs for StdFs {
+    fn open(&self, path: &Path, mode: OpenMode) -> io::Result<Box<dyn FsFile>> {
+        let mut opts = OpenOptions::new();
+        match mode {
+            OpenMode::Read => opts.read(true),
+            OpenMode::ReadWrite => opts.read(true).write(true).create(true).truncate(false),
+            OpenMode::Append => opts.append(true).create(true),
+            OpenMode::Create => opts.write(true).create(true).truncate(true),
+        };
+        Ok(Box::new(opts.open(path)?))
+    }
+
+    fn read_dir(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        let mut paths = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.is_file() {
+                paths.push(path);
+            }
+        }
+        Ok(paths)
+    }
+
+    fn subdirs(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        let mut paths = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.is_dir() {
+                paths.push(path);
+            }
+        }
+        Ok(paths)
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        path.exists()
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        retry_denied(|| fs::rename(from, to))
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        retry_denied(|| fs::remove_file(path))
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        fs::create_dir_all(path)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn create_direct(&self, path: &Path) -> io::Result<Box<dyn FsFile>> {
+        use std::os::unix::fs::OpenOptionsExt;
+
+        let direct = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .custom_flags(libc::O_DIRECT)
+            .open(path);
+        match direct {
+            Ok(file) => Ok(Box::new(DirectFile::new(file))),
+            Err(err) if err.raw_os_error() == Some(libc::EINVAL) => {
+                self.open(path, OpenMode::Create)
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    #[cfg(unix)]
+    fn sync_dir(&self, dir: &Path) -> io::Result<()> {
+        File::open(dir)?.sync_all()
+    }
+
+    #[cfg(target_os = "linux")]
+    fn available_space(&self, dir: &Path) -> Option<io::Result<u64>> {
+        use std::ffi::CString;
+        use std::os::unix::ffi::OsStrExt;
+
+        let path = match CString::new(dir.as_os_str().as_bytes()) {
+            Ok(path) => path,
+            Err(err) => return Some(Err(err.into())),
+        };
+        // SAFETY: `path` is a NUL-terminated string, and `stat` is written to
+        // by `statvfs` before it is read.
+        let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+        if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+            return Some(Err(io::Error::last_os_error()));
+        }
+        Some(Ok(stat.f_bavail as u64 * stat.f_frsize as u64))
+    }
+
+    fn lock(&self, path: &Path) -> io::Result<Option<LockGuard>> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        match file.try_lock() {
+            Ok(()) => Ok(Some(LockGuard::new(file))),
+            // Where the platform has no file locks, such as on some WASI
+            // runtimes, the store is left unlocked, and nothing stops a
+            // second process from opening it.
+            Err(TryLockError::Error(err)) if err.kind() == io::ErrorKind::Unsupported => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+/// Runs `op`, retrying it a few times if it is denied, which on Windows is
+/// usually because another process briefly holds the file open.
+#[cfg(windows)]
+fn retry_denied<T>(mut op: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+    const RETRIES: u32 = 5;
+    let mut attempt = 0;
+    loop {
+        match op() {
+            Err(err) if err.kind() == io::ErrorKind::PermissionDenied && attempt < RETRIES => {
+                attempt += 1;
+                thread::sleep(Duration::from_millis(10 << attempt));
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Runs `op`. Only Windows denies removing or replacing files that are
+/// open, so there is nothing to retry elsewhere.
+#[cfg(not(windows))]
+fn retry_denied<T>(mut op: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+    op()
+}
+
+impl FsFile for File {
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        File::set_len(self, len)
+    }
+
+    fn sync_data(&mut self) -> io::Result<()> {
+        File::sync_data(self)
+    }
+}
+
+/// An [`Fs`] that keeps every file in memory.
+///
+/// Directories are implied by the paths of the files in them, so a store
+/// can be opened at any path without creating anything first. Clones share
+/// their files, so a store can be closed and opened again on a clone of the
+/// same `MemFs`.
+///
+/// Locks are kept in memory too, and shared by clones, so a store cannot be
+/// opened twice on the same `MemFs` at once.
+///
+/// [`Fs`]: trait.Fs.html
+#[derive(Debug, Clone, Default)]
+pub struct MemFs {
+    files: Arc<Mutex<HashMap<PathBuf, MemData>>>,
+    locks: Arc<Mutex<HashSet<PathBuf>>>,
+}
+
+/// The contents of a file in a `MemFs`, shared by every handle to it.
+type MemData = Arc<Mutex<Vec<u8>>>;
+
+impl MemFs {
+    /// Creates an empty file system.
+    pub fn new() -> MemFs {
+        MemFs::default()
+    }
+
+    fn files(&self) -> MutexGuard<'_, HashMap<PathBuf, MemData>> {
+        self.files.lock().expect("file system poisoned")
+    }
+}
+
+impl Fs for MemFs {
+    fn open(&self, path: &Path, mode: OpenMode) -> io::Result<Box<dyn FsFile>> {
+        let mut files = self.files();
+        let data = match mode {
+            OpenMode::Read => files
+                .get(path)
+                .cloned()
+                .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "file not found"))?,
+            OpenMode::ReadWrite | OpenMode::Append => {
+                Arc::clone(files.entry(path.to_owned()).or_default())
+            }
+            OpenMode::Create => {
+                let data = MemData::default();
+                files.insert(path.to_owned(), Arc::clone(&data));
+                data
+            }
+        };
+        Ok(Box::new(MemFile { data, pos: 0, mode }))
+    }
+
+    fn read_dir(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        Ok(self
+            .files()
+            .keys()
+            .filter(|path| path.parent() == Some(dir))
+            .cloned()
+            .collect())
+    }
+
+    fn subdirs(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        let mut subdirs: Vec<PathBuf> = self
+            .files()
+            .keys()
+            .filter_map(|path| {
+                let subdir = path.ancestors().find(|a| a.parent() == Some(dir))?;
+                if subdir == path {
+                    None
+                } else {
+                    Some(subdir.to_owned())
+                }
+            })
+            .collect();
+        subdirs.sort_unstable();
+        subdirs.dedup();
+        Ok(subdirs)
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.files().contains_key(path)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let mut files = self.files();
+        let data = files
+            .remove(from)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "file not found"))?;
+        files.insert(to.to_owned(), data);
+        Ok(())
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        // Handles that are still open keep the contents alive, just like
+        // they do on Unix.
+        self.files()
+            .remove(path)
+            .map(drop)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "file not found"))
+    }
+
+    fn create_dir_all(&self, _path: &Path) -> io::Result<()> {
+        // Directories are implied by the paths of the files in them.
+        Ok(())
+    }
+
+    fn lock(&self, path: &Path) -> io::Result<Option<LockGuard>> {
+        self.files().entry(path.to_owned()).or_default();
+        let mut locks = self.locks.lock().expect("file system poisoned");
+        if !locks.insert(path.to_owned()) {
+            return Err(io::Error::new(
+                io::ErrorKind::WouldBlock,
+                "the lock is already held",
+            ));
+        }
+        Ok(Some(LockGuard::new(MemLock {
+            locks: Arc::clone(&self.locks),
+            path: path.to_owned(),
+        })))
+    }
+}
+
+/// A lock held on a file in a `MemFs`, given back when it is dropped.
+struct MemLock {
+    locks: Arc<Mutex<HashSet<PathBuf>>>,
+    path: PathBuf,
+}
+
+impl Drop for MemLock {
+    fn drop(&mut self) {
+        if let Ok(mut locks) = self.locks.lock() {
+            locks.remove(&self.path);
+        }
+    }
+}
+
+struct MemFile {
+    data: MemData,
+    pos: u64,
+    mode: OpenMode,
+}
+
+impl MemFile {
+    fn data(&self) -> MutexGuard<'_, Vec<u8>> {
+        self.data.lock().expect("file poisoned")
+    }
+}
+
+impl Read for MemFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let data = self.data();
+        let start = (self.pos as usize).min(data.len());
+        let len = buf.len().min(data.len() - start);
+        buf[..len].copy_from_slice(&data[start..start + len]);
+        drop(data);
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+impl Write for MemFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.mode == OpenMode::Read {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "file opened for reading",
+            ));
+        }
+        let mut data = self.data.lock().expect("file poisoned");
+        if self.mode == OpenMode::Append {
+            self.pos = data.len() as u64;
+        }
+        let start = self.pos as usize;
+        if data.len() < start + buf.len() {
+            data.resize(start + buf.len(), 0);
+        }
+        data[start..start + buf.len()].copy_from_slice(buf);
+        self.pos += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for MemFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let (base, offset) = match pos {
+            SeekFrom::Start(offset) => {
+                self.pos = offset;
+                return Ok(offset);
+            }
+            SeekFrom::End(offset) => (self.data().len() as u64, offset),
+            SeekFrom::Current(offset) => (self.pos, offset),
+        };
+        self.pos = base.checked_add_signed(offset).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "seek to a negative position")
+        })?;
+        Ok(self.pos)
+    }
+}
+
+impl FsFile for MemFile {
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        self.data().resize(len as usize, 0);
+        Ok(())
+    }
+
+    fn sync_data(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
@@ -1,2 +1,9 @@
+pub mod block;
+pub mod counted;
+#[cfg(target_os = "linux")]
+pub mod direct;
+pub mod footer;
+pub mod fs;
 pub mod reader;
+pub mod wal;
 pub mod writer;
@@ -1,36 +1,124 @@
-use std::io::{self, BufReader, Read, Seek, SeekFrom};
+use std::io::{self, Read, Seek, SeekFrom};
 
+use crate::kvio::block::BLOCK_SIZE;
 use crate::util::errors::Result;
 
+/// How far ahead reads fetch while they jump around, the same as
+/// `BufReader`'s buffer.
+const RANDOM_READAHEAD: usize = 8 * 1024;
+
+/// How far ahead reads fetch once they are found to be sequential, so that
+/// a scan is bounded by the disk rather than by one read call per command.
+const SEQUENTIAL_READAHEAD: usize = 256 * 1024;
+
+/// The number of seeks in a row that move forward by at most `BLOCK_SIZE`
+/// before reads are treated as sequential. Neighbouring commands can have a
+/// block's trailer, or the padding at the end of a block, between them.
+const SEQUENTIAL_SEEKS: u32 = 4;
+
+/// A buffered reader that fetches further ahead while it is read
+/// sequentially, and keeps its buffer across seeks that land inside of it.
 #[derive(Debug)]
 pub struct KvsReader<R: Read + Seek> {
-    reader: BufReader<R>,
+    inner: R,
+    /// Bytes fetched from `inner`, of which those from `start` on have not
+    /// been read yet. `inner` is positioned just past the end of them.
+    buf: Vec<u8>,
+    start: usize,
     pos: u64,
+    /// How many bytes to fetch at a time.
+    readahead: usize,
+    /// The number of seeks in a row that moved forward by a little.
+    forward_seeks: u32,
 }
 
 impl<R: Read + Seek> KvsReader<R> {
     pub fn new(mut inner: R) -> Result<Self> {
         let pos = inner.seek(SeekFrom::Start(0))?;
         Ok(KvsReader {
-            reader: BufReader::new(inner),
+            inner,
+            buf: Vec::new(),
+            start: 0,
             pos,
+            readahead: RANDOM_READAHEAD,
+            forward_seeks: 0,
         })
     }
 
     pub fn pos(&self) -> u64 {
         self.pos
     }
+
+    /// Moves to `target`, within the buffer if it holds `target`.
+    fn seek_to(&mut self, target: u64) -> io::Result<u64> {
+        if target >= self.pos && target - self.pos <= BLOCK_SIZE {
+            self.forward_seeks = self.forward_seeks.saturating_add(1);
+        } else {
+            self.forward_seeks = 0;
+        }
+        self.readahead = if self.forward_seeks >= SEQUENTIAL_SEEKS {
+            SEQUENTIAL_READAHEAD
+        } else {
+            RANDOM_READAHEAD
+        };
+
+        let buf_start = self.pos - self.start as u64;
+        let buf_end = buf_start + self.buf.len() as u64;
+        if (buf_start..=buf_end).contains(&target) {
+            self.start = (target - buf_start) as usize;
+        } else {
+            self.inner.seek(SeekFrom::Start(target))?;
+            self.buf.clear();
+            self.start = 0;
+        }
+        self.pos = target;
+        Ok(target)
+    }
 }
 
 impl<R: Read + Seek> Read for KvsReader<R> {
-    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        self.reader.read(buf)
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        if self.start == self.buf.len() {
+            // A read at least as large as the readahead has nothing to gain
+            // from going through the buffer.
+            if out.len() >= self.readahead {
+                let len = self.inner.read(out)?;
+                self.buf.clear();
+                self.start = 0;
+                self.pos += len as u64;
+                return Ok(len);
+            }
+            self.buf.resize(self.readahead, 0);
+            let len = self.inner.read(&mut self.buf)?;
+            self.buf.truncate(len);
+            self.start = 0;
+        }
+        let len = out.len().min(self.buf.len() - self.start);
+        out[..len].copy_from_slice(&self.buf[self.start..self.start + len]);
+        self.start += len;
+        self.pos += len as u64;
+        Ok(len)
     }
 }
 
 impl<R: Read + Seek> Seek for KvsReader<R> {
     fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
-        self.pos = self.reader.seek(pos)?;
-        Ok(self.pos)
+        match pos {
+            SeekFrom::Start(target) => self.seek_to(target),
+            SeekFrom::Current(offset) => match self.pos.checked_add_signed(offset) {
+                Some(target) => self.seek_to(target),
+                None => Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "invalid seek to a negative or overflowing position",
+                )),
+            },
+            SeekFrom::End(_) => {
+                self.pos = self.inner.seek(pos)?;
+                self.buf.clear();
+                self.start = 0;
+                self.forward_seeks = 0;
+                Ok(self.pos)
+            }
+        }
     }
 }
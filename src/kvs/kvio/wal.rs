@@ -0,0 +1,145 @@
+//! The write-ahead log.
+//!
+//! Every command is appended to the write-ahead log _before_ it is handed to
+//! the active data segment. The data segment is written lazily, in large
+//! chunks, so the only file that has to be flushed (and optionally fsynced)
+//! on every write is this small, sequential one.
+//!
+//! The log starts with a [`WalHeader`] that records which segment, and at
+//! which position in that segment, the logged commands belong. Whenever the
+//! pending commands reach their data segment the log is [`reset`], so it
+//! never holds more than one chunk's worth of commands. The new header is
+//! only written along with the first command appended after a reset, in a
+//! single vectored write; a log that was reset and has nothing appended to it
+//! is empty, which recovers just the same as one with only a header.
+//!
+//! [`WalHeader`]: struct.WalHeader.html
+//! [`reset`]: struct.Wal.html#method.reset
+use std::io::{self, IoSlice, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Deserializer;
+
+use crate::kvio::fs::{Fs, FsFile, OpenMode};
+use crate::util::errors::Result;
+
+/// The name of the write-ahead log inside of a store's directory.
+pub const WAL_FILE_NAME: &str = "kvs.wal";
+
+/// Describes where the commands held in the log belong.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct WalHeader {
+    /// The version of the data segment the commands are destined for.
+    pub version: u64,
+    /// The length of that data segment when the log was last reset.
+    pub pos: u64,
+}
+
+/// A header recovered from the log along with the raw bytes that follow it.
+pub type Recovered = (WalHeader, Vec<u8>);
+
+pub struct Wal {
+    file: Box<dyn FsFile>,
+    sync: bool,
+    /// The length of the log, up to the end of the last complete append.
+    len: u64,
+    /// The serialized header of a log that was reset, if no command has been
+    /// appended to it since.
+    header: Option<Vec<u8>>,
+}
+
+impl Wal {
+    /// Opens (or creates) the write-ahead log in the directory `dir`.
+    ///
+    /// If the log holds a header, the header is returned along with the raw
+    /// bytes that follow it. Validating those bytes is left to the caller.
+    pub fn open<P: AsRef<Path>>(
+        fs: &dyn Fs,
+        dir: P,
+        sync: bool,
+    ) -> Result<(Wal, Option<Recovered>)> {
+        let mut file = fs.open(&dir.as_ref().join(WAL_FILE_NAME), OpenMode::ReadWrite)?;
+
+        let mut buf = Vec::new();
+        file.read_to_end(&mut buf)?;
+
+        // A log that is missing its header (e.g. the process died while the
+        // log was being reset) carries no commands worth recovering.
+        let mut stream = Deserializer::from_slice(&buf).into_iter::<WalHeader>();
+        let recovered = match stream.next() {
+            Some(Ok(header)) => {
+                let offset = stream.byte_offset();
+                Some((header, buf[offset..].to_vec()))
+            }
+            _ => None,
+        };
+        let len = buf.len() as u64;
+        let wal = Wal {
+            file,
+            sync,
+            len,
+            header: None,
+        };
+        Ok((wal, recovered))
+    }
+
+    /// Appends an already serialized command to the log.
+    ///
+    /// If the append fails, whatever part of the command reached the log is
+    /// cut off again, so that the command is not recovered and does not hide
+    /// the commands appended after it.
+    pub fn append(&mut self, buf: &[u8]) -> Result<()> {
+        let header = self.header.take().unwrap_or_default();
+        if let Err(err) = self.write(&header, buf) {
+            let _ = self.file.set_len(self.len);
+            let _ = self.file.seek(SeekFrom::Start(self.len));
+            if !header.is_empty() {
+                self.header = Some(header);
+            }
+            return Err(err);
+        }
+        self.len += (header.len() + buf.len()) as u64;
+        Ok(())
+    }
+
+    fn write(&mut self, header: &[u8], buf: &[u8]) -> Result<()> {
+        let mut slices = [IoSlice::new(header), IoSlice::new(buf)];
+        let mut slices = &mut slices[..];
+        while !slices.is_empty() {
+            match self.file.write_vectored(slices) {
+                Ok(0) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::WriteZero,
+                        "failed to write whole command",
+                    )
+                    .into())
+                }
+                Ok(len) => IoSlice::advance_slices(&mut slices, len),
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err.into()),
+            }
+        }
+        self.file.flush()?;
+        if self.sync {
+            self.file.sync_data()?;
+        }
+        Ok(())
+    }
+
+    /// Empties the log and starts it over with a new header, which is
+    /// written along with the next command appended.
+    ///
+    /// This must only be called once every command in the log has safely
+    /// reached its data segment.
+    pub fn reset(&mut self, version: u64, pos: u64) -> Result<()> {
+        self.file.set_len(0)?;
+        self.file.seek(SeekFrom::Start(0))?;
+        self.len = 0;
+        self.header = Some(serde_json::to_vec(&WalHeader { version, pos })?);
+        if self.sync {
+            self.file.sync_data()?;
+        }
+        Ok(())
+    }
+}
@@ -1,5 +1,6 @@
-use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::io::{self, BufWriter, Seek, Write};
 
+use crate::kvio::fs::FsFile;
 use crate::util::errors::Result;
 
 pub struct KvsWriter<W: Write + Seek> {
@@ -9,7 +10,7 @@ pub struct KvsWriter<W: Write + Seek> {
 
 impl<W: Write + Seek> KvsWriter<W> {
     pub fn new(mut inner: W) -> Result<Self> {
-        let pos = inner.seek(SeekFrom::Current(0))?;
+        let pos = inner.stream_position()?;
         Ok(KvsWriter {
             writer: BufWriter::new(inner),
             pos,
@@ -21,6 +22,15 @@ impl<W: Write + Seek> KvsWriter<W> {
     }
 }
 
+impl KvsWriter<Box<dyn FsFile>> {
+    /// Flushes the writer and asks the OS to persist the file's contents.
+    pub fn sync(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        self.writer.get_mut().sync_data()?;
+        Ok(())
+    }
+}
+
 impl<W: Write + Seek> Write for KvsWriter<W> {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
         let len = self.writer.write(buf)?;
@@ -0,0 +1,213 @@
+//! Where a store keeps its data segments.
+//!
+//! A [`SegmentLayout`] decides what every segment file is called and which
+//! directory it goes in. It is recorded in the store's metadata when the
+//! store is created, and every later `open` follows it.
+//!
+//! [`SegmentLayout`]: struct.SegmentLayout.html
+use std::collections::BinaryHeap;
+use std::ffi::OsStr;
+use std::num::NonZeroU64;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::kvio::fs::Fs;
+use crate::util::errors::Result;
+
+/// How a store's data segments are named and laid out on disk.
+///
+/// By default, segment `n` is the file `<n>.log` in the store's directory.
+///
+/// ```rust
+/// # use kvs::SegmentLayout;
+/// // Segments named `seg-00000042.dat`, sharded into subdirectories of at
+/// // most 1000 segments each.
+/// let layout = SegmentLayout::new()
+///     .prefix("seg-")
+///     .width(8)
+///     .extension("dat")
+///     .segments_per_dir(1000);
+/// ```
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct SegmentLayout {
+    prefix: String,
+    width: usize,
+    extension: String,
+    segments_per_dir: Option<NonZeroU64>,
+}
+
+impl Default for SegmentLayout {
+    fn default() -> SegmentLayout {
+        SegmentLayout {
+            prefix: String::new(),
+            width: 0,
+            extension: "log".to_owned(),
+            segments_per_dir: None,
+        }
+    }
+}
+
+impl SegmentLayout {
+    /// Creates the default layout.
+    pub fn new() -> SegmentLayout {
+        SegmentLayout::default()
+    }
+
+    /// Sets what every segment's file name starts with. Defaults to nothing.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `prefix` contains a path separator.
+    pub fn prefix<S: Into<String>>(mut self, prefix: S) -> SegmentLayout {
+        self.prefix = prefix.into();
+        assert!(
+            !self.prefix.contains(['/', '\\']),
+            "segment prefix contains a path separator"
+        );
+        self
+    }
+
+    /// Sets the number of digits a segment's version is padded to with
+    /// leading zeros. Defaults to 0, for no padding.
+    pub fn width(mut self, width: usize) -> SegmentLayout {
+        self.width = width;
+        self
+    }
+
+    /// Sets the extension of every segment's file name. Defaults to `log`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `extension` is empty or contains a `.` or a path separator.
+    pub fn extension<S: Into<String>>(mut self, extension: S) -> SegmentLayout {
+        self.extension = extension.into();
+        assert!(
+            !self.extension.is_empty() && !self.extension.contains(['.', '/', '\\']),
+            "segment extension is empty or contains a '.' or a path separator"
+        );
+        self
+    }
+
+    /// Shards segments across subdirectories of the store's directory, each
+    /// holding at most `segments_per_dir` segments. The subdirectory of
+    /// segment `n` is named after `n / segments_per_dir`. By default, every
+    /// segment is in the store's directory itself.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `segments_per_dir` is 0.
+    pub fn segments_per_dir(mut self, segments_per_dir: u64) -> SegmentLayout {
+        self.segments_per_dir =
+            Some(NonZeroU64::new(segments_per_dir).expect("segments_per_dir is 0"));
+        self
+    }
+
+    /// Returns the file name of the segment with the given version.
+    pub fn file_name(&self, version: u64) -> String {
+        format!(
+            "{}{:0width$}.{}",
+            self.prefix,
+            version,
+            self.extension,
+            width = self.width
+        )
+    }
+
+    /// Returns the version of the segment called `file_name`, if that is
+    /// exactly what this layout calls it.
+    pub fn version_of(&self, file_name: &str) -> Option<u64> {
+        let digits = file_name
+            .strip_prefix(&*self.prefix)?
+            .strip_suffix(&*self.extension)?
+            .strip_suffix('.')?;
+        if !digits.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        let version = digits.parse().ok()?;
+        if self.file_name(version) == file_name {
+            Some(version)
+        } else {
+            None
+        }
+    }
+
+    /// Returns whether a file called `file_name` could be mistaken for a
+    /// segment, which is the case if its name contains `.` followed by this
+    /// layout's extension.
+    pub(crate) fn resembles(&self, file_name: &str) -> bool {
+        file_name.contains(&format!(".{}", self.extension))
+    }
+
+    /// Returns the path of the segment with the given version in `dir`.
+    pub(crate) fn path(&self, dir: &Path, version: u64) -> PathBuf {
+        match self.segments_per_dir {
+            Some(n) => dir
+                .join((version / n.get()).to_string())
+                .join(self.file_name(version)),
+            None => dir.join(self.file_name(version)),
+        }
+    }
+
+    /// Returns the path of the segment with the given version in `dir`,
+    /// creating the subdirectory it goes in if need be.
+    pub(crate) fn create_path(&self, fs: &dyn Fs, dir: &Path, version: u64) -> Result<PathBuf> {
+        let path = self.path(dir, version);
+        if self.segments_per_dir.is_some() {
+            if let Some(parent) = path.parent() {
+                fs.create_dir_all(parent)?;
+            }
+        }
+        Ok(path)
+    }
+
+    /// Lists the versions of the segments in `dir`.
+    pub(crate) fn versions(&self, fs: &dyn Fs, dir: &Path) -> Result<BinaryHeap<u64>> {
+        let n = match self.segments_per_dir {
+            Some(n) => n.get(),
+            None => return Ok(self.versions_in(fs, dir)?.collect()),
+        };
+        let mut versions = BinaryHeap::new();
+        for subdir in fs.subdirs(dir)? {
+            let shard = match shard_of(&subdir) {
+                Some(shard) => shard,
+                None => continue,
+            };
+            versions.extend(self.versions_in(fs, &subdir)?.filter(|v| v / n == shard));
+        }
+        Ok(versions)
+    }
+
+    /// Returns `dir` along with every subdirectory of it that segments are
+    /// sharded into.
+    pub(crate) fn dirs(&self, fs: &dyn Fs, dir: &Path) -> Result<Vec<PathBuf>> {
+        let mut dirs = vec![dir.to_owned()];
+        if self.segments_per_dir.is_some() {
+            dirs.extend(
+                fs.subdirs(dir)?
+                    .into_iter()
+                    .filter(|subdir| shard_of(subdir).is_some()),
+            );
+        }
+        Ok(dirs)
+    }
+
+    fn versions_in<'a>(
+        &'a self,
+        fs: &dyn Fs,
+        dir: &Path,
+    ) -> Result<impl Iterator<Item = u64> + 'a> {
+        Ok(fs.read_dir(dir)?.into_iter().filter_map(move |path| {
+            path.file_name()
+                .and_then(OsStr::to_str)
+                .and_then(|name| self.version_of(name))
+        }))
+    }
+}
+
+/// Returns the shard a directory holds the segments of, if it is named like
+/// one.
+fn shard_of(dir: &Path) -> Option<u64> {
+    let name = dir.file_name()?.to_str()?;
+    name.parse::<u64>().ok().filter(|s| s.to_string() == name)
+}
@@ -1,30 +1,106 @@
 #![warn(missing_docs)]
 //! Primary data structures and algorithms for creating and manipulating
 //! [`KvStore`](struct.KvStore.html)
-use std::collections::{BinaryHeap, HashMap};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryFrom;
 use std::ffi::OsStr;
-use std::fs::{self, File, OpenOptions};
+use std::fmt;
+use std::fs;
 use std::io::{self, Read, Seek, SeekFrom, Write};
-use std::ops::Range;
+use std::mem;
+use std::ops::{Bound, Range, RangeBounds};
 use std::path::{Path, PathBuf};
-use std::str;
+use std::str::{self, FromStr};
+use std::sync::Arc;
+use std::time::Duration;
 
 // Third party crates.
+use serde::de::IgnoredAny;
 use serde::{Deserialize, Serialize};
 use serde_json::Deserializer;
 
 // Module declarations.
+mod analyze;
+mod batch;
+mod budget;
+mod cas;
+mod clock;
+mod compaction;
+mod doctor;
+mod hasher;
+mod key_codec;
 mod kvio;
+mod layout;
+mod lru;
+mod meta;
+mod ops;
+mod readers;
+mod scan;
+mod scoped;
+mod secondary;
+mod snapshot;
+#[cfg(feature = "testing")]
+pub mod testing;
 mod util;
+mod view;
+mod watchdog;
 
-use kvio::{reader::KvsReader, writer::KvsWriter};
+use batch::BatchOp;
+use budget::CacheCharge;
+use compaction::CompactionState;
+use key_codec::AsStored;
+use kvio::block::{BlockBuilder, BlockReader, BLOCK_SIZE, KIND_DATA};
+use kvio::counted::{CountedFs, IoCounters};
+use kvio::footer::{Footer, FooterEntry, SoftRemoved};
+use kvio::reader::KvsReader;
+use kvio::wal::{Wal, WalHeader, WAL_FILE_NAME};
+use kvio::writer::KvsWriter;
+use lru::Lru;
+use meta::{CODEC, FORMAT_VERSION, META_FILE_NAME};
+use ops::{Publisher, STATS_FILE_NAME};
+use readers::Readers;
+use secondary::SecondaryIndex;
+use util::rand::Rng;
+use watchdog::CompactionWatch;
 
+pub use analyze::{Analysis, Bucket, KeySpan, Prefix, SizeEstimate, TtlBuckets};
+pub use batch::WriteBatch;
+pub use budget::Budget;
+pub use cas::CasHash;
+pub use clock::{Clock, ManualClock, SystemClock};
+pub use compaction::{AdaptiveCompaction, CompactionPolicy};
+pub use doctor::{Check, CheckStatus};
+pub use hasher::IndexHasher;
+pub use key_codec::{CaseInsensitive, Exact, KeyCodec, Trimmed};
+pub use kvio::fs::{Fs, FsFile, LockGuard, MemFs, OpenMode, StdFs};
+pub use layout::SegmentLayout;
+pub use meta::StoreMeta;
+pub use ops::OpCounts;
+pub use scan::{Keys, Scan, ScanOpts};
+pub use scoped::ScopedStore;
+pub use secondary::{tokenize, Extractor, IndexKey, Tokenizer};
+pub use snapshot::Snapshot;
 /// Re-exports `util::command_prelude` to be brought in by
 /// `use kvs::command_prelude`.
+#[cfg(feature = "cli")]
 pub use util::command_prelude;
 pub use util::errors::{KvsError, Result};
+pub use view::LiveView;
+pub use watchdog::{Stall, StallKind, Watchdog};
 
-const MAX_STALE_BYTES: u64 = 512;
+/// A log file, opened through the store's [`Fs`].
+///
+/// [`Fs`]: trait.Fs.html
+type LogFile = Box<dyn FsFile>;
+
+/// A map keyed by normalized keys, kept in key order, so that a range of
+/// keys is found without looking at the keys outside of it.
+type Index<V> = BTreeMap<String, V>;
+
+/// The number of bytes worth of sealed blocks that triggers a write to the
+/// active data segment.
+const SEGMENT_BUFFER_SIZE: usize = 64 * 1024;
 
 /// Primary key-value store structure.
 ///
@@ -33,17 +109,93 @@ const MAX_STALE_BYTES: u64 = 512;
 /// [`KvsReader`].
 pub struct KvStore {
     /// A mapping between key-strings and their corresponding CommandPosition.
-    index: HashMap<String, CommandPosition>,
+    index: Index<CommandPosition>,
+    /// The soft-deleted keys, by the normalized key.
+    deleted: Index<SoftDeleted>,
     /// The path to this store's directory.
     path: PathBuf,
-    /// A mapping between a given version number and its corresponding reader.
-    readers: HashMap<u64, KvsReader<File>>,
+    /// The readers of the store's segments.
+    readers: Readers,
     /// The number of 'stale bytes' the current store contains.
     stale_bytes: u64,
+    /// The stale bytes taken up by the soft `Remove` of every soft-deleted
+    /// key, which compaction keeps until the key's window has passed.
+    soft_remove_bytes: u64,
     /// The writer of a log.
-    writer: KvsWriter<File>,
+    writer: KvsWriter<LogFile>,
+    /// Commands that are in the write-ahead log but have not yet been
+    /// written to the active data segment, grouped into blocks.
+    pending: BlockBuilder,
+    /// The buffer every command is serialized into before it is appended,
+    /// kept to save an allocation per write.
+    cmd_buf: Vec<u8>,
+    /// Whether a [`WriteBatch`] is being applied, which has been logged as a
+    /// whole already and is flushed once it has been applied.
+    ///
+    /// [`WriteBatch`]: struct.WriteBatch.html
+    in_batch: bool,
+    /// The buffer every command is read into before its value is taken out.
+    read_buf: Vec<u8>,
+    /// The value last read by `get_ref`.
+    value_buf: String,
+    /// The number of damaged blocks skipped while loading the logs.
+    damaged_blocks: u64,
+    /// The write-ahead log.
+    wal: Wal,
     /// The version number of a log.
     version: u64,
+    /// The options this store was opened with.
+    opts: KvOpts,
+    /// The store's metadata.
+    meta: StoreMeta,
+    /// Normalizes every key handed to the store.
+    key_codec: Arc<dyn KeyCodec>,
+    /// The registered secondary indexes, by name.
+    secondary: HashMap<String, SecondaryIndex>,
+    /// The token index backing `search`, if one is registered.
+    tokens: Option<SecondaryIndex>,
+    /// The number of bytes taken up by the latest `Set` command of every
+    /// live key.
+    live_bytes: u64,
+    /// The order in which keys were last used, in cache mode.
+    lru: Option<Lru>,
+    /// The number of keys evicted since the store was opened.
+    evictions: u64,
+    /// The I/O done on the store's files since the store was opened.
+    io_counters: Arc<IoCounters>,
+    /// The bytes of the keys and values written since the store was opened.
+    logical_bytes_written: u64,
+    /// The number of gets since the store was opened.
+    gets: u64,
+    /// The bytes those gets read from the store's files.
+    get_bytes_read: u64,
+    /// The number of `Set` commands written since the store was opened.
+    sets: u64,
+    /// The number of `Remove` commands written since the store was opened.
+    removes: u64,
+    /// Publishes the operation counts, if the store was asked to.
+    publisher: Option<Publisher>,
+    /// What the compaction policy needs to remember between writes.
+    compaction: CompactionState,
+    /// Tells the versions handed out by this handle apart from those handed
+    /// out by any other.
+    incarnation: u64,
+    /// The sequence number of the next `Set` written.
+    next_seq: u64,
+    /// The live bytes reported to the budget, if it limits them.
+    cache_charge: Option<CacheCharge>,
+    /// The file system the store lives on.
+    fs: Arc<dyn Fs>,
+    /// The clock every time-dependent feature reads the time from.
+    clock: Arc<dyn Clock>,
+    /// The unexpected files found in the store's directory on `open`.
+    unexpected_files: Vec<PathBuf>,
+    /// The leftovers of an interrupted compaction removed on `open`.
+    removed_orphans: Vec<PathBuf>,
+    /// The file the store's lock is held on, if its file system has locks
+    /// and the store is not read-only. This is the last field so that it is
+    /// the last one dropped.
+    _lock: Option<LockGuard>,
 }
 
 /// A `KvStore` is a directory. Specifically, a `KvStore` is a directory that
@@ -56,6 +208,8 @@ impl KvStore {
     /// This associated function can error under the following conditions:
     ///
     /// * creating the directory, specified by the path, fails
+    /// * the directory was created by a different storage engine
+    /// * the store is already open, in this process or another one
     /// * acquiring the version list fails
     /// * constructing each version's `KvsReader` fails
     /// * loading a log fails
@@ -65,353 +219,3597 @@ impl KvStore {
     /// ```
     /// ```
     pub fn open<P: AsRef<Path>>(path: P) -> Result<KvStore> {
+        fs::create_dir_all(path.as_ref())?;
+        KvStore::open_with_opts(path, KvOpts::default())
+    }
+
+    /// Opens a `KvStore` given the path to the store's directory and the
+    /// [`KvOpts`] it should exercise. Unlike [`KvStore::open`], the directory
+    /// is expected to exist already.
+    ///
+    /// # Errors
+    ///
+    /// This associated function errors similarly to [`KvStore::open`]. It
+    /// also errors if the commands left in the write-ahead log cannot be
+    /// moved into their data segment.
+    ///
+    /// [`KvOpts`]: struct.KvOpts.html
+    /// [`KvStore::open`]: #method.open
+    pub fn open_with_opts<P: AsRef<Path>>(path: P, opts: KvOpts) -> Result<KvStore> {
         let path = path.as_ref().to_owned();
-        fs::create_dir_all(&path)?;
-        let mut readers = HashMap::new();
-        let mut index = HashMap::new();
+        let io_counters = Arc::new(IoCounters::default());
+        let fs: Arc<dyn Fs> = Arc::new(CountedFs::new(
+            opts.fs.clone().unwrap_or_else(|| Arc::new(StdFs)),
+            Arc::clone(&io_counters),
+            opts.watchdog.clone(),
+        ));
+        let clock: Arc<dyn Clock> = opts.clock.clone().unwrap_or_else(|| Arc::new(SystemClock));
+        let mut index = BTreeMap::new();
+        let mut deleted = BTreeMap::new();
+
+        // Another engine's files must not be misread, or written next to.
+        meta::check_engine(&*fs, &path)?;
+
+        // Nothing in the directory can be touched until the store is ours. A
+        // read-only store touches nothing, so it can be opened alongside the
+        // process that holds the lock, but it has to exist already.
+        let lock = if opts.read_only {
+            if !fs.exists(&path.join(META_FILE_NAME)) {
+                return Err(KvsError::Io(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("no store at {}", path.display()),
+                )));
+            }
+            None
+        } else {
+            lock_store(&*fs, &path)?
+        };
+
+        let meta = StoreMeta::load_or_create(&*fs, &*clock, &path, &opts)?;
+        if !opts.any_format {
+            meta.check_format()?;
+        }
+        let key_codec = key_codec::resolve(&meta.key_codec, opts.key_codec.as_ref())?;
+        let layout = meta.segment_layout.clone();
+        let requested_layout = opts.segment_layout.clone().unwrap_or_default();
+        if opts.segment_layout.is_some() && requested_layout != layout {
+            return Err(KvsError::SegmentLayoutMismatch(format!(
+                "store uses the segment layout {:?}, not {:?}",
+                layout, requested_layout
+            )));
+        }
+        let mut readers = Readers::new(
+            fs.clone(),
+            layout.clone(),
+            path.clone(),
+            opts.budget.clone(),
+        );
+
+        // A read-only store never writes, so its write-ahead log, and later
+        // its active data segment, are kept in memory. The commands in the
+        // real write-ahead log are left for the process that holds the lock
+        // to move into their data segment, and are not seen until it does.
+        let scratch = MemFs::new();
+        let wal_fs: &dyn Fs = if opts.read_only { &scratch } else { &*fs };
+
+        // Commands left in the write-ahead log by the previous session have
+        // to reach their data segment before any of the segments are loaded.
+        let (mut wal, recovered) = Wal::open(wal_fs, &path, opts.sync)?;
+        if opts.sync {
+            wal_fs.sync_dir(&path)?;
+        }
+        if let Some((header, buf)) = recovered {
+            recover(&*fs, &layout, &path, header, &buf, opts.sync)?;
+        }
+
+        // A compaction that was interrupted before its output was renamed
+        // into place leaves that output behind under a temporary name. So
+        // does one that is still running in the process that holds the lock,
+        // which a read-only store has to leave alone.
+        let (mut removed_orphans, compacting) = if opts.read_only {
+            (Vec::new(), temporary_segments(&*fs, &layout, &path)?)
+        } else {
+            (remove_temporary_segments(&*fs, &layout, &path)?, Vec::new())
+        };
+
+        // Anything that could be mistaken for one of the store's files has to
+        // be dealt with before the segments are listed. A read-only store
+        // leaves the files where they are.
+        let policy = match opts.unexpected_files {
+            UnexpectedFiles::Quarantine if opts.read_only => UnexpectedFiles::Report,
+            policy => policy,
+        };
+        let unexpected_files = check_unexpected_files(&*fs, &layout, &path, &compacting, policy)?;
 
         // The number of stale bytes that can be compacted.
         let mut stale_bytes = 0u64;
+        let mut damaged_blocks = 0u64;
 
-        // Get the version heap.
-        let version_heap = version_list(&path)?;
+        // Get the version list.
+        let versions = layout.versions(&*fs, &path)?.into_sorted_vec();
 
         // Get the current version number. This is the last version generated
-        // and is at the top of the version list heap.
-        let current_version = version_heap.peek().unwrap_or(&0) + 1;
-
-        for &version in version_heap.iter().rev() {
-            let mut reader = KvsReader::new(File::open(log_path(&path, version))?)?;
-            stale_bytes += Loader::load(version, &mut reader, &mut index)?;
-            // If this is the way we are going to go about this, then the readers
-            // need to be re-constructed after the initial `load`. It seems that
-            // `load`ing exhausts the readers from being able to read again.
-            // I am not entirely certain what is going on, but I know that the way
-            // the pna example code is written is somewhat incorrect.
-            let reader = KvsReader::new(File::open(log_path(&path, version))?)?;
-            readers.insert(version, reader);
-        }
-
-        let writer = new_log_file(&path, current_version, &mut readers)?;
+        // and is at the end of the sorted version list.
+        let current_version = *versions.last().unwrap_or(&0) + 1;
+
+        // Load the logs oldest first, so that newer commands win. A heap's
+        // iterator visits its elements in no particular order.
+        for &version in &versions {
+            let mut reader =
+                KvsReader::new(fs.open(&layout.path(&path, version), OpenMode::Read)?)?;
+            let mut loaded =
+                Loader::load(version, &mut reader, &mut index, &mut deleted, opts.verify)?;
+            if loaded.compaction && !readers.is_empty() {
+                // Every segment before a compaction's output was compacted into
+                // it, so any that are still around were left behind by a crash
+                // part way through removing them. Loading them would bring back
+                // keys that were removed before the compaction. A read-only
+                // store may also find them while the process that holds the
+                // lock is removing them.
+                let orphans: Vec<_> = readers.versions().collect();
+                for orphan in orphans {
+                    readers.remove(orphan);
+                    if opts.read_only {
+                        continue;
+                    }
+                    let orphan = layout.path(&path, orphan);
+                    fs.remove_file(&orphan)?;
+                    removed_orphans.push(orphan);
+                }
+                if opts.sync && !opts.read_only {
+                    sync_segment_dirs(&*fs, &layout, &path)?;
+                }
+                index.clear();
+                deleted.clear();
+                stale_bytes = 0;
+                damaged_blocks = 0;
+                let mut reader =
+                    KvsReader::new(fs.open(&layout.path(&path, version), OpenMode::Read)?)?;
+                loaded = Loader::load(version, &mut reader, &mut index, &mut deleted, opts.verify)?;
+            }
+            stale_bytes += loaded.stale_bytes;
+            damaged_blocks += loaded.damaged_blocks;
+            // Every existing log belongs to a previous session, so any log
+            // that is still unsealed can be sealed now, unless the store is
+            // read-only, in which case it may still be being written.
+            if let Some(footer) = loaded.unsealed.filter(|_| !opts.read_only) {
+                seal_log(&*fs, &layout.path(&path, version), &footer, opts.sync)?;
+            }
+            // The segment is opened for reading again when it is first read.
+            readers.add(version);
+        }
+        let writer = if opts.read_only {
+            KvsWriter::new(scratch.open(&layout.path(&path, current_version), OpenMode::Append)?)?
+        } else {
+            new_log_file(
+                &*fs,
+                &layout,
+                &path,
+                current_version,
+                opts.sync,
+                &mut readers,
+            )?
+        };
+        wal.reset(current_version, 0)?;
+        let publisher = match opts.publish_stats {
+            Some(every) if !opts.read_only => Some(Publisher::new(&*fs, &path, every)?),
+            _ => None,
+        };
+
+        let soft_remove_bytes = deleted
+            .iter()
+            .map(|(key, soft): (&String, &SoftDeleted)| soft_remove_len(key, soft.at))
+            .sum();
+        let live_bytes = index
+            .values()
+            .map(|cmd_pos: &CommandPosition| cmd_pos.len)
+            .sum();
+        // A budget that limits live bytes makes a cache of every store that
+        // is opened with it.
+        let cache_charge = opts
+            .budget
+            .as_ref()
+            .filter(|budget| budget.limits_cache())
+            .map(|budget| CacheCharge::new(budget.clone()));
+        // How recently keys were used does not survive a restart, so the
+        // order in which they were written stands in for it.
+        let is_cache = opts.cache_budget.is_some() || cache_charge.is_some();
+        let lru = if !is_cache {
+            None
+        } else {
+            let mut by_position: Vec<_> = index.iter().collect();
+            by_position.sort_unstable_by_key(|(_, cmd_pos)| (cmd_pos.ver, cmd_pos.pos));
+            let mut lru = Lru::default();
+            for (key, _) in by_position {
+                lru.touch(key);
+            }
+            Some(lru)
+        };
         Ok(KvStore {
             path,
             readers,
             writer,
+            pending: BlockBuilder::new(),
+            cmd_buf: Vec::new(),
+            in_batch: false,
+            read_buf: Vec::new(),
+            value_buf: String::new(),
+            damaged_blocks,
+            wal,
             version: current_version,
             index,
+            deleted,
             stale_bytes,
+            soft_remove_bytes,
+            opts,
+            meta,
+            key_codec,
+            secondary: HashMap::new(),
+            tokens: None,
+            live_bytes,
+            lru,
+            evictions: 0,
+            io_counters,
+            logical_bytes_written: 0,
+            gets: 0,
+            get_bytes_read: 0,
+            sets: 0,
+            removes: 0,
+            publisher,
+            compaction: CompactionState::default(),
+            incarnation: Rng::from_entropy().next_u64(),
+            next_seq: 1,
+            cache_charge,
+            fs,
+            clock,
+            unexpected_files,
+            removed_orphans,
+            _lock: lock,
         })
     }
 
-    /// Opens a given `KvStore` _without_ generating a new log file.
+    /// Checks everything around the store at `path` that can keep it from
+    /// opening or working well: whether its directory is writable, whether
+    /// it is open elsewhere, its format version, the free disk space, the
+    /// process's open file limit, leftover temporary files, and whether the
+    /// system clock is behind the store. The store is not opened, and the
+    /// only file written to its directory is an empty one that is removed
+    /// again straight away.
+    ///
+    /// ```rust
+    /// # use kvs::{CheckStatus, KvStore};
+    /// # let dir = tempfile::TempDir::new().unwrap();
+    /// for check in KvStore::diagnose(dir.path()) {
+    ///     assert_ne!(check.status, CheckStatus::Error, "{}", check.detail);
+    /// }
+    /// ```
+    pub fn diagnose<P: AsRef<Path>>(path: P) -> Vec<Check> {
+        doctor::diagnose(path.as_ref())
+    }
+
+    /// Inspects the store at `path` without opening it: its metadata,
+    /// whether it can be opened as it is, its data segments, whether it is
+    /// open already, and roughly how much disk space it takes up.
+    ///
+    /// Nothing is written to the store's directory, and the store's lock is
+    /// only tested, so this is safe to call on a store that is open.
+    ///
+    /// ```rust
+    /// # use kvs::{KvStore, Result};
+    /// # fn main() -> Result<()> {
+    /// # let dir = tempfile::TempDir::new()?;
+    /// let mut store = KvStore::open(dir.path())?;
+    /// store.set("key".to_owned(), "value".to_owned())?;
+    /// assert!(KvStore::validate(dir.path())?.locked);
+    ///
+    /// drop(store);
+    /// let info = KvStore::validate(dir.path())?;
+    /// assert!(info.current && !info.locked);
+    /// # Ok(())
+    /// # }
+    /// ```
     ///
     /// # Errors
     ///
-    /// This associated function errors similarly to [`KvStore::open`].
+    /// Errors with [`KvsError::Io`] if there is no store at `path`, with
+    /// [`KvsError::WrongEngine`] if another engine created it, and with
+    /// [`KvsError::UnsupportedFormat`] if it is in a format newer than this
+    /// kvs can read. An older store is reported, rather than an error, as it
+    /// can be opened once it has been rewritten by [`upgrade`].
     ///
-    /// [`KvStore::open`]: #method.open
-    pub fn open_with_opts<P: AsRef<Path>>(path: P, _opts: KvOpts) -> Result<KvStore> {
-        let path = path.as_ref().to_owned();
-        let mut readers = HashMap::new();
-        let mut index = HashMap::new();
+    /// [`KvsError::Io`]: enum.KvsError.html#variant.Io
+    /// [`KvsError::WrongEngine`]: enum.KvsError.html#variant.WrongEngine
+    /// [`KvsError::UnsupportedFormat`]: enum.KvsError.html#variant.UnsupportedFormat
+    /// [`upgrade`]: #method.upgrade
+    pub fn validate<P: AsRef<Path>>(path: P) -> Result<StoreInfo> {
+        let path = path.as_ref();
+        let meta_path = path.join(META_FILE_NAME);
+        if !meta_path.exists() {
+            return Err(KvsError::Io(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no store at {}", path.display()),
+            )));
+        }
+        meta::check_engine(&StdFs, path)?;
+        let meta: StoreMeta = serde_json::from_slice(&StdFs.read(&meta_path)?)?;
+        if meta.format_version > FORMAT_VERSION {
+            meta.check_format()?;
+        }
 
-        // The number of stale bytes that can be compacted.
-        let mut stale_bytes = 0u64;
+        let segments = meta
+            .segment_layout
+            .versions(&StdFs, path)?
+            .into_sorted_vec();
+        let mut files: Vec<PathBuf> = segments
+            .iter()
+            .map(|&version| meta.segment_layout.path(path, version))
+            .collect();
+        files.push(path.join(WAL_FILE_NAME));
+        files.push(meta_path);
+        // A file removed since the directory was listed takes up nothing.
+        let size = files
+            .iter()
+            .filter_map(|file| fs::metadata(file).ok())
+            .map(|metadata| metadata.len())
+            .sum();
 
-        // Get the version heap.
-        let version_heap = version_list(&path)?;
+        // Taking the lock would create its file; a store without one has
+        // never been opened by this kvs.
+        let lock_path = path.join(LOCK_FILE_NAME);
+        let locked = lock_path.exists()
+            && matches!(
+                StdFs.lock(&lock_path),
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock
+            );
 
-        // Get the current version number. This is the last version generated
-        // and is at the top of the version list heap.
-        let current_version = *version_heap.peek().unwrap_or(&0) + 1;
-
-        // Load the appropriate logs.
-        for &version in version_heap.iter().rev() {
-            let mut reader = KvsReader::new(File::open(log_path(&path, version))?)?;
-            stale_bytes += Loader::load(version, &mut reader, &mut index)?;
-            // If this is the way we are going to go about this, then the readers
-            // need to be re-constructed after the initial `load`. It seems that
-            // `load`ing exhausts the readers from being able to read again.
-            // I am not entirely certain what is going on, but I know that the way
-            // the pna example code is written is somewhat incorrect.
-            let reader = KvsReader::new(File::open(log_path(&path, version))?)?;
-            readers.insert(version, reader);
-        }
-        let writer = new_log_file(&path, current_version, &mut readers)?;
-        Ok(KvStore {
-            path,
-            readers,
-            writer,
-            version: current_version,
-            index,
-            stale_bytes,
+        let ops = OpCounts::load(&StdFs, path)?;
+
+        Ok(StoreInfo {
+            current: meta.is_current(),
+            meta,
+            segments,
+            locked,
+            size,
+            ops,
         })
     }
 
-    /// Gets a string value if the given key has been [`set`]; otherwise this
-    /// method returns `None`.
+    /// Returns the directory of every store at or beneath `root`, in order.
+    /// A store is recognized by its `kvs.meta` file, and the directories
+    /// inside of a store are not searched for more stores. Symbolic links
+    /// are not followed, and directories that cannot be read are skipped.
     ///
-    /// # Examples
+    /// ```rust
+    /// # use kvs::{KvStore, Result};
+    /// # fn main() -> Result<()> {
+    /// # let root = tempfile::TempDir::new()?;
+    /// std::fs::create_dir_all(root.path().join("tenants/a"))?;
+    /// KvStore::open(root.path().join("tenants/a"))?;
+    /// assert_eq!(
+    ///     KvStore::discover(root.path())?,
+    ///     vec![root.path().join("tenants/a")]
+    /// );
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// Errors if `root` cannot be read, or listing any directory beneath it
+    /// fails for any reason other than a lack of permission.
+    pub fn discover<P: AsRef<Path>>(root: P) -> Result<Vec<PathBuf>> {
+        let mut stores = Vec::new();
+        meta::discover(root.as_ref(), &mut stores)?;
+        stores.sort();
+        Ok(stores)
+    }
+
+    /// Copies the store at `src` into the directory `dst`, which is created
+    /// if need be, and opens the copy. Compacting the copy that is returned
+    /// leaves it with only the live commands, in fresh segments.
+    ///
+    /// The store at `src` is locked while it is copied, so that it cannot
+    /// change part way through, but it is never opened and nothing in it is
+    /// written to. Only the store's own files are copied: its metadata, its
+    /// write-ahead log and its data segments.
     ///
     /// ```rust
+    /// # use kvs::{KvStore, Result};
+    /// # fn main() -> Result<()> {
+    /// # let src = tempfile::TempDir::new()?;
+    /// # let dst = tempfile::TempDir::new()?;
+    /// let mut store = KvStore::open(src.path())?;
+    /// store.set("key".to_owned(), "value".to_owned())?;
+    /// drop(store);
+    ///
+    /// let mut copy = KvStore::copy(src.path(), dst.path().join("copy"))?;
+    /// copy.compact()?;
+    /// assert_eq!(copy.get("key".to_owned())?, Some("value".to_owned()));
+    /// # Ok(())
+    /// # }
     /// ```
-    /// [`set`]: #method.set
-    pub fn get(&mut self, key: String) -> Result<Option<String>> {
-        if let Some(cmd_pos) = self.index.get(&key) {
-            let reader = self
-                .readers
-                .get_mut(&cmd_pos.ver)
-                .expect("Cannot find log reader");
+    ///
+    /// # Errors
+    ///
+    /// Errors with [`KvsError::StoreLocked`] if the store at `src` is open,
+    /// and with an I/O error if there is no store at `src`, there is a
+    /// store at `dst` already, or copying any file fails.
+    ///
+    /// [`KvsError::StoreLocked`]: enum.KvsError.html#variant.StoreLocked
+    pub fn copy<P: AsRef<Path>, Q: AsRef<Path>>(src: P, dst: Q) -> Result<KvStore> {
+        let (src, dst) = (src.as_ref(), dst.as_ref());
+        let meta_path = src.join(META_FILE_NAME);
+        if !meta_path.exists() {
+            return Err(KvsError::Io(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no store at {}", src.display()),
+            )));
+        }
+        if dst.join(META_FILE_NAME).exists() {
+            return Err(KvsError::Io(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("a store already exists at {}", dst.display()),
+            )));
+        }
+        meta::check_engine(&StdFs, src)?;
 
-            reader.seek(SeekFrom::Start(cmd_pos.pos))?;
+        // Held until every file is copied.
+        let _lock = lock_store(&StdFs, src)?;
+        let meta: StoreMeta = serde_json::from_slice(&StdFs.read(&meta_path)?)?;
+        let mut files = vec![PathBuf::from(META_FILE_NAME)];
+        if src.join(WAL_FILE_NAME).exists() {
+            files.push(PathBuf::from(WAL_FILE_NAME));
+        }
+        let layout = &meta.segment_layout;
+        for version in layout.versions(&StdFs, src)?.into_sorted_vec() {
+            let segment = layout.path(src, version);
+            let segment = segment.strip_prefix(src).expect("segment is in the store");
+            files.push(segment.to_owned());
+        }
+        for file in files {
+            let to = dst.join(&file);
+            if let Some(parent) = to.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::copy(src.join(&file), to)?;
+        }
+        drop(_lock);
+        KvStore::open(dst)
+    }
 
-            let cmd_reader = reader.take(cmd_pos.len);
-            if let Command::Set { value, .. } = serde_json::from_reader(cmd_reader)? {
-                Ok(Some(value))
-            } else {
-                Err(KvsError::UnexpectedCommandType(format!(
-                    "no existing command for key: {}",
-                    key
+    /// Rewrites the store at `path`, which must not be open, in the on-disk
+    /// format this crate writes, and returns whether it had to. A store in
+    /// an older format cannot be opened until it has been upgraded.
+    ///
+    /// The store is copied into `<path>.upgrade` first, and only swapped
+    /// into place once the copy is complete, by renaming `path` to
+    /// `<path>.old`, the copy to `path`, and removing `<path>.old`. The copy
+    /// keeps the store's identity and settings, and every key along with
+    /// when it expires, but not soft-deleted keys or stale commands.
+    ///
+    /// # Errors
+    ///
+    /// Errors with [`KvsError::UnsupportedFormat`] if the store is in a
+    /// format newer than this crate's, with [`KvsError::StoreLocked`] if it
+    /// is open, and with an I/O error if there is no store at `path`, or
+    /// reading or writing any of it fails. An upgrade that is interrupted
+    /// before its copy is complete leaves the store as it was; one that is
+    /// interrupted between the renames leaves the store at `<path>.old`
+    /// and its upgraded copy at `<path>.upgrade`.
+    ///
+    /// [`KvsError::UnsupportedFormat`]: enum.KvsError.html#variant.UnsupportedFormat
+    /// [`KvsError::StoreLocked`]: enum.KvsError.html#variant.StoreLocked
+    pub fn upgrade<P: AsRef<Path>>(path: P) -> Result<bool> {
+        let path = path.as_ref();
+        let meta_path = path.join(META_FILE_NAME);
+        if !meta_path.exists() {
+            return Err(KvsError::Io(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no store at {}", path.display()),
+            )));
+        }
+        meta::check_engine(&StdFs, path)?;
+        let meta: StoreMeta = serde_json::from_slice(&StdFs.read(&meta_path)?)?;
+        if meta.is_current() {
+            return Ok(false);
+        }
+        if meta.format_version > FORMAT_VERSION {
+            meta.check_format()?;
+        }
+        let name = match path.file_name() {
+            Some(name) => name.to_string_lossy().into_owned(),
+            None => {
+                return Err(KvsError::Io(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("cannot upgrade a store at {}", path.display()),
                 )))
             }
-        } else {
-            Ok(None)
+        };
+        let copy = path.with_file_name(format!("{}.upgrade", name));
+        let old = path.with_file_name(format!("{}.old", name));
+
+        // The store is locked while it is open, and nothing else can open it
+        // in an older format, so it cannot change once it is closed again.
+        let mut opts = KvOpts::new()
+            .key_codec(AsStored(meta.key_codec.clone()))
+            .segment_layout(meta.segment_layout.clone());
+        opts.any_format = true;
+        let mut store = KvStore::open_with_opts(path, opts.clone())?;
+        // A copy left behind by an interrupted upgrade is started over.
+        if copy.exists() {
+            fs::remove_dir_all(&copy)?;
+        }
+        fs::create_dir_all(&copy)?;
+        let mut upgraded = KvStore::open_with_opts(&copy, opts)?;
+        // Keys are copied along with when they expire, whether or not they
+        // have, so the copy does not depend on the clock.
+        let keys: Vec<String> = store.index.keys().cloned().collect();
+        for key in keys {
+            let expires = store.index[&key].expires;
+            if let Some(value) = store.read_value(&key)? {
+                upgraded.write_set(key, value, expires)?;
+            }
         }
+        upgraded.flush_pending()?;
+        upgraded.writer.sync()?;
+        drop(upgraded);
+        drop(store);
+        sync_segment_dirs(&StdFs, &meta.segment_layout, &copy)?;
+        StoreMeta {
+            format_version: FORMAT_VERSION,
+            codec: CODEC.to_owned(),
+            ..meta
+        }
+        .write(&StdFs, &copy, true)?;
+
+        let _lock = lock_store(&StdFs, path)?;
+        fs::rename(path, &old)?;
+        fs::rename(&copy, path)?;
+        if let Some(parent) = path.parent() {
+            StdFs.sync_dir(parent)?;
+        }
+        fs::remove_dir_all(&old)?;
+        Ok(true)
     }
 
-    /// Removes a key, along with its corresponding value, from the `KvStore`
-    /// If the given key is in the `KvStore`, then the removed value will be
-    /// return. Otherwise, if the key does not exist, then `None` is returned.
+    /// Opens the `KvStore` at `path`, as [`KvStore::open`] does, and sets
+    /// every key-value pair in `iter`.
+    ///
+    /// # Errors
+    ///
+    /// Errors if opening the store or any of the writes does.
+    ///
+    /// [`KvStore::open`]: #method.open
+    pub fn from_iter_at<P, I>(path: P, iter: I) -> Result<KvStore>
+    where
+        P: AsRef<Path>,
+        I: IntoIterator<Item = (String, String)>,
+    {
+        let mut store = KvStore::open(path)?;
+        for (key, value) in iter {
+            store.set(key, value)?;
+        }
+        Ok(store)
+    }
+
+    /// Gets a string value if the given key has been [`set`]; otherwise this
+    /// method returns `None`.
     ///
     /// # Examples
     ///
     /// ```rust
     /// ```
-    pub fn remove(&mut self, key: String) -> Result<()> {
-        if self.index.contains_key(&key) {
-            let cmd = Command::Remove { key };
-            serde_json::to_writer(&mut self.writer, &cmd)?;
-            self.writer.flush()?;
-            if let Command::Remove { key } = cmd {
-                let old_cmd = self.index.remove(&key).expect("key not found");
-                self.stale_bytes += old_cmd.len;
-            }
-            Ok(())
+    /// [`set`]: #method.set
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        let key = self.key_codec.normalize(key);
+        let bytes_read = self.io_counters.bytes_read();
+        let value = if self.start_read(&key) {
+            self.read_value(&key)
         } else {
-            Err(KvsError::KeyNotFound(format!(
-                "could not find key: {}",
-                key
-            )))
-        }
+            Ok(None)
+        };
+        self.count_get(bytes_read);
+        value
     }
 
-    /// Sets a key-value pair in the `KvStore` by inserting this entry-pair into
-    /// the underlying map. If the given key has not already been set, then this
-    /// method returns `None`. Otherwise, the given key's value is updated, and
-    /// the old value is returned.
-    ///
-    /// # Examples
+    /// Returns whether a key is set, without reading its value.
     ///
     /// ```rust
+    /// # use kvs::{KvStore, Result};
+    /// # fn main() -> Result<()> {
+    /// # let dir = tempfile::TempDir::new()?;
+    /// let mut store = KvStore::open(dir.path())?;
+    /// store.set("key".to_owned(), "value".to_owned())?;
+    /// assert!(store.contains_key("key"));
+    /// assert!(!store.contains_key("missing"));
+    /// # Ok(())
+    /// # }
     /// ```
-    pub fn set(&mut self, key: String, value: String) -> Result<()> {
-        let cmd = Command::Set { key, value };
-        let pos = self.writer.pos();
-        serde_json::to_writer(&mut self.writer, &cmd)?;
-        self.writer.flush()?;
-        if let Command::Set { key, .. } = cmd {
-            // The call to `insert` returns `None` if the key is not present
-            // upon insertion; otherwise, the previous value is returned.
-            if let Some(old_cmd) = self
-                .index
-                .insert(key, (self.version, pos..self.writer.pos()).into())
-            {
-                // Record the old command's length as stale bytes.
-                self.stale_bytes += old_cmd.len;
-            }
-        }
+    pub fn contains_key(&self, key: &str) -> bool {
+        let key = self.key_codec.normalize_str(key);
+        let now = self.now_millis();
+        self.index
+            .get(key.as_ref())
+            .is_some_and(|cmd_pos| !cmd_pos.is_expired(now))
+    }
 
-        if self.stale_bytes > MAX_STALE_BYTES {
-            self.compact()?;
+    /// Returns the number of keys that are set. Expired keys that have not
+    /// been dropped yet are left out, so this looks at the expiry of every
+    /// key, but reads nothing from disk.
+    pub fn len(&self) -> usize {
+        let now = self.now_millis();
+        self.index
+            .values()
+            .filter(|cmd_pos| !cmd_pos.is_expired(now))
+            .count()
+    }
+
+    /// Returns whether no keys are set.
+    pub fn is_empty(&self) -> bool {
+        let now = self.now_millis();
+        self.index.values().all(|cmd_pos| cmd_pos.is_expired(now))
+    }
+
+    /// Gets the values of several keys at once, in the order of `keys`, with
+    /// `None` for every key that has not been set. The values are read in
+    /// the order they sit in the store's segments, not in the order asked
+    /// for, so that reading many keys seeks as little as it can.
+    ///
+    /// # Errors
+    ///
+    /// Errors if reading any of the values does.
+    pub fn multi_get(&mut self, keys: &[String]) -> Result<Vec<Option<String>>> {
+        let bytes_read = self.io_counters.bytes_read();
+        let keys: Vec<String> = keys
+            .iter()
+            .map(|key| self.key_codec.normalize(key.clone()))
+            .collect();
+        for key in &keys {
+            // An expired key is dropped from the index, and so read as `None`.
+            self.start_read(key);
         }
-        Ok(())
+        let values = self.read_values(&keys).into_iter().collect();
+        self.count_gets(keys.len() as u64, bytes_read);
+        values
     }
 
-    /// Clears stale command entries from the `KvStore`s logs.
+    /// Gets a value like [`get`], along with the [`Version`] the key is at,
+    /// which [`set_if_version`] takes to make sure nothing has changed the
+    /// key in the meantime.
+    ///
+    /// [`get`]: #method.get
+    /// [`Version`]: struct.Version.html
+    /// [`set_if_version`]: #method.set_if_version
+    pub fn get_versioned(&mut self, key: String) -> Result<Option<(String, Version)>> {
+        let key = self.key_codec.normalize(key);
+        let bytes_read = self.io_counters.bytes_read();
+        let value = if self.start_read(&key) {
+            self.read_value(&key)
+        } else {
+            Ok(None)
+        };
+        self.count_get(bytes_read);
+        Ok(value?.map(|value| {
+            let version = self.version_of(&key).expect("key was just read");
+            (value, version)
+        }))
+    }
+
+    /// Gets the value of a key into `value`, replacing what it held, and
+    /// returns whether the key was found. A key that is not found leaves
+    /// `value` as it was.
+    ///
+    /// Unlike [`get`], this takes the key by reference and reuses `value`'s
+    /// allocation, so a caller that reads many keys through one `String`
+    /// does not allocate once its capacity is large enough (or at all with
+    /// the default key codec).
     ///
-    /// # Examples
     /// ```rust
+    /// # use kvs::{KvStore, Result};
+    /// # fn main() -> Result<()> {
+    /// # let dir = tempfile::TempDir::new()?;
+    /// let mut store = KvStore::open(dir.path())?;
+    /// store.set("key".to_owned(), "value".to_owned())?;
+    /// let mut value = String::new();
+    /// assert!(store.get_into("key", &mut value)?);
+    /// assert_eq!(value, "value");
+    /// # Ok(())
+    /// # }
     /// ```
     ///
-    /// # Panics
-    ///
-    pub fn compact(&mut self) -> Result<()> {
-        let compact_version = self.version + 1;
-        self.version += 2;
-        self.writer = self.new_log_file(self.version)?;
-
-        let mut compaction_writer = self.new_log_file(compact_version)?;
+    /// [`get`]: #method.get
+    pub fn get_into(&mut self, key: &str, value: &mut String) -> Result<bool> {
+        let key_codec = Arc::clone(&self.key_codec);
+        let key = key_codec.normalize_str(key);
+        let bytes_read = self.io_counters.bytes_read();
+        let found = if self.start_read(&key) {
+            self.read_value_into(&key, value)
+        } else {
+            Ok(false)
+        };
+        self.count_get(bytes_read);
+        found
+    }
 
-        let mut new_pos = 0;
-        for cmd_pos in &mut self.index.values_mut() {
-            let reader = self
-                .readers
-                .get_mut(&cmd_pos.ver)
-                .expect("Cannot find log reader");
-            if reader.pos() != cmd_pos.pos {
-                reader.seek(SeekFrom::Start(cmd_pos.pos))?;
+    /// Reads the value of a normalized key into `value`, and returns whether
+    /// the key was found.
+    fn read_value_into(&mut self, key: &str, value: &mut String) -> Result<bool> {
+        match self.index.get(key) {
+            Some(&cmd_pos) => {
+                self.read_into(key, cmd_pos, value)?;
+                Ok(true)
             }
-
-            let mut entry_reader = reader.take(cmd_pos.len);
-            let len = io::copy(&mut entry_reader, &mut compaction_writer)?;
-            *cmd_pos = (compact_version, new_pos..new_pos + len).into();
-            new_pos += len;
+            None => Ok(false),
         }
+    }
 
-        compaction_writer.flush()?;
-
-        let stale_versions: Vec<_> = self
-            .readers
-            .keys()
-            .filter(|v| **v < compact_version)
-            .cloned()
-            .collect();
+    /// Gets the value of a key like [`get_into`], but into a buffer the
+    /// store keeps, which the value borrows from until the store is next
+    /// used.
+    ///
+    /// [`get_into`]: #method.get_into
+    pub fn get_ref(&mut self, key: &str) -> Result<Option<&str>> {
+        let mut value = mem::take(&mut self.value_buf);
+        let found = self.get_into(key, &mut value);
+        self.value_buf = value;
+        Ok(if found? { Some(&self.value_buf) } else { None })
+    }
 
-        for stale_gen in stale_versions {
-            self.readers.remove(&stale_gen);
-            fs::remove_file(log_path(&self.path, stale_gen))?;
-        }
-        Ok(())
+    /// Gets a value like [`get`], but checks the checksum of the block the
+    /// value is stored in first, rather than trusting what is on disk.
+    ///
+    /// A value that has not reached its data segment yet is read from
+    /// memory, where there is nothing to check.
+    ///
+    /// # Errors
+    ///
+    /// Errors with `KvsError::Corruption` if the block fails its checksum,
+    /// or does not hold the key's latest command where the index says.
+    ///
+    /// [`get`]: #method.get
+    pub fn get_verified(&mut self, key: String) -> Result<Option<String>> {
+        let key = self.key_codec.normalize(key);
+        let bytes_read = self.io_counters.bytes_read();
+        let value = self.read_verified(&key);
+        self.count_get(bytes_read);
+        value
     }
 
-    fn new_log_file(&mut self, gen: u64) -> Result<KvsWriter<File>> {
-        new_log_file(&self.path, gen, &mut self.readers)
+    /// Counts a get that started when the store's files had had
+    /// `bytes_read` bytes read from them.
+    fn count_get(&mut self, bytes_read: u64) {
+        self.count_gets(1, bytes_read);
     }
-}
 
-/// Constructs a new log file and returns a `KvsWriter` to it.
-///
-/// # Errors
-///
-/// * if `open`ing the file to be consumed by the `KvsWriter` results in an error or
-/// * if `open`ing the file to be consumed by the `KvsReader` results in an error
-///
-/// # Examples
-///
-/// ```rust
-/// ```
-fn new_log_file<P: AsRef<Path>>(
-    path: P,
-    version: u64,
-    readers: &mut HashMap<u64, KvsReader<File>>,
-) -> Result<KvsWriter<File>> {
-    // Construct the log path.
-    let path = log_path(path.as_ref(), version);
+    /// Counts `gets` gets that started together, like [`count_get`].
+    ///
+    /// [`count_get`]: #method.count_get
+    fn count_gets(&mut self, gets: u64, bytes_read: u64) {
+        self.gets += gets;
+        self.get_bytes_read += self.io_counters.bytes_read() - bytes_read;
+        self.publish_ops(false);
+    }
 
-    // Construct the writer in append mode.
-    let writer = KvsWriter::new(
-        OpenOptions::new()
-            .create(true)
-            .write(true)
-            .append(true)
-            .open(&path)?,
-    )?;
-
-    // Finally, insert this log file's reader into the readers map.
-    readers.insert(version, KvsReader::new(File::open(&path)?)?);
-    Ok(writer)
-}
+    /// Publishes the operation counts, if the store was asked to, and the
+    /// interval has passed or `force` is set.
+    fn publish_ops(&mut self, force: bool) {
+        let counts = OpCounts {
+            sets: self.sets,
+            gets: self.gets,
+            removes: self.removes,
+        };
+        let now = self.now_millis();
+        if let Some(publisher) = &mut self.publisher {
+            publisher.publish(&*self.fs, &self.path, now, counts, force);
+        }
+    }
 
-fn version_list<P: AsRef<Path>>(path: P) -> Result<BinaryHeap<u64>> {
-    Ok(fs::read_dir(path.as_ref())?
-        .flat_map(|res| -> Result<_> { Ok(res?.path()) })
-        .filter(|path| path.is_file() && path.extension() == Some("log".as_ref()))
-        .flat_map(|path| {
-            path.file_name()
-                .and_then(OsStr::to_str)
-                .map(|s| s.trim_end_matches(".log"))
-                .map(str::parse::<u64>)
-        })
-        .flatten()
-        .collect())
-}
+    /// Fails a write to a read-only store.
+    fn check_writable(&self) -> Result<()> {
+        if self.opts.read_only {
+            return Err(KvsError::ReadOnly(self.path.display().to_string()));
+        }
+        Ok(())
+    }
 
-fn log_path<P: AsRef<Path>>(path: P, version: u64) -> PathBuf {
-    path.as_ref().join(format!("{}.log", version))
-}
+    /// Reads the value of a normalized key for `get_verified`.
+    fn read_verified(&mut self, key: &str) -> Result<Option<String>> {
+        if !self.start_read(key) {
+            return Ok(None);
+        }
+        let (ver, pos, len) = match self.index.get(key) {
+            Some(cmd_pos) => (cmd_pos.ver, cmd_pos.pos, cmd_pos.len),
+            None => return Ok(None),
+        };
+        if ver == self.version && pos >= self.writer.pos() {
+            return self.read_value(key);
+        }
 
-struct Loader;
+        let corruption = || {
+            KvsError::Corruption(format!(
+                "the block holding key {} in log {} is damaged",
+                key, ver
+            ))
+        };
+        let reader = self.readers.get(ver)?;
+        // Commands never span blocks, and a block always starts on a unit
+        // boundary with its first command, so the block holding a command
+        // starts at the boundary at or before it.
+        let start = pos - pos % BLOCK_SIZE;
+        let block = match BlockReader::new(reader)?.block_at(start)? {
+            Some(block) if block.trailer.kind == KIND_DATA => block,
+            _ => return Err(corruption()),
+        };
+        let offset = (pos - start) as usize;
+        let record = block
+            .payload
+            .get(offset..offset + len as usize)
+            .ok_or_else(corruption)?;
+        match serde_json::from_slice(record) {
+            Ok(Command::Set {
+                key: found, value, ..
+            }) if found == key => Ok(Some(value)),
+            _ => Err(corruption()),
+        }
+    }
 
-impl Loader {
-    /// Loads the log from disk, into memory.
-    fn load(
-        version: u64,
-        reader: &mut KvsReader<File>,
-        index: &mut HashMap<String, CommandPosition>,
-    ) -> Result<u64> {
-        let mut pos = reader.seek(SeekFrom::Start(0))?;
-        let mut stream = Deserializer::from_reader(reader).into_iter::<Command>();
-        let mut stale_bytes = 0u64;
-        while let Some(cmd) = stream.next() {
-            // Update the new position to the number of bytes successfully
-            // deserialized into a `Command`.
-            let new_pos = stream.byte_offset() as u64;
-            match cmd? {
-                Command::Set { key, .. } => {
-                    // If a given key is present in the map, then `insert` is updating
-                    // a value that is already present in the map. The old value,
-                    // in this case the old `CommandPosition`, is returned.
-                    //
-                    // This old `CommandPosition`'s length represents a number of stale bytes
-                    // that can be compacted.
-                    if let Some(old_cmd) = index.insert(key, (version, pos..new_pos).into()) {
-                        stale_bytes += old_cmd.len;
-                    }
-                }
-                Command::Remove { key } => {
-                    // If a given key is present in the map, then `remove` will return
-                    // the value. In this case, the old `CommandPosition` is returned.
-                    //
-                    // The removed `CommandPosition`'s length represents a number of
-                    // stale bytes that can be compacted.
-                    if let Some(old_cmd) = index.remove(&key) {
-                        stale_bytes += old_cmd.len;
-                    }
-                    // The removal command's length (in bytes) can also be safely
-                    // compacted.
-                    stale_bytes += new_pos - pos;
-                }
+    /// Drops a normalized key if it has expired, and marks it as just used
+    /// in cache mode otherwise. Returns whether the key may be live.
+    fn start_read(&mut self, key: &str) -> bool {
+        if self.drop_if_expired(key) {
+            return false;
+        }
+        if let Some(lru) = &mut self.lru {
+            if self.index.contains_key(key) {
+                lru.touch(key);
             }
-            pos = new_pos;
         }
-        Ok(stale_bytes)
+        true
     }
-}
 
-/// Structure describing the various options a given `KvStore` can
-/// exercise.
-pub struct KvOpts;
+    /// Reads the values of normalized keys, in the order their commands sit
+    /// in the segments rather than in the order of `keys`.
+    pub(crate) fn read_values(&mut self, keys: &[String]) -> Vec<Result<Option<String>>> {
+        let mut order: Vec<usize> = (0..keys.len()).collect();
+        order.sort_by_key(|&i| {
+            self.index
+                .get(&keys[i])
+                .map(|cmd_pos| (cmd_pos.ver, cmd_pos.pos))
+        });
+        let mut values: Vec<_> = keys.iter().map(|_| None).collect();
+        for i in order {
+            values[i] = Some(self.read_value(&keys[i]));
+        }
+        values
+            .into_iter()
+            .map(|value| value.expect("every value was read"))
+            .collect()
+    }
 
-#[derive(Debug)]
-struct CommandPosition {
-    ver: u64,
-    pos: u64,
-    len: u64,
-}
+    /// Returns the length of the latest command of a normalized key, or 0
+    /// if the key is not in the index.
+    pub(crate) fn command_len(&self, key: &str) -> u64 {
+        self.index.get(key).map_or(0, |cmd_pos| cmd_pos.len)
+    }
 
-impl From<(u64, Range<u64>)> for CommandPosition {
-    fn from((ver, range): (u64, Range<u64>)) -> Self {
-        CommandPosition {
-            ver,
-            pos: range.start,
-            len: range.end - range.start,
+    /// Reads the value of a key that has already been normalized.
+    fn read_value(&mut self, key: &str) -> Result<Option<String>> {
+        match self.index.get(key) {
+            Some(&cmd_pos) => self.read_at(key, cmd_pos).map(Some),
+            None => Ok(None),
         }
     }
-}
+
+    /// Reads the value of the `Set` command for `key` at `cmd_pos`.
+    fn read_at(&mut self, key: &str, cmd_pos: CommandPosition) -> Result<String> {
+        let mut value = String::new();
+        self.read_into(key, cmd_pos, &mut value)?;
+        Ok(value)
+    }
+
+    /// Reads the value of the `Set` command for `key` at `cmd_pos` into
+    /// `value`, going through `read_buf` rather than allocating.
+    fn read_into(&mut self, key: &str, cmd_pos: CommandPosition, value: &mut String) -> Result<()> {
+        if cmd_pos.ver == self.version && cmd_pos.pos >= self.writer.pos() {
+            // The command has not reached the active data segment yet.
+            let start = (cmd_pos.pos - self.writer.pos()) as usize;
+            let pending = self.pending.as_slice();
+            self.read_buf.clear();
+            self.read_buf
+                .extend_from_slice(&pending[start..start + cmd_pos.len as usize]);
+        } else {
+            read_command(&mut self.readers, &cmd_pos, &mut self.read_buf)?;
+        }
+        match serde_json::from_slice(&self.read_buf)? {
+            CommandRef::Set { value: found } => {
+                value.clear();
+                value.push_str(&found);
+                Ok(())
+            }
+            CommandRef::Remove(_) => Err(KvsError::UnexpectedCommandType(format!(
+                "no existing command for key: {}",
+                key
+            ))),
+        }
+    }
+
+    /// Removes a key, along with its corresponding value, from the `KvStore`
+    /// If the given key is in the `KvStore`, then the removed value will be
+    /// return. Otherwise, if the key does not exist, then `None` is returned.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// ```
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        let key = self.key_codec.normalize(key);
+        self.check_write_once(&key)?;
+        if !self.drop_if_expired(&key) && self.index.contains_key(&key) {
+            self.delete(key)
+        } else {
+            Err(KvsError::KeyNotFound(format!(
+                "could not find key: {}",
+                key
+            )))
+        }
+    }
+
+    /// Removes a key, along with its value, if it is in the `KvStore`.
+    /// Returns whether it was.
+    ///
+    /// Unlike [`remove`], a missing key is not an error, which suits callers
+    /// that only need the key to be gone.
+    ///
+    /// ```rust
+    /// # use kvs::{KvStore, Result};
+    /// # fn main() -> Result<()> {
+    /// # let dir = tempfile::TempDir::new()?;
+    /// let mut store = KvStore::open(dir.path())?;
+    /// store.set("key".to_owned(), "value".to_owned())?;
+    /// assert!(store.remove_if_exists("key".to_owned())?);
+    /// assert!(!store.remove_if_exists("key".to_owned())?);
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// [`remove`]: #method.remove
+    pub fn remove_if_exists(&mut self, key: String) -> Result<bool> {
+        let key = self.key_codec.normalize(key);
+        self.check_write_once(&key)?;
+        if !self.drop_if_expired(&key) && self.index.contains_key(&key) {
+            self.delete(key)?;
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
+    /// Applies every write in `batch`, in order, as a unit.
+    ///
+    /// The batch is logged as a single record before any of it is applied,
+    /// and flushed once, so that after a crash either all of its writes are
+    /// visible or none are. A remove of a key that is not there, by the time
+    /// the batch gets to it, is skipped.
+    ///
+    /// # Errors
+    ///
+    /// Errors with [`KvsError::WriteOnce`] if the batch would overwrite or
+    /// remove a write-once key that is set, in which case none of the batch
+    /// is written.
+    ///
+    /// [`KvsError::WriteOnce`]: enum.KvsError.html#variant.WriteOnce
+    pub fn write(&mut self, batch: WriteBatch) -> Result<()> {
+        self.check_writable()?;
+        // Every write is checked before anything is logged, against the
+        // keys as the batch's earlier writes leave them.
+        let now = self.now_millis();
+        let soft = self.soft_delete_window().is_some();
+        let mut live: HashMap<String, bool> = HashMap::new();
+        let mut cmds = Vec::with_capacity(batch.len());
+        for op in batch.ops {
+            let (key, cmd) = match op {
+                BatchOp::Set { key, value, ttl } => {
+                    let key = self.key_codec.normalize(key);
+                    let cmd = Command::Set {
+                        key: key.clone(),
+                        value,
+                        expires: ttl.map(|ttl| self.expires_in(ttl)),
+                    };
+                    (key, cmd)
+                }
+                BatchOp::Remove { key } => {
+                    let key = self.key_codec.normalize(key);
+                    let cmd = Command::Remove {
+                        key: key.clone(),
+                        deleted: if soft { Some(now) } else { None },
+                    };
+                    (key, cmd)
+                }
+            };
+            let is_live = match live.get(&key) {
+                Some(&is_live) => is_live,
+                None => !self.drop_if_expired(&key) && self.index.contains_key(&key),
+            };
+            if is_live && self.is_write_once(&key) {
+                return Err(write_once_error(&key));
+            }
+            match cmd {
+                Command::Remove { .. } if !is_live => continue,
+                Command::Set { .. } => live.insert(key, true),
+                Command::Remove { .. } => live.insert(key, false),
+            };
+            cmds.push(cmd);
+        }
+        if cmds.is_empty() {
+            return Ok(());
+        }
+
+        self.cmd_buf.clear();
+        serde_json::to_writer(&mut self.cmd_buf, &cmds)?;
+        self.wal.append(&self.cmd_buf)?;
+
+        let mut last_set = None;
+        self.in_batch = true;
+        let applied = cmds.into_iter().try_for_each(|cmd| match cmd {
+            Command::Set {
+                key,
+                value,
+                expires,
+            } => {
+                last_set = Some(key.clone());
+                self.write_set(key, value, expires)
+            }
+            Command::Remove {
+                key,
+                deleted: Some(at),
+            } => self.soft_remove(key, at),
+            Command::Remove { key, deleted: None } => self.write_remove(key),
+        });
+        self.in_batch = false;
+        applied?;
+
+        if let Some(key) = last_set {
+            self.evict(&key)?;
+        }
+        self.flush_if_full()?;
+        self.maybe_compact()
+    }
+
+    /// Sets a key-value pair in the `KvStore` by inserting this entry-pair into
+    /// the underlying map. If the given key has not already been set, then this
+    /// method returns `None`. Otherwise, the given key's value is updated, and
+    /// the old value is returned.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// ```
+    ///
+    /// Setting a key clears any expiry it had; see [`set_with_ttl`].
+    ///
+    /// [`set_with_ttl`]: #method.set_with_ttl
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        let key = self.key_codec.normalize(key);
+        self.check_write_once(&key)?;
+        self.write_set(key, value, None)
+    }
+
+    /// Sets a key like [`set`], and returns the [`Version`] the key is at
+    /// afterwards.
+    ///
+    /// [`set`]: #method.set
+    /// [`Version`]: struct.Version.html
+    pub fn set_versioned(&mut self, key: String, value: String) -> Result<Version> {
+        let key = self.key_codec.normalize(key);
+        self.check_write_once(&key)?;
+        self.write_set(key.clone(), value, None)?;
+        Ok(self.version_of(&key).expect("key was just set"))
+    }
+
+    /// Sets a key only if it is still at `version`, as returned by an
+    /// earlier [`get_versioned`] or [`set_versioned`], and returns the
+    /// version the key is at afterwards. This makes read-modify-write
+    /// cycles safe without holding on to the store in between.
+    ///
+    /// ```rust
+    /// # use kvs::{KvStore, KvsError, Result};
+    /// # fn main() -> Result<()> {
+    /// # let dir = tempfile::TempDir::new()?;
+    /// let mut store = KvStore::open(dir.path())?;
+    /// let first = store.set_versioned("counter".to_owned(), "1".to_owned())?;
+    /// let second = store.set_if_version("counter".to_owned(), "2".to_owned(), first)?;
+    /// // Someone holding the first version is too late.
+    /// let stale = store.set_if_version("counter".to_owned(), "3".to_owned(), first);
+    /// assert!(matches!(stale, Err(KvsError::VersionMismatch(_))));
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// Errors with [`KvsError::VersionMismatch`] if the key has been
+    /// written to since, has been removed, or has expired.
+    ///
+    /// [`get_versioned`]: #method.get_versioned
+    /// [`set_versioned`]: #method.set_versioned
+    /// [`KvsError::VersionMismatch`]: enum.KvsError.html#variant.VersionMismatch
+    pub fn set_if_version(
+        &mut self,
+        key: String,
+        value: String,
+        version: Version,
+    ) -> Result<Version> {
+        let key = self.key_codec.normalize(key);
+        self.check_write_once(&key)?;
+        self.drop_if_expired(&key);
+        if self.version_of(&key) != Some(version) {
+            return Err(KvsError::VersionMismatch(format!(
+                "key {} is no longer at version {}",
+                key, version
+            )));
+        }
+        self.write_set(key.clone(), value, None)?;
+        Ok(self.version_of(&key).expect("key was just set"))
+    }
+
+    /// Sets a key to `new`, or removes it if `new` is `None`, but only if its
+    /// value is `expected`, where `None` means the key is not set. Returns
+    /// whether it was, and so whether the key was changed.
+    ///
+    /// The value is checked and written under the same `&mut` borrow, so
+    /// nothing can change the key in between. Like [`set`], setting the key
+    /// clears any expiry it had.
+    ///
+    /// ```rust
+    /// # use kvs::{KvStore, Result};
+    /// # fn main() -> Result<()> {
+    /// # let dir = tempfile::TempDir::new()?;
+    /// let mut store = KvStore::open(dir.path())?;
+    /// let key = || "leader".to_owned();
+    /// assert!(store.compare_and_swap(key(), None, Some("a".to_owned()))?);
+    /// // Someone else has taken it.
+    /// assert!(!store.compare_and_swap(key(), None, Some("b".to_owned()))?);
+    /// assert!(store.compare_and_swap(key(), Some("a".to_owned()), None)?);
+    /// assert_eq!(store.get(key())?, None);
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// Errors with [`KvsError::WriteOnce`] if the key is write-once and
+    /// set, and it would be changed.
+    ///
+    /// [`set`]: #method.set
+    /// [`KvsError::WriteOnce`]: enum.KvsError.html#variant.WriteOnce
+    pub fn compare_and_swap(
+        &mut self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<bool> {
+        let key = self.key_codec.normalize(key);
+        let current = self.live_value(&key)?;
+        if current != expected {
+            return Ok(false);
+        }
+        match new {
+            Some(value) => {
+                self.check_write_once(&key)?;
+                self.write_set(key, value, None)?;
+            }
+            None if current.is_some() => {
+                self.check_write_once(&key)?;
+                self.delete(key)?;
+            }
+            None => {}
+        }
+        Ok(true)
+    }
+
+    /// Returns the version a normalized key in the index is at.
+    fn version_of(&self, key: &str) -> Option<Version> {
+        self.index.get(key).map(|cmd_pos| Version {
+            incarnation: self.incarnation,
+            seq: cmd_pos.seq,
+        })
+    }
+
+    /// Stores `value` under a key derived from a hash of it (see
+    /// [`KvOpts::cas_hash`]), and returns the key. Putting a value that is
+    /// in the store already writes nothing, so identical values are only
+    /// ever stored once.
+    ///
+    /// ```rust
+    /// # use kvs::{KvStore, Result};
+    /// # fn main() -> Result<()> {
+    /// # let dir = tempfile::TempDir::new()?;
+    /// let mut store = KvStore::open(dir.path())?;
+    /// let key = store.put_cas("blob".to_owned())?;
+    /// assert!(key.starts_with("sha256:"));
+    /// assert_eq!(store.put_cas("blob".to_owned())?, key);
+    /// assert_eq!(store.get(key)?, Some("blob".to_owned()));
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// Errors with [`KvsError::HashCollision`] if a different value is
+    /// stored under the key already.
+    ///
+    /// [`KvOpts::cas_hash`]: struct.KvOpts.html#method.cas_hash
+    /// [`KvsError::HashCollision`]: enum.KvsError.html#variant.HashCollision
+    pub fn put_cas(&mut self, value: String) -> Result<String> {
+        let key = self
+            .key_codec
+            .normalize(self.opts.cas_hash.key(value.as_bytes()));
+        match self.live_value(&key)? {
+            Some(existing) if existing == value => {
+                if let Some(lru) = &mut self.lru {
+                    lru.touch(&key);
+                }
+                Ok(key)
+            }
+            Some(_) => Err(KvsError::HashCollision(format!(
+                "a different value is stored under: {}",
+                key
+            ))),
+            None => {
+                self.write_set(key.clone(), value, None)?;
+                Ok(key)
+            }
+        }
+    }
+
+    /// Sets a key-value pair that expires once `ttl` has passed. An expired
+    /// key behaves exactly as if it had been removed.
+    pub fn set_with_ttl(&mut self, key: String, value: String, ttl: Duration) -> Result<()> {
+        let key = self.key_codec.normalize(key);
+        self.check_write_once(&key)?;
+        self.write_set(key, value, Some(self.expires_in(ttl)))
+    }
+
+    /// Sets a key to expire once `ttl` has passed, replacing any expiry it
+    /// had.
+    ///
+    /// # Errors
+    ///
+    /// Errors with [`KvsError::KeyNotFound`] if the key does not exist, and
+    /// with [`KvsError::WriteOnce`] if it is write-once.
+    ///
+    /// [`KvsError::KeyNotFound`]: enum.KvsError.html#variant.KeyNotFound
+    /// [`KvsError::WriteOnce`]: enum.KvsError.html#variant.WriteOnce
+    pub fn expire(&mut self, key: String, ttl: Duration) -> Result<()> {
+        let key = self.key_codec.normalize(key);
+        self.check_write_once(&key)?;
+        match self.live_value(&key)? {
+            Some(value) => self.write_set(key, value, Some(self.expires_in(ttl))),
+            None => Err(KvsError::KeyNotFound(format!(
+                "could not find key: {}",
+                key
+            ))),
+        }
+    }
+
+    /// Clears a key's expiry, so that it lives until it is removed.
+    ///
+    /// # Errors
+    ///
+    /// Errors with [`KvsError::KeyNotFound`] if the key does not exist, and
+    /// with [`KvsError::WriteOnce`] if it is write-once.
+    ///
+    /// [`KvsError::KeyNotFound`]: enum.KvsError.html#variant.KeyNotFound
+    /// [`KvsError::WriteOnce`]: enum.KvsError.html#variant.WriteOnce
+    pub fn persist(&mut self, key: String) -> Result<()> {
+        let key = self.key_codec.normalize(key);
+        self.check_write_once(&key)?;
+        match self.live_value(&key)? {
+            // A key that never expires has nothing to clear.
+            Some(_) if self.index[&key].expires.is_none() => Ok(()),
+            Some(value) => self.write_set(key, value, None),
+            None => Err(KvsError::KeyNotFound(format!(
+                "could not find key: {}",
+                key
+            ))),
+        }
+    }
+
+    /// Returns how long a key has left before it expires, or `None` if the
+    /// key does not exist.
+    pub fn ttl(&mut self, key: String) -> Result<Option<Ttl>> {
+        let key = self.key_codec.normalize(key);
+        if self.drop_if_expired(&key) {
+            return Ok(None);
+        }
+        Ok(self.index.get(&key).map(|cmd_pos| match cmd_pos.expires {
+            Some(expires) => Ttl::Expires(Duration::from_millis(
+                expires.saturating_sub(self.now_millis()),
+            )),
+            None => Ttl::Persistent,
+        }))
+    }
+
+    /// Errors with [`KvsError::WriteOnce`] if a normalized key is write-once
+    /// and has been set already.
+    ///
+    /// [`KvsError::WriteOnce`]: enum.KvsError.html#variant.WriteOnce
+    fn check_write_once(&mut self, key: &str) -> Result<()> {
+        if self.is_write_once(key) && !self.drop_if_expired(key) && self.index.contains_key(key) {
+            return Err(write_once_error(key));
+        }
+        Ok(())
+    }
+
+    /// Returns whether a normalized key falls under a write-once prefix.
+    fn is_write_once(&self, key: &str) -> bool {
+        self.meta
+            .write_once
+            .iter()
+            .chain(&self.opts.write_once)
+            .any(|prefix| key.starts_with(prefix.as_str()))
+    }
+
+    /// Reads the value of a normalized key, unless the key has expired.
+    fn live_value(&mut self, key: &str) -> Result<Option<String>> {
+        if self.drop_if_expired(key) {
+            return Ok(None);
+        }
+        self.read_value(key)
+    }
+
+    /// Drops a normalized key from the index, and from every secondary
+    /// index, if it has expired. Returns whether it had.
+    ///
+    /// Nothing is written for an expired key: the expiry is part of the
+    /// key's `Set` command, so it is expired on every later `open` too, and
+    /// the command is left behind by the next compaction.
+    fn drop_if_expired(&mut self, key: &str) -> bool {
+        let now = self.now_millis();
+        if !self
+            .index
+            .get(key)
+            .is_some_and(|cmd_pos| cmd_pos.is_expired(now))
+        {
+            return false;
+        }
+        self.unindex(key);
+        true
+    }
+
+    /// Drops a live key from the index and from every secondary index.
+    fn unindex(&mut self, key: &str) {
+        let old_cmd = self.take_live(key);
+        self.stale_bytes += old_cmd.len;
+    }
+
+    /// Takes a live key out of the index and every secondary index, and
+    /// returns where its latest `Set` command is.
+    fn take_live(&mut self, key: &str) -> CommandPosition {
+        for secondary in self.secondary.values_mut().chain(&mut self.tokens) {
+            secondary.remove(key);
+        }
+        if let Some(lru) = &mut self.lru {
+            lru.remove(key);
+        }
+        let old_cmd = self.index.remove(key).expect("key not found");
+        self.live_bytes -= old_cmd.len;
+        old_cmd
+    }
+
+    /// Removes a live key that is normalized already, softly if the store
+    /// has a soft delete window.
+    fn delete(&mut self, key: String) -> Result<()> {
+        if self.soft_delete_window().is_none() {
+            return self.write_remove(key);
+        }
+        let at = self.now_millis();
+        self.soft_remove(key, at)
+    }
+
+    /// Writes a soft `Remove` command, deleted at `at`, for a live key that
+    /// is normalized already.
+    fn soft_remove(&mut self, key: String, at: u64) -> Result<()> {
+        let cmd = Command::Remove {
+            key,
+            deleted: Some(at),
+        };
+        let range = self.append(&cmd)?;
+        if let Command::Remove { key, .. } = cmd {
+            // The soft `Remove` is only needed until the next compaction,
+            // which keeps the key's `Set` around by itself.
+            self.stale_bytes += range.end - range.start;
+            self.soft_remove_bytes += range.end - range.start;
+            let cmd_pos = self.take_live(&key);
+            self.deleted.insert(key, SoftDeleted { cmd_pos, at });
+        }
+        Ok(())
+    }
+
+    /// Writes a `Remove` command for a live key that is normalized already.
+    fn write_remove(&mut self, key: String) -> Result<()> {
+        let cmd = Command::Remove { key, deleted: None };
+        self.append(&cmd)?;
+        if let Command::Remove { key, .. } = cmd {
+            self.unindex(&key);
+        }
+        Ok(())
+    }
+
+    /// Restores a key that was removed within the soft delete window (see
+    /// [`KvOpts::soft_delete`]), along with the value and expiry it had.
+    ///
+    /// ```rust
+    /// # use std::time::Duration;
+    /// # use kvs::{KvOpts, KvStore, Result};
+    /// # fn main() -> Result<()> {
+    /// # let dir = tempfile::TempDir::new()?;
+    /// let opts = KvOpts::new().soft_delete(Duration::from_secs(3600));
+    /// let mut store = KvStore::open_with_opts(dir.path(), opts)?;
+    /// store.set("key".to_owned(), "value".to_owned())?;
+    /// store.remove("key".to_owned())?;
+    /// assert_eq!(store.get("key".to_owned())?, None);
+    ///
+    /// store.undelete("key".to_owned())?;
+    /// assert_eq!(store.get("key".to_owned())?, Some("value".to_owned()));
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// Errors with [`KvsError::KeyNotFound`] if the key was not soft-deleted,
+    /// or its window has passed.
+    ///
+    /// [`KvOpts::soft_delete`]: struct.KvOpts.html#method.soft_delete
+    /// [`KvsError::KeyNotFound`]: enum.KvsError.html#variant.KeyNotFound
+    pub fn undelete(&mut self, key: String) -> Result<()> {
+        let key = self.key_codec.normalize(key);
+        let soft = match self.deleted.get(&key) {
+            Some(soft) if !self.is_past_window(soft) => soft,
+            _ => {
+                return Err(KvsError::KeyNotFound(format!(
+                    "could not find deleted key: {}",
+                    key
+                )))
+            }
+        };
+        let cmd_pos = soft.cmd_pos;
+        let value = self.read_at(&key, cmd_pos)?;
+        self.write_set(key, value, cmd_pos.expires)
+    }
+
+    /// Returns the soft delete window, in milliseconds, if there is one.
+    fn soft_delete_window(&self) -> Option<u64> {
+        self.opts
+            .soft_delete
+            .map(|window| window.as_millis() as u64)
+            .or(self.meta.soft_delete_ms)
+    }
+
+    /// Returns the number of stale bytes the next compaction can reclaim.
+    fn reclaimable_bytes(&self) -> u64 {
+        self.stale_bytes.saturating_sub(self.soft_remove_bytes)
+    }
+
+    /// Returns whether a soft-deleted key can no longer be restored.
+    fn is_past_window(&self, soft: &SoftDeleted) -> bool {
+        let window = self.soft_delete_window().unwrap_or(0);
+        soft.at.saturating_add(window) <= self.now_millis()
+    }
+
+    /// Evicts the least recently used keys, other than `keep`, until the
+    /// live bytes fit the cache budget, and the shared [`Budget`], again.
+    ///
+    /// [`Budget`]: struct.Budget.html
+    fn evict(&mut self, keep: &str) -> Result<()> {
+        while self.over_budget() {
+            let oldest = match self.lru.as_ref().and_then(Lru::oldest) {
+                Some(oldest) if oldest != keep => oldest.to_owned(),
+                // A single value larger than the budget is kept anyway.
+                _ => break,
+            };
+            self.write_remove(oldest)?;
+            self.evictions += 1;
+        }
+        Ok(())
+    }
+
+    /// Reports the live bytes to the shared [`Budget`], if it limits them,
+    /// and returns whether the store is over either budget.
+    ///
+    /// [`Budget`]: struct.Budget.html
+    fn over_budget(&mut self) -> bool {
+        let live_bytes = self.live_bytes;
+        let over_shared = self
+            .cache_charge
+            .as_mut()
+            .is_some_and(|charge| charge.report(live_bytes));
+        over_shared || self.opts.cache_budget.is_some_and(|max| live_bytes > max)
+    }
+
+    /// Drops every expired key, and returns how many there were. See
+    /// [`drop_if_expired`].
+    ///
+    /// [`drop_if_expired`]: #method.drop_if_expired
+    fn drop_expired(&mut self) -> usize {
+        let now = self.now_millis();
+        let expired: Vec<String> = self
+            .index
+            .iter()
+            .filter(|(_, cmd_pos)| cmd_pos.is_expired(now))
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in &expired {
+            self.drop_if_expired(key);
+        }
+        expired.len()
+    }
+
+    /// Writes a `Set` command for a key that is normalized already.
+    fn write_set(&mut self, key: String, value: String, expires: Option<u64>) -> Result<()> {
+        let cmd = Command::Set {
+            key,
+            value,
+            expires,
+        };
+        let range = self.append(&cmd)?;
+        if let Command::Set { key, value, .. } = cmd {
+            for secondary in self.secondary.values_mut().chain(&mut self.tokens) {
+                secondary.insert(&key, &value);
+            }
+            let mut cmd_pos: CommandPosition = (self.version, range).into();
+            cmd_pos.expires = expires;
+            cmd_pos.seq = self.next_seq;
+            self.next_seq += 1;
+            self.live_bytes += cmd_pos.len;
+            if let Some(lru) = &mut self.lru {
+                lru.touch(&key);
+            }
+            // The call to `insert` returns `None` if the key is not present
+            // upon insertion; otherwise, the previous value is returned.
+            if let Some(old_cmd) = self.index.insert(key.clone(), cmd_pos) {
+                // Record the old command's length as stale bytes.
+                self.stale_bytes += old_cmd.len;
+                self.live_bytes -= old_cmd.len;
+            }
+            // A key that is set again can no longer be restored.
+            if let Some(old) = self.deleted.remove(&key) {
+                self.stale_bytes += old.cmd_pos.len;
+                self.soft_remove_bytes -= soft_remove_len(&key, old.at);
+            }
+            // A batch evicts once it has been applied, so that none of its
+            // own keys are evicted before they are all in.
+            if !self.in_batch {
+                self.evict(&key)?;
+            }
+        }
+        self.maybe_compact()
+    }
+
+    /// Compacts the store if its compaction policy says to, unless a batch
+    /// is being applied.
+    fn maybe_compact(&mut self) -> Result<()> {
+        if self.in_batch {
+            return Ok(());
+        }
+        let now = self.now_millis();
+        let (fs, path) = (&self.fs, &self.path);
+        let compact = self.compaction.should_compact(
+            &self.opts.compaction_policy,
+            now,
+            self.reclaimable_bytes(),
+            self.live_bytes,
+            || {
+                fs.available_space(path)
+                    .and_then(|available| available.ok())
+            },
+        );
+        if compact {
+            self.compact()?;
+        }
+        Ok(())
+    }
+
+    /// Drops every expired key from the index, so that the bytes its `Set`
+    /// takes up count as stale, and compacts the store if that is enough
+    /// for its compaction policy. Returns how many keys were dropped.
+    ///
+    /// Expired keys are otherwise only dropped once something touches
+    /// them. Nothing is written for them: the expiry is part of each key's
+    /// `Set`, so the key is expired again on every later open, without a
+    /// `Remove` to say so, and the next compaction leaves the `Set` behind.
+    ///
+    /// ```rust
+    /// # use std::time::Duration;
+    /// # use kvs::{KvOpts, KvStore, ManualClock, Result};
+    /// # fn main() -> Result<()> {
+    /// # let dir = tempfile::TempDir::new()?;
+    /// let clock = ManualClock::new(Duration::from_secs(1_000_000));
+    /// let mut store = KvStore::open_with_opts(dir.path(), KvOpts::new().clock(clock.clone()))?;
+    /// store.set_with_ttl("key".to_owned(), "value".to_owned(), Duration::from_secs(60))?;
+    /// clock.advance(Duration::from_secs(60));
+    /// assert_eq!(store.purge_expired()?, 1);
+    /// assert_eq!(store.purge_expired()?, 0);
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// Errors if compacting the store does.
+    pub fn purge_expired(&mut self) -> Result<usize> {
+        let purged = self.drop_expired();
+        self.maybe_compact()?;
+        Ok(purged)
+    }
+
+    /// Clears stale command entries from the `KvStore`s logs.
+    ///
+    /// # Examples
+    /// ```rust
+    /// ```
+    ///
+    /// # Panics
+    ///
+    pub fn compact(&mut self) -> Result<()> {
+        self.compact_with_progress(|_| {})
+    }
+
+    /// Compacts the store like [`compact`], calling `progress` after every
+    /// live command is copied into the compaction log.
+    ///
+    /// # Errors
+    ///
+    /// Errors like [`compact`] does.
+    ///
+    /// [`compact`]: #method.compact
+    pub fn compact_with_progress<F: FnMut(Progress)>(&mut self, mut progress: F) -> Result<()> {
+        self.check_writable()?;
+        // Expired keys are not worth copying into the compaction log, and
+        // neither are keys whose soft delete window has passed.
+        self.drop_expired();
+        let now = self.now_millis();
+        let window = self.soft_delete_window().unwrap_or(0);
+        self.deleted
+            .retain(|_, soft| soft.at.saturating_add(window) > now);
+
+        // Pending commands have to be on disk before they can be copied into
+        // the compaction log.
+        self.flush_pending()?;
+
+        let compact_version = self.version + 1;
+        self.version += 2;
+        self.writer = self.new_log_file(self.version)?;
+        self.wal.reset(self.version, 0)?;
+
+        // The compaction log is written under a temporary name and only
+        // renamed into place once it is complete, so that a crash part way
+        // through leaves nothing behind that could be loaded.
+        let compaction_path = self.segment_path(compact_version);
+        let tmp = temporary_path(&self.meta.segment_layout.create_path(
+            &*self.fs,
+            &self.path,
+            compact_version,
+        )?);
+        let file = if self.opts.compaction_direct_io {
+            self.fs.create_direct(&tmp)?
+        } else {
+            self.fs.open(&tmp, OpenMode::Create)?
+        };
+        let mut compaction_writer = KvsWriter::new(file)?;
+        let mut blocks = BlockBuilder::new();
+        let mut copied = Progress {
+            done: 0,
+            total: self
+                .index
+                .values()
+                .chain(self.deleted.values().map(|soft| &soft.cmd_pos))
+                .map(|cmd_pos| cmd_pos.len)
+                .sum(),
+        };
+
+        let mut watch = CompactionWatch::start();
+        let mut buf = Vec::new();
+        for cmd_pos in &mut self.index.values_mut() {
+            read_command(&mut self.readers, cmd_pos, &mut buf)?;
+            let new_pos = add_compacted(&mut compaction_writer, &mut blocks, &buf)?;
+            cmd_pos.ver = compact_version;
+            cmd_pos.pos = new_pos;
+
+            copied.done += cmd_pos.len;
+            progress(copied);
+            watch.check(&self.opts.watchdog, &self.io_counters);
+        }
+
+        // A soft-deleted key is copied along with a soft `Remove`, so that it
+        // stays restorable until its window passes. The `Remove` is stale
+        // from the start.
+        let mut soft_stale_bytes = 0;
+        for (key, soft) in &mut self.deleted {
+            let cmd_pos = &mut soft.cmd_pos;
+            read_command(&mut self.readers, cmd_pos, &mut buf)?;
+            let new_pos = add_compacted(&mut compaction_writer, &mut blocks, &buf)?;
+            cmd_pos.ver = compact_version;
+            cmd_pos.pos = new_pos;
+
+            let cmd = Command::Remove {
+                key: key.clone(),
+                deleted: Some(soft.at),
+            };
+            buf = serde_json::to_vec(&cmd)?;
+            add_compacted(&mut compaction_writer, &mut blocks, &buf)?;
+            soft_stale_bytes += buf.len() as u64;
+
+            copied.done += cmd_pos.len;
+            progress(copied);
+            watch.check(&self.opts.watchdog, &self.io_counters);
+        }
+
+        // The compaction log is never written to again, so it is sealed
+        // straight away. Every command in it is live, other than the soft
+        // `Remove`s.
+        let live = self.index.iter().map(|(key, cmd_pos)| (key, cmd_pos, None));
+        let soft = self
+            .deleted
+            .iter()
+            .map(|(key, soft)| (key, &soft.cmd_pos, Some(soft.at)));
+        let mut entries: Vec<_> = live
+            .chain(soft)
+            .map(|(key, cmd_pos, deleted)| FooterEntry {
+                key: key.clone(),
+                pos: cmd_pos.pos,
+                len: cmd_pos.len,
+                expires: cmd_pos.expires,
+                deleted,
+            })
+            .collect();
+        entries.sort_unstable_by(|a, b| a.key.cmp(&b.key));
+        let footer = Footer {
+            entries,
+            stale_bytes: soft_stale_bytes,
+            compaction: true,
+            ..Footer::default()
+        };
+        blocks.add_footer(&serde_json::to_vec(&footer)?);
+        compaction_writer.write_all(blocks.as_slice())?;
+        if self.opts.sync {
+            compaction_writer.sync()?;
+        } else {
+            compaction_writer.flush()?;
+        }
+        self.fs.rename(&tmp, &compaction_path)?;
+        self.readers.add(compact_version);
+
+        let stale_versions: Vec<_> = self
+            .readers
+            .versions()
+            .filter(|v| *v < compact_version)
+            .collect();
+
+        // Readers are closed before their segments are removed, which
+        // Windows insists on.
+        for stale_gen in stale_versions {
+            self.readers.remove(stale_gen);
+            self.fs.remove_file(&self.segment_path(stale_gen))?;
+        }
+        if self.opts.sync {
+            sync_segment_dirs(&*self.fs, &self.meta.segment_layout, &self.path)?;
+        }
+
+        watch.check(&self.opts.watchdog, &self.io_counters);
+
+        // Only live commands and soft-deleted keys survived, which is exactly
+        // what the compaction log's footer tells the next `open`.
+        self.stale_bytes = soft_stale_bytes;
+        self.soft_remove_bytes = soft_stale_bytes;
+        Ok(())
+    }
+
+    /// Registers a secondary index under `name`, replacing any index already
+    /// registered under that name.
+    ///
+    /// The index files every key under the index keys `extractor` pulls out
+    /// of the key's value, and is kept up to date by every write that
+    /// follows. Secondary indexes live in memory only, so they have to be
+    /// registered again each time the store is opened.
+    ///
+    /// # Errors
+    ///
+    /// Building the index reads every live value, and errors if any of those
+    /// reads do.
+    pub fn register_index(&mut self, name: &str, extractor: Extractor) -> Result<()> {
+        let secondary = self.build_index(extractor)?;
+        self.secondary.insert(name.to_owned(), secondary);
+        Ok(())
+    }
+
+    /// Returns the keys whose values are filed under `index_key` in the
+    /// secondary index registered as `name`, in order.
+    ///
+    /// # Errors
+    ///
+    /// Errors with [`KvsError::IndexNotFound`] if no index is registered
+    /// under `name`.
+    ///
+    /// [`KvsError::IndexNotFound`]: enum.KvsError.html#variant.IndexNotFound
+    pub fn query_index(&self, name: &str, index_key: &str) -> Result<Vec<String>> {
+        let mut keys = self
+            .secondary
+            .get(name)
+            .map(|secondary| secondary.query(index_key))
+            .ok_or_else(|| KvsError::IndexNotFound(name.to_owned()))?;
+        self.retain_live(&mut keys);
+        Ok(keys)
+    }
+
+    /// Registers the token index that backs [`search`], replacing any token
+    /// index already registered.
+    ///
+    /// Every value is split into tokens by `tokenizer`, and each key is filed
+    /// under the tokens of its value. [`tokenize`] is a reasonable default.
+    /// Like any other secondary index, the token index lives in memory only.
+    ///
+    /// # Errors
+    ///
+    /// Building the index reads every live value, and errors if any of those
+    /// reads do.
+    ///
+    /// [`search`]: #method.search
+    /// [`tokenize`]: fn.tokenize.html
+    pub fn register_token_index(&mut self, tokenizer: Tokenizer) -> Result<()> {
+        self.tokens = Some(self.build_index(tokenizer)?);
+        Ok(())
+    }
+
+    /// Returns, in order, the keys whose values contain every token of
+    /// `term`. The term is split into tokens by the token index's tokenizer.
+    ///
+    /// # Errors
+    ///
+    /// Errors with [`KvsError::IndexNotFound`] if no token index has been
+    /// registered.
+    ///
+    /// [`KvsError::IndexNotFound`]: enum.KvsError.html#variant.IndexNotFound
+    pub fn search(&self, term: &str) -> Result<Vec<String>> {
+        let tokens = self
+            .tokens
+            .as_ref()
+            .ok_or_else(|| KvsError::IndexNotFound("token index".to_owned()))?;
+
+        let mut terms = tokens.extract(term).into_iter();
+        let mut keys = match terms.next() {
+            Some(first) => tokens.query(&first),
+            None => return Ok(Vec::new()),
+        };
+        for term in terms {
+            let matches = tokens.query(&term);
+            keys.retain(|key| matches.binary_search(key).is_ok());
+        }
+        self.retain_live(&mut keys);
+        Ok(keys)
+    }
+
+    /// Returns, in key order, every live key that starts with `prefix`,
+    /// along with its value. The prefix is normalized like any other key.
+    ///
+    /// # Errors
+    ///
+    /// Errors if reading any of the values does.
+    pub fn scan(&mut self, prefix: &str) -> Result<Vec<(String, String)>> {
+        self.scan_with(ScanOpts::new().prefix(prefix))
+    }
+
+    /// Returns the live keys picked out by `opts`, along with their values,
+    /// in the order `opts` asks for. Only the values of the keys returned
+    /// are read, so a small limit keeps a scan cheap however many keys fall
+    /// within its bounds.
+    ///
+    /// # Errors
+    ///
+    /// Errors if reading any of the values does.
+    pub fn scan_with(&mut self, opts: ScanOpts) -> Result<Vec<(String, String)>> {
+        self.scan_iter(opts).collect()
+    }
+
+    /// Returns an iterator over the live keys picked out by `opts`, along
+    /// with their values, in the order `opts` asks for. The keys are picked
+    /// out up front, but the values are only read as the iterator gets to
+    /// them, a batch of keys at a time, so going through a large scan never
+    /// holds more than one batch of values in memory. Each batch is read in
+    /// the order its values sit in the store's segments, which keeps the
+    /// reads sequential wherever the keys are close together on disk.
+    pub fn scan_iter(&mut self, opts: ScanOpts) -> Scan<'_> {
+        let batch_bytes = opts.batch_bytes;
+        let keys = self.scan_keys(opts);
+        Scan::new(self, keys, batch_bytes)
+    }
+
+    /// Returns an iterator over every live key, in key order. The keys are
+    /// borrowed from the index rather than copied, and no values are read,
+    /// so walking the keys of a large store costs no memory. [`iter`] walks
+    /// them along with their values.
+    ///
+    /// [`iter`]: #method.iter
+    pub fn keys(&mut self) -> Keys<'_> {
+        self.drop_expired();
+        Keys::new(self.index.keys())
+    }
+
+    /// Returns an iterator over every live key, along with its value, in key
+    /// order. The values are read as the iterator gets to them, as
+    /// [`scan_iter`] reads them.
+    ///
+    /// [`scan_iter`]: #method.scan_iter
+    pub fn iter(&mut self) -> Scan<'_> {
+        self.scan_iter(ScanOpts::new())
+    }
+
+    /// Returns an iterator over the live keys within `range`, such as
+    /// `"a".."n"`, along with their values, in key order. This is
+    /// [`scan_iter`] with [`ScanOpts::range`].
+    ///
+    /// [`scan_iter`]: #method.scan_iter
+    /// [`ScanOpts::range`]: struct.ScanOpts.html#method.range
+    pub fn range<'a, R: RangeBounds<&'a str>>(&mut self, range: R) -> Scan<'_> {
+        self.scan_iter(ScanOpts::new().range(range))
+    }
+
+    /// Returns the live normalized keys picked out by `opts`, in the order it
+    /// asks for. Only the keys within its bounds are looked at.
+    fn scan_keys(&mut self, opts: ScanOpts) -> Vec<String> {
+        let opts = opts.normalize(|key| self.key_codec.normalize(key));
+        let now = self.now_millis();
+        let limit = opts.limit.unwrap_or(usize::MAX);
+        let keys = self
+            .index_range(opts.key_range())
+            .filter(|(key, cmd_pos)| !cmd_pos.is_expired(now) && opts.contains(key))
+            .map(|(key, _)| key.clone());
+        if opts.reverse {
+            keys.rev().take(limit).collect()
+        } else {
+            keys.take(limit).collect()
+        }
+    }
+
+    /// Returns the entries of the index within `range`, a range from
+    /// [`ScanOpts::key_range`], in key order.
+    ///
+    /// [`ScanOpts::key_range`]: struct.ScanOpts.html#method.key_range
+    fn index_range(
+        &self,
+        range: Option<(Bound<String>, Bound<String>)>,
+    ) -> impl DoubleEndedIterator<Item = (&String, &CommandPosition)> {
+        range.into_iter().flat_map(move |(from, to)| {
+            self.index.range::<str, _>((
+                from.as_ref().map(String::as_str),
+                to.as_ref().map(String::as_str),
+            ))
+        })
+    }
+
+    /// Returns every live key as a JSON object, in key order. The whole
+    /// store is read into memory; [`live_view`] serializes it one value at a
+    /// time instead.
+    ///
+    /// # Errors
+    ///
+    /// Errors if reading any of the values does.
+    ///
+    /// [`live_view`]: #method.live_view
+    pub fn to_value(&mut self) -> Result<serde_json::Value> {
+        let map = self
+            .scan("")?
+            .into_iter()
+            .map(|(key, value)| (key, serde_json::Value::String(value)))
+            .collect();
+        Ok(serde_json::Value::Object(map))
+    }
+
+    /// Returns a view of the live keys that serializes as a map from key to
+    /// value, in key order, reading each value only as it is serialized.
+    ///
+    /// ```rust
+    /// # use kvs::{KvStore, Result};
+    /// # fn main() -> Result<()> {
+    /// # let temp_dir = tempfile::TempDir::new()?;
+    /// let mut store = KvStore::open(temp_dir.path())?;
+    /// store.set("key".to_owned(), "value".to_owned())?;
+    /// let json = serde_json::to_string(&store.live_view())?;
+    /// assert_eq!(json, r#"{"key":"value"}"#);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn live_view(&mut self) -> LiveView<'_> {
+        LiveView::new(self)
+    }
+
+    /// Takes a read-only [`Snapshot`] of the live keys, which can be read,
+    /// from any thread, while the store goes on being written to.
+    ///
+    /// Every segment the snapshot refers to is opened up front, so that a
+    /// compaction removing the segment afterwards does not pull it out from
+    /// under the snapshot. On Windows, a segment cannot be removed while it
+    /// is open, so compactions fail until the snapshot is dropped.
+    ///
+    /// # Errors
+    ///
+    /// Errors if opening any of the segments does.
+    ///
+    /// [`Snapshot`]: struct.Snapshot.html
+    pub fn snapshot(&self) -> Result<Snapshot> {
+        let now = self.now_millis();
+        let index: Index<CommandPosition> = self
+            .index
+            .iter()
+            .filter(|(_, cmd_pos)| !cmd_pos.is_expired(now))
+            .map(|(key, cmd_pos)| (key.clone(), *cmd_pos))
+            .collect();
+        let pending_pos = self.writer.pos();
+        let mut readers = HashMap::new();
+        for cmd_pos in index.values() {
+            let pending = cmd_pos.ver == self.version && cmd_pos.pos >= pending_pos;
+            if !pending && !readers.contains_key(&cmd_pos.ver) {
+                let file = self
+                    .fs
+                    .open(&self.segment_path(cmd_pos.ver), OpenMode::Read)?;
+                readers.insert(cmd_pos.ver, KvsReader::new(file)?);
+            }
+        }
+        let pending = (self.pending.as_slice().to_vec(), self.version, pending_pos);
+        Ok(Snapshot::new(
+            index,
+            readers,
+            pending,
+            Arc::clone(&self.key_codec),
+        ))
+    }
+
+    /// Returns a view of the keys that start with `prefix`, through which
+    /// keys are handed over, and returned, without the prefix. See
+    /// [`ScopedStore`].
+    ///
+    /// [`ScopedStore`]: struct.ScopedStore.html
+    pub fn scoped(&mut self, prefix: &str) -> ScopedStore<'_> {
+        ScopedStore::new(self, prefix)
+    }
+
+    /// Drops expired keys and returns the live normalized keys that start
+    /// with the normalized `prefix`, in order.
+    fn sorted_keys(&mut self, prefix: &str) -> Vec<String> {
+        self.drop_expired();
+        self.index
+            .range::<str, _>((Bound::Included(prefix), Bound::Unbounded))
+            .map(|(key, _)| key)
+            .take_while(|key| key.starts_with(prefix))
+            .cloned()
+            .collect()
+    }
+
+    /// Filters out keys that have expired but have not been dropped yet.
+    fn retain_live(&self, keys: &mut Vec<String>) {
+        let now = self.now_millis();
+        keys.retain(|key| {
+            !self
+                .index
+                .get(key)
+                .is_some_and(|cmd_pos| cmd_pos.is_expired(now))
+        });
+    }
+
+    /// Builds a secondary index over every live value.
+    fn build_index(&mut self, extractor: Extractor) -> Result<SecondaryIndex> {
+        self.drop_expired();
+        let mut secondary = SecondaryIndex::new(extractor, self.opts.index_hasher);
+        let keys: Vec<String> = self.index.keys().cloned().collect();
+        for key in keys {
+            if let Some(value) = self.read_value(&key)? {
+                secondary.insert(&key, &value);
+            }
+        }
+        Ok(secondary)
+    }
+
+    /// Returns the store's metadata, as recorded in its `kvs.meta` file when
+    /// the store was created.
+    pub fn info(&self) -> &StoreMeta {
+        &self.meta
+    }
+
+    /// Returns the path of the segment with the given version.
+    fn segment_path(&self, version: u64) -> PathBuf {
+        self.meta.segment_layout.path(&self.path, version)
+    }
+
+    /// Returns the leftovers of an interrupted compaction that were removed
+    /// when the store was opened, in the order they were removed.
+    ///
+    /// Those are a compaction log that never made it into place, and
+    /// segments that a finished compaction had not got around to removing.
+    pub fn removed_orphans(&self) -> &[PathBuf] {
+        &self.removed_orphans
+    }
+
+    /// Returns the unexpected files found in the store's directory when it
+    /// was opened, in order. See [`UnexpectedFiles`].
+    ///
+    /// [`UnexpectedFiles`]: enum.UnexpectedFiles.html
+    pub fn unexpected_files(&self) -> &[PathBuf] {
+        &self.unexpected_files
+    }
+
+    /// Returns the number of damaged blocks that were skipped while loading
+    /// the store's logs. Commands in those blocks are lost.
+    pub fn damaged_blocks(&self) -> u64 {
+        self.damaged_blocks
+    }
+
+    /// Summarizes a random sample of up to `sample` live keys. Sampling reads
+    /// the value of every sampled key.
+    ///
+    /// # Errors
+    ///
+    /// Errors if reading any of the sampled values does.
+    pub fn analyze(&mut self, sample: usize) -> Result<Analysis> {
+        self.drop_expired();
+        let mut keys: Vec<String> = self.index.keys().cloned().collect();
+        // A partial Fisher-Yates shuffle moves a uniform sample to the front.
+        let mut rng = Rng::from_entropy();
+        let sample = sample.min(keys.len());
+        for i in 0..sample {
+            let j = i + rng.below((keys.len() - i) as u64) as usize;
+            keys.swap(i, j);
+        }
+        keys.truncate(sample);
+
+        let now = self.now_millis();
+        let mut analyzer = analyze::Analyzer::new(self.index.len() as u64);
+        for key in keys {
+            if let Some(value) = self.read_value(&key)? {
+                let cmd_pos = &self.index[&key];
+                let ttl = cmd_pos
+                    .expires
+                    .map(|expires| Duration::from_millis(expires.saturating_sub(now)));
+                analyzer.observe(&key, value.len() as u64, cmd_pos.len, ttl);
+            }
+        }
+        Ok(analyzer.finish())
+    }
+
+    /// Estimates the number of live keys in `span`, and the live bytes they
+    /// take up, from the index alone.
+    ///
+    /// Up to a thousand keys, spread evenly through the store's keys in key
+    /// order, are looked at, and what they turn up is scaled to the whole
+    /// store, so no values are read. A store with no more keys than that is
+    /// measured exactly.
+    ///
+    /// ```rust
+    /// # use kvs::{KeySpan, KvStore, Result};
+    /// # fn main() -> Result<()> {
+    /// # let dir = tempfile::TempDir::new()?;
+    /// let mut store = KvStore::open(dir.path())?;
+    /// store.set("user:1".to_owned(), "alice".to_owned())?;
+    /// store.set("user:2".to_owned(), "bob".to_owned())?;
+    /// store.set("item:1".to_owned(), "book".to_owned())?;
+    /// assert_eq!(store.estimate_size("user:").keys, 2);
+    /// assert_eq!(store.estimate_size(KeySpan::range("item:".."user:")).keys, 1);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn estimate_size<S: Into<KeySpan>>(&self, span: S) -> SizeEstimate {
+        let span = span.into().normalize(|key| self.key_codec.normalize(key));
+        let now = self.now_millis();
+        let mut estimate = SizeEstimate::default();
+        // Key `i` is sampled where `i * sample / total` moves on to the next
+        // whole number, which happens exactly `sample` times.
+        let total = self.index.len() as u64;
+        let sample = total.min(analyze::ESTIMATE_SAMPLE as u64);
+        let sampled = self
+            .index
+            .iter()
+            .zip(0..)
+            .filter(|&(_, i)| i * sample % total < sample)
+            .map(|(entry, _)| entry);
+        for (key, cmd_pos) in sampled {
+            estimate.sampled += 1;
+            if !cmd_pos.is_expired(now) && span.contains(key) {
+                estimate.keys += 1;
+                estimate.bytes += cmd_pos.len;
+            }
+        }
+
+        estimate.exact = estimate.sampled == total;
+        if !estimate.exact {
+            let sampled = estimate.sampled;
+            let scale = |n: u64| (u128::from(n) * u128::from(total) / u128::from(sampled)) as u64;
+            estimate.keys = scale(estimate.keys);
+            estimate.bytes = scale(estimate.bytes);
+        }
+        estimate
+    }
+
+    /// Returns the smallest live key in `span`, comparing keys byte by byte.
+    ///
+    /// Only the keys at the start of the span, up to the first live one, are
+    /// looked at, and no values are read.
+    ///
+    /// ```rust
+    /// # use kvs::{KeySpan, KvStore, Result};
+    /// # fn main() -> Result<()> {
+    /// # let dir = tempfile::TempDir::new()?;
+    /// let mut store = KvStore::open(dir.path())?;
+    /// store.set("user:1".to_owned(), "alice".to_owned())?;
+    /// store.set("user:2".to_owned(), "bob".to_owned())?;
+    /// assert_eq!(store.first_key_in("user:"), Some("user:1".to_owned()));
+    /// assert_eq!(store.last_key_in(KeySpan::range(.."user:2")), Some("user:1".to_owned()));
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn first_key_in<S: Into<KeySpan>>(&self, span: S) -> Option<String> {
+        self.live_keys_in(span.into()).next().cloned()
+    }
+
+    /// Returns the largest live key in `span`, comparing keys byte by byte.
+    /// Like [`first_key_in`], this looks only at the keys at the end of the
+    /// span.
+    ///
+    /// [`first_key_in`]: #method.first_key_in
+    pub fn last_key_in<S: Into<KeySpan>>(&self, span: S) -> Option<String> {
+        self.live_keys_in(span.into()).next_back().cloned()
+    }
+
+    /// Returns a live key picked uniformly at random, or `None` if the store
+    /// is empty. This is meant for sampling and debugging; it looks at every
+    /// key.
+    pub fn random_key(&self) -> Option<String> {
+        let keys: Vec<&String> = self.live_keys_in(KeySpan::from("")).collect();
+        if keys.is_empty() {
+            return None;
+        }
+        let i = Rng::from_entropy().below(keys.len() as u64) as usize;
+        Some(keys[i].clone())
+    }
+
+    /// Returns a random sample of up to `count` live keys, along with their
+    /// values, in key order. The same `seed` picks the same keys out of the
+    /// same store, so a sample can be pulled again, from one open to the
+    /// next, to reproduce a fixture.
+    ///
+    /// The keys are picked by reservoir sampling, going through the keys in
+    /// key order, and only the values of the sampled keys are read.
+    ///
+    /// ```rust
+    /// # use kvs::{KvStore, Result};
+    /// # fn main() -> Result<()> {
+    /// # let dir = tempfile::TempDir::new()?;
+    /// let mut store = KvStore::open(dir.path())?;
+    /// for i in 0..100 {
+    ///     store.set(format!("key{}", i), format!("value{}", i))?;
+    /// }
+    /// let sample = store.sample(10, 42)?;
+    /// assert_eq!(sample.len(), 10);
+    /// assert_eq!(store.sample(10, 42)?, sample);
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// Errors if reading any of the sampled values does.
+    pub fn sample(&mut self, count: usize, seed: u64) -> Result<Vec<(String, String)>> {
+        self.drop_expired();
+        let keys = self.index.keys();
+        let mut rng = Rng::from_seed(seed);
+        let mut reservoir: Vec<String> = Vec::with_capacity(count.min(keys.len()));
+        for (seen, key) in keys.enumerate() {
+            if reservoir.len() < count {
+                reservoir.push(key.clone());
+            } else {
+                let i = rng.below(seen as u64 + 1) as usize;
+                if i < count {
+                    reservoir[i] = key.clone();
+                }
+            }
+        }
+        reservoir.sort_unstable();
+
+        let values = self.read_values(&reservoir);
+        let mut sample = Vec::with_capacity(reservoir.len());
+        for (key, value) in reservoir.into_iter().zip(values) {
+            if let Some(value) = value? {
+                sample.push((key, value));
+            }
+        }
+        Ok(sample)
+    }
+
+    /// Returns every live key in `span`, in key order.
+    fn live_keys_in(&self, span: KeySpan) -> impl DoubleEndedIterator<Item = &String> {
+        let span = span.normalize(|key| self.key_codec.normalize(key));
+        let now = self.now_millis();
+        self.index_range(span.key_range())
+            .filter(move |(key, cmd_pos)| !cmd_pos.is_expired(now) && span.contains(key))
+            .map(|(key, _)| key)
+    }
+
+    /// Returns the current time, according to the store's clock, in
+    /// milliseconds since the Unix epoch.
+    fn now_millis(&self) -> u64 {
+        self.clock.now().as_millis() as u64
+    }
+
+    /// Returns the expiry, in milliseconds since the Unix epoch, of a key
+    /// that lives for `ttl` from now.
+    fn expires_in(&self, ttl: Duration) -> u64 {
+        self.now_millis().saturating_add(ttl.as_millis() as u64)
+    }
+
+    /// Returns statistics about the store's keys and logs.
+    pub fn stats(&self) -> Stats {
+        Stats {
+            keys: self.index.len() as u64,
+            live_bytes: self.live_bytes,
+            stale_bytes: self.reclaimable_bytes(),
+            evictions: self.evictions,
+            soft_deleted: self.deleted.len() as u64,
+            damaged_blocks: self.damaged_blocks,
+            reclaim_forecast: self.reclaim_forecast(),
+            io: IoStats {
+                logical_bytes_written: self.logical_bytes_written,
+                bytes_written: self.io_counters.bytes_written(),
+                bytes_read: self.io_counters.bytes_read(),
+                syncs: self.io_counters.syncs(),
+                gets: self.gets,
+                get_bytes_read: self.get_bytes_read,
+                sets: self.sets,
+                removes: self.removes,
+                slow_syncs: self.io_counters.slow_syncs(),
+                slow_compactions: self.io_counters.slow_compactions(),
+            },
+        }
+    }
+
+    /// Works out how many bytes compaction can reclaim at a few points in
+    /// the future, from the keys' expiries and the soft delete window.
+    fn reclaim_forecast(&self) -> ReclaimForecast {
+        let now = self.now_millis();
+        let reclaimable = self.reclaimable_bytes();
+        let mut forecast = ReclaimForecast {
+            in_a_minute: reclaimable,
+            in_an_hour: reclaimable,
+            in_a_day: reclaimable,
+            in_a_week: reclaimable,
+        };
+        let expiring = self
+            .index
+            .values()
+            .filter_map(|cmd_pos| Some((cmd_pos.expires?, cmd_pos.len)));
+        // A soft-deleted key takes its `Set` and its soft `Remove` along
+        // when it is hard deleted.
+        let window = self.soft_delete_window().unwrap_or(0);
+        let hard_deleted = self.deleted.iter().map(|(key, soft)| {
+            let bytes = soft.cmd_pos.len + soft_remove_len(key, soft.at);
+            (soft.at.saturating_add(window), bytes)
+        });
+        for (at, bytes) in expiring.chain(hard_deleted) {
+            forecast.add(Duration::from_millis(at.saturating_sub(now)), bytes);
+        }
+        forecast
+    }
+
+    /// Logs a command and stages it for the active data segment. Returns the
+    /// range the command occupies within that segment.
+    fn append(&mut self, cmd: &Command) -> Result<Range<u64>> {
+        self.check_writable()?;
+        // The whole command is serialized before any of it is written, so
+        // that the log sees it in a single write.
+        self.cmd_buf.clear();
+        serde_json::to_writer(&mut self.cmd_buf, cmd)?;
+        // A batch's commands are in the log already, as a single record.
+        if !self.in_batch {
+            self.wal.append(&self.cmd_buf)?;
+        }
+        let logical_bytes = match cmd {
+            Command::Set { key, value, .. } => {
+                self.sets += 1;
+                key.len() + value.len()
+            }
+            Command::Remove { key, .. } => {
+                self.removes += 1;
+                key.len()
+            }
+        } as u64;
+        self.logical_bytes_written += logical_bytes;
+        self.publish_ops(false);
+        let now = self.now_millis();
+        self.compaction.record_write(now, logical_bytes);
+
+        let len = self.cmd_buf.len() as u64;
+        let pos = self.writer.pos() + self.pending.add(&self.cmd_buf) as u64;
+        // Flushing part of a batch would reset the log while the rest of it
+        // is only there.
+        if !self.in_batch {
+            self.flush_if_full()?;
+        }
+        Ok(pos..pos + len)
+    }
+
+    /// Flushes the pending blocks once they fill the segment buffer.
+    fn flush_if_full(&mut self) -> Result<()> {
+        if !self.pending.is_open() && self.pending.len() >= SEGMENT_BUFFER_SIZE {
+            self.flush_pending()?;
+        }
+        Ok(())
+    }
+
+    /// Seals the open block and writes every pending block to the active data
+    /// segment in one go. Once they are there, the write-ahead log no longer
+    /// needs them.
+    fn flush_pending(&mut self) -> Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        self.pending.seal();
+        self.writer.write_all(self.pending.as_slice())?;
+        if self.opts.sync {
+            self.writer.sync()?;
+        } else {
+            self.writer.flush()?;
+        }
+        self.pending.clear();
+        self.wal.reset(self.version, self.writer.pos())
+    }
+
+    fn new_log_file(&mut self, gen: u64) -> Result<KvsWriter<LogFile>> {
+        new_log_file(
+            &*self.fs,
+            &self.meta.segment_layout,
+            &self.path,
+            gen,
+            self.opts.sync,
+            &mut self.readers,
+        )
+    }
+}
+
+/// Sets every key-value pair, in order.
+///
+/// # Panics
+///
+/// Panics if a write fails. Use [`KvStore::set`] to handle the error instead.
+///
+/// [`KvStore::set`]: struct.KvStore.html#method.set
+impl Extend<(String, String)> for KvStore {
+    fn extend<I: IntoIterator<Item = (String, String)>>(&mut self, iter: I) {
+        for (key, value) in iter {
+            self.set(key, value).expect("failed to set key");
+        }
+    }
+}
+
+/// Reads every live key and its value.
+impl TryFrom<&mut KvStore> for HashMap<String, String> {
+    type Error = KvsError;
+
+    fn try_from(store: &mut KvStore) -> Result<HashMap<String, String>> {
+        Ok(store.scan("")?.into_iter().collect())
+    }
+}
+
+impl Drop for KvStore {
+    fn drop(&mut self) {
+        if self.opts.read_only {
+            return;
+        }
+        self.publish_ops(true);
+        // This is best effort. Whatever does not make it to the data segment
+        // here is still in the write-ahead log and is recovered on `open`.
+        if self.flush_pending().is_ok() && self.writer.pos() == 0 {
+            // Nothing was written to the active data segment, so there is no
+            // need to leave an empty one behind on every open.
+            self.readers.remove(self.version);
+            let _ = self.fs.remove_file(&self.segment_path(self.version));
+        }
+    }
+}
+
+/// Takes the lock of the store in `dir`, which is given back when it is
+/// dropped.
+fn lock_store(fs: &dyn Fs, dir: &Path) -> Result<Option<LockGuard>> {
+    match fs.lock(&dir.join(LOCK_FILE_NAME)) {
+        Ok(lock) => Ok(lock),
+        Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+            Err(KvsError::StoreLocked(dir.display().to_string()))
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Returns the length of the soft `Remove` for a key soft-deleted at `at`.
+fn soft_remove_len(key: &str, at: u64) -> u64 {
+    let cmd = Command::Remove {
+        key: key.to_owned(),
+        deleted: Some(at),
+    };
+    serde_json::to_vec(&cmd).map_or(0, |buf| buf.len() as u64)
+}
+
+/// The error for a write to a write-once key that has been set already.
+fn write_once_error(key: &str) -> KvsError {
+    KvsError::WriteOnce(format!("key can only be set once: {}", key))
+}
+
+/// Reads the command at `cmd_pos` into `buf`.
+fn read_command(readers: &mut Readers, cmd_pos: &CommandPosition, buf: &mut Vec<u8>) -> Result<()> {
+    let reader = readers.get(cmd_pos.ver)?;
+    if reader.pos() != cmd_pos.pos {
+        reader.seek(SeekFrom::Start(cmd_pos.pos))?;
+    }
+    buf.clear();
+    reader.take(cmd_pos.len).read_to_end(buf)?;
+    Ok(())
+}
+
+/// Adds a command to a compaction log's blocks, writing them out once there
+/// are enough of them. Returns the command's position in the log.
+fn add_compacted(
+    writer: &mut KvsWriter<LogFile>,
+    blocks: &mut BlockBuilder,
+    cmd: &[u8],
+) -> Result<u64> {
+    let pos = writer.pos() + blocks.add(cmd) as u64;
+    if !blocks.is_open() && blocks.len() >= SEGMENT_BUFFER_SIZE {
+        writer.write_all(blocks.as_slice())?;
+        blocks.clear();
+    }
+    Ok(pos)
+}
+
+/// Constructs a new log file and returns a `KvsWriter` to it.
+///
+/// # Errors
+///
+/// * if `open`ing the file to be consumed by the `KvsWriter` results in an error or
+/// * if `open`ing the file to be consumed by the `KvsReader` results in an error
+///
+/// # Examples
+///
+/// ```rust
+/// ```
+fn new_log_file<P: AsRef<Path>>(
+    fs: &dyn Fs,
+    layout: &SegmentLayout,
+    path: P,
+    version: u64,
+    sync: bool,
+    readers: &mut Readers,
+) -> Result<KvsWriter<LogFile>> {
+    // Construct the log path.
+    let dir = path.as_ref();
+    let path = layout.create_path(fs, dir, version)?;
+
+    // Construct the writer in append mode.
+    let writer = KvsWriter::new(fs.open(&path, OpenMode::Append)?)?;
+
+    // The new file has to outlive a crash before the write-ahead log points
+    // at it, and so does the shard directory it may have been created in.
+    if sync {
+        let parent = path.parent().unwrap_or(dir);
+        fs.sync_dir(parent)?;
+        if parent != dir {
+            fs.sync_dir(dir)?;
+        }
+    }
+
+    // Finally, add this log file to the readers, which open it when it is
+    // first read.
+    readers.add(version);
+    Ok(writer)
+}
+
+/// Moves the commands recovered from the write-ahead log into the data
+/// segment they were destined for.
+fn recover(
+    fs: &dyn Fs,
+    layout: &SegmentLayout,
+    path: &Path,
+    header: WalHeader,
+    buf: &[u8],
+    sync: bool,
+) -> Result<()> {
+    // Only records that deserialize cleanly are moved. A torn write at the
+    // tail of the log was never acknowledged to the caller, and a torn batch
+    // is dropped as a whole.
+    let mut blocks = BlockBuilder::new();
+    let stream = Deserializer::from_slice(buf).into_iter::<WalRecord>();
+    for record in stream {
+        let cmds = match record {
+            Ok(WalRecord::Command(cmd)) => vec![cmd],
+            Ok(WalRecord::Batch(cmds)) => cmds,
+            Err(_) => break,
+        };
+        for cmd in &cmds {
+            blocks.add(&serde_json::to_vec(cmd)?);
+        }
+    }
+    blocks.seal();
+
+    if blocks.is_empty() && !fs.exists(&layout.path(path, header.version)) {
+        return Ok(());
+    }
+    let path = layout.create_path(fs, path, header.version)?;
+
+    let mut file = fs.open(&path, OpenMode::ReadWrite)?;
+
+    // Anything past `header.pos` is a partially written chunk of commands
+    // that the log still holds in full.
+    if file.seek(SeekFrom::End(0))? > header.pos {
+        file.set_len(header.pos)?;
+    }
+    file.seek(SeekFrom::End(0))?;
+    file.write_all(blocks.as_slice())?;
+    if sync {
+        file.sync_data()?;
+    }
+    Ok(())
+}
+
+/// Seals the log at `path` by appending its footer.
+fn seal_log(fs: &dyn Fs, path: &Path, footer: &Footer, sync: bool) -> Result<()> {
+    let mut blocks = BlockBuilder::new();
+    blocks.add_footer(&serde_json::to_vec(footer)?);
+
+    let mut file = fs.open(path, OpenMode::Append)?;
+    file.write_all(blocks.as_slice())?;
+    if sync {
+        file.sync_data()?;
+    }
+    Ok(())
+}
+
+/// Returns the path a segment is written to before it is renamed to `path`.
+fn temporary_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_owned();
+    name.push(".tmp");
+    path.with_file_name(name)
+}
+
+/// Lists every segment still under its temporary name in a store's
+/// directory.
+fn temporary_segments(fs: &dyn Fs, layout: &SegmentLayout, path: &Path) -> Result<Vec<PathBuf>> {
+    let mut leftovers = Vec::new();
+    for dir in layout.dirs(fs, path)? {
+        leftovers.extend(fs.read_dir(&dir)?.into_iter().filter(|file| {
+            file.file_name()
+                .and_then(OsStr::to_str)
+                .and_then(|name| name.strip_suffix(".tmp"))
+                .and_then(|name| layout.version_of(name))
+                .is_some()
+        }));
+    }
+    leftovers.sort_unstable();
+    Ok(leftovers)
+}
+
+/// Removes every segment still under its temporary name in a store's
+/// directory. Returns the files that were removed.
+fn remove_temporary_segments(
+    fs: &dyn Fs,
+    layout: &SegmentLayout,
+    path: &Path,
+) -> Result<Vec<PathBuf>> {
+    let leftovers = temporary_segments(fs, layout, path)?;
+    for file in &leftovers {
+        fs.remove_file(file)?;
+    }
+    Ok(leftovers)
+}
+
+/// Syncs every directory a store's segments are in, so that segments that
+/// were removed stay removed after a crash.
+fn sync_segment_dirs(fs: &dyn Fs, layout: &SegmentLayout, path: &Path) -> Result<()> {
+    for dir in layout.dirs(fs, path)? {
+        fs.sync_dir(&dir)?;
+    }
+    Ok(())
+}
+
+/// Finds the unexpected files in a store's directory, as described by
+/// `UnexpectedFiles`, and deals with them as `policy` asks. Returns the
+/// files that should be reported. The output of a running compaction, in
+/// `compacting`, is expected.
+fn check_unexpected_files(
+    fs: &dyn Fs,
+    layout: &SegmentLayout,
+    path: &Path,
+    compacting: &[PathBuf],
+    policy: UnexpectedFiles,
+) -> Result<Vec<PathBuf>> {
+    if policy == UnexpectedFiles::Ignore {
+        return Ok(Vec::new());
+    }
+    let mut unexpected: Vec<PathBuf> = fs
+        .read_dir(path)?
+        .into_iter()
+        .filter(|file| {
+            let name = match file.file_name().and_then(OsStr::to_str) {
+                Some(name) => name,
+                // The store never names a file with anything but UTF-8.
+                None => return false,
+            };
+            let owned = name == META_FILE_NAME
+                || name == WAL_FILE_NAME
+                || name == LOCK_FILE_NAME
+                || name == STATS_FILE_NAME
+                || layout.version_of(name).is_some()
+                || compacting.contains(file);
+            !owned && (name.starts_with("kvs.") || layout.resembles(name))
+        })
+        .collect();
+    unexpected.sort_unstable();
+
+    match policy {
+        UnexpectedFiles::Error => match unexpected.first() {
+            Some(file) => Err(KvsError::UnexpectedFile(file.display().to_string())),
+            None => Ok(unexpected),
+        },
+        UnexpectedFiles::Quarantine if !unexpected.is_empty() => {
+            let quarantine = path.join(QUARANTINE_DIR_NAME);
+            fs.create_dir_all(&quarantine)?;
+            let mut moved = Vec::with_capacity(unexpected.len());
+            for file in unexpected {
+                let to = quarantine.join(file.file_name().expect("listed files have names"));
+                fs.rename(&file, &to)?;
+                moved.push(to);
+            }
+            Ok(moved)
+        }
+        _ => Ok(unexpected),
+    }
+}
+
+/// The name of the file a store's lock is held on.
+const LOCK_FILE_NAME: &str = "kvs.lock";
+
+/// The subdirectory unexpected files are moved into by
+/// `UnexpectedFiles::Quarantine`.
+const QUARANTINE_DIR_NAME: &str = "quarantine";
+
+struct Loader;
+
+/// What loading a single log turned up.
+struct Loaded {
+    /// The number of stale bytes found in the log.
+    stale_bytes: u64,
+    /// The number of damaged blocks that had to be skipped.
+    damaged_blocks: u64,
+    /// The footer to seal the log with, if the log was not sealed already
+    /// and can safely be sealed.
+    unsealed: Option<Footer>,
+    /// Whether the log was written by a compaction.
+    compaction: bool,
+}
+
+impl Loader {
+    /// Loads the log from disk, into memory.
+    ///
+    /// A sealed log is loaded straight from its footer, unless `verify` asks
+    /// for a full check. Any other log is replayed command by command, and
+    /// the footer it can be sealed with is handed back to the caller.
+    fn load(
+        version: u64,
+        reader: &mut KvsReader<LogFile>,
+        index: &mut Index<CommandPosition>,
+        deleted: &mut Index<SoftDeleted>,
+        verify: Verify,
+    ) -> Result<Loaded> {
+        let mut blocks = BlockReader::new(reader)?;
+        let sealed = match blocks.footer(verify != Verify::None)? {
+            Some(block) => Some(serde_json::from_slice::<Footer>(&block.payload)?),
+            None => None,
+        };
+        if verify != Verify::Full {
+            if let Some(footer) = &sealed {
+                return Ok(Loaded {
+                    stale_bytes: Loader::apply(version, footer, index, deleted),
+                    damaged_blocks: 0,
+                    unsealed: None,
+                    compaction: footer.compaction,
+                });
+            }
+        }
+
+        let mut footer = Loader::replay(&mut blocks)?;
+        let damaged_blocks = blocks.damaged();
+        if verify == Verify::Full {
+            if damaged_blocks > 0 {
+                return Err(KvsError::Corruption(format!(
+                    "{} damaged block(s) in log {}",
+                    damaged_blocks, version
+                )));
+            }
+            // Whether a log was written by a compaction is not something its
+            // commands can tell.
+            if let Some(sealed) = &sealed {
+                footer.compaction = sealed.compaction;
+            }
+            if sealed.as_ref().is_some_and(|sealed| *sealed != footer) {
+                return Err(KvsError::Corruption(format!(
+                    "footer of log {} does not match its commands",
+                    version
+                )));
+            }
+        }
+        let stale_bytes = Loader::apply(version, &footer, index, deleted);
+
+        // Sealing a log with damaged blocks would hide the damage from every
+        // later `open`, and there is nothing to seal in an empty log.
+        let sealable = sealed.is_none()
+            && damaged_blocks == 0
+            && !(footer.entries.is_empty()
+                && footer.removed.is_empty()
+                && footer.soft_removed.is_empty());
+        let compaction = footer.compaction;
+        Ok(Loaded {
+            stale_bytes,
+            damaged_blocks,
+            unsealed: if sealable { Some(footer) } else { None },
+            compaction,
+        })
+    }
+
+    /// Replays a log's commands to find the latest command for each key in
+    /// it. Blocks that fail their checksum are skipped rather than treated as
+    /// the end of the log, so a single bad byte only costs the commands in
+    /// the block it landed in.
+    fn replay<R: Read + Seek>(blocks: &mut BlockReader<R>) -> Result<Footer> {
+        let mut latest: HashMap<String, Latest> = HashMap::new();
+        let mut stale_bytes = 0u64;
+        while let Some(block) = blocks.next_block()? {
+            let mut pos = block.start;
+            let mut stream = Deserializer::from_slice(&block.payload).into_iter::<Command>();
+            while let Some(cmd) = stream.next() {
+                // Update the new position to the number of bytes successfully
+                // deserialized into a `Command`.
+                let new_pos = block.start + stream.byte_offset() as u64;
+                match cmd? {
+                    Command::Set { key, expires, .. } => {
+                        let entry = FooterEntry {
+                            key: key.clone(),
+                            pos,
+                            len: new_pos - pos,
+                            expires,
+                            deleted: None,
+                        };
+                        // A `Set` that is overwritten within the same log is
+                        // stale no matter what the other logs hold.
+                        if let Some(Latest::Set(old)) = latest.insert(key, Latest::Set(entry)) {
+                            stale_bytes += old.len;
+                        }
+                    }
+                    Command::Remove {
+                        key,
+                        deleted: Some(deleted),
+                    } => {
+                        // A soft-deleted `Set` stays where it is, and so
+                        // does not become stale.
+                        let soft = match latest.remove(&key) {
+                            Some(Latest::Set(mut entry)) => {
+                                entry.deleted = Some(deleted);
+                                Latest::Set(entry)
+                            }
+                            _ => Latest::SoftRemoved(deleted),
+                        };
+                        latest.insert(key, soft);
+                        stale_bytes += new_pos - pos;
+                    }
+                    Command::Remove { key, .. } => {
+                        if let Some(Latest::Set(old)) = latest.insert(key, Latest::Removed) {
+                            stale_bytes += old.len;
+                        }
+                        // The removal command's length (in bytes) can also be safely
+                        // compacted.
+                        stale_bytes += new_pos - pos;
+                    }
+                }
+                pos = new_pos;
+            }
+        }
+
+        let mut footer = Footer {
+            stale_bytes,
+            ..Footer::default()
+        };
+        for (key, latest) in latest {
+            match latest {
+                Latest::Set(entry) => footer.entries.push(entry),
+                Latest::Removed => footer.removed.push(key),
+                Latest::SoftRemoved(deleted) => {
+                    footer.soft_removed.push(SoftRemoved { key, deleted })
+                }
+            }
+        }
+        footer.entries.sort_unstable_by(|a, b| a.key.cmp(&b.key));
+        footer.removed.sort_unstable();
+        footer
+            .soft_removed
+            .sort_unstable_by(|a, b| a.key.cmp(&b.key));
+        Ok(footer)
+    }
+
+    /// Applies a log's footer to the index and the soft-deleted keys.
+    /// Returns the number of stale bytes the log accounts for.
+    fn apply(
+        version: u64,
+        footer: &Footer,
+        index: &mut Index<CommandPosition>,
+        deleted: &mut Index<SoftDeleted>,
+    ) -> u64 {
+        let mut stale_bytes = footer.stale_bytes;
+        for entry in &footer.entries {
+            // A key that is set again is no longer soft-deleted, and the
+            // `Set` kept for restoring it is stale.
+            if let Some(old) = deleted.remove(&entry.key) {
+                stale_bytes += old.cmd_pos.len;
+            }
+            // If a given key is present in the map, then `insert` is updating
+            // a value that is already present in the map. The old value,
+            // in this case the old `CommandPosition`, is returned.
+            //
+            // This old `CommandPosition`'s length represents a number of stale bytes
+            // that can be compacted.
+            let range = entry.pos..entry.pos + entry.len;
+            let mut cmd_pos: CommandPosition = (version, range).into();
+            cmd_pos.expires = entry.expires;
+            if let Some(deleted_at) = entry.deleted {
+                let soft = SoftDeleted {
+                    cmd_pos,
+                    at: deleted_at,
+                };
+                deleted.insert(entry.key.clone(), soft);
+                if let Some(old_cmd) = index.remove(&entry.key) {
+                    stale_bytes += old_cmd.len;
+                }
+            } else if let Some(old_cmd) = index.insert(entry.key.clone(), cmd_pos) {
+                stale_bytes += old_cmd.len;
+            }
+        }
+        for soft in &footer.soft_removed {
+            if let Some(cmd_pos) = index.remove(&soft.key) {
+                let soft_deleted = SoftDeleted {
+                    cmd_pos,
+                    at: soft.deleted,
+                };
+                deleted.insert(soft.key.clone(), soft_deleted);
+            }
+        }
+        for key in &footer.removed {
+            // If a given key is present in the map, then `remove` will return
+            // the value. In this case, the old `CommandPosition` is returned.
+            //
+            // The removed `CommandPosition`'s length represents a number of
+            // stale bytes that can be compacted.
+            if let Some(old_cmd) = index.remove(key) {
+                stale_bytes += old_cmd.len;
+            }
+            if let Some(old) = deleted.remove(key) {
+                stale_bytes += old.cmd_pos.len;
+            }
+        }
+        stale_bytes
+    }
+}
+
+/// How thoroughly a `KvStore`'s logs are checked when the store is opened.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Verify {
+    /// Trust the footers of sealed logs without checking their checksums.
+    None,
+    /// Check the checksums of sealed logs' footers, and of every block in
+    /// logs that have to be replayed. Damaged blocks are skipped and counted
+    /// by [`KvStore::damaged_blocks`].
+    ///
+    /// [`KvStore::damaged_blocks`]: struct.KvStore.html#method.damaged_blocks
+    #[default]
+    Fast,
+    /// Replay every log, sealed or not, checking the checksum of every block
+    /// and that every footer matches the commands it describes. Any damage
+    /// fails the `open` with [`KvsError::Corruption`].
+    ///
+    /// [`KvsError::Corruption`]: enum.KvsError.html#variant.Corruption
+    Full,
+}
+
+/// What a `KvStore` does about unexpected files in its directory.
+///
+/// A file is unexpected if it is not one of the store's own, but could be
+/// mistaken for one: its name starts with `kvs.`, or contains `.log` (or
+/// `.` followed by whatever extension the store's [`SegmentLayout`] gives
+/// segments) without being a segment's. With the default layout, a stray
+/// `backup.log`, a `01.log` or an editor's `.1.log.swp` are all
+/// unexpected; a `README.md` is not.
+///
+/// [`SegmentLayout`]: struct.SegmentLayout.html
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum UnexpectedFiles {
+    /// Leave unexpected files alone without reporting them.
+    Ignore,
+    /// Leave unexpected files alone, but report them through
+    /// [`KvStore::unexpected_files`].
+    ///
+    /// [`KvStore::unexpected_files`]: struct.KvStore.html#method.unexpected_files
+    #[default]
+    Report,
+    /// Fail the `open` with [`KvsError::UnexpectedFile`].
+    ///
+    /// [`KvsError::UnexpectedFile`]: enum.KvsError.html#variant.UnexpectedFile
+    Error,
+    /// Move unexpected files into the `quarantine` subdirectory of the
+    /// store's directory, and report them, under their new paths, through
+    /// [`KvStore::unexpected_files`].
+    ///
+    /// [`KvStore::unexpected_files`]: struct.KvStore.html#method.unexpected_files
+    Quarantine,
+}
+
+/// Structure describing the various options a given `KvStore` can
+/// exercise.
+#[derive(Debug, Clone, Default)]
+pub struct KvOpts {
+    sync: bool,
+    compaction_direct_io: bool,
+    verify: Verify,
+    unexpected_files: UnexpectedFiles,
+    segment_layout: Option<SegmentLayout>,
+    soft_delete: Option<Duration>,
+    write_once: Vec<String>,
+    cas_hash: CasHash,
+    index_hasher: IndexHasher,
+    compaction_policy: CompactionPolicy,
+    key_codec: Option<Arc<dyn KeyCodec>>,
+    cache_budget: Option<u64>,
+    budget: Option<Budget>,
+    fs: Option<Arc<dyn Fs>>,
+    clock: Option<Arc<dyn Clock>>,
+    watchdog: Watchdog,
+    read_only: bool,
+    publish_stats: Option<Duration>,
+    /// Opens a store in any format, for `KvStore::upgrade` to rewrite.
+    any_format: bool,
+}
+
+impl KvOpts {
+    /// Creates the default set of options.
+    pub fn new() -> KvOpts {
+        KvOpts::default()
+    }
+
+    /// Sets whether writes are fsynced. When enabled, every write to the
+    /// write-ahead log and every write to a data segment is followed by an
+    /// fsync, and so is every change to the store's directory, such as a
+    /// segment being created or removed. Defaults to `false`.
+    pub fn sync(mut self, sync: bool) -> KvOpts {
+        self.sync = sync;
+        self
+    }
+
+    /// Sets whether compaction logs are written with direct I/O, around the
+    /// OS's page cache, so that rewriting a large store does not evict the
+    /// pages foreground reads rely on. Only [`StdFs`] on Linux does direct
+    /// I/O; elsewhere this does nothing. Defaults to `false`.
+    ///
+    /// [`StdFs`]: struct.StdFs.html
+    pub fn compaction_direct_io(mut self, direct: bool) -> KvOpts {
+        self.compaction_direct_io = direct;
+        self
+    }
+
+    /// Sets how thoroughly the store's logs are checked on `open`. Defaults
+    /// to [`Verify::Fast`]; restoring from a backup is a good time for
+    /// [`Verify::Full`].
+    ///
+    /// [`Verify::Fast`]: enum.Verify.html#variant.Fast
+    /// [`Verify::Full`]: enum.Verify.html#variant.Full
+    pub fn verify_on_open(mut self, verify: Verify) -> KvOpts {
+        self.verify = verify;
+        self
+    }
+
+    /// Sets the [`KeyCodec`] every key is normalized with.
+    ///
+    /// The codec is recorded in the store's metadata when the store is
+    /// created, and a store can only ever be opened with the codec it was
+    /// created with. If no codec is set, a new store uses keys exactly as
+    /// given and an existing store uses the codec it recorded, provided that
+    /// codec is one of the built-ins.
+    ///
+    /// [`KeyCodec`]: trait.KeyCodec.html
+    pub fn key_codec<C: KeyCodec + 'static>(mut self, codec: C) -> KvOpts {
+        self.key_codec = Some(Arc::new(codec));
+        self
+    }
+
+    /// Turns the store into a persistent LRU cache that keeps its live bytes
+    /// (see [`Stats::live_bytes`]) under `max_live_bytes`.
+    ///
+    /// Whenever a write takes the store over budget, the least recently used
+    /// keys are removed until it fits again, and counted by
+    /// [`Stats::evictions`]. Recency is tracked in memory only; when the
+    /// store is opened, keys are ranked by when they were last written.
+    ///
+    /// [`Stats::live_bytes`]: struct.Stats.html#structfield.live_bytes
+    /// [`Stats::evictions`]: struct.Stats.html#structfield.evictions
+    pub fn cache(mut self, max_live_bytes: u64) -> KvOpts {
+        self.cache_budget = Some(max_live_bytes);
+        self
+    }
+
+    /// Sets the [`Budget`] the store shares with every other store opened
+    /// with a clone of it. By default, a store keeps every segment open and
+    /// its live bytes count against nothing but [`KvOpts::cache`].
+    ///
+    /// [`Budget`]: struct.Budget.html
+    /// [`KvOpts::cache`]: #method.cache
+    pub fn budget(mut self, budget: Budget) -> KvOpts {
+        self.budget = Some(budget);
+        self
+    }
+
+    /// Sets the [`Fs`] every file of the store is opened through. Defaults
+    /// to [`StdFs`].
+    ///
+    /// [`Fs`]: trait.Fs.html
+    /// [`StdFs`]: struct.StdFs.html
+    pub fn fs<F: Fs + 'static>(mut self, fs: F) -> KvOpts {
+        self.fs = Some(Arc::new(fs));
+        self
+    }
+
+    /// Sets the [`Clock`] key expiry and the store's timestamps are based
+    /// on. Defaults to [`SystemClock`].
+    ///
+    /// [`Clock`]: trait.Clock.html
+    /// [`SystemClock`]: struct.SystemClock.html
+    pub fn clock<C: Clock + 'static>(mut self, clock: C) -> KvOpts {
+        self.clock = Some(Arc::new(clock));
+        self
+    }
+
+    /// Sets what the store does about unexpected files in its directory.
+    /// Defaults to [`UnexpectedFiles::Report`].
+    ///
+    /// [`UnexpectedFiles::Report`]: enum.UnexpectedFiles.html#variant.Report
+    pub fn unexpected_files(mut self, unexpected_files: UnexpectedFiles) -> KvOpts {
+        self.unexpected_files = unexpected_files;
+        self
+    }
+
+    /// Sets how the store's data segments are named and laid out.
+    ///
+    /// Like the key codec, the layout is recorded in the store's metadata
+    /// when the store is created, and a store can only ever be opened with
+    /// the layout it was created with. If no layout is set, a new store uses
+    /// [`SegmentLayout::default`] and an existing store uses the layout it
+    /// recorded.
+    ///
+    /// [`SegmentLayout::default`]: struct.SegmentLayout.html#impl-Default
+    pub fn segment_layout(mut self, layout: SegmentLayout) -> KvOpts {
+        self.segment_layout = Some(layout);
+        self
+    }
+
+    /// Makes removes soft: a removed key can be restored with
+    /// [`KvStore::undelete`] until `window` has passed, and is only deleted
+    /// for good by the first compaction after that. A soft-deleted key
+    /// behaves exactly as if it had been removed otherwise.
+    ///
+    /// The window is recorded in the store's metadata when the store is
+    /// created, and used by every later `open` that does not set a window of
+    /// its own. Keys evicted in cache mode are never restorable.
+    ///
+    /// [`KvStore::undelete`]: struct.KvStore.html#method.undelete
+    pub fn soft_delete(mut self, window: Duration) -> KvOpts {
+        self.soft_delete = Some(window);
+        self
+    }
+
+    /// Makes every key that starts with `prefix` write-once: it can be set
+    /// a single time, and any later write to it, be it a set, an expiry
+    /// change or a remove, fails with [`KvsError::WriteOnce`]. An empty
+    /// prefix makes the whole store write-once, and the option can be given
+    /// more than once. Prefixes are matched against keys after the key
+    /// codec has normalized them.
+    ///
+    /// Like the soft delete window, the prefixes are recorded in the store's
+    /// metadata when the store is created, and every later `open` keeps
+    /// them on top of any prefixes of its own. A write-once key that expires
+    /// can be set again, and keys evicted in cache mode are evicted all the
+    /// same.
+    ///
+    /// [`KvsError::WriteOnce`]: enum.KvsError.html#variant.WriteOnce
+    pub fn write_once(mut self, prefix: &str) -> KvOpts {
+        self.write_once.push(prefix.to_owned());
+        self
+    }
+
+    /// Sets the hash [`KvStore::put_cas`] derives keys from. Defaults to
+    /// [`CasHash::Sha256`]. The hash's name is part of every key, so values
+    /// put with one hash are never mistaken for values put with another.
+    ///
+    /// [`KvStore::put_cas`]: struct.KvStore.html#method.put_cas
+    /// [`CasHash::Sha256`]: enum.CasHash.html#variant.Sha256
+    pub fn cas_hash(mut self, hash: CasHash) -> KvOpts {
+        self.cas_hash = hash;
+        self
+    }
+
+    /// Sets the hasher the in-memory secondary and token indexes hash their
+    /// keys with. Defaults to [`IndexHasher::SipHash`]; a store whose values
+    /// are all trusted builds and queries them faster with
+    /// [`IndexHasher::Fx`]. The indexes are rebuilt on every `open`, so the
+    /// hasher can differ between opens.
+    ///
+    /// [`IndexHasher::SipHash`]: enum.IndexHasher.html#variant.SipHash
+    /// [`IndexHasher::Fx`]: enum.IndexHasher.html#variant.Fx
+    pub fn index_hasher(mut self, hasher: IndexHasher) -> KvOpts {
+        self.index_hasher = hasher;
+        self
+    }
+
+    /// Sets when the store compacts itself after a write. Defaults to
+    /// compacting once more than 512 bytes can be reclaimed; large stores
+    /// are better served by [`CompactionPolicy::Adaptive`].
+    ///
+    /// [`CompactionPolicy::Adaptive`]: enum.CompactionPolicy.html#variant.Adaptive
+    pub fn compaction_policy(mut self, policy: CompactionPolicy) -> KvOpts {
+        self.compaction_policy = policy;
+        self
+    }
+
+    /// Sets how long syncs and compactions may take before they are
+    /// reported as stalls, and where they are reported to. See
+    /// [`Watchdog`] for the defaults.
+    ///
+    /// [`Watchdog`]: struct.Watchdog.html
+    pub fn watchdog(mut self, watchdog: Watchdog) -> KvOpts {
+        self.watchdog = watchdog;
+        self
+    }
+
+    /// Sets whether the store is opened read-only. A read-only store takes
+    /// no lock and writes nothing, so it can be opened while another process
+    /// has the store open, to watch it or read from it. Every write to it
+    /// fails with [`KvsError::ReadOnly`]. Defaults to `false`.
+    ///
+    /// A read-only store is what was in the store's data segments when it
+    /// was opened. Commands that the process holding the lock has only in
+    /// its write-ahead log, up to 64 KiB of them, are not seen until that
+    /// process moves them into a data segment. Reading a value can fail if
+    /// that process compacts the store away from under it.
+    ///
+    /// [`KvsError::ReadOnly`]: enum.KvsError.html#variant.ReadOnly
+    pub fn read_only(mut self, read_only: bool) -> KvOpts {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Publishes how many sets, gets and removes the store has served to its
+    /// `kvs.stats` file, at most once per `every` and when the store is
+    /// dropped, so that other processes can read them from
+    /// [`KvStore::validate`]. The counts carry on from those published
+    /// before. Defaults to not publishing them.
+    ///
+    /// [`KvStore::validate`]: struct.KvStore.html#method.validate
+    pub fn publish_stats(mut self, every: Duration) -> KvOpts {
+        self.publish_stats = Some(every);
+        self
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct CommandPosition {
+    ver: u64,
+    pos: u64,
+    len: u64,
+    /// When the key expires, in milliseconds since the Unix epoch.
+    expires: Option<u64>,
+    /// The sequence number of the `Set`, or 0 for one loaded from disk.
+    seq: u64,
+}
+
+impl CommandPosition {
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires.is_some_and(|expires| expires <= now)
+    }
+}
+
+/// The latest `Set` command of a soft-deleted key, kept until the key is
+/// restored or hard deleted.
+#[derive(Debug)]
+struct SoftDeleted {
+    cmd_pos: CommandPosition,
+    /// When the key was soft-deleted, in milliseconds since the Unix epoch.
+    at: u64,
+}
+
+/// The latest command for a key within a single log.
+enum Latest {
+    Set(FooterEntry),
+    Removed,
+    /// Soft-deleted at the given time, with its `Set` in an earlier log.
+    SoftRemoved(u64),
+}
+
+impl From<(u64, Range<u64>)> for CommandPosition {
+    fn from((ver, range): (u64, Range<u64>)) -> Self {
+        CommandPosition {
+            ver,
+            pos: range.start,
+            len: range.end - range.start,
+            expires: None,
+            seq: 0,
+        }
+    }
+}
+
+/// What [`KvStore::validate`] finds in a store's directory.
+///
+/// [`KvStore::validate`]: struct.KvStore.html#method.validate
+#[derive(Debug, Clone, PartialEq)]
+pub struct StoreInfo {
+    /// The store's metadata, as recorded in its `kvs.meta` file.
+    pub meta: StoreMeta,
+    /// Whether the store is in the format this kvs writes. A store that is
+    /// not has to be rewritten by [`KvStore::upgrade`] before it can be
+    /// opened.
+    ///
+    /// [`KvStore::upgrade`]: struct.KvStore.html#method.upgrade
+    pub current: bool,
+    /// The versions of the store's data segments, oldest first.
+    pub segments: Vec<u64>,
+    /// Whether the store is open, in this process or another one.
+    pub locked: bool,
+    /// The bytes taken up by the store's data segments, write-ahead log and
+    /// metadata, which changes for as long as the store is open.
+    pub size: u64,
+    /// The operations the store has served, as last published by a process
+    /// that opened it with [`KvOpts::publish_stats`], or `None` if none has.
+    ///
+    /// [`KvOpts::publish_stats`]: struct.KvOpts.html#method.publish_stats
+    pub ops: Option<OpCounts>,
+}
+
+/// Statistics about a `KvStore`, as reported by [`KvStore::stats`].
+///
+/// [`KvStore::stats`]: struct.KvStore.html#method.stats
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Stats {
+    /// The number of live keys, including expired keys that have not been
+    /// dropped yet.
+    pub keys: u64,
+    /// The number of bytes taken up by the latest `Set` command of every
+    /// live key.
+    pub live_bytes: u64,
+    /// The number of bytes the next compaction can reclaim.
+    pub stale_bytes: u64,
+    /// The number of keys evicted in cache mode since the store was opened.
+    pub evictions: u64,
+    /// The number of soft-deleted keys that have not been hard deleted by a
+    /// compaction yet, whether or not their window has passed.
+    pub soft_deleted: u64,
+    /// The number of damaged blocks skipped while the store was opened.
+    pub damaged_blocks: u64,
+    /// How many bytes compaction will be able to reclaim in the future.
+    pub reclaim_forecast: ReclaimForecast,
+    /// The I/O the store has done since it was opened.
+    pub io: IoStats,
+}
+
+/// The I/O a store has done since it was opened, as reported by
+/// [`Stats::io`].
+///
+/// Every read and write of the store's files is counted, whether it was
+/// for a get, a write, a compaction, or opening the store itself.
+///
+/// [`Stats::io`]: struct.Stats.html#structfield.io
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct IoStats {
+    /// The bytes of the keys and values written: the key and value of every
+    /// set, and the key of every remove.
+    pub logical_bytes_written: u64,
+    /// The bytes written to the store's files, including the write-ahead
+    /// log, block trailers, footers and compactions.
+    pub bytes_written: u64,
+    /// The bytes read from the store's files.
+    pub bytes_read: u64,
+    /// The number of files and directories synced.
+    pub syncs: u64,
+    /// The number of gets, found or not.
+    pub gets: u64,
+    /// The bytes those gets read from the store's files. Values that are
+    /// still in memory, or in a reader's buffer, cost nothing.
+    pub get_bytes_read: u64,
+    /// The number of sets, including those in batches and those that only
+    /// change a key's expiry.
+    pub sets: u64,
+    /// The number of removes, including those in batches.
+    pub removes: u64,
+    /// The number of syncs that took longer than the store's [`Watchdog`]
+    /// allows.
+    ///
+    /// [`Watchdog`]: struct.Watchdog.html
+    pub slow_syncs: u64,
+    /// The number of compactions that ran for longer than the store's
+    /// [`Watchdog`] allows.
+    ///
+    /// [`Watchdog`]: struct.Watchdog.html
+    pub slow_compactions: u64,
+}
+
+impl IoStats {
+    /// Returns the bytes written to the store's files for every byte of the
+    /// keys and values written, or `None` if nothing was written.
+    pub fn write_amplification(&self) -> Option<f64> {
+        ratio(self.bytes_written, self.logical_bytes_written)
+    }
+
+    /// Returns the bytes read from the store's files per get, or `None` if
+    /// there were no gets.
+    pub fn read_bytes_per_get(&self) -> Option<f64> {
+        ratio(self.get_bytes_read, self.gets)
+    }
+}
+
+fn ratio(numerator: u64, denominator: u64) -> Option<f64> {
+    if denominator == 0 {
+        None
+    } else {
+        Some(numerator as f64 / denominator as f64)
+    }
+}
+
+/// How many bytes compaction will be able to reclaim at a few points in the
+/// future, as reported by [`Stats::reclaim_forecast`].
+///
+/// Every figure counts the stale bytes there are now, the live bytes of the
+/// keys that will have expired by then, and the bytes of the soft-deleted
+/// keys whose window will have passed by then. The forecast assumes nothing
+/// is written, or compacted, in the meantime.
+///
+/// [`Stats::reclaim_forecast`]: struct.Stats.html#structfield.reclaim_forecast
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ReclaimForecast {
+    /// The bytes reclaimable a minute from now.
+    pub in_a_minute: u64,
+    /// The bytes reclaimable an hour from now.
+    pub in_an_hour: u64,
+    /// The bytes reclaimable a day from now.
+    pub in_a_day: u64,
+    /// The bytes reclaimable a week from now.
+    pub in_a_week: u64,
+}
+
+impl ReclaimForecast {
+    /// Counts `bytes` that become reclaimable `after` from now.
+    fn add(&mut self, after: Duration, bytes: u64) {
+        const MINUTE: u64 = 60;
+        let horizons = [
+            (MINUTE, &mut self.in_a_minute),
+            (60 * MINUTE, &mut self.in_an_hour),
+            (24 * 60 * MINUTE, &mut self.in_a_day),
+            (7 * 24 * 60 * MINUTE, &mut self.in_a_week),
+        ];
+        for (secs, reclaimable) in horizons {
+            if after <= Duration::from_secs(secs) {
+                *reclaimable += bytes;
+            }
+        }
+    }
+}
+
+/// How far along a long-running operation, such as
+/// [`KvStore::compact_with_progress`], is.
+///
+/// [`KvStore::compact_with_progress`]: struct.KvStore.html#method.compact_with_progress
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Progress {
+    /// The number of bytes processed so far.
+    pub done: u64,
+    /// The number of bytes to process in all.
+    pub total: u64,
+}
+
+/// The time remaining before a key expires, as reported by
+/// [`KvStore::ttl`].
+///
+/// [`KvStore::ttl`]: struct.KvStore.html#method.ttl
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ttl {
+    /// The key never expires.
+    Persistent,
+    /// The key expires after the given duration.
+    Expires(Duration),
+}
+
+/// An opaque token for the version a key is at, as returned by
+/// [`KvStore::get_versioned`] and taken by [`KvStore::set_if_version`].
+///
+/// Every write to a key moves it to a new version. Versions are only
+/// handed out, and only match, within one open store handle: once the store
+/// is opened again, every version handed out before is stale. A version
+/// displays as, and parses from, a short string, so it can be passed along
+/// wherever an ETag would be.
+///
+/// [`KvStore::get_versioned`]: struct.KvStore.html#method.get_versioned
+/// [`KvStore::set_if_version`]: struct.KvStore.html#method.set_if_version
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Version {
+    incarnation: u64,
+    seq: u64,
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:016x}.{}", self.incarnation, self.seq)
+    }
+}
+
+impl FromStr for Version {
+    type Err = KvsError;
+
+    /// Parses a version from the string it displays as. A string that is
+    /// not a version is a `KvsError::VersionMismatch`, since no key can be
+    /// at it.
+    fn from_str(s: &str) -> Result<Version> {
+        let parsed = s.split_once('.').and_then(|(incarnation, seq)| {
+            Some(Version {
+                incarnation: u64::from_str_radix(incarnation, 16).ok()?,
+                seq: seq.parse().ok()?,
+            })
+        });
+        parsed.ok_or_else(|| KvsError::VersionMismatch(format!("not a version: {}", s)))
+    }
+}
 
 /// Struct representation of a command.
 #[derive(Serialize, Deserialize, Debug)]
 enum Command {
-    Set { key: String, value: String },
-    Remove { key: String },
+    Set {
+        key: String,
+        value: String,
+        /// When the key expires, in milliseconds since the Unix epoch.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        expires: Option<u64>,
+    },
+    Remove {
+        key: String,
+        /// When the key was soft-deleted, in milliseconds since the Unix
+        /// epoch. A soft `Remove` leaves the key's latest `Set` live on disk
+        /// until a compaction after the soft delete window hard deletes it.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        deleted: Option<u64>,
+    },
+}
+
+/// A record in the write-ahead log: a single command, or the commands of a
+/// [`WriteBatch`], which are logged together.
+///
+/// [`WriteBatch`]: struct.WriteBatch.html
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum WalRecord {
+    Command(Command),
+    Batch(Vec<Command>),
+}
+
+/// A `Command` read for its value only, which borrows the value from the
+/// buffer it is read from unless it holds escapes.
+#[derive(Deserialize)]
+enum CommandRef<'a> {
+    Set {
+        #[serde(borrow)]
+        value: Cow<'a, str>,
+    },
+    Remove(IgnoredAny),
 }
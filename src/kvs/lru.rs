@@ -0,0 +1,43 @@
+//! Recency tracking for cache mode.
+//!
+//! In cache mode a `KvStore` keeps its live bytes under a budget by evicting
+//! the keys that were least recently used. [`Lru`] remembers the order in
+//! which keys were last used, so that the least recently used one can be
+//! found without scanning every key.
+//!
+//! [`Lru`]: struct.Lru.html
+use std::collections::{BTreeMap, HashMap};
+
+#[derive(Default)]
+pub(crate) struct Lru {
+    /// The tick at which each key was last used.
+    ticks: HashMap<String, u64>,
+    /// Every key, by the tick at which it was last used.
+    order: BTreeMap<u64, String>,
+    /// The next tick to hand out.
+    next: u64,
+}
+
+impl Lru {
+    /// Marks `key` as the most recently used key.
+    pub(crate) fn touch(&mut self, key: &str) {
+        let tick = self.next;
+        self.next += 1;
+        if let Some(old) = self.ticks.insert(key.to_owned(), tick) {
+            self.order.remove(&old);
+        }
+        self.order.insert(tick, key.to_owned());
+    }
+
+    /// Stops tracking `key`.
+    pub(crate) fn remove(&mut self, key: &str) {
+        if let Some(tick) = self.ticks.remove(key) {
+            self.order.remove(&tick);
+        }
+    }
+
+    /// Returns the least recently used key.
+    pub(crate) fn oldest(&self) -> Option<&str> {
+        self.order.values().next().map(String::as_str)
+    }
+}
@@ -0,0 +1,227 @@
+//! Store metadata.
+//!
+//! Every store directory holds a small `kvs.meta` file that is written once,
+//! when the store is created. It identifies the store and records the
+//! decisions that were made about its on-disk format.
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::clock::Clock;
+use crate::kvio::fs::{Fs, OpenMode};
+use crate::layout::SegmentLayout;
+use crate::util::errors::{KvsError, Result};
+use crate::util::rand::Rng;
+use crate::KvOpts;
+
+/// The name of the metadata file inside of a store's directory.
+pub const META_FILE_NAME: &str = "kvs.meta";
+
+/// The version of the on-disk format written by this crate.
+pub const FORMAT_VERSION: u32 = 1;
+
+/// The name of this storage engine.
+pub const ENGINE: &str = "kvs";
+
+/// The name of the codec commands are serialized with.
+pub const CODEC: &str = "json";
+
+/// Identifying information about a `KvStore`, read from its `kvs.meta` file.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct StoreMeta {
+    /// A randomly generated (version 4) UUID, unique to the store.
+    pub uuid: String,
+    /// When the store was created, in seconds since the Unix epoch.
+    pub created: u64,
+    /// The version of the on-disk format the store was created with.
+    pub format_version: u32,
+    /// The storage engine that created the store.
+    pub engine: String,
+    /// The codec commands are serialized with.
+    pub codec: String,
+    /// The name of the [`KeyCodec`] keys are normalized with.
+    ///
+    /// [`KeyCodec`]: trait.KeyCodec.html
+    #[serde(default = "default_key_codec")]
+    pub key_codec: String,
+    /// How the store's data segments are named and laid out.
+    #[serde(default)]
+    pub segment_layout: SegmentLayout,
+    /// How long, in milliseconds, removed keys can be restored for, if
+    /// removes are soft.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub soft_delete_ms: Option<u64>,
+    /// The prefixes of the keys that can only be set once. An empty prefix
+    /// covers every key.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub write_once: Vec<String>,
+}
+
+/// Stores created before key codecs existed use keys exactly as given.
+fn default_key_codec() -> String {
+    "exact".to_owned()
+}
+
+impl StoreMeta {
+    /// Reads the metadata of the store in `dir`, writing it first if the
+    /// store does not have any yet. The key codec, segment layout, soft
+    /// delete window and write-once prefixes in `opts` are only recorded for
+    /// a store that is being created.
+    pub(crate) fn load_or_create(
+        fs: &dyn Fs,
+        clock: &dyn Clock,
+        dir: &Path,
+        opts: &KvOpts,
+    ) -> Result<StoreMeta> {
+        let path = dir.join(META_FILE_NAME);
+        if fs.exists(&path) {
+            return Ok(serde_json::from_slice(&fs.read(&path)?)?);
+        }
+
+        let meta = StoreMeta {
+            uuid: new_uuid(),
+            created: clock.now().as_secs(),
+            format_version: FORMAT_VERSION,
+            engine: ENGINE.to_owned(),
+            codec: CODEC.to_owned(),
+            key_codec: opts
+                .key_codec
+                .as_ref()
+                .map_or("exact", |codec| codec.name())
+                .to_owned(),
+            segment_layout: opts.segment_layout.clone().unwrap_or_default(),
+            soft_delete_ms: opts.soft_delete.map(|window| window.as_millis() as u64),
+            write_once: opts.write_once.clone(),
+        };
+
+        meta.write(fs, dir, opts.sync)?;
+        Ok(meta)
+    }
+
+    /// Writes the metadata into the store in `dir`, in place of whatever
+    /// metadata it has.
+    pub(crate) fn write(&self, fs: &dyn Fs, dir: &Path, sync: bool) -> Result<()> {
+        // Write to a temporary file first so that a crash can never leave a
+        // half-written metadata file behind.
+        let tmp = dir.join(format!("{}.tmp", META_FILE_NAME));
+        let mut file = fs.open(&tmp, OpenMode::Create)?;
+        serde_json::to_writer_pretty(&mut file, self)?;
+        file.flush()?;
+        if sync {
+            file.sync_data()?;
+        }
+        fs.rename(&tmp, &dir.join(META_FILE_NAME))?;
+        if sync {
+            fs.sync_dir(dir)?;
+        }
+        Ok(())
+    }
+
+    /// Returns whether the store is in the format this crate writes.
+    pub(crate) fn is_current(&self) -> bool {
+        self.format_version == FORMAT_VERSION && self.codec == CODEC
+    }
+
+    /// Checks that the store is in the format this crate writes, so that an
+    /// older store is never read part of the way before failing.
+    pub(crate) fn check_format(&self) -> Result<()> {
+        if self.format_version > FORMAT_VERSION {
+            return Err(KvsError::UnsupportedFormat(format!(
+                "store has format version {}, newer than this kvs's {}; upgrade kvs to open it",
+                self.format_version, FORMAT_VERSION
+            )));
+        }
+        if !self.is_current() {
+            return Err(KvsError::UnsupportedFormat(format!(
+                "store has format version {} with the {} codec, not version {} with the {} codec; rewrite it with `kvs upgrade` first",
+                self.format_version, self.codec, FORMAT_VERSION, CODEC
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Checks that the directory `dir`, if it holds anything, was created by
+/// this engine, before anything is written to it.
+pub(crate) fn check_engine(fs: &dyn Fs, dir: &Path) -> Result<()> {
+    let path = dir.join(META_FILE_NAME);
+    let engine = if fs.exists(&path) {
+        let meta: StoreMeta = serde_json::from_slice(&fs.read(&path)?)?;
+        if meta.engine == ENGINE {
+            return Ok(());
+        }
+        meta.engine
+    } else {
+        match detect_engine(fs, dir) {
+            Some(engine) => engine.to_owned(),
+            None => return Ok(()),
+        }
+    };
+    Err(KvsError::WrongEngine(format!(
+        "directory was created by the {} engine, not {}",
+        engine, ENGINE
+    )))
+}
+
+/// Recognizes the directory of another storage engine by the files that
+/// engine always creates.
+fn detect_engine(fs: &dyn Fs, dir: &Path) -> Option<&'static str> {
+    // sled keeps its configuration in `conf` and its data in `db`.
+    if fs.exists(&dir.join("conf")) && fs.exists(&dir.join("db")) {
+        return Some("sled");
+    }
+    None
+}
+
+/// Collects the directory of every store beneath `dir` into `stores`. A
+/// store's own directory is not searched any further, and neither are
+/// symbolic links or directories that cannot be read.
+pub(crate) fn discover(dir: &Path, stores: &mut Vec<PathBuf>) -> Result<()> {
+    if is_store(dir) {
+        stores.push(dir.to_owned());
+        return Ok(());
+    }
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == io::ErrorKind::PermissionDenied => return Ok(()),
+        Err(err) => return Err(err.into()),
+    };
+    for entry in entries {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            discover(&entry.path(), stores)?;
+        }
+    }
+    Ok(())
+}
+
+/// Returns whether `dir` holds the metadata of a store of this engine.
+fn is_store(dir: &Path) -> bool {
+    fs::read(dir.join(META_FILE_NAME))
+        .ok()
+        .and_then(|buf| serde_json::from_slice::<StoreMeta>(&buf).ok())
+        .is_some_and(|meta| meta.engine == ENGINE)
+}
+
+/// Generates a random (version 4) UUID in its usual hyphenated form.
+fn new_uuid() -> String {
+    let mut rng = Rng::from_entropy();
+    let mut bytes = [0u8; 16];
+    bytes[..8].copy_from_slice(&rng.next_u64().to_be_bytes());
+    bytes[8..].copy_from_slice(&rng.next_u64().to_be_bytes());
+    // Set the version (4) and the variant (RFC 4122).
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+
+    let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[0..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..32]
+    )
+}
@@ -0,0 +1,116 @@
+//! Operation counts that other processes can read.
+//!
+//! A store opened with [`KvOpts::publish_stats`] keeps how many sets, gets
+//! and removes it has served in its `kvs.stats` file, so that a process that
+//! does not have the store open, such as `kvs top`, can tell how busy it is
+//! from [`KvStore::validate`]. The counts carry on from one open to the next.
+//!
+//! [`KvOpts::publish_stats`]: ../struct.KvOpts.html#method.publish_stats
+//! [`KvStore::validate`]: ../struct.KvStore.html#method.validate
+use std::io::{self, Write};
+use std::ops::Add;
+use std::path::Path;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::kvio::fs::{Fs, OpenMode};
+use crate::util::errors::Result;
+
+/// The name of the operation counts file inside of a store's directory.
+pub const STATS_FILE_NAME: &str = "kvs.stats";
+
+/// The operations a store has served, as published in its `kvs.stats` file.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct OpCounts {
+    /// The number of sets, including those in batches and those that only
+    /// change a key's expiry.
+    pub sets: u64,
+    /// The number of gets, found or not.
+    pub gets: u64,
+    /// The number of removes, including those in batches.
+    pub removes: u64,
+}
+
+impl OpCounts {
+    /// Reads the counts published in `dir`, or `None` if there are none. A
+    /// file that cannot be parsed was not written by a store, and counts as
+    /// none.
+    pub(crate) fn load(fs: &dyn Fs, dir: &Path) -> Result<Option<OpCounts>> {
+        match fs.read(&dir.join(STATS_FILE_NAME)) {
+            Ok(buf) => Ok(serde_json::from_slice(&buf).ok()),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Writes the counts into the store in `dir`, in place of whatever
+    /// counts it has.
+    fn write(&self, fs: &dyn Fs, dir: &Path) -> Result<()> {
+        // A reader must never see a half-written file. The counts are not
+        // worth an fsync, though.
+        let tmp = dir.join(format!("{}.tmp", STATS_FILE_NAME));
+        let mut file = fs.open(&tmp, OpenMode::Create)?;
+        serde_json::to_writer(&mut file, self)?;
+        file.flush()?;
+        fs.rename(&tmp, &dir.join(STATS_FILE_NAME))?;
+        Ok(())
+    }
+}
+
+impl Add for OpCounts {
+    type Output = OpCounts;
+
+    fn add(self, other: OpCounts) -> OpCounts {
+        OpCounts {
+            sets: self.sets + other.sets,
+            gets: self.gets + other.gets,
+            removes: self.removes + other.removes,
+        }
+    }
+}
+
+/// Publishes a store's counts, no more often than it was asked to.
+#[derive(Debug)]
+pub(crate) struct Publisher {
+    every: Duration,
+    /// The counts published by earlier opens of the store.
+    base: OpCounts,
+    /// When the counts were last published, in milliseconds since the Unix
+    /// epoch.
+    last: Option<u64>,
+}
+
+impl Publisher {
+    /// Creates a publisher that carries on from the counts published in
+    /// `dir`.
+    pub(crate) fn new(fs: &dyn Fs, dir: &Path, every: Duration) -> Result<Publisher> {
+        Ok(Publisher {
+            every,
+            base: OpCounts::load(fs, dir)?.unwrap_or_default(),
+            last: None,
+        })
+    }
+
+    /// Publishes `counts`, this open's counts, if the interval has passed
+    /// since they were last published, or if `force` is set.
+    ///
+    /// This is best effort: the counts are only there to be watched, and a
+    /// write must not fail because they could not be published.
+    pub(crate) fn publish(
+        &mut self,
+        fs: &dyn Fs,
+        dir: &Path,
+        now: u64,
+        counts: OpCounts,
+        force: bool,
+    ) {
+        let due = self
+            .last
+            .is_none_or(|last| now.saturating_sub(last) >= self.every.as_millis() as u64);
+        if due || force {
+            self.last = Some(now);
+            let _ = (self.base + counts).write(fs, dir);
+        }
+    }
+}
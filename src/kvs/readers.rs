@@ -0,0 +1,143 @@
+//! The readers of a store's data segments.
+//!
+//! [`Readers`] knows every segment of a store, but only opens a segment for
+//! reading when it is first read, and closes the least recently read ones
+//! again whenever the store's [`Budget`] has no open files to spare.
+//!
+//! [`Readers`]: struct.Readers.html
+//! [`Budget`]: ../struct.Budget.html
+use std::collections::{BTreeSet, HashMap};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use crate::budget::Budget;
+use crate::kvio::fs::{Fs, OpenMode};
+use crate::kvio::reader::KvsReader;
+use crate::layout::SegmentLayout;
+use crate::util::errors::{KvsError, Result};
+use crate::LogFile;
+
+pub(crate) struct Readers {
+    fs: Arc<dyn Fs>,
+    layout: SegmentLayout,
+    dir: PathBuf,
+    budget: Option<Budget>,
+    /// The version of every segment, open or not.
+    versions: BTreeSet<u64>,
+    /// The open readers, along with the tick at which each was last read.
+    open: HashMap<u64, (KvsReader<LogFile>, u64)>,
+    /// The next tick to hand out.
+    next: u64,
+}
+
+impl Readers {
+    pub(crate) fn new(
+        fs: Arc<dyn Fs>,
+        layout: SegmentLayout,
+        dir: PathBuf,
+        budget: Option<Budget>,
+    ) -> Readers {
+        Readers {
+            fs,
+            layout,
+            dir,
+            budget,
+            versions: BTreeSet::new(),
+            open: HashMap::new(),
+            next: 0,
+        }
+    }
+
+    /// Adds the segment with the given version, without opening it.
+    pub(crate) fn add(&mut self, version: u64) {
+        self.versions.insert(version);
+    }
+
+    /// Returns whether there are any segments.
+    pub(crate) fn is_empty(&self) -> bool {
+        self.versions.is_empty()
+    }
+
+    /// Returns the version of every segment, oldest first.
+    pub(crate) fn versions(&self) -> impl Iterator<Item = u64> + '_ {
+        self.versions.iter().copied()
+    }
+
+    /// Returns the reader of the segment with the given version, opening
+    /// the segment if need be.
+    pub(crate) fn get(&mut self, version: u64) -> Result<&mut KvsReader<LogFile>> {
+        let tick = self.next;
+        self.next += 1;
+        if !self.open.contains_key(&version) {
+            self.make_room();
+            let path = self.layout.path(&self.dir, version);
+            let opened = self
+                .fs
+                .open(&path, OpenMode::Read)
+                .map_err(KvsError::from)
+                .and_then(KvsReader::new);
+            let reader = match opened {
+                Ok(reader) => reader,
+                Err(err) => {
+                    // The file never took up the room made for it.
+                    if let Some(budget) = &self.budget {
+                        budget.close_file();
+                    }
+                    return Err(err);
+                }
+            };
+            self.versions.insert(version);
+            self.open.insert(version, (reader, tick));
+        }
+        let (reader, last_read) = self.open.get_mut(&version).expect("reader was just opened");
+        *last_read = tick;
+        Ok(reader)
+    }
+
+    /// Forgets the segment with the given version, closing it if it is
+    /// open.
+    pub(crate) fn remove(&mut self, version: u64) {
+        self.versions.remove(&version);
+        if self.open.remove(&version).is_some() {
+            if let Some(budget) = &self.budget {
+                budget.close_file();
+            }
+        }
+    }
+
+    /// Takes up an open file from the budget, closing the least recently
+    /// read segments until the budget allows it or none are left open.
+    fn make_room(&mut self) {
+        let budget = match &self.budget {
+            Some(budget) => budget,
+            None => return,
+        };
+        while !budget.try_open_file() {
+            let oldest = self
+                .open
+                .iter()
+                .min_by_key(|(_, (_, last_read))| *last_read)
+                .map(|(version, _)| *version);
+            match oldest {
+                Some(oldest) => {
+                    self.open.remove(&oldest);
+                    budget.close_file();
+                }
+                None => {
+                    budget.force_open_file();
+                    return;
+                }
+            }
+        }
+    }
+}
+
+impl Drop for Readers {
+    fn drop(&mut self) {
+        if let Some(budget) = &self.budget {
+            for _ in self.open.drain() {
+                budget.close_file();
+            }
+        }
+    }
+}
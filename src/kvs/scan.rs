@@ -0,0 +1,316 @@
+//! Ordered, bounded scans.
+//!
+//! [`KvStore::scan_with`] reads the live keys picked out by a [`ScanOpts`],
+//! in either order, stopping after a limit. Only the values of the keys that
+//! are returned are read. [`KvStore::scan_iter`] reads them a batch at a
+//! time, and bounds both the keys and the bytes in a batch, so however many
+//! or however large the values, a scan only holds a batch's worth.
+//!
+//! [`KvStore::keys`] walks the live keys without copying them.
+//!
+//! [`KvStore::scan_with`]: ../struct.KvStore.html#method.scan_with
+//! [`KvStore::scan_iter`]: ../struct.KvStore.html#method.scan_iter
+//! [`KvStore::keys`]: ../struct.KvStore.html#method.keys
+//! [`ScanOpts`]: struct.ScanOpts.html
+use std::collections::{btree_map, VecDeque};
+use std::ops::{Bound, RangeBounds};
+use std::vec;
+
+use crate::util::errors::Result;
+use crate::{CommandPosition, KvStore};
+
+/// The number of keys whose values a [`Scan`] reads at a time.
+///
+/// [`Scan`]: struct.Scan.html
+const SCAN_BATCH: usize = 256;
+
+/// The bytes of values a [`Scan`] reads at a time, unless told otherwise.
+///
+/// [`Scan`]: struct.Scan.html
+const SCAN_BATCH_BYTES: u64 = 4 << 20;
+
+/// Which keys [`KvStore::scan_with`] returns, and in which order.
+///
+/// Every bound is normalized like any other key, and keys are compared byte
+/// by byte.
+///
+/// ```rust
+/// # use kvs::{KvStore, Result, ScanOpts};
+/// # fn main() -> Result<()> {
+/// # let dir = tempfile::TempDir::new()?;
+/// let mut store = KvStore::open(dir.path())?;
+/// for day in 1..=9 {
+///     store.set(format!("log:2024-01-0{}", day), format!("day {}", day))?;
+/// }
+/// // The latest three entries, newest first.
+/// let latest = store.scan_with(ScanOpts::new().prefix("log:").reverse(true).limit(3))?;
+/// assert_eq!(latest[0].0, "log:2024-01-09");
+/// assert_eq!(latest.len(), 3);
+/// # Ok(())
+/// # }
+/// ```
+///
+/// [`KvStore::scan_with`]: struct.KvStore.html#method.scan_with
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScanOpts {
+    pub(crate) prefix: String,
+    pub(crate) from: Bound<String>,
+    pub(crate) to: Bound<String>,
+    pub(crate) limit: Option<usize>,
+    pub(crate) reverse: bool,
+    pub(crate) batch_bytes: Option<u64>,
+}
+
+impl ScanOpts {
+    /// Scans every key, in order.
+    pub fn new() -> ScanOpts {
+        ScanOpts {
+            prefix: String::new(),
+            from: Bound::Unbounded,
+            to: Bound::Unbounded,
+            limit: None,
+            reverse: false,
+            batch_bytes: None,
+        }
+    }
+
+    /// Only scans keys starting with `prefix`.
+    pub fn prefix<S: Into<String>>(mut self, prefix: S) -> ScanOpts {
+        self.prefix = prefix.into();
+        self
+    }
+
+    /// Only scans keys at or after `from`.
+    pub fn from<S: Into<String>>(mut self, from: S) -> ScanOpts {
+        self.from = Bound::Included(from.into());
+        self
+    }
+
+    /// Only scans keys before `to`.
+    pub fn to<S: Into<String>>(mut self, to: S) -> ScanOpts {
+        self.to = Bound::Excluded(to.into());
+        self
+    }
+
+    /// Only scans keys within `range`, such as `"a".."n"` or `"a"..="m"`,
+    /// in place of any bounds set with [`from`] and [`to`].
+    ///
+    /// [`from`]: #method.from
+    /// [`to`]: #method.to
+    pub fn range<'a, R: RangeBounds<&'a str>>(mut self, range: R) -> ScanOpts {
+        self.from = range.start_bound().map(|s| s.to_string());
+        self.to = range.end_bound().map(|s| s.to_string());
+        self
+    }
+
+    /// Stops after `limit` keys.
+    pub fn limit(mut self, limit: usize) -> ScanOpts {
+        self.limit = Some(limit);
+        self
+    }
+
+    /// Scans from the largest key down, rather than from the smallest up. A
+    /// limit then keeps the largest keys.
+    pub fn reverse(mut self, reverse: bool) -> ScanOpts {
+        self.reverse = reverse;
+        self
+    }
+
+    /// Bounds the bytes of values [`KvStore::scan_iter`] holds in memory at
+    /// a time, which defaults to 4 MiB. A value larger than the bound is
+    /// still read, on its own. Scans that collect every value, such as
+    /// [`KvStore::scan_with`], hold them all regardless.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `bytes` is 0.
+    ///
+    /// [`KvStore::scan_iter`]: struct.KvStore.html#method.scan_iter
+    /// [`KvStore::scan_with`]: struct.KvStore.html#method.scan_with
+    pub fn batch_bytes(mut self, bytes: u64) -> ScanOpts {
+        assert!(bytes > 0, "batch_bytes is 0");
+        self.batch_bytes = Some(bytes);
+        self
+    }
+
+    /// Returns the options with every bound passed through `normalize`.
+    pub(crate) fn normalize<F: Fn(String) -> String>(self, normalize: F) -> ScanOpts {
+        ScanOpts {
+            prefix: normalize(self.prefix),
+            from: self.from.map(&normalize),
+            to: self.to.map(&normalize),
+            ..self
+        }
+    }
+
+    /// Returns the range of the index that holds every key within the
+    /// bounds, or `None` if no key can be. Not every key in the range need
+    /// be within the bounds, so each is still checked with [`contains`].
+    ///
+    /// [`contains`]: #method.contains
+    pub(crate) fn key_range(&self) -> Option<(Bound<String>, Bound<String>)> {
+        // A key starting with the prefix is at least the prefix, and less
+        // than the prefix's successor.
+        let at_prefix = Bound::Included(self.prefix.clone());
+        let from = match &self.from {
+            Bound::Included(from) | Bound::Excluded(from) if *from >= self.prefix => {
+                self.from.clone()
+            }
+            _ => at_prefix,
+        };
+        let to = match (&self.to, prefix_end(&self.prefix)) {
+            (Bound::Included(to) | Bound::Excluded(to), Some(end)) if *to > end => {
+                Bound::Excluded(end)
+            }
+            (Bound::Unbounded, Some(end)) => Bound::Excluded(end),
+            _ => self.to.clone(),
+        };
+        // `BTreeMap::range` panics on a range that ends before it starts.
+        let empty = match (&from, &to) {
+            (Bound::Included(from), Bound::Included(to)) => from > to,
+            (
+                Bound::Included(from) | Bound::Excluded(from),
+                Bound::Included(to) | Bound::Excluded(to),
+            ) => from >= to,
+            _ => false,
+        };
+        if empty {
+            None
+        } else {
+            Some((from, to))
+        }
+    }
+
+    /// Returns whether `key` is within the bounds.
+    pub(crate) fn contains(&self, key: &str) -> bool {
+        key.starts_with(self.prefix.as_str())
+            && match &self.from {
+                Bound::Included(from) => key >= from.as_str(),
+                Bound::Excluded(from) => key > from.as_str(),
+                Bound::Unbounded => true,
+            }
+            && match &self.to {
+                Bound::Included(to) => key <= to.as_str(),
+                Bound::Excluded(to) => key < to.as_str(),
+                Bound::Unbounded => true,
+            }
+    }
+}
+
+/// Returns the smallest string greater than every string starting with
+/// `prefix`, or `None` if there is none, as for an empty prefix.
+fn prefix_end(prefix: &str) -> Option<String> {
+    let mut end: Vec<char> = prefix.chars().collect();
+    // Strings compare by code point, so bumping the last character that can
+    // be bumped, and dropping those after it, gives the successor.
+    while let Some(last) = end.pop() {
+        let next = (last as u32 + 1..=char::MAX as u32).find_map(char::from_u32);
+        if let Some(next) = next {
+            end.push(next);
+            return Some(end.into_iter().collect());
+        }
+    }
+    None
+}
+
+impl Default for ScanOpts {
+    fn default() -> ScanOpts {
+        ScanOpts::new()
+    }
+}
+
+/// An iterator over the live keys picked out by a [`ScanOpts`], along with
+/// their values. Returned by [`KvStore::scan_iter`].
+///
+/// [`ScanOpts`]: struct.ScanOpts.html
+/// [`KvStore::scan_iter`]: struct.KvStore.html#method.scan_iter
+pub struct Scan<'a> {
+    store: &'a mut KvStore,
+    keys: vec::IntoIter<String>,
+    /// The keys of the current batch, in order, along with their values.
+    batch: VecDeque<(String, Result<Option<String>>)>,
+    /// The most bytes of commands read into a batch.
+    batch_bytes: u64,
+}
+
+impl<'a> Scan<'a> {
+    pub(crate) fn new(
+        store: &'a mut KvStore,
+        keys: Vec<String>,
+        batch_bytes: Option<u64>,
+    ) -> Scan<'a> {
+        Scan {
+            store,
+            keys: keys.into_iter(),
+            batch: VecDeque::new(),
+            batch_bytes: batch_bytes.unwrap_or(SCAN_BATCH_BYTES),
+        }
+    }
+
+    /// Returns the number of keys in the next batch: as many as fit within
+    /// the bounds, but at least one.
+    fn next_batch_len(&self) -> usize {
+        let mut bytes = 0;
+        let fits = self
+            .keys
+            .as_slice()
+            .iter()
+            .take(SCAN_BATCH)
+            .position(|key| {
+                bytes += self.store.command_len(key);
+                bytes > self.batch_bytes
+            });
+        fits.unwrap_or(SCAN_BATCH).max(1)
+    }
+}
+
+impl Iterator for Scan<'_> {
+    type Item = Result<(String, String)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if self.batch.is_empty() {
+                let len = self.next_batch_len();
+                let keys: Vec<String> = self.keys.by_ref().take(len).collect();
+                if keys.is_empty() {
+                    return None;
+                }
+                let values = self.store.read_values(&keys);
+                self.batch = keys.into_iter().zip(values).collect();
+            }
+            match self.batch.pop_front().expect("batch is not empty") {
+                (key, Ok(Some(value))) => return Some(Ok((key, value))),
+                (_, Ok(None)) => continue,
+                (_, Err(err)) => return Some(Err(err)),
+            }
+        }
+    }
+}
+
+/// An iterator over the live keys of a store, in key order. Returned by
+/// [`KvStore::keys`].
+///
+/// [`KvStore::keys`]: struct.KvStore.html#method.keys
+pub struct Keys<'a> {
+    keys: btree_map::Keys<'a, String, CommandPosition>,
+}
+
+impl<'a> Keys<'a> {
+    pub(crate) fn new(keys: btree_map::Keys<'a, String, CommandPosition>) -> Keys<'a> {
+        Keys { keys }
+    }
+}
+
+impl<'a> Iterator for Keys<'a> {
+    type Item = &'a str;
+
+    fn next(&mut self) -> Option<&'a str> {
+        self.keys.next().map(String::as_str)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.keys.size_hint()
+    }
+}
+
+impl ExactSizeIterator for Keys<'_> {}
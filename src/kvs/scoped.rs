@@ -0,0 +1,149 @@
+//! Keeping tenants apart within one store.
+use std::time::Duration;
+
+use crate::util::errors::Result;
+use crate::{KvStore, Ttl};
+
+/// A view of the keys of a store that start with a prefix, returned by
+/// [`KvStore::scoped`].
+///
+/// Every key handed to a scoped store has the prefix put in front of it, and
+/// every key it returns has the prefix taken off again, so code given a
+/// scoped store never sees, or touches, a key outside of its scope.
+///
+/// ```rust
+/// # use kvs::{KvStore, Result};
+/// # fn main() -> Result<()> {
+/// # let dir = tempfile::TempDir::new()?;
+/// let mut store = KvStore::open(dir.path())?;
+/// store.scoped("tenant42:").set("name".to_owned(), "acme".to_owned())?;
+/// assert_eq!(store.get("tenant42:name".to_owned())?, Some("acme".to_owned()));
+///
+/// let mut other = store.scoped("tenant7:");
+/// assert_eq!(other.get("name".to_owned())?, None);
+/// # Ok(())
+/// # }
+/// ```
+///
+/// [`KvStore::scoped`]: struct.KvStore.html#method.scoped
+pub struct ScopedStore<'a> {
+    store: &'a mut KvStore,
+    prefix: String,
+    /// The prefix as it appears at the start of the store's normalized keys.
+    normalized: String,
+}
+
+impl<'a> ScopedStore<'a> {
+    pub(crate) fn new(store: &'a mut KvStore, prefix: &str) -> ScopedStore<'a> {
+        let normalized = store.key_codec.normalize(prefix.to_owned());
+        ScopedStore {
+            store,
+            prefix: prefix.to_owned(),
+            normalized,
+        }
+    }
+
+    /// Returns the prefix of every key in scope.
+    pub fn prefix(&self) -> &str {
+        &self.prefix
+    }
+
+    fn key(&self, key: &str) -> String {
+        format!("{}{}", self.prefix, key)
+    }
+
+    fn strip(&self, key: String) -> String {
+        key[self.normalized.len()..].to_owned()
+    }
+
+    /// Gets the value of a key in scope, as [`KvStore::get`] does.
+    ///
+    /// [`KvStore::get`]: struct.KvStore.html#method.get
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        let key = self.key(&key);
+        self.store.get(key)
+    }
+
+    /// Sets a key in scope, as [`KvStore::set`] does.
+    ///
+    /// [`KvStore::set`]: struct.KvStore.html#method.set
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        let key = self.key(&key);
+        self.store.set(key, value)
+    }
+
+    /// Sets a key in scope that expires, as [`KvStore::set_with_ttl`] does.
+    ///
+    /// [`KvStore::set_with_ttl`]: struct.KvStore.html#method.set_with_ttl
+    pub fn set_with_ttl(&mut self, key: String, value: String, ttl: Duration) -> Result<()> {
+        let key = self.key(&key);
+        self.store.set_with_ttl(key, value, ttl)
+    }
+
+    /// Returns the time to live of a key in scope, as [`KvStore::ttl`] does.
+    ///
+    /// [`KvStore::ttl`]: struct.KvStore.html#method.ttl
+    pub fn ttl(&mut self, key: String) -> Result<Option<Ttl>> {
+        let key = self.key(&key);
+        self.store.ttl(key)
+    }
+
+    /// Removes a key in scope, as [`KvStore::remove`] does.
+    ///
+    /// [`KvStore::remove`]: struct.KvStore.html#method.remove
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        let key = self.key(&key);
+        self.store.remove(key)
+    }
+
+    /// Removes a key in scope if it exists, as
+    /// [`KvStore::remove_if_exists`] does.
+    ///
+    /// [`KvStore::remove_if_exists`]: struct.KvStore.html#method.remove_if_exists
+    pub fn remove_if_exists(&mut self, key: String) -> Result<bool> {
+        let key = self.key(&key);
+        self.store.remove_if_exists(key)
+    }
+
+    /// Returns, in key order, every live key in scope that starts with
+    /// `prefix`, along with its value, without the scope's prefix.
+    ///
+    /// # Errors
+    ///
+    /// Errors if reading any of the values does.
+    pub fn scan(&mut self, prefix: &str) -> Result<Vec<(String, String)>> {
+        let pairs = self.store.scan(&self.key(prefix))?;
+        Ok(pairs
+            .into_iter()
+            .map(|(key, value)| (self.strip(key), value))
+            .collect())
+    }
+
+    /// Returns every live key in scope, in key order, without the scope's
+    /// prefix.
+    pub fn keys(&mut self) -> Vec<String> {
+        let keys = self.store.sorted_keys(&self.normalized);
+        keys.into_iter().map(|key| self.strip(key)).collect()
+    }
+
+    /// Removes every key in scope, and returns how many there were. Keys
+    /// are removed one at a time, as [`KvStore::remove`] would, so write-once
+    /// keys stop the removal and soft deletes can be undone.
+    ///
+    /// # Errors
+    ///
+    /// Errors if removing any of the keys does. The keys removed before it
+    /// stay removed.
+    ///
+    /// [`KvStore::remove`]: struct.KvStore.html#method.remove
+    pub fn clear(&mut self) -> Result<u64> {
+        let keys = self.store.sorted_keys(&self.normalized);
+        let mut removed = 0;
+        for key in keys {
+            if self.store.remove_if_exists(key)? {
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+}
@@ -0,0 +1,100 @@
+//! Secondary indexes over values.
+//!
+//! A secondary index maps the index keys an [`Extractor`] pulls out of each
+//! value back to the primary keys holding those values. Secondary indexes
+//! live in memory only; they are built when registered and kept up to date
+//! by every write that follows.
+//!
+//! A token index is a secondary index whose extractor is a [`Tokenizer`],
+//! which makes simple word searches over values possible.
+//!
+//! [`Extractor`]: type.Extractor.html
+//! [`Tokenizer`]: type.Tokenizer.html
+use std::collections::{BTreeSet, HashMap};
+
+use crate::hasher::IndexState;
+use crate::IndexHasher;
+
+/// A key within a secondary index.
+pub type IndexKey = String;
+
+/// Pulls the index keys a value should be filed under out of the value.
+pub type Extractor = fn(&str) -> Vec<IndexKey>;
+
+/// Splits a value into the tokens a token index files it under.
+pub type Tokenizer = fn(&str) -> Vec<String>;
+
+/// The default [`Tokenizer`]: splits on anything that is not alphanumeric
+/// and lowercases what is left.
+///
+/// [`Tokenizer`]: type.Tokenizer.html
+pub fn tokenize(value: &str) -> Vec<String> {
+    value
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|token| !token.is_empty())
+        .map(str::to_lowercase)
+        .collect()
+}
+
+pub(crate) struct SecondaryIndex {
+    extractor: Extractor,
+    /// Maps each index key to the primary keys filed under it.
+    entries: HashMap<IndexKey, BTreeSet<String>, IndexState>,
+    /// Maps each primary key to the index keys it is filed under, so that a
+    /// key can be unfiled without reading its old value back from disk.
+    filed: HashMap<String, Vec<IndexKey>, IndexState>,
+}
+
+impl SecondaryIndex {
+    pub(crate) fn new(extractor: Extractor, hasher: IndexHasher) -> SecondaryIndex {
+        let state = IndexState::new(hasher);
+        SecondaryIndex {
+            extractor,
+            entries: HashMap::with_hasher(state.clone()),
+            filed: HashMap::with_hasher(state),
+        }
+    }
+
+    /// Returns the index keys `value` would be filed under.
+    pub(crate) fn extract(&self, value: &str) -> Vec<IndexKey> {
+        (self.extractor)(value)
+    }
+
+    /// Files `key` under the index keys extracted from `value`, replacing
+    /// wherever it was filed before.
+    pub(crate) fn insert(&mut self, key: &str, value: &str) {
+        self.remove(key);
+        let mut index_keys = self.extract(value);
+        index_keys.sort_unstable();
+        index_keys.dedup();
+        for index_key in &index_keys {
+            self.entries
+                .entry(index_key.clone())
+                .or_default()
+                .insert(key.to_owned());
+        }
+        if !index_keys.is_empty() {
+            self.filed.insert(key.to_owned(), index_keys);
+        }
+    }
+
+    /// Unfiles `key` from every index key it is filed under.
+    pub(crate) fn remove(&mut self, key: &str) {
+        for index_key in self.filed.remove(key).unwrap_or_default() {
+            if let Some(keys) = self.entries.get_mut(&index_key) {
+                keys.remove(key);
+                if keys.is_empty() {
+                    self.entries.remove(&index_key);
+                }
+            }
+        }
+    }
+
+    /// Returns the primary keys filed under `index_key`, in order.
+    pub(crate) fn query(&self, index_key: &str) -> Vec<String> {
+        self.entries
+            .get(index_key)
+            .map(|keys| keys.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+}
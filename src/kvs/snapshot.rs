@@ -0,0 +1,153 @@
+//! Frozen, read-only views of a store.
+use std::collections::HashMap;
+use std::io::{Read, Seek, SeekFrom};
+use std::ops::Bound;
+use std::sync::Arc;
+
+use crate::key_codec::KeyCodec;
+use crate::kvio::reader::KvsReader;
+use crate::util::errors::{KvsError, Result};
+use crate::{CommandPosition, CommandRef, Index, LogFile};
+
+/// The live keys of a store, and their values, as they were when
+/// [`KvStore::snapshot`] was called.
+///
+/// A snapshot is its own handle: it holds its own copy of the index, and its
+/// own open files, so the store can go on being written to, and compacted,
+/// while the snapshot is read. A snapshot is `Send`, so a background thread
+/// can export or analyze it while the store's owner keeps writing.
+///
+/// Keys that expire after the snapshot was taken stay in it. The files a
+/// snapshot holds open are not counted against the store's [`Budget`].
+///
+/// ```rust
+/// # use kvs::{KvStore, Result};
+/// # use std::thread;
+/// # fn main() -> Result<()> {
+/// # let dir = tempfile::TempDir::new()?;
+/// let mut store = KvStore::open(dir.path())?;
+/// store.set("key".to_owned(), "before".to_owned())?;
+/// let mut snapshot = store.snapshot()?;
+/// store.set("key".to_owned(), "after".to_owned())?;
+///
+/// let exported = thread::spawn(move || snapshot.scan("")).join().unwrap()?;
+/// assert_eq!(exported, vec![("key".to_owned(), "before".to_owned())]);
+/// # Ok(())
+/// # }
+/// ```
+///
+/// [`KvStore::snapshot`]: struct.KvStore.html#method.snapshot
+/// [`Budget`]: struct.Budget.html
+pub struct Snapshot {
+    index: Index<CommandPosition>,
+    readers: HashMap<u64, KvsReader<LogFile>>,
+    /// The commands that had not reached the active data segment, which
+    /// start at `pending_pos` in the segment with the version `pending_ver`.
+    pending: Vec<u8>,
+    pending_ver: u64,
+    pending_pos: u64,
+    key_codec: Arc<dyn KeyCodec>,
+    /// The buffer every command is read into.
+    buf: Vec<u8>,
+}
+
+impl Snapshot {
+    pub(crate) fn new(
+        index: Index<CommandPosition>,
+        readers: HashMap<u64, KvsReader<LogFile>>,
+        pending: (Vec<u8>, u64, u64),
+        key_codec: Arc<dyn KeyCodec>,
+    ) -> Snapshot {
+        let (pending, pending_ver, pending_pos) = pending;
+        Snapshot {
+            index,
+            readers,
+            pending,
+            pending_ver,
+            pending_pos,
+            key_codec,
+            buf: Vec::new(),
+        }
+    }
+
+    /// Returns the number of keys in the snapshot.
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    /// Returns whether the snapshot has no keys.
+    pub fn is_empty(&self) -> bool {
+        self.index.is_empty()
+    }
+
+    /// Gets the value a key had when the snapshot was taken.
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        let key = self.key_codec.normalize(key);
+        match self.index.get(&key) {
+            Some(&cmd_pos) => self.read_at(&key, cmd_pos).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns, in key order, every key in the snapshot that starts with
+    /// `prefix`. The prefix is normalized like any other key.
+    pub fn keys(&self, prefix: &str) -> Vec<String> {
+        let prefix = self.key_codec.normalize(prefix.to_owned());
+        self.index
+            .range::<str, _>((Bound::Included(prefix.as_str()), Bound::Unbounded))
+            .map(|(key, _)| key)
+            .take_while(|key| key.starts_with(&prefix))
+            .cloned()
+            .collect()
+    }
+
+    /// Returns, in key order, every key in the snapshot that starts with
+    /// `prefix`, along with its value.
+    ///
+    /// # Errors
+    ///
+    /// Errors if reading any of the values does.
+    pub fn scan(&mut self, prefix: &str) -> Result<Vec<(String, String)>> {
+        let mut keys: Vec<(String, CommandPosition)> = self
+            .keys(prefix)
+            .into_iter()
+            .map(|key| {
+                let cmd_pos = self.index[&key];
+                (key, cmd_pos)
+            })
+            .collect();
+        // Reading in the order the values sit in the segments keeps the
+        // reads sequential.
+        keys.sort_by_key(|(_, cmd_pos)| (cmd_pos.ver, cmd_pos.pos));
+        let mut pairs = Vec::with_capacity(keys.len());
+        for (key, cmd_pos) in keys {
+            let value = self.read_at(&key, cmd_pos)?;
+            pairs.push((key, value));
+        }
+        pairs.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+        Ok(pairs)
+    }
+
+    fn read_at(&mut self, key: &str, cmd_pos: CommandPosition) -> Result<String> {
+        let record = if cmd_pos.ver == self.pending_ver && cmd_pos.pos >= self.pending_pos {
+            let start = (cmd_pos.pos - self.pending_pos) as usize;
+            &self.pending[start..start + cmd_pos.len as usize]
+        } else {
+            let reader = self
+                .readers
+                .get_mut(&cmd_pos.ver)
+                .expect("every segment the snapshot refers to is open");
+            reader.seek(SeekFrom::Start(cmd_pos.pos))?;
+            self.buf.clear();
+            reader.take(cmd_pos.len).read_to_end(&mut self.buf)?;
+            &self.buf[..]
+        };
+        match serde_json::from_slice(record)? {
+            CommandRef::Set { value } => Ok(value.into_owned()),
+            CommandRef::Remove(_) => Err(KvsError::UnexpectedCommandType(format!(
+                "no existing command for key: {}",
+                key
+            ))),
+        }
+    }
+}
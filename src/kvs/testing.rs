@@ -0,0 +1,715 @@
+//! A reusable correctness suite, available with the `testing` feature.
+//!
+//! The suite is model based: randomly generated operations are applied both
+//! to a [`KvStore`] and to a [`Model`], a plain `HashMap`, and every answer
+//! the store gives is checked against the model's. Generated workloads also
+//! crash the store at random points and reopen it, so recovery is checked
+//! alongside everything else. A workload is fully determined by its seed,
+//! which every failure reports.
+//!
+//! [`fuzz_log`] is a fuzz target for the log parser, ready to be wrapped by
+//! `cargo fuzz` or any other fuzzer that hands out byte slices.
+//!
+//! [`FaultyFs`] is an [`Fs`] that injects short writes, failed syncs and
+//! full disks wherever it is told to.
+//!
+//! [`KvStore`]: ../struct.KvStore.html
+//! [`Model`]: struct.Model.html
+//! [`fuzz_log`]: fn.fuzz_log.html
+//! [`FaultyFs`]: struct.FaultyFs.html
+//! [`Fs`]: ../trait.Fs.html
+use std::collections::HashMap;
+use std::io::{self, Cursor, IoSlice, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::Duration;
+
+use serde_json::Deserializer;
+
+use crate::kvio::block::BlockReader;
+use crate::kvio::footer::Footer;
+use crate::kvio::fs::{Fs, FsFile, LockGuard, OpenMode, StdFs};
+use crate::kvio::wal::WAL_FILE_NAME;
+use crate::util::rand::Rng;
+use crate::{Clock, KvOpts, KvStore, Loader, ManualClock, Result, WalRecord};
+
+/// When the clocks of generated workloads start, in seconds since the Unix
+/// epoch.
+const START: u64 = 1_500_000_000;
+
+/// A single operation of a generated workload.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Op {
+    /// Sets a key to a value.
+    Set(String, String),
+    /// Sets a key to a value that expires after the given duration.
+    SetWithTtl(String, String, Duration),
+    /// Gets a key's value.
+    Get(String),
+    /// Removes a key.
+    Remove(String),
+    /// Compacts the store's logs.
+    Compact,
+    /// Lets the given duration pass.
+    Sleep(Duration),
+    /// Crashes the store at the given point and reopens it.
+    Crash(CrashPoint),
+}
+
+/// Where a generated workload crashes the store.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CrashPoint {
+    /// The store is closed cleanly.
+    Close,
+    /// The process dies between two operations, before the store can flush
+    /// anything on its way out.
+    BetweenOps,
+    /// The process dies part way through appending a command to the
+    /// write-ahead log. The torn command was never acknowledged.
+    TornWrite,
+}
+
+/// Generates the operations of a workload from a seed.
+///
+/// Keys are drawn from a small key space so that operations frequently hit
+/// the same keys.
+#[derive(Debug, Clone)]
+pub struct OpGenerator {
+    rng: Rng,
+    keys: u64,
+}
+
+impl OpGenerator {
+    /// Creates a generator of operations over `keys` distinct keys.
+    /// `keys` must not be zero.
+    pub fn new(seed: u64, keys: u64) -> OpGenerator {
+        OpGenerator {
+            rng: Rng::from_seed(seed),
+            keys,
+        }
+    }
+
+    fn key(&mut self) -> String {
+        format!("key{}", self.rng.below(self.keys))
+    }
+
+    fn value(&mut self) -> String {
+        // Now and then a value spans several blocks.
+        let len = match self.rng.below(50) {
+            0 => 5000 + self.rng.below(5000),
+            _ => self.rng.below(100),
+        };
+        format!("{}", self.rng.next_u64()).repeat(len as usize / 20 + 1)
+    }
+}
+
+impl Iterator for OpGenerator {
+    type Item = Op;
+
+    fn next(&mut self) -> Option<Op> {
+        let op = match self.rng.below(100) {
+            0..=37 => Op::Set(self.key(), self.value()),
+            38..=44 => {
+                let ttl = Duration::from_millis(self.rng.below(10_000));
+                Op::SetWithTtl(self.key(), self.value(), ttl)
+            }
+            45..=72 => Op::Get(self.key()),
+            73..=90 => Op::Remove(self.key()),
+            91..=92 => Op::Sleep(Duration::from_millis(self.rng.below(5_000))),
+            93..=94 => Op::Compact,
+            95..=96 => Op::Crash(CrashPoint::Close),
+            97..=98 => Op::Crash(CrashPoint::BetweenOps),
+            _ => Op::Crash(CrashPoint::TornWrite),
+        };
+        Some(op)
+    }
+}
+
+/// The reference model a store is checked against.
+///
+/// The model keeps its own time, which only moves when it is advanced.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Model {
+    /// Every key's value, along with when it expires, in milliseconds.
+    map: HashMap<String, (String, Option<u64>)>,
+    /// The current time, in milliseconds.
+    now: u64,
+}
+
+impl Model {
+    /// Creates an empty model whose time is `now`.
+    pub fn new(now: Duration) -> Model {
+        Model {
+            map: HashMap::new(),
+            now: now.as_millis() as u64,
+        }
+    }
+
+    /// Sets a key to a value.
+    pub fn set(&mut self, key: String, value: String) {
+        self.map.insert(key, (value, None));
+    }
+
+    /// Sets a key to a value that expires after `ttl`.
+    pub fn set_with_ttl(&mut self, key: String, value: String, ttl: Duration) {
+        let expires = self.now + ttl.as_millis() as u64;
+        self.map.insert(key, (value, Some(expires)));
+    }
+
+    /// Gets a key's value.
+    pub fn get(&self, key: &str) -> Option<String> {
+        match self.map.get(key) {
+            Some((value, expires)) if self.is_live(*expires) => Some(value.clone()),
+            _ => None,
+        }
+    }
+
+    /// Removes a key. Returns whether the key existed, which is whether
+    /// removing it from a store should succeed.
+    pub fn remove(&mut self, key: &str) -> bool {
+        let existed = self.get(key).is_some();
+        self.map.remove(key);
+        existed
+    }
+
+    /// Moves the model's time forward by `by`.
+    pub fn advance(&mut self, by: Duration) {
+        self.now += by.as_millis() as u64;
+    }
+
+    /// Applies an operation that writes to the model. Other operations
+    /// leave the model as it is.
+    pub fn apply(&mut self, op: &Op) {
+        match op.clone() {
+            Op::Set(key, value) => self.set(key, value),
+            Op::SetWithTtl(key, value, ttl) => self.set_with_ttl(key, value, ttl),
+            Op::Remove(key) => {
+                self.remove(&key);
+            }
+            Op::Sleep(by) => self.advance(by),
+            _ => {}
+        }
+    }
+
+    /// Returns every key in the model that has not expired.
+    pub fn keys(&self) -> Vec<String> {
+        self.map
+            .iter()
+            .filter(|(_, (_, expires))| self.is_live(*expires))
+            .map(|(key, _)| key.clone())
+            .collect()
+    }
+
+    fn is_live(&self, expires: Option<u64>) -> bool {
+        expires.is_none_or(|expires| expires > self.now)
+    }
+}
+
+/// Runs `steps` generated operations against a store in the directory `dir`
+/// and against a [`Model`], opening the store with `opts`. The store's clock
+/// is replaced with a [`ManualClock`] that moves along with the model's.
+///
+/// # Panics
+///
+/// Panics, naming the seed and step, as soon as the store disagrees with
+/// the model.
+///
+/// # Errors
+///
+/// Errors if any of the store's operations fail other than by disagreeing
+/// with the model.
+///
+/// [`Model`]: struct.Model.html
+/// [`ManualClock`]: ../struct.ManualClock.html
+pub fn check_model(dir: &Path, opts: KvOpts, seed: u64, steps: usize) -> Result<()> {
+    let clock = ManualClock::new(Duration::from_secs(START));
+    let opts = opts.clock(clock.clone());
+    let mut model = Model::new(clock.now());
+    let mut ops = OpGenerator::new(seed, 64);
+    let mut store = KvStore::open_with_opts(dir, opts.clone())?;
+    for step in 0..steps {
+        let op = ops.next().expect("operations never run out");
+        match op.clone() {
+            Op::Set(key, value) => store.set(key, value)?,
+            Op::SetWithTtl(key, value, ttl) => store.set_with_ttl(key, value, ttl)?,
+            Op::Get(key) => {
+                let expected = model.get(&key);
+                let actual = store.get(key)?;
+                assert_eq!(
+                    actual, expected,
+                    "seed {} diverged at step {}: {:?}",
+                    seed, step, op
+                );
+            }
+            Op::Remove(key) => {
+                let expected = model.remove(&key);
+                let actual = store.remove(key).is_ok();
+                assert_eq!(
+                    actual, expected,
+                    "seed {} diverged at step {}: {:?}",
+                    seed, step, op
+                );
+            }
+            Op::Compact => store.compact()?,
+            Op::Sleep(by) => clock.advance(by),
+            Op::Crash(point) => {
+                crash(store, dir, point)?;
+                store = KvStore::open_with_opts(dir, opts.clone())?;
+                for key in model.keys() {
+                    assert_eq!(
+                        store.get(key.clone())?,
+                        model.get(&key),
+                        "seed {} diverged after recovering at step {}: {:?}",
+                        seed,
+                        step,
+                        op
+                    );
+                }
+            }
+        }
+        if let Op::Set(..) | Op::SetWithTtl(..) | Op::Sleep(_) = op {
+            model.apply(&op);
+        }
+    }
+    Ok(())
+}
+
+/// What a [`simulate`] run went through.
+///
+/// [`simulate`]: fn.simulate.html
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Report {
+    /// The number of operations run.
+    pub steps: u64,
+    /// The number of times the store was crashed and reopened.
+    pub crashes: u64,
+    /// The number of faults scheduled on the file system.
+    pub faults: u64,
+    /// The number of operations that failed because of a fault.
+    pub failed_ops: u64,
+}
+
+/// Runs a randomized workload determined entirely by `seed` against a store
+/// in the directory `dir`, on a [`FaultyFs`] that injects faults at random.
+///
+/// Whenever an operation fails, the store is crashed and reopened, the way
+/// an application would restart after an I/O error. An operation that
+/// failed may or may not have reached the write-ahead log, so afterwards
+/// its key must hold either its old value or its new one. Every other key
+/// must hold exactly what the workload acknowledged writing.
+///
+/// # Panics
+///
+/// Panics, naming the seed and step, as soon as the store loses or invents
+/// a write.
+///
+/// # Errors
+///
+/// Errors if reopening the store fails.
+///
+/// [`FaultyFs`]: struct.FaultyFs.html
+pub fn simulate(dir: &Path, seed: u64, steps: usize) -> Result<Report> {
+    let mut rng = Rng::from_seed(seed);
+    let fs = FaultyFs::new();
+    let clock = ManualClock::new(Duration::from_secs(START));
+    let opts = KvOpts::new()
+        .sync(rng.below(2) == 0)
+        .fs(fs.clone())
+        .clock(clock.clone());
+    let mut ops = OpGenerator::new(rng.next_u64(), 64);
+    let mut model = Model::new(clock.now());
+    let mut report = Report::default();
+    let mut store = KvStore::open_with_opts(dir, opts.clone())?;
+
+    for step in 0..steps {
+        report.steps += 1;
+        if rng.below(100) < 3 {
+            let fault = match rng.below(3) {
+                0 => Fault::ShortWrite,
+                1 => Fault::SyncFailure,
+                _ => Fault::NoSpace,
+            };
+            fs.inject(fault, 1 + rng.below(4));
+            report.faults += 1;
+        }
+
+        let op = ops.next().expect("operations never run out");
+        let result = match op.clone() {
+            Op::Set(key, value) => store.set(key, value),
+            Op::SetWithTtl(key, value, ttl) => store.set_with_ttl(key, value, ttl),
+            Op::Get(key) => store.get(key.clone()).map(|actual| {
+                assert_eq!(
+                    actual,
+                    model.get(&key),
+                    "seed {} diverged at step {}: {:?}",
+                    seed,
+                    step,
+                    op
+                );
+            }),
+            Op::Remove(key) => store.remove(key),
+            Op::Compact => store.compact(),
+            Op::Sleep(by) => {
+                clock.advance(by);
+                Ok(())
+            }
+            Op::Crash(_) => Ok(()),
+        };
+        // The key a write that failed may, or may not, have changed.
+        let mut uncertain = None;
+        match (&op, result) {
+            (Op::Crash(_), _) => {}
+            (Op::Remove(key), Ok(())) => {
+                assert!(
+                    model.remove(key),
+                    "seed {} diverged at step {}: {:?}",
+                    seed,
+                    step,
+                    op
+                );
+                continue;
+            }
+            // A key that does not exist cannot be removed whether or not
+            // the file system misbehaves.
+            (Op::Remove(key), Err(_)) if model.get(key).is_none() => continue,
+            (_, Ok(())) => {
+                model.apply(&op);
+                continue;
+            }
+            (Op::Set(key, ..), Err(_))
+            | (Op::SetWithTtl(key, ..), Err(_))
+            | (Op::Remove(key), Err(_)) => {
+                report.failed_ops += 1;
+                uncertain = Some(key.clone());
+            }
+            (_, Err(_)) => report.failed_ops += 1,
+        }
+        let point = match op {
+            Op::Crash(point) => point,
+            _ => CrashPoint::BetweenOps,
+        };
+        // A crash that fails part way is just as good a crash.
+        let _ = crash(store, dir, point);
+
+        report.crashes += 1;
+        fs.clear();
+        store = KvStore::open_with_opts(dir, opts.clone())?;
+        if let Some(key) = uncertain {
+            let mut applied = model.clone();
+            applied.apply(&op);
+            let actual = store.get(key.clone())?;
+            assert!(
+                actual == model.get(&key) || actual == applied.get(&key),
+                "seed {} diverged after failing step {}: {:?}",
+                seed,
+                step,
+                op
+            );
+            if actual == applied.get(&key) {
+                model = applied;
+            }
+        }
+        for key in model.keys() {
+            assert_eq!(
+                store.get(key.clone())?,
+                model.get(&key),
+                "seed {} diverged after recovering at step {}: {:?}",
+                seed,
+                step,
+                op
+            );
+        }
+    }
+    Ok(report)
+}
+
+/// Crashes `store`, which lives in the directory `dir`, at `point`.
+pub fn crash(mut store: KvStore, dir: &Path, point: CrashPoint) -> Result<()> {
+    if point != CrashPoint::Close {
+        // Whatever has not reached the active data segment yet is only in
+        // the write-ahead log, as it would be had the process died.
+        store.pending.clear();
+    }
+    let fs = Arc::clone(&store.fs);
+    drop(store);
+    if point == CrashPoint::TornWrite {
+        let mut wal = fs.open(&dir.join(WAL_FILE_NAME), OpenMode::Append)?;
+        wal.write_all(br#"{"Set":{"key":"torn","val"#)?;
+    }
+    Ok(())
+}
+
+/// A fuzz target for the log parser: parses `data` as a data segment the
+/// way loading a store does, checking block checksums and the footer.
+///
+/// Parsing may fail, but it must never panic, and replaying the segment
+/// must only find commands at positions inside of it.
+pub fn fuzz_log(data: &[u8]) {
+    let mut cursor = Cursor::new(data);
+    let mut blocks = match BlockReader::new(&mut cursor) {
+        Ok(blocks) => blocks,
+        Err(_) => return,
+    };
+    if let Ok(Some(block)) = blocks.footer(true) {
+        let _ = serde_json::from_slice::<Footer>(&block.payload);
+    }
+    if let Ok(footer) = Loader::replay(&mut blocks) {
+        for entry in &footer.entries {
+            assert!(entry.pos + entry.len <= data.len() as u64);
+        }
+    }
+    // The write-ahead log is parsed as a bare stream of commands and
+    // batches.
+    for record in Deserializer::from_slice(data).into_iter::<WalRecord>() {
+        if record.is_err() {
+            break;
+        }
+    }
+}
+
+/// A fault a [`FaultyFs`] can inject.
+///
+/// [`FaultyFs`]: struct.FaultyFs.html
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    /// A write stores only the first half of its buffer, and says so.
+    ShortWrite,
+    /// A sync fails.
+    SyncFailure,
+    /// A sync takes a tenth of a second before it succeeds, like one on a
+    /// disk that stalls.
+    SlowSync,
+    /// The disk fills up part way through a write. The write stores the
+    /// first half of its buffer, and every write after it fails with
+    /// `ErrorKind::StorageFull` until the faults are cleared.
+    NoSpace,
+}
+
+impl Fault {
+    /// Returns whether the fault is injected into a sync, not a write.
+    fn is_sync(self) -> bool {
+        matches!(self, Fault::SyncFailure | Fault::SlowSync)
+    }
+}
+
+/// An [`Fs`] over the local file system that injects faults on request.
+///
+/// Clones share their faults, so a clone can be handed to a store while the
+/// original stays behind to inject faults into it.
+///
+/// [`Fs`]: ../trait.Fs.html
+#[derive(Debug, Clone, Default)]
+pub struct FaultyFs {
+    state: Arc<Mutex<FaultState>>,
+}
+
+#[derive(Debug, Default)]
+struct FaultState {
+    /// The number of writes made so far.
+    writes: u64,
+    /// The number of syncs made so far.
+    syncs: u64,
+    /// The number of reads made so far.
+    reads: u64,
+    /// Faults waiting to be injected, along with the number of the write,
+    /// or sync, they are injected into.
+    pending: Vec<(Fault, u64)>,
+    /// Whether the disk is full.
+    full: bool,
+}
+
+impl FaultState {
+    /// Counts a write and returns the fault to inject into it, if any.
+    fn next_write(&mut self) -> Option<Fault> {
+        self.writes += 1;
+        if self.full {
+            return Some(Fault::NoSpace);
+        }
+        let writes = self.writes;
+        self.take(|fault, at| !fault.is_sync() && at == writes)
+    }
+
+    /// Counts a sync and returns the fault to inject into it, if any.
+    fn next_sync(&mut self) -> Option<Fault> {
+        self.syncs += 1;
+        let syncs = self.syncs;
+        self.take(|fault, at| fault.is_sync() && at == syncs)
+    }
+
+    fn take<F: Fn(Fault, u64) -> bool>(&mut self, due: F) -> Option<Fault> {
+        let i = self
+            .pending
+            .iter()
+            .position(|&(fault, at)| due(fault, at))?;
+        Some(self.pending.swap_remove(i).0)
+    }
+}
+
+impl FaultyFs {
+    /// Creates a file system that behaves until it is told otherwise.
+    pub fn new() -> FaultyFs {
+        FaultyFs::default()
+    }
+
+    /// Injects `fault` into the `n`th write from now, or into the `n`th
+    /// sync for [`Fault::SyncFailure`] and [`Fault::SlowSync`]. The very
+    /// next write is the first.
+    ///
+    /// Writes are counted as they reach the file system, after any
+    /// buffering the store does.
+    ///
+    /// [`Fault::SyncFailure`]: enum.Fault.html#variant.SyncFailure
+    /// [`Fault::SlowSync`]: enum.Fault.html#variant.SlowSync
+    pub fn inject(&self, fault: Fault, n: u64) {
+        let mut state = self.state();
+        let at = if fault.is_sync() {
+            state.syncs + n
+        } else {
+            state.writes + n
+        };
+        state.pending.push((fault, at));
+    }
+
+    /// Drops every fault that has not been injected yet, and frees up the
+    /// disk.
+    pub fn clear(&self) {
+        let mut state = self.state();
+        state.pending.clear();
+        state.full = false;
+    }
+
+    /// Returns the number of writes made so far.
+    pub fn writes(&self) -> u64 {
+        self.state().writes
+    }
+
+    /// Returns the number of syncs made so far.
+    pub fn syncs(&self) -> u64 {
+        self.state().syncs
+    }
+
+    /// Returns the number of reads made so far. Like writes, reads are
+    /// counted as they reach the file system.
+    pub fn reads(&self) -> u64 {
+        self.state().reads
+    }
+
+    fn state(&self) -> MutexGuard<'_, FaultState> {
+        self.state.lock().expect("fault state poisoned")
+    }
+}
+
+impl Fs for FaultyFs {
+    fn open(&self, path: &Path, mode: OpenMode) -> io::Result<Box<dyn FsFile>> {
+        Ok(Box::new(FaultyFile {
+            inner: StdFs.open(path, mode)?,
+            state: Arc::clone(&self.state),
+        }))
+    }
+
+    fn read_dir(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        StdFs.read_dir(dir)
+    }
+
+    fn subdirs(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        StdFs.subdirs(dir)
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        StdFs.exists(path)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        StdFs.rename(from, to)
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        StdFs.remove_file(path)
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        StdFs.create_dir_all(path)
+    }
+
+    fn sync_dir(&self, dir: &Path) -> io::Result<()> {
+        StdFs.sync_dir(dir)
+    }
+
+    fn lock(&self, path: &Path) -> io::Result<Option<LockGuard>> {
+        StdFs.lock(path)
+    }
+
+    fn available_space(&self, dir: &Path) -> Option<io::Result<u64>> {
+        StdFs.available_space(dir)
+    }
+}
+
+struct FaultyFile {
+    inner: Box<dyn FsFile>,
+    state: Arc<Mutex<FaultState>>,
+}
+
+impl Read for FaultyFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.state.lock().expect("fault state poisoned").reads += 1;
+        self.inner.read(buf)
+    }
+}
+
+impl Seek for FaultyFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+impl Write for FaultyFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_vectored(&[IoSlice::new(buf)])
+    }
+
+    /// A vectored write counts as a single write. A fault injected into it
+    /// cuts it short within its first buffer.
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        let mut state = self.state.lock().expect("fault state poisoned");
+        let buf = bufs
+            .iter()
+            .find(|buf| !buf.is_empty())
+            .map_or(&[][..], |buf| &**buf);
+        let half = buf.len().div_ceil(2);
+        match state.next_write() {
+            None => self.inner.write_vectored(bufs),
+            Some(Fault::ShortWrite) => self.inner.write(&buf[..half]),
+            Some(Fault::NoSpace) if !state.full => {
+                state.full = true;
+                self.inner.write(&buf[..half])
+            }
+            Some(_) => Err(io::Error::new(
+                io::ErrorKind::StorageFull,
+                "no space left on device",
+            )),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl FsFile for FaultyFile {
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        self.inner.set_len(len)
+    }
+
+    fn sync_data(&mut self) -> io::Result<()> {
+        let fault = self.state.lock().expect("fault state poisoned").next_sync();
+        match fault {
+            Some(Fault::SyncFailure) => Err(io::Error::other("injected sync failure")),
+            Some(Fault::SlowSync) => {
+                thread::sleep(Duration::from_millis(100));
+                self.inner.sync_data()
+            }
+            _ => self.inner.sync_data(),
+        }
+    }
+}
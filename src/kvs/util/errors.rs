@@ -1,4 +1,6 @@
 //! Primary error structures for kvs.
+use std::error::Error;
+use std::fmt;
 use std::io;
 
 /// Error types for the key-value store.
@@ -18,6 +20,83 @@ pub enum KvsError {
     /// named Clear is written to the log, but
     /// this is not a valid Kvs `Command`)
     UnexpectedCommandType(String),
+    /// Error type indicating that data read from
+    /// disk failed its integrity checks.
+    Corruption(String),
+    /// Error type indicating that a store was opened
+    /// with a different key codec than the one it
+    /// was created with.
+    KeyCodecMismatch(String),
+    /// Error type indicating that no secondary index
+    /// has been registered under the given name.
+    IndexNotFound(String),
+    /// Error type indicating that a store's directory
+    /// holds a file that could be mistaken for one of
+    /// the store's own.
+    UnexpectedFile(String),
+    /// Error type indicating that a store was opened
+    /// with a different segment layout than the one it
+    /// was created with.
+    SegmentLayoutMismatch(String),
+    /// Error type indicating that a store is already
+    /// open, in this process or another one.
+    StoreLocked(String),
+    /// Error type indicating that a directory was
+    /// created by a different storage engine.
+    WrongEngine(String),
+    /// Error type indicating that a write-once key
+    /// was written to after it had been set.
+    WriteOnce(String),
+    /// Error type indicating that two different values
+    /// hashed to the same content-addressed key.
+    HashCollision(String),
+    /// Error type indicating that a conditional write
+    /// was handed a version the key is no longer at.
+    VersionMismatch(String),
+    /// Error type indicating that a store's on-disk
+    /// format is not the one this crate writes.
+    UnsupportedFormat(String),
+    /// Error type indicating that a store opened
+    /// read-only was written to.
+    ReadOnly(String),
+    /// Error type indicating that input handed to
+    /// kvs, rather than read from a store, such as a
+    /// file to import, is malformed.
+    InvalidInput(String),
+}
+
+impl fmt::Display for KvsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KvsError::Io(err) => write!(f, "{}", err),
+            KvsError::Serde(err) => write!(f, "could not (de)serialize: {}", err),
+            KvsError::KeyNotFound(msg) => write!(f, "{}", msg),
+            KvsError::UnexpectedCommandType(msg) => write!(f, "unexpected command: {}", msg),
+            KvsError::Corruption(msg) => write!(f, "corruption: {}", msg),
+            KvsError::KeyCodecMismatch(msg) => write!(f, "{}", msg),
+            KvsError::IndexNotFound(name) => write!(f, "index not found: {}", name),
+            KvsError::UnexpectedFile(path) => write!(f, "unexpected file in the store: {}", path),
+            KvsError::SegmentLayoutMismatch(msg) => write!(f, "{}", msg),
+            KvsError::StoreLocked(dir) => write!(f, "the store at {} is already open", dir),
+            KvsError::WrongEngine(msg) => write!(f, "{}", msg),
+            KvsError::WriteOnce(msg) => write!(f, "{}", msg),
+            KvsError::HashCollision(msg) => write!(f, "hash collision: {}", msg),
+            KvsError::VersionMismatch(msg) => write!(f, "{}", msg),
+            KvsError::UnsupportedFormat(msg) => write!(f, "{}", msg),
+            KvsError::ReadOnly(dir) => write!(f, "the store at {} is open read-only", dir),
+            KvsError::InvalidInput(msg) => write!(f, "invalid input: {}", msg),
+        }
+    }
+}
+
+impl Error for KvsError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            KvsError::Io(err) => Some(err),
+            KvsError::Serde(err) => Some(err),
+            _ => None,
+        }
+    }
 }
 
 impl From<io::Error> for KvsError {
@@ -1,3 +1,7 @@
 /// Utility module declaration.
+#[cfg(feature = "cli")]
 pub mod command_prelude;
+pub mod crc;
 pub mod errors;
+pub mod rand;
+pub mod sha256;
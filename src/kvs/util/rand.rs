@@ -0,0 +1,65 @@
+//! A small pseudo-random number generator.
+//!
+//! This is SplitMix64. It is fast and has good statistical properties, but
+//! it is **not** cryptographically secure.
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Tells generators created in quick succession apart.
+static GENERATORS: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Clone)]
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    /// Creates a generator seeded from the randomness the standard library
+    /// uses to key its hash maps, mixed with the current time where there
+    /// is a system clock to read it from.
+    pub fn from_entropy() -> Rng {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u64(GENERATORS.fetch_add(1, Ordering::Relaxed));
+        // Reading the time panics on wasm32-unknown-unknown.
+        #[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+        {
+            use std::time::{SystemTime, UNIX_EPOCH};
+            let nanos = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_nanos())
+                .unwrap_or(0);
+            hasher.write_u128(nanos);
+        }
+        Rng {
+            state: hasher.finish(),
+        }
+    }
+
+    /// Creates a generator that always produces the same numbers for the
+    /// same `seed`.
+    pub fn from_seed(seed: u64) -> Rng {
+        Rng { state: seed }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Returns a number in `0..n`. `n` must not be zero.
+    pub fn below(&mut self, n: u64) -> u64 {
+        // Rejecting the values past the last multiple of `n` keeps the
+        // result unbiased.
+        let zone = u64::MAX - u64::MAX % n;
+        loop {
+            let x = self.next_u64();
+            if x < zone {
+                return x % n;
+            }
+        }
+    }
+}
@@ -0,0 +1,45 @@
+//! Serializing a store's contents.
+use std::cell::RefCell;
+
+use serde::ser::{Error, Serialize, SerializeMap, Serializer};
+
+use crate::KvStore;
+
+/// A view of a store's live keys that serializes as a map from key to value,
+/// in key order. Returned by [`KvStore::live_view`].
+///
+/// Values are read one at a time, as they are serialized, so serializing to
+/// a writer never holds more than one value in memory. A value that cannot
+/// be read fails the serialization.
+///
+/// [`KvStore::live_view`]: struct.KvStore.html#method.live_view
+pub struct LiveView<'a> {
+    // Reading a value moves the store's readers, which `serialize` cannot do
+    // through the shared reference it is handed.
+    store: RefCell<&'a mut KvStore>,
+}
+
+impl<'a> LiveView<'a> {
+    pub(crate) fn new(store: &'a mut KvStore) -> LiveView<'a> {
+        LiveView {
+            store: RefCell::new(store),
+        }
+    }
+}
+
+impl Serialize for LiveView<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut store = self.store.borrow_mut();
+        let keys = store.sorted_keys("");
+        let mut map = serializer.serialize_map(Some(keys.len()))?;
+        for key in keys {
+            let value = store
+                .read_value(&key)
+                .map_err(|err| S::Error::custom(format!("{:?}", err)))?;
+            if let Some(value) = value {
+                map.serialize_entry(&key, &value)?;
+            }
+        }
+        map.end()
+    }
+}
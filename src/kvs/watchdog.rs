@@ -0,0 +1,193 @@
+//! Reports of syncs and compactions that take too long.
+//!
+//! A disk that stalls makes a store look hung, and the application using it
+//! look broken. A store's [`Watchdog`] times every sync of its files and
+//! every compaction, counts the ones that go past its limits in
+//! [`IoStats`], and hands each of them as a [`Stall`] to the handler it was
+//! given, if any, so that a slow disk is named as such.
+//!
+//! A sync is reported once it returns. A compaction is checked after every
+//! record it copies, and after it syncs its segments, so it is reported
+//! while it is still running, but only once one of those steps returns; a
+//! single copy or sync that hangs is not reported until it is done.
+//!
+//! [`Watchdog`]: struct.Watchdog.html
+//! [`IoStats`]: struct.IoStats.html
+//! [`Stall`]: struct.Stall.html
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::kvio::counted::IoCounters;
+
+/// How long a sync may take by default.
+const DEFAULT_MAX_SYNC: Duration = Duration::from_secs(1);
+
+/// How long a compaction may run for by default.
+const DEFAULT_MAX_COMPACTION: Duration = Duration::from_secs(10 * 60);
+
+/// Where a [`Watchdog`] reports stalls to.
+///
+/// [`Watchdog`]: struct.Watchdog.html
+type StallHandler = Arc<dyn Fn(&Stall) + Send + Sync>;
+
+/// What took longer than a [`Watchdog`] allows.
+///
+/// [`Watchdog`]: struct.Watchdog.html
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StallKind {
+    /// A sync of one of the store's files or directories.
+    Sync,
+    /// A compaction, which is still running when it is reported, between
+    /// the records it copies.
+    Compaction,
+}
+
+/// A sync or compaction that took longer than a [`Watchdog`] allows.
+///
+/// [`Watchdog`]: struct.Watchdog.html
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stall {
+    /// What took too long.
+    pub kind: StallKind,
+    /// How long it took, or, for a compaction, how long it had been running.
+    pub elapsed: Duration,
+    /// How long it was allowed to take.
+    pub limit: Duration,
+}
+
+impl fmt::Display for Stall {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.kind {
+            StallKind::Sync => write!(
+                f,
+                "a sync took {:?}, longer than the {:?} allowed; the disk may be stalling",
+                self.elapsed, self.limit
+            ),
+            StallKind::Compaction => write!(
+                f,
+                "a compaction has been running for {:?}, longer than the {:?} allowed; the disk may be stalling",
+                self.elapsed, self.limit
+            ),
+        }
+    }
+}
+
+/// How long a store's syncs and compactions may take before they are
+/// reported, as set by [`KvOpts::watchdog`].
+///
+/// By default a sync may take a second and a compaction ten minutes, and
+/// stalls are only counted.
+///
+/// ```rust
+/// # use std::time::Duration;
+/// # use kvs::{KvOpts, Watchdog};
+/// let watchdog = Watchdog::new()
+///     .max_sync(Duration::from_millis(200))
+///     .on_stall(|stall| eprintln!("storage: {}", stall));
+/// let opts = KvOpts::new().watchdog(watchdog);
+/// ```
+///
+/// [`KvOpts::watchdog`]: struct.KvOpts.html#method.watchdog
+#[derive(Clone)]
+pub struct Watchdog {
+    max_sync: Duration,
+    max_compaction: Duration,
+    on_stall: Option<StallHandler>,
+}
+
+impl Watchdog {
+    /// Creates a watchdog with the default limits.
+    pub fn new() -> Watchdog {
+        Watchdog::default()
+    }
+
+    /// Sets how long a sync may take.
+    pub fn max_sync(mut self, max: Duration) -> Watchdog {
+        self.max_sync = max;
+        self
+    }
+
+    /// Sets how long a compaction may run for.
+    pub fn max_compaction(mut self, max: Duration) -> Watchdog {
+        self.max_compaction = max;
+        self
+    }
+
+    /// Hands every stall to `report`, as well as counting it.
+    pub fn on_stall<F: Fn(&Stall) + Send + Sync + 'static>(mut self, report: F) -> Watchdog {
+        self.on_stall = Some(Arc::new(report));
+        self
+    }
+
+    /// Reports a sync that took `elapsed`, and returns whether it was over
+    /// the limit.
+    pub(crate) fn check_sync(&self, elapsed: Duration) -> bool {
+        self.check(StallKind::Sync, elapsed, self.max_sync)
+    }
+
+    /// Reports a compaction that has been running for `elapsed`, and
+    /// returns whether it is over the limit.
+    pub(crate) fn check_compaction(&self, elapsed: Duration) -> bool {
+        self.check(StallKind::Compaction, elapsed, self.max_compaction)
+    }
+
+    fn check(&self, kind: StallKind, elapsed: Duration, limit: Duration) -> bool {
+        if elapsed <= limit {
+            return false;
+        }
+        let stall = Stall {
+            kind,
+            elapsed,
+            limit,
+        };
+        if let Some(report) = &self.on_stall {
+            report(&stall);
+        }
+        true
+    }
+}
+
+impl Default for Watchdog {
+    fn default() -> Watchdog {
+        Watchdog {
+            max_sync: DEFAULT_MAX_SYNC,
+            max_compaction: DEFAULT_MAX_COMPACTION,
+            on_stall: None,
+        }
+    }
+}
+
+impl fmt::Debug for Watchdog {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Watchdog")
+            .field("max_sync", &self.max_sync)
+            .field("max_compaction", &self.max_compaction)
+            .field("on_stall", &self.on_stall.is_some())
+            .finish()
+    }
+}
+
+/// Watches a single compaction, reporting it at most once.
+pub(crate) struct CompactionWatch {
+    start: Instant,
+    stalled: bool,
+}
+
+impl CompactionWatch {
+    pub(crate) fn start() -> CompactionWatch {
+        CompactionWatch {
+            start: Instant::now(),
+            stalled: false,
+        }
+    }
+
+    /// Reports and counts the compaction if it has just gone past the
+    /// limit.
+    pub(crate) fn check(&mut self, watchdog: &Watchdog, counters: &IoCounters) {
+        if !self.stalled && watchdog.check_compaction(self.start.elapsed()) {
+            self.stalled = true;
+            counters.compaction_stalled();
+        }
+    }
+}
@@ -1,8 +1,21 @@
 use assert_cmd::prelude::*;
-use kvs::{KvStore, Result};
+use kvs::testing::{CrashPoint, Fault, FaultyFs};
+use kvs::{
+    AdaptiveCompaction, Budget, CasHash, CaseInsensitive, CheckStatus, CompactionPolicy, Exact, Fs,
+    FsFile, IndexHasher, KeyCodec, KeySpan, KvOpts, KvStore, KvsError, ManualClock, MemFs,
+    OpCounts, OpenMode, ReclaimForecast, Result, ScanOpts, SegmentLayout, StallKind, Ttl,
+    UnexpectedFiles, Verify, Version, Watchdog, WriteBatch,
+};
+use predicates::boolean::PredicateBooleanExt;
 use predicates::ord::eq;
-use predicates::str::{contains, is_empty, PredicateStrExt};
+use predicates::str::{contains, is_empty, is_match, PredicateStrExt};
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
 use std::process::Command;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -17,7 +30,7 @@ fn cli_no_args() {
 fn cli_version() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["-V"])
+        .args(["-V"])
         .assert()
         .stdout(contains(env!("CARGO_PKG_VERSION")));
 }
@@ -28,24 +41,25 @@ fn cli_get_non_existent_key() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "key1"])
+        .args(["get", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
         .stdout(eq("Key not found").trim());
 }
 
-// `kvs rm <KEY>` should print "Key not found" for an empty database and exit with non-zero code.
+// `kvs rm <KEY>` should print "Key not found" to stderr for an empty database and exit with code 2.
 #[test]
 fn cli_rm_non_existent_key() {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["rm", "key1"])
+        .args(["rm", "key1"])
         .current_dir(&temp_dir)
         .assert()
-        .failure()
-        .stdout(eq("Key not found").trim());
+        .code(2)
+        .stdout(is_empty())
+        .stderr(eq("Key not found").trim());
 }
 
 // `kvs set <KEY> <VALUE>` should print nothing and exit with zero.
@@ -54,7 +68,7 @@ fn cli_set() {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["set", "key1", "value1"])
+        .args(["set", "key1", "value1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -72,7 +86,7 @@ fn cli_get_stored() -> Result<()> {
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "key1"])
+        .args(["get", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -80,7 +94,7 @@ fn cli_get_stored() -> Result<()> {
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "key2"])
+        .args(["get", "key2"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -100,7 +114,7 @@ fn cli_rm_stored() -> Result<()> {
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["rm", "key1"])
+        .args(["rm", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -108,7 +122,7 @@ fn cli_rm_stored() -> Result<()> {
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "key1"])
+        .args(["get", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -121,13 +135,13 @@ fn cli_rm_stored() -> Result<()> {
 fn cli_invalid_get() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get"])
+        .args(["get"])
         .assert()
         .failure();
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "extra", "field"])
+        .args(["get", "extra", "field"])
         .assert()
         .failure();
 }
@@ -136,19 +150,19 @@ fn cli_invalid_get() {
 fn cli_invalid_set() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["set"])
+        .args(["set"])
         .assert()
         .failure();
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["set", "missing_field"])
+        .args(["set", "missing_field"])
         .assert()
         .failure();
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["set", "extra", "extra", "field"])
+        .args(["set", "extra", "extra", "field"])
         .assert()
         .failure();
 }
@@ -157,13 +171,13 @@ fn cli_invalid_set() {
 fn cli_invalid_rm() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["rm"])
+        .args(["rm"])
         .assert()
         .failure();
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["rm", "extra", "field"])
+        .args(["rm", "extra", "field"])
         .assert()
         .failure();
 }
@@ -172,7 +186,7 @@ fn cli_invalid_rm() {
 fn cli_invalid_subcommand() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["unknown", "subcommand"])
+        .args(["unknown", "subcommand"])
         .assert()
         .failure();
 }
@@ -198,6 +212,33 @@ fn get_stored_value() -> Result<()> {
     Ok(())
 }
 
+// `get_into` and `get_ref` should read the same values as `get`, from
+// memory and from disk, through a reused buffer.
+#[test]
+fn get_into_buffer() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let opts = KvOpts::new().key_codec(CaseInsensitive);
+    let mut store = KvStore::open_with_opts(temp_dir.path(), opts.clone())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "a \"quoted\"\nvalue".to_owned())?;
+
+    for _ in 0..2 {
+        let mut value = "previous".to_owned();
+        assert!(!store.get_into("missing", &mut value)?);
+        assert_eq!(value, "previous");
+        assert!(store.get_into("KEY1", &mut value)?);
+        assert_eq!(value, "value1");
+        assert!(store.get_into("key2", &mut value)?);
+        assert_eq!(value, "a \"quoted\"\nvalue");
+        assert_eq!(store.get_ref("key1")?, Some("value1"));
+        assert_eq!(store.get_ref("missing")?, None);
+
+        drop(store);
+        store = KvStore::open_with_opts(temp_dir.path(), opts.clone())?;
+    }
+    Ok(())
+}
+
 // Should overwrite existent value
 #[test]
 fn overwrite_value() -> Result<()> {
@@ -254,6 +295,36 @@ fn remove_key() -> Result<()> {
     Ok(())
 }
 
+// Removing a key that may not exist should report whether it did, rather
+// than fail.
+#[test]
+fn remove_if_exists() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(store.remove_if_exists("key1".to_owned())?);
+    assert!(!store.remove_if_exists("key1".to_owned())?);
+    assert!(!store.remove_if_exists("key2".to_owned())?);
+    assert_eq!(store.get("key1".to_owned())?, None);
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    drop(store);
+
+    for _ in 0..2 {
+        Command::cargo_bin("kvs")
+            .unwrap()
+            .args(["rm", "--force", "key1"])
+            .current_dir(&temp_dir)
+            .assert()
+            .success()
+            .stdout(is_empty())
+            .stderr(is_empty());
+    }
+    Ok(())
+}
+
 // Insert data until total size of the directory decreases.
 // Test data correctness after compaction.
 #[test]
@@ -299,3 +370,3038 @@ fn compaction() -> Result<()> {
 
     panic!("No compaction detected");
 }
+
+// Writes that only reached the write-ahead log should survive a crash.
+#[test]
+fn recover_from_wal() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key1".to_owned())?;
+
+    // Simulate a crash: the store never gets the chance to write its pending
+    // commands to the data segment.
+    kvs::testing::crash(store, temp_dir.path(), CrashPoint::BetweenOps)?;
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// A torn write at the tail of the write-ahead log should be discarded.
+#[test]
+fn recover_from_torn_wal() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    kvs::testing::crash(store, temp_dir.path(), CrashPoint::BetweenOps)?;
+
+    let wal = temp_dir.path().join("kvs.wal");
+    let mut contents = std::fs::read(&wal)?;
+    contents.extend_from_slice(b"{\"Set\":{\"key\":\"key2\",\"va");
+    std::fs::write(&wal, contents)?;
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    Ok(())
+}
+
+// A damaged block should cost only the commands inside of it.
+#[test]
+fn skip_damaged_block() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..500 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    drop(store);
+
+    let log = temp_dir.path().join("1.log");
+    let mut contents = std::fs::read(&log)?;
+    contents[10] ^= 0xff;
+    std::fs::write(&log, contents)?;
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.damaged_blocks(), 1);
+    assert_eq!(store.get("key0".to_owned())?, None);
+    assert_eq!(store.get("key499".to_owned())?, Some("value499".to_owned()));
+    Ok(())
+}
+
+// A damaged run of units at the front of a large segment should cost only
+// its own commands, and opening the store should still read the segment
+// about once rather than once per damaged unit.
+#[test]
+fn skip_damaged_units() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..20_000 {
+        store.set(format!("key{}", key_id), format!("{:0100}", key_id))?;
+    }
+    drop(store);
+
+    let log = temp_dir.path().join("1.log");
+    let mut contents = std::fs::read(&log)?;
+    let len = contents.len() as u64;
+    assert!(len > 500 * 4096);
+    // Damage the first unit, and the seven after it.
+    for unit in 0..8 {
+        contents[unit * 4096 + 10] ^= 0xff;
+    }
+    std::fs::write(&log, contents)?;
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.damaged_blocks(), 8);
+    assert_eq!(store.get("key0".to_owned())?, None);
+    assert_eq!(
+        store.get("key19999".to_owned())?,
+        Some(format!("{:0100}", 19_999))
+    );
+    // An intact segment is read about twice while it is opened.
+    assert!(store.stats().io.bytes_read < 3 * len);
+    Ok(())
+}
+
+// Damaged blocks should be a warning, or, with `--strict`, a failure with
+// exit code 3.
+#[test]
+fn cli_strict() -> Result<()> {
+    for &strict in &[false, true] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::open(temp_dir.path())?;
+        for key_id in 0..500 {
+            store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+        }
+        drop(store);
+
+        let log = temp_dir.path().join("1.log");
+        let mut contents = std::fs::read(&log)?;
+        contents[10] ^= 0xff;
+        std::fs::write(&log, contents)?;
+
+        let mut cmd = Command::cargo_bin("kvs").unwrap();
+        if strict {
+            cmd.arg("--strict");
+        }
+        let assert = cmd
+            .args(["get", "key499"])
+            .current_dir(&temp_dir)
+            .assert()
+            .stderr(contains("skipped 1 damaged block(s)"));
+        if strict {
+            assert.code(3).stdout(is_empty());
+        } else {
+            assert.success().stdout(eq("value499").trim());
+        }
+    }
+    Ok(())
+}
+
+// A store that is open elsewhere should fail every command with exit code 4,
+// and leave stdout empty.
+#[test]
+fn cli_locked() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(4)
+        .stdout(is_empty());
+    drop(store);
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("value1"));
+    Ok(())
+}
+
+// Files that could be mistaken for segments should never be loaded, and
+// should be reported, refused or quarantined as asked.
+#[test]
+fn unexpected_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let backup = temp_dir.path().join("backup.log");
+    let padded = temp_dir.path().join("01.log");
+    std::fs::write(&backup, "not a segment")?;
+    std::fs::write(&padded, "not a segment either")?;
+    std::fs::write(temp_dir.path().join("notes.txt"), "unrelated")?;
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.unexpected_files(), [padded.clone(), backup.clone()]);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stderr(contains("warning: unexpected file in the store"));
+
+    let opts = KvOpts::new().unexpected_files(UnexpectedFiles::Ignore);
+    let store = KvStore::open_with_opts(temp_dir.path(), opts)?;
+    assert!(store.unexpected_files().is_empty());
+    drop(store);
+
+    let opts = KvOpts::new().unexpected_files(UnexpectedFiles::Error);
+    match KvStore::open_with_opts(temp_dir.path(), opts) {
+        Err(KvsError::UnexpectedFile(file)) => assert!(file.ends_with("01.log")),
+        _ => panic!("expected an UnexpectedFile error"),
+    }
+
+    let opts = KvOpts::new().unexpected_files(UnexpectedFiles::Quarantine);
+    let store = KvStore::open_with_opts(temp_dir.path(), opts)?;
+    let quarantine = temp_dir.path().join("quarantine");
+    assert_eq!(
+        store.unexpected_files(),
+        [quarantine.join("01.log"), quarantine.join("backup.log")]
+    );
+    assert!(!backup.exists() && quarantine.join("backup.log").exists());
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert!(store.unexpected_files().is_empty());
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// Segments should be named and sharded as the store's layout says, and the
+// layout should be recorded so that later opens follow it.
+#[test]
+fn segment_layout() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let layout = SegmentLayout::new()
+        .prefix("seg-")
+        .width(8)
+        .extension("dat")
+        .segments_per_dir(2);
+    assert_eq!(layout.file_name(42), "seg-00000042.dat");
+    assert_eq!(layout.version_of("seg-00000042.dat"), Some(42));
+    assert_eq!(layout.version_of("seg-42.dat"), None);
+    assert_eq!(layout.version_of("42.log"), None);
+
+    let opts = KvOpts::new().segment_layout(layout.clone());
+    let mut store = KvStore::open_with_opts(temp_dir.path(), opts)?;
+    for i in 0..4 {
+        store.set("key1".to_owned(), format!("value{}", i))?;
+        store.compact()?;
+    }
+    assert_eq!(store.info().segment_layout, layout);
+    drop(store);
+
+    let segments: Vec<_> = WalkDir::new(temp_dir.path())
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| {
+            layout
+                .version_of(&entry.file_name().to_string_lossy())
+                .is_some()
+        })
+        .map(|entry| entry.into_path())
+        .collect();
+    assert!(!segments.is_empty());
+    for segment in &segments {
+        let version = layout
+            .version_of(&segment.file_name().unwrap().to_string_lossy())
+            .unwrap();
+        assert_eq!(
+            segment,
+            &temp_dir
+                .path()
+                .join((version / 2).to_string())
+                .join(layout.file_name(version))
+        );
+    }
+    assert!(!temp_dir.path().join("1.log").exists());
+
+    // Opening without a layout follows the recorded one; opening with a
+    // different one is refused.
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert!(store.unexpected_files().is_empty());
+    drop(store);
+
+    let opts = KvOpts::new().segment_layout(SegmentLayout::new());
+    match KvStore::open_with_opts(temp_dir.path(), opts) {
+        Err(KvsError::SegmentLayoutMismatch(_)) => {}
+        _ => panic!("expected a SegmentLayoutMismatch error"),
+    }
+
+    for seed in 0..4 {
+        let opts = KvOpts::new()
+            .fs(MemFs::new())
+            .segment_layout(layout.clone());
+        kvs::testing::check_model(&temp_dir.path().join("memory"), opts, seed, 400)?;
+    }
+    Ok(())
+}
+
+// A store should only ever be open once at a time, and should sync its
+// directory along with its files when asked to.
+#[test]
+fn store_lock() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open_with_opts(temp_dir.path(), KvOpts::new().sync(true))?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.compact()?;
+
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::StoreLocked(_)) => {}
+        _ => panic!("expected a StoreLocked error"),
+    }
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert!(store.unexpected_files().is_empty());
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(store);
+
+    // A `MemFs` keeps locks of its own, shared by its clones.
+    let fs = MemFs::new();
+    let store = KvStore::open_with_opts(temp_dir.path(), KvOpts::new().fs(fs.clone()))?;
+    match KvStore::open_with_opts(temp_dir.path(), KvOpts::new().fs(fs.clone())) {
+        Err(KvsError::StoreLocked(_)) => {}
+        _ => panic!("expected a StoreLocked error"),
+    }
+    KvStore::open_with_opts(temp_dir.path(), KvOpts::new().fs(MemFs::new()))?;
+    drop(store);
+    KvStore::open_with_opts(temp_dir.path(), KvOpts::new().fs(fs))?;
+    Ok(())
+}
+
+// A directory created by a different storage engine should be refused,
+// and left untouched.
+#[test]
+fn wrong_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let sled = temp_dir.path().join("sled");
+    std::fs::create_dir(&sled)?;
+    std::fs::write(sled.join("conf"), "segment_size: 524288")?;
+    std::fs::write(sled.join("db"), [0u8; 16])?;
+    match KvStore::open(&sled) {
+        Err(KvsError::WrongEngine(_)) => {}
+        _ => panic!("expected a WrongEngine error"),
+    }
+    assert_eq!(std::fs::read_dir(&sled)?.count(), 2);
+
+    let store = KvStore::open(temp_dir.path())?;
+    drop(store);
+    let meta_path = temp_dir.path().join("kvs.meta");
+    let meta = std::fs::read_to_string(&meta_path)?;
+    std::fs::write(&meta_path, meta.replace("\"kvs\"", "\"other\""))?;
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::WrongEngine(message)) => assert!(message.contains("other")),
+        _ => panic!("expected a WrongEngine error"),
+    }
+    Ok(())
+}
+
+// The leftovers of a compaction that was interrupted should be removed on
+// open, rather than loaded.
+#[test]
+fn remove_compaction_orphans() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+    let first = temp_dir.path().join("1.log");
+    let contents = std::fs::read(&first)?;
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.remove("key1".to_owned())?;
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.compact()?;
+    drop(store);
+    assert!(!first.exists());
+
+    // A crash part way through removing the compacted segments leaves some
+    // of them behind, and one while compacting leaves a temporary log.
+    std::fs::write(&first, contents)?;
+    let tmp = temp_dir.path().join("9.log.tmp");
+    std::fs::write(&tmp, "half a compaction")?;
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("Key not found").trim())
+        .stderr(contains("interrupted compaction"));
+    assert!(!first.exists() && !tmp.exists());
+
+    std::fs::write(&first, std::fs::read(temp_dir.path().join("4.log"))?)?;
+    std::fs::write(&tmp, "half a compaction")?;
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.removed_orphans(), [tmp, first]);
+    assert!(store.unexpected_files().is_empty());
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.removed_orphans().is_empty());
+    Ok(())
+}
+
+// Values larger than a block should round-trip through their own block.
+#[test]
+fn large_value() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let value = "v".repeat(20_000);
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("small".to_owned(), "value".to_owned())?;
+    store.set("large".to_owned(), value.clone())?;
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.damaged_blocks(), 0);
+    assert_eq!(store.get("small".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.get("large".to_owned())?, Some(value));
+    Ok(())
+}
+
+// Once sealed, a log should be loaded from its footer rather than replayed.
+#[test]
+fn load_sealed_log_from_footer() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..500 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    store.remove("key1".to_owned())?;
+    drop(store);
+
+    // The first reopen seals the log written above.
+    drop(KvStore::open(temp_dir.path())?);
+
+    // Damage a data block. A replay would notice, but the footer is all that
+    // has to be read.
+    let log = temp_dir.path().join("1.log");
+    let mut contents = std::fs::read(&log)?;
+    contents[10] ^= 0xff;
+    std::fs::write(&log, contents)?;
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.damaged_blocks(), 0);
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key499".to_owned())?, Some("value499".to_owned()));
+    Ok(())
+}
+
+// A full check should catch damage that the footer alone would hide.
+#[test]
+fn verify_full_on_open() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..500 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    drop(store);
+    drop(KvStore::open(temp_dir.path())?);
+
+    let opts = || KvOpts::new().verify_on_open(Verify::Full);
+    drop(KvStore::open_with_opts(temp_dir.path(), opts())?);
+
+    let log = temp_dir.path().join("1.log");
+    let mut contents = std::fs::read(&log)?;
+    contents[10] ^= 0xff;
+    std::fs::write(&log, contents)?;
+
+    match KvStore::open_with_opts(temp_dir.path(), opts()) {
+        Err(KvsError::Corruption(_)) => {}
+        _ => panic!("expected a corruption error"),
+    }
+    assert!(KvStore::open_with_opts(temp_dir.path(), KvOpts::new()).is_ok());
+    Ok(())
+}
+
+// A verified get should catch damage that a plain get reads right past.
+#[test]
+fn get_verified() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..500 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    drop(store);
+    drop(KvStore::open(temp_dir.path())?);
+
+    // Damage a value without breaking the JSON it is stored in.
+    let log = temp_dir.path().join("1.log");
+    let mut contents = std::fs::read(&log)?;
+    let at = contents
+        .windows(7)
+        .position(|window| window == b"value0\"")
+        .expect("value0 is in the log");
+    contents[at] = b'w';
+    std::fs::write(&log, contents)?;
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, Some("walue0".to_owned()));
+    match store.get_verified("key0".to_owned()) {
+        Err(KvsError::Corruption(_)) => {}
+        _ => panic!("expected a corruption error"),
+    }
+    assert_eq!(
+        store.get_verified("key499".to_owned())?,
+        Some("value499".to_owned())
+    );
+    assert_eq!(store.get_verified("key500".to_owned())?, None);
+
+    store.set("key500".to_owned(), "value500".to_owned())?;
+    assert_eq!(
+        store.get_verified("key500".to_owned())?,
+        Some("value500".to_owned())
+    );
+    Ok(())
+}
+
+// A store's metadata should be written once and survive reopening.
+#[test]
+fn store_info() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let info = store.info().clone();
+    assert_eq!(info.engine, "kvs");
+    assert_eq!(info.codec, "json");
+    assert_eq!(info.uuid.len(), 36);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.info(), &info);
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["info"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains(info.uuid.as_str()));
+    Ok(())
+}
+
+// Keys should be normalized with the codec the store was created with.
+#[test]
+fn case_insensitive_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let opts = KvOpts::new().key_codec(CaseInsensitive);
+    let mut store = KvStore::open_with_opts(temp_dir.path(), opts)?;
+    store.set("Key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get("KEY1".to_owned())?, Some("value1".to_owned()));
+    drop(store);
+
+    // The recorded codec is picked up when none is given...
+    let mut store = KvStore::open_with_opts(temp_dir.path(), KvOpts::new())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    store.remove("kEy1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    drop(store);
+
+    // ...but a different one is refused.
+    match KvStore::open_with_opts(temp_dir.path(), KvOpts::new().key_codec(Exact)) {
+        Err(KvsError::KeyCodecMismatch(_)) => {}
+        _ => panic!("expected a key codec mismatch"),
+    }
+    Ok(())
+}
+
+#[derive(Debug)]
+struct Reversed;
+
+impl KeyCodec for Reversed {
+    fn name(&self) -> &str {
+        "reversed"
+    }
+
+    fn normalize(&self, key: String) -> String {
+        key.chars().rev().collect()
+    }
+}
+
+// A custom codec has to be supplied every time the store is opened.
+#[test]
+fn custom_key_codec() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open_with_opts(temp_dir.path(), KvOpts::new().key_codec(Reversed))?;
+    store.set("abc".to_owned(), "value".to_owned())?;
+    assert_eq!(store.info().key_codec, "reversed");
+    drop(store);
+
+    assert!(KvStore::open_with_opts(temp_dir.path(), KvOpts::new()).is_err());
+    let mut store = KvStore::open_with_opts(temp_dir.path(), KvOpts::new().key_codec(Reversed))?;
+    assert_eq!(store.get("abc".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+fn tags(value: &str) -> Vec<String> {
+    value.split(',').map(str::to_owned).collect()
+}
+
+// Secondary indexes should follow every write to the primary keys.
+#[test]
+fn secondary_index() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("a".to_owned(), "red,small".to_owned())?;
+    store.set("b".to_owned(), "red".to_owned())?;
+
+    // Values written before the index is registered are indexed too.
+    store.register_index("tags", tags)?;
+    store.set("c".to_owned(), "blue,small".to_owned())?;
+    assert_eq!(store.query_index("tags", "red")?, vec!["a", "b"]);
+    assert_eq!(store.query_index("tags", "small")?, vec!["a", "c"]);
+
+    store.set("b".to_owned(), "blue".to_owned())?;
+    store.remove("a".to_owned())?;
+    assert!(store.query_index("tags", "red")?.is_empty());
+    assert_eq!(store.query_index("tags", "blue")?, vec!["b", "c"]);
+
+    assert!(store.query_index("colors", "red").is_err());
+    Ok(())
+}
+
+// Searching should find keys whose values contain every token of the term.
+#[test]
+fn token_search() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert!(store.search("disk").is_err());
+
+    store.set("log1".to_owned(), "Disk full on /var".to_owned())?;
+    store.register_token_index(kvs::tokenize)?;
+    store.set("log2".to_owned(), "disk healthy".to_owned())?;
+    store.set("log3".to_owned(), "network down".to_owned())?;
+
+    assert_eq!(store.search("DISK")?, vec!["log1", "log2"]);
+    assert_eq!(store.search("disk full")?, vec!["log1"]);
+    assert!(store.search("")?.is_empty());
+
+    store.remove("log1".to_owned())?;
+    assert_eq!(store.search("disk")?, vec!["log2"]);
+    Ok(())
+}
+
+// `kvs top` should print the store's statistics and hottest prefixes.
+#[test]
+fn cli_top() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("user:1".to_owned(), "alice".to_owned())?;
+    store.set("user:2".to_owned(), "bob".to_owned())?;
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["top", "--iterations", "2", "--interval", "0.01"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("keys                         2\n"))
+        .stdout(contains("+0.0/s"))
+        .stdout(contains("user:                                  2"));
+
+    // The store is watched while it is open elsewhere, without being
+    // touched, along with the operations the CLI published.
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["set", "user:3", "carol"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("user:4".to_owned(), "dave".to_owned())?;
+    let before = dir_contents(temp_dir.path());
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["top", "--iterations", "1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("sets                         1\n"))
+        .stdout(contains("keys                         3\n"));
+    assert_eq!(dir_contents(temp_dir.path()), before);
+    drop(store);
+
+    for interval in ["0", "-1", "NaN", "inf", "1e300"] {
+        Command::cargo_bin("kvs")
+            .unwrap()
+            .args([
+                "top",
+                "--iterations",
+                "1",
+                &format!("--interval={}", interval),
+            ])
+            .current_dir(&temp_dir)
+            .assert()
+            .failure()
+            .stderr(contains("greater than zero"));
+    }
+    Ok(())
+}
+
+// `kvs compact` should report what it did on stderr, unless told to be
+// quiet.
+#[test]
+fn cli_compact() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["compact"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty())
+        .stderr(contains("Compacted 39 B live, reclaimed 39 B"));
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["compact", "--quiet"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stderr(is_empty());
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// `kvs doctor` should report what is wrong around a store, with advice, and
+// fail only on what keeps the store from working.
+#[test]
+fn cli_doctor() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    std::fs::write(temp_dir.path().join("1.log.tmp"), "")?;
+
+    let checks = KvStore::diagnose(temp_dir.path());
+    let status = |name: &str| {
+        checks
+            .iter()
+            .find(|check| check.name == name)
+            .map(|check| check.status)
+    };
+    assert_eq!(status("permissions"), Some(CheckStatus::Ok));
+    assert_eq!(status("format"), Some(CheckStatus::Ok));
+    assert_eq!(status("lock"), Some(CheckStatus::Ok));
+    assert_eq!(status("temporary files"), Some(CheckStatus::Warning));
+    assert_eq!(status("clock"), Some(CheckStatus::Ok));
+
+    let _store = KvStore::open(temp_dir.path())?;
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["doctor"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("warning  lock: the store is open"))
+        .stdout(contains("ok       temporary files: none"));
+
+    let file = temp_dir.path().join("file");
+    std::fs::write(&file, "")?;
+    let checks = KvStore::diagnose(&file);
+    assert_eq!(checks.len(), 1);
+    assert_eq!(checks[0].status, CheckStatus::Error);
+    assert!(checks[0].advice.is_some());
+    Ok(())
+}
+
+// `kvs import` should set keys from JSON lines, changing them on the way in
+// as the flags or a mapping file ask.
+#[test]
+fn cli_import() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("old:user:1".to_owned(), "kept".to_owned())?;
+    drop(store);
+    let records = temp_dir.path().join("records.json");
+    std::fs::write(
+        &records,
+        r#"{"key": "user:1", "value": "alice"}
+{"key": "user:2", "value": "bob"}
+{"key": "session:1", "value": "x"}
+"#,
+    )?;
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args([
+            "import",
+            "records.json",
+            "--prefix",
+            "old:",
+            "--filter",
+            "^user:",
+        ])
+        .args(["--value-template", "{key}={value}"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stderr(contains(
+            "Imported 1 key(s), skipped 1 existing, filtered out 1",
+        ));
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("old:user:1".to_owned())?, Some("kept".to_owned()));
+    assert_eq!(
+        store.get("old:user:2".to_owned())?,
+        Some("user:2=bob".to_owned())
+    );
+    assert_eq!(store.get("old:session:1".to_owned())?, None);
+    drop(store);
+
+    // Flags win over the mapping file.
+    let mapping = temp_dir.path().join("mapping.json");
+    std::fs::write(&mapping, r#"{"prefix": "new:", "filter": "^session:"}"#)?;
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["import", "--mapping", "mapping.json", "--overwrite"])
+        .args(["--prefix", "old:"])
+        .current_dir(&temp_dir)
+        .with_stdin()
+        .buffer(std::fs::read(&records)?)
+        .assert()
+        .success();
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("old:session:1".to_owned())?, Some("x".to_owned()));
+    assert_eq!(store.get("new:session:1".to_owned())?, None);
+    assert_eq!(store.get("old:user:1".to_owned())?, Some("kept".to_owned()));
+    drop(store);
+
+    // Malformed input is not corruption of the store.
+    std::fs::write(
+        temp_dir.path().join("bad.json"),
+        "{\"key\": \"a\", \"value\": \"b\"}\nnot json\n",
+    )?;
+    std::fs::write(&mapping, r#"{"prefix": 1}"#)?;
+    for args in [
+        &["import", "bad.json"][..],
+        &["import", "records.json", "--mapping", "mapping.json"][..],
+    ] {
+        Command::cargo_bin("kvs")
+            .unwrap()
+            .args(args)
+            .current_dir(&temp_dir)
+            .assert()
+            .code(1)
+            .stderr(contains("Error: invalid input: ").and(contains("Serde(").not()));
+    }
+    Ok(())
+}
+
+// `kvs sample` should print the same live keys for the same seed, in key
+// order, as JSON lines of the keys' current values.
+#[test]
+fn cli_sample() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for i in 0..1000 {
+        store.set(format!("key{:04}", i), format!("value{}", i))?;
+    }
+    store.remove("key0500".to_owned())?;
+    let sample = store.sample(20, 7)?;
+    assert_eq!(sample.len(), 20);
+    assert!(sample.windows(2).all(|pair| pair[0].0 < pair[1].0));
+    assert!(sample.iter().all(|(key, _)| key != "key0500"));
+    assert_ne!(store.sample(20, 8)?, sample);
+    assert_eq!(store.sample(2000, 7)?.len(), 999);
+    assert!(store.sample(0, 7)?.is_empty());
+    drop(store);
+
+    let expected: String = KvStore::open(temp_dir.path())?
+        .sample(20, 7)?
+        .iter()
+        .map(|(key, value)| format!("{{\"key\":\"{}\",\"value\":\"{}\"}}\n", key, value))
+        .collect();
+    let mut reopened = KvStore::open(temp_dir.path())?;
+    assert_eq!(reopened.sample(20, 7)?, sample);
+    drop(reopened);
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["sample", "--count", "20", "--seed", "7"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(expected);
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["sample"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+    Ok(())
+}
+
+// `kvs export` should print the keys it is asked for as JSON lines that
+// `kvs import` reads back.
+#[test]
+fn cli_export() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key in ["a:1", "a:2", "b:1", "b:2", "c:1"] {
+        store.set(key.to_owned(), format!("value of {}", key))?;
+    }
+    let mut scan = store.scan_iter(ScanOpts::new().prefix("b:"));
+    assert_eq!(
+        scan.next().transpose()?,
+        Some(("b:1".to_owned(), "value of b:1".to_owned()))
+    );
+    assert_eq!(scan.count(), 1);
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["export", "--prefix", "a:"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(
+            "{\"key\":\"a:1\",\"value\":\"value of a:1\"}\n\
+             {\"key\":\"a:2\",\"value\":\"value of a:2\"}\n",
+        );
+
+    let output = Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["export", "--range", "a:2..c:"])
+        .current_dir(&temp_dir)
+        .output()
+        .expect("kvs export failed");
+    assert!(output.status.success());
+
+    let copy = temp_dir.path().join("copy");
+    std::fs::create_dir(&copy)?;
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["import"])
+        .current_dir(&copy)
+        .with_stdin()
+        .buffer(output.stdout)
+        .assert()
+        .success();
+    let mut copy = KvStore::open(&copy)?;
+    let keys: Vec<_> = copy.scan("")?.into_iter().map(|(key, _)| key).collect();
+    assert_eq!(keys, ["a:2", "b:1", "b:2"]);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["export", "--range", "a"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+    Ok(())
+}
+
+// `--dry-run` should report what a destructive command would do without
+// doing it, and `-v` should name every key it touches.
+#[test]
+fn cli_dry_run() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+    let kvs = |args: &[&str]| {
+        let mut cmd = Command::cargo_bin("kvs").unwrap();
+        cmd.args(args).current_dir(&temp_dir);
+        cmd
+    };
+
+    kvs(&["rm", "key1", "--dry-run", "-v"])
+        .assert()
+        .success()
+        .stderr(contains("  would remove key1\n"))
+        .stderr(contains("Would remove 1 key(s)"));
+    kvs(&["rm", "key3", "--dry-run"]).assert().code(2);
+    kvs(&["compact", "--dry-run"])
+        .assert()
+        .success()
+        .stderr(contains("Would compact 78 B live, reclaiming 39 B"));
+
+    std::fs::write(
+        temp_dir.path().join("records.json"),
+        r#"{"key": "key2", "value": "new"}
+{"key": "key3", "value": "new"}
+{"key": "key3", "value": "newer"}
+"#,
+    )?;
+    kvs(&["import", "records.json", "--dry-run", "-v"])
+        .assert()
+        .success()
+        .stderr(contains(
+            "  skipped key2\n  would set key3\n  skipped key3\n",
+        ))
+        .stderr(contains("Would import 1 key(s) (7 B), skip 2 existing"));
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, None);
+    assert_eq!(store.stats().stale_bytes, 39);
+    drop(store);
+
+    kvs(&["rm", "key1", "-v"])
+        .assert()
+        .success()
+        .stderr("  removed key1\n");
+    Ok(())
+}
+
+// Should report compaction progress in bytes, ending at the total.
+#[test]
+fn compaction_progress() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), "value".to_owned())?;
+    }
+
+    let mut updates = Vec::new();
+    store.compact_with_progress(|progress| updates.push(progress))?;
+    assert_eq!(updates.len(), 10);
+    assert!(updates.windows(2).all(|pair| pair[0].done < pair[1].done));
+    let last = updates.last().unwrap();
+    assert_eq!(last.done, last.total);
+    assert_eq!(last.total, store.stats().live_bytes);
+    Ok(())
+}
+
+// Should return the live keys under a prefix, and their values, in order.
+#[test]
+fn prefix_scan() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    store.set("user:2".to_owned(), "bob".to_owned())?;
+    store.set("user:1".to_owned(), "alice".to_owned())?;
+    store.set("user:3".to_owned(), "carol".to_owned())?;
+    store.set("group:1".to_owned(), "admins".to_owned())?;
+    store.remove("user:3".to_owned())?;
+
+    assert_eq!(
+        store.scan("user:")?,
+        vec![
+            ("user:1".to_owned(), "alice".to_owned()),
+            ("user:2".to_owned(), "bob".to_owned()),
+        ]
+    );
+    assert_eq!(store.scan("")?.len(), 3);
+    assert!(store.scan("none")?.is_empty());
+    Ok(())
+}
+
+// Should build a store from, extend it with, and read it back into, std
+// collections.
+#[test]
+fn std_collections() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let pairs = vec![
+        ("key1".to_owned(), "value1".to_owned()),
+        ("key2".to_owned(), "value2".to_owned()),
+    ];
+    let mut store = KvStore::from_iter_at(temp_dir.path(), pairs)?;
+    store.extend(vec![
+        ("key2".to_owned(), "updated".to_owned()),
+        ("key3".to_owned(), "value3".to_owned()),
+    ]);
+
+    let map = HashMap::try_from(&mut store)?;
+    let expected: HashMap<String, String> =
+        [("key1", "value1"), ("key2", "updated"), ("key3", "value3")]
+            .iter()
+            .map(|&(key, value)| (key.to_owned(), value.to_owned()))
+            .collect();
+    assert_eq!(map, expected);
+    Ok(())
+}
+
+// Should serialize the live keys, and nothing else, as a JSON object.
+#[test]
+fn serialize_live_state() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    store.remove("key3".to_owned())?;
+
+    let expected = serde_json::json!({"key1": "value1", "key2": "value2"});
+    assert_eq!(store.to_value()?, expected);
+    assert_eq!(
+        serde_json::to_string(&store.live_view())?,
+        r#"{"key1":"value1","key2":"value2"}"#
+    );
+    Ok(())
+}
+
+// Expired keys should behave as if they had been removed, across reopens.
+#[test]
+fn expire_and_persist() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.ttl("key1".to_owned())?, Some(Ttl::Persistent));
+    assert_eq!(store.ttl("key2".to_owned())?, None);
+
+    store.expire("key1".to_owned(), Duration::from_secs(100))?;
+    match store.ttl("key1".to_owned())? {
+        Some(Ttl::Expires(remaining)) => assert!(remaining <= Duration::from_secs(100)),
+        ttl => panic!("unexpected ttl: {:?}", ttl),
+    }
+    store.persist("key1".to_owned())?;
+    assert_eq!(store.ttl("key1".to_owned())?, Some(Ttl::Persistent));
+    assert!(store
+        .expire("key2".to_owned(), Duration::from_secs(1))
+        .is_err());
+    assert!(store.persist("key2".to_owned()).is_err());
+
+    store.set_with_ttl(
+        "key2".to_owned(),
+        "value2".to_owned(),
+        Duration::from_secs(0),
+    )?;
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert!(store.remove("key2".to_owned()).is_err());
+
+    store.set_with_ttl(
+        "key3".to_owned(),
+        "value3".to_owned(),
+        Duration::from_secs(100),
+    )?;
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    assert!(matches!(
+        store.ttl("key3".to_owned())?,
+        Some(Ttl::Expires(_))
+    ));
+
+    store.compact()?;
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert!(matches!(
+        store.ttl("key3".to_owned())?,
+        Some(Ttl::Expires(_))
+    ));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    Ok(())
+}
+
+// purge_expired should count expired keys as stale without any read in
+// between, and compact once they are enough for the compaction policy.
+#[test]
+fn purge_expired() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let clock = ManualClock::new(Duration::from_secs(1_000_000));
+    let opts = KvOpts::new()
+        .clock(clock.clone())
+        .compaction_policy(CompactionPolicy::StaleBytes(1 << 30));
+    let mut store = KvStore::open_with_opts(temp_dir.path(), opts)?;
+    for i in 0..10 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+        store.set_with_ttl(
+            format!("temp{}", i),
+            format!("value{}", i),
+            Duration::from_secs(60),
+        )?;
+    }
+    assert_eq!(store.purge_expired()?, 0);
+
+    clock.advance(Duration::from_secs(60));
+    let before = store.stats();
+    assert_eq!(before.keys, 20);
+    assert_eq!(store.purge_expired()?, 10);
+    let after = store.stats();
+    assert_eq!(after.keys, 10);
+    assert!(after.live_bytes < before.live_bytes);
+    assert_eq!(
+        after.stale_bytes - before.stale_bytes,
+        before.live_bytes - after.live_bytes
+    );
+    assert_eq!(store.purge_expired()?, 0);
+    drop(store);
+
+    // Nothing was written for the expired keys, and none come back.
+    let mut store = KvStore::open_with_opts(temp_dir.path(), KvOpts::new().clock(clock.clone()))?;
+    assert_eq!(store.len(), 10);
+    for i in 0..10 {
+        store.set_with_ttl(
+            format!("temp{}", i),
+            format!("value{}", i),
+            Duration::from_secs(60),
+        )?;
+    }
+    clock.advance(Duration::from_secs(60));
+    assert_eq!(store.purge_expired()?, 10);
+    assert_eq!(store.stats().stale_bytes, 0);
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// `kvs expire`, `kvs ttl` and `kvs persist` should manage a key's expiry.
+#[test]
+fn cli_expire_ttl_persist() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["ttl", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("No expiry").trim());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["expire", "key1", "100"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["ttl", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("9"));
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["persist", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["ttl", "key2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("Key not found").trim());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["expire", "key2", "1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(2)
+        .stderr(eq("Key not found").trim());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["expire", "key1", "soon"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    Ok(())
+}
+
+// In cache mode, the least recently used keys should be evicted to keep the
+// store's live bytes under budget.
+#[test]
+fn cache_mode_evicts_least_recently_used() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key0".to_owned(), "value0".to_owned())?;
+    let budget = store.stats().live_bytes * 3;
+    drop(store);
+
+    let mut store = KvStore::open_with_opts(temp_dir.path(), KvOpts::new().cache(budget))?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.stats().evictions, 0);
+
+    // `key0` was written first, but reading it makes `key1` the oldest.
+    assert_eq!(store.get("key0".to_owned())?, Some("value0".to_owned()));
+    store.set("key3".to_owned(), "value3".to_owned())?;
+
+    let stats = store.stats();
+    assert_eq!(stats.evictions, 1);
+    assert_eq!(stats.keys, 3);
+    assert!(stats.live_bytes <= budget);
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key0".to_owned())?, Some("value0".to_owned()));
+    drop(store);
+
+    // Evictions are written down, so they survive a restart.
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.stats().keys, 3);
+    Ok(())
+}
+
+// Stores opened with the same budget should share its open files and live
+// bytes between them.
+#[test]
+fn shared_budget() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path();
+
+    // Every session leaves one more segment behind.
+    for i in 0..4 {
+        let mut store = KvStore::open(path)?;
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    let budget = Budget::new().max_open_files(2);
+    let mut store = KvStore::open_with_opts(path, KvOpts::new().budget(budget.clone()))?;
+    for i in 0..4 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+        assert!(budget.open_files() <= 2);
+    }
+    assert_eq!(store.get("key0".to_owned())?, Some("value0".to_owned()));
+    assert_eq!(budget.open_files(), 2);
+    drop(store);
+    assert_eq!(budget.open_files(), 0);
+
+    let first = temp_dir.path().join("first");
+    let second = temp_dir.path().join("second");
+    std::fs::create_dir(&second)?;
+    let mut store = KvStore::open(&first)?;
+    store.set("key0".to_owned(), "value0".to_owned())?;
+    let max = store.stats().live_bytes * 3;
+    drop(store);
+
+    // Either store is under budget on its own, but not both together.
+    let budget = Budget::new().max_cache_bytes(max);
+    let opts = KvOpts::new().budget(budget.clone());
+    let mut first = KvStore::open_with_opts(&first, opts.clone())?;
+    let mut second = KvStore::open_with_opts(&second, opts)?;
+    first.set("key1".to_owned(), "value1".to_owned())?;
+    second.set("key0".to_owned(), "value0".to_owned())?;
+    assert_eq!(budget.cache_bytes(), max);
+    second.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(budget.cache_bytes() <= max);
+    assert_eq!(first.stats().evictions, 0);
+    assert_eq!(second.stats().evictions, 1);
+    assert_eq!(second.get("key0".to_owned())?, None);
+    drop(second);
+    assert_eq!(budget.cache_bytes(), first.stats().live_bytes);
+    drop(first);
+    assert_eq!(budget.cache_bytes(), 0);
+    Ok(())
+}
+
+// Analyzing a store should summarize a sample of its keys.
+#[test]
+fn analyze_key_space() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for i in 0..30 {
+        store.set(format!("user:{}", i), "x".repeat(100))?;
+    }
+    for i in 0..10 {
+        let key = format!("session:{}", i);
+        store.set_with_ttl(key, "y".to_owned(), Duration::from_secs(600))?;
+    }
+    store.set("plain".to_owned(), "z".to_owned())?;
+
+    let analysis = store.analyze(1000)?;
+    assert_eq!(analysis.keys, 41);
+    assert_eq!(analysis.sampled, 41);
+    assert_eq!(analysis.prefixes[0].prefix, "user:");
+    assert_eq!(analysis.prefixes[0].count, 30);
+    assert_eq!(analysis.prefixes[1].prefix, "session:");
+    assert_eq!(analysis.prefixes[2].prefix, "");
+    assert_eq!(analysis.ttls.persistent, 31);
+    assert_eq!(analysis.ttls.under_an_hour, 10);
+    let large: u64 = analysis
+        .value_sizes
+        .iter()
+        .filter(|bucket| bucket.max == 128)
+        .map(|bucket| bucket.count)
+        .sum();
+    assert_eq!(large, 30);
+
+    let analysis = store.analyze(5)?;
+    assert_eq!(analysis.keys, 41);
+    assert_eq!(analysis.sampled, 5);
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["analyze", "--sample", "10"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("\"sampled\": 10"));
+    Ok(())
+}
+
+// Size estimates should be exact for small stores, and close for large ones.
+#[test]
+fn estimate_size() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for i in 0..100 {
+        store.set(format!("user:{:04}", i), "x".repeat(100))?;
+    }
+    let users = store.estimate_size("user:");
+    assert!(users.exact);
+    assert_eq!(users.keys, 100);
+    assert_eq!(users.bytes, store.stats().live_bytes);
+    assert_eq!(store.estimate_size("item:").keys, 0);
+
+    for i in 0..3900 {
+        store.set(format!("item:{:04}", i), "y".to_owned())?;
+    }
+    store.set_with_ttl(
+        "item:expired".to_owned(),
+        "z".to_owned(),
+        Duration::from_millis(1),
+    )?;
+    std::thread::sleep(Duration::from_millis(5));
+
+    let users = store.estimate_size("user:");
+    assert!(!users.exact);
+    assert_eq!(users.sampled, 1000);
+    assert!((0..400).contains(&users.keys));
+    let items = store.estimate_size(KeySpan::range("item:".."item:2000"));
+    assert!((1500..2500).contains(&items.keys), "{:?}", items);
+    let everything = store.estimate_size("");
+    assert!((3900..=4001).contains(&everything.keys), "{:?}", everything);
+    Ok(())
+}
+
+// The smallest, largest and a random key should come straight from the
+// index, skipping expired keys.
+#[test]
+fn key_accessors() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.first_key_in(""), None);
+    assert_eq!(store.random_key(), None);
+
+    for key in &["b", "c:1", "c:2", "c:3", "d"] {
+        store.set(key.to_string(), "value".to_owned())?;
+    }
+    store.set_with_ttl("a".to_owned(), "value".to_owned(), Duration::from_millis(1))?;
+    std::thread::sleep(Duration::from_millis(5));
+
+    assert_eq!(store.first_key_in(""), Some("b".to_owned()));
+    assert_eq!(store.last_key_in(""), Some("d".to_owned()));
+    assert_eq!(store.first_key_in("c:"), Some("c:1".to_owned()));
+    assert_eq!(store.last_key_in("c:"), Some("c:3".to_owned()));
+    assert_eq!(store.first_key_in("e"), None);
+    assert_eq!(
+        store.last_key_in(KeySpan::range("b".."c:3")),
+        Some("c:2".to_owned())
+    );
+    assert_eq!(
+        store.first_key_in(KeySpan::range("c:1"..="c:9")),
+        Some("c:1".to_owned())
+    );
+
+    let mut seen = HashSet::new();
+    for _ in 0..200 {
+        seen.insert(store.random_key().expect("the store is not empty"));
+    }
+    assert_eq!(seen.len(), 5);
+    assert!(!seen.contains("a"));
+    Ok(())
+}
+
+// Scans should combine a prefix, bounds, a limit and either order.
+#[test]
+fn bounded_scan() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for i in 0..20 {
+        store.set(format!("item:{:02}", i), format!("value{}", i))?;
+    }
+    store.set("other".to_owned(), "value".to_owned())?;
+    let keys = |entries: Vec<(String, String)>| -> Vec<String> {
+        entries.into_iter().map(|(key, _)| key).collect()
+    };
+
+    let latest = store.scan_with(ScanOpts::new().prefix("item:").reverse(true).limit(3))?;
+    assert_eq!(keys(latest), ["item:19", "item:18", "item:17"]);
+    let first = store.scan_with(ScanOpts::new().limit(2))?;
+    assert_eq!(keys(first), ["item:00", "item:01"]);
+    let window = store.scan_with(ScanOpts::new().from("item:05").to("item:08"))?;
+    assert_eq!(
+        window,
+        [
+            ("item:05".to_owned(), "value5".to_owned()),
+            ("item:06".to_owned(), "value6".to_owned()),
+            ("item:07".to_owned(), "value7".to_owned()),
+        ]
+    );
+    let window = store.scan_with(
+        ScanOpts::new()
+            .prefix("item:")
+            .from("item:05")
+            .to("item:08")
+            .reverse(true)
+            .limit(2),
+    )?;
+    assert_eq!(keys(window), ["item:07", "item:06"]);
+    assert_eq!(store.scan_with(ScanOpts::new().limit(0))?, []);
+    assert_eq!(store.scan_with(ScanOpts::new().from("z"))?, []);
+    assert_eq!(store.scan_with(ScanOpts::new().limit(100))?.len(), 21);
+    assert_eq!(
+        store.scan_with(ScanOpts::new().prefix("item:"))?,
+        store.scan("item:")?
+    );
+    Ok(())
+}
+
+// Compacting with direct I/O should produce the same store as without it.
+#[test]
+fn compaction_direct_io() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let opts = || KvOpts::new().compaction_direct_io(true);
+    let mut store = KvStore::open_with_opts(temp_dir.path(), opts())?;
+    for i in 0..2000 {
+        store.set(format!("key{}", i % 500), format!("value{}", i))?;
+    }
+    store.set("large".to_owned(), "v".repeat(20_000))?;
+    store.compact()?;
+    assert_eq!(store.get("key0".to_owned())?, Some("value1500".to_owned()));
+    drop(store);
+
+    let opts_full = opts().verify_on_open(Verify::Full);
+    let mut store = KvStore::open_with_opts(temp_dir.path(), opts_full)?;
+    assert_eq!(store.damaged_blocks(), 0);
+    assert_eq!(store.scan("key")?.len(), 500);
+    assert_eq!(
+        store.get("key499".to_owned())?,
+        Some("value1999".to_owned())
+    );
+    assert_eq!(store.get("large".to_owned())?, Some("v".repeat(20_000)));
+    drop(store);
+
+    let model = temp_dir.path().join("model");
+    std::fs::create_dir(&model)?;
+    kvs::testing::check_model(&model, opts(), 0, 400)?;
+    Ok(())
+}
+
+// Generated workloads, crashes included, should never make the store
+// disagree with the reference model.
+#[test]
+fn model_based_workloads() -> Result<()> {
+    for seed in 0..8 {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        kvs::testing::check_model(temp_dir.path(), KvOpts::new(), seed, 400)?;
+    }
+    Ok(())
+}
+
+// Parsing damaged or truncated logs should fail gracefully, never panic.
+#[test]
+fn fuzz_log_parser() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for i in 0..200 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.compact()?;
+    drop(store);
+
+    for entry in WalkDir::new(temp_dir.path()) {
+        let path = entry.unwrap().into_path();
+        if path.extension().and_then(|ext| ext.to_str()) != Some("log") {
+            continue;
+        }
+        let data = std::fs::read(&path)?;
+        kvs::testing::fuzz_log(&data);
+        for cut in (0..data.len()).step_by(611) {
+            kvs::testing::fuzz_log(&data[..cut]);
+            let mut damaged = data.clone();
+            damaged[cut] ^= 0x5a;
+            kvs::testing::fuzz_log(&damaged);
+        }
+    }
+    Ok(())
+}
+
+// Short writes should be retried until the whole command is written.
+#[test]
+fn survive_short_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let fs = FaultyFs::new();
+    let mut store = KvStore::open_with_opts(temp_dir.path(), KvOpts::new().fs(fs.clone()))?;
+    for n in 1..=20 {
+        fs.inject(Fault::ShortWrite, n);
+    }
+    for i in 0..10 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    for i in 0..10 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    Ok(())
+}
+
+// A write that fails because the disk is full should leave no trace, and
+// should not get in the way of the writes that follow once there is room.
+#[test]
+fn survive_full_disk() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let fs = FaultyFs::new();
+    let mut store = KvStore::open_with_opts(temp_dir.path(), KvOpts::new().fs(fs.clone()))?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    fs.inject(Fault::NoSpace, 1);
+    assert!(store.set("key2".to_owned(), "value2".to_owned()).is_err());
+    assert!(store.set("key3".to_owned(), "value3".to_owned()).is_err());
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    fs.clear();
+    store.set("key4".to_owned(), "value4".to_owned())?;
+    // Crashing leaves `key4` in the write-ahead log only, right behind the
+    // command the full disk cut short.
+    kvs::testing::crash(store, temp_dir.path(), CrashPoint::BetweenOps)?;
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, None);
+    assert_eq!(store.get("key4".to_owned())?, Some("value4".to_owned()));
+    Ok(())
+}
+
+// A write whose fsync fails should be reported, and not recovered later.
+#[test]
+fn survive_sync_failure() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let fs = FaultyFs::new();
+    let opts = KvOpts::new().sync(true).fs(fs.clone());
+    let mut store = KvStore::open_with_opts(temp_dir.path(), opts)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    fs.inject(Fault::SyncFailure, 1);
+    assert!(store.set("key2".to_owned(), "value2".to_owned()).is_err());
+    assert!(fs.syncs() > 0);
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// Randomized workloads on a misbehaving file system should never lose an
+// acknowledged write, however often the store crashes.
+#[test]
+fn deterministic_simulation() -> Result<()> {
+    let mut failed_ops = 0;
+    for seed in 0..8 {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let report = kvs::testing::simulate(temp_dir.path(), seed, 500)?;
+        assert_eq!(report.steps, 500);
+        failed_ops += report.failed_ops;
+    }
+    assert!(failed_ops > 0);
+    Ok(())
+}
+
+// Keys should expire, and the store should be stamped, by the store's clock.
+#[test]
+fn manual_clock() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let clock = ManualClock::new(Duration::from_secs(1_000_000));
+    let opts = KvOpts::new().clock(clock.clone());
+    let mut store = KvStore::open_with_opts(temp_dir.path(), opts.clone())?;
+    assert_eq!(store.info().created, 1_000_000);
+
+    store.set_with_ttl(
+        "key1".to_owned(),
+        "value1".to_owned(),
+        Duration::from_secs(10),
+    )?;
+    clock.advance(Duration::from_secs(9));
+    assert_eq!(
+        store.ttl("key1".to_owned())?,
+        Some(Ttl::Expires(Duration::from_secs(1)))
+    );
+    drop(store);
+
+    let mut store = KvStore::open_with_opts(temp_dir.path(), opts)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    clock.advance(Duration::from_secs(1));
+    assert_eq!(store.get("key1".to_owned())?, None);
+    Ok(())
+}
+
+// A removed key should be restorable within the soft delete window, across
+// reopens and compactions, and hard deleted by the first compaction after it.
+#[test]
+fn soft_delete() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let clock = ManualClock::new(Duration::from_secs(1_000_000));
+    let opts = KvOpts::new()
+        .clock(clock.clone())
+        .soft_delete(Duration::from_secs(60));
+    let mut store = KvStore::open_with_opts(temp_dir.path(), opts)?;
+    store.set_with_ttl(
+        "key1".to_owned(),
+        "value1".to_owned(),
+        Duration::from_secs(3600),
+    )?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert!(!store.remove_if_exists("key1".to_owned())?);
+    assert_eq!(store.stats().soft_deleted, 1);
+
+    store.undelete("key1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(matches!(
+        store.ttl("key1".to_owned())?,
+        Some(Ttl::Expires(_))
+    ));
+    assert!(matches!(
+        store.undelete("key1".to_owned()),
+        Err(KvsError::KeyNotFound(_))
+    ));
+    store.remove("key1".to_owned())?;
+    store.remove("key2".to_owned())?;
+    drop(store);
+
+    // The window was recorded when the store was created.
+    let opts = KvOpts::new()
+        .clock(clock.clone())
+        .verify_on_open(Verify::Full);
+    let mut store = KvStore::open_with_opts(temp_dir.path(), opts.clone())?;
+    assert_eq!(store.stats().soft_deleted, 2);
+    store.compact()?;
+    drop(store);
+    let mut store = KvStore::open_with_opts(temp_dir.path(), opts.clone())?;
+    assert_eq!(store.get("key2".to_owned())?, None);
+    store.undelete("key2".to_owned())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    clock.advance(Duration::from_secs(60));
+    assert!(matches!(
+        store.undelete("key1".to_owned()),
+        Err(KvsError::KeyNotFound(_))
+    ));
+    store.compact()?;
+    assert_eq!(store.stats().soft_deleted, 0);
+    assert_eq!(store.stats().stale_bytes, 0);
+    drop(store);
+
+    let store = KvStore::open_with_opts(temp_dir.path(), opts)?;
+    assert_eq!(store.stats().keys, 1);
+    assert_eq!(store.stats().soft_deleted, 0);
+    Ok(())
+}
+
+// Keys under a write-once prefix should be settable exactly once, on every
+// later open too, while every other key can still be overwritten.
+#[test]
+fn write_once() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let clock = ManualClock::new(Duration::from_secs(1_000_000));
+    let opts = KvOpts::new().clock(clock.clone()).write_once("audit/");
+    let mut store = KvStore::open_with_opts(temp_dir.path(), opts)?;
+    store.set("audit/1".to_owned(), "created".to_owned())?;
+    store.set("other".to_owned(), "value1".to_owned())?;
+    store.set("other".to_owned(), "value2".to_owned())?;
+    assert!(matches!(
+        store.set("audit/1".to_owned(), "changed".to_owned()),
+        Err(KvsError::WriteOnce(_))
+    ));
+    assert!(matches!(
+        store.remove("audit/2".to_owned()),
+        Err(KvsError::KeyNotFound(_))
+    ));
+    store.set_with_ttl(
+        "audit/2".to_owned(),
+        "short-lived".to_owned(),
+        Duration::from_secs(10),
+    )?;
+    drop(store);
+
+    // The prefix was recorded when the store was created.
+    let mut store = KvStore::open_with_opts(temp_dir.path(), KvOpts::new().clock(clock.clone()))?;
+    for result in [
+        store.remove("audit/1".to_owned()),
+        store.remove_if_exists("audit/1".to_owned()).map(|_| ()),
+        store.expire("audit/1".to_owned(), Duration::from_secs(1)),
+        store.persist("audit/2".to_owned()),
+    ] {
+        assert!(matches!(result, Err(KvsError::WriteOnce(_))));
+    }
+    assert_eq!(store.get("audit/1".to_owned())?, Some("created".to_owned()));
+
+    // An expired key no longer exists, and so can be set again.
+    clock.advance(Duration::from_secs(10));
+    store.set("audit/2".to_owned(), "again".to_owned())?;
+    assert_eq!(store.get("audit/2".to_owned())?, Some("again".to_owned()));
+    Ok(())
+}
+
+// `put_cas` should file values under their digest, and store identical values
+// only once.
+#[test]
+fn put_cas() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    let key = store.put_cas("abc".to_owned())?;
+    assert_eq!(
+        key,
+        "sha256:ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+    );
+    assert_eq!(
+        store.put_cas(String::new())?,
+        "sha256:e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+    );
+    // Long enough for the length to spill over into a block of its own.
+    assert_eq!(
+        store.put_cas("abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq".to_owned())?,
+        "sha256:248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1"
+    );
+
+    let stats = store.stats();
+    assert_eq!(store.put_cas("abc".to_owned())?, key);
+    assert_eq!(store.stats(), stats);
+    assert_eq!(store.get(key.clone())?, Some("abc".to_owned()));
+
+    // A different value under the same key can only be a collision.
+    store.set(key.clone(), "other".to_owned())?;
+    assert!(matches!(
+        store.put_cas("abc".to_owned()),
+        Err(KvsError::HashCollision(_))
+    ));
+    drop(store);
+
+    let opts = KvOpts::new().cas_hash(CasHash::Fnv1a64);
+    let mut store = KvStore::open_with_opts(temp_dir.path(), opts)?;
+    assert_eq!(store.put_cas("a".to_owned())?, "fnv1a64:af63dc4c8601ec8c");
+    Ok(())
+}
+
+// The reclaim forecast should count the stale bytes there are now, plus the
+// keys that expire, and the soft deletes that lapse, by each horizon.
+#[test]
+fn reclaim_forecast() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let clock = ManualClock::new(Duration::from_secs(1_000_000));
+    let opts = KvOpts::new()
+        .clock(clock.clone())
+        .soft_delete(Duration::from_secs(2 * 24 * 3600));
+    let mut store = KvStore::open_with_opts(temp_dir.path(), opts)?;
+    let live = |store: &KvStore| store.stats().live_bytes;
+
+    store.set("stale".to_owned(), "value".to_owned())?;
+    store.set("stale".to_owned(), "value".to_owned())?;
+    let stale = store.stats().stale_bytes;
+    let before = live(&store);
+    store.set_with_ttl("minute".to_owned(), "v".to_owned(), Duration::from_secs(30))?;
+    let minute = live(&store) - before;
+    let before = live(&store);
+    store.set_with_ttl("hour".to_owned(), "v".to_owned(), Duration::from_secs(1800))?;
+    let hour = live(&store) - before;
+    let before = live(&store);
+    store.set("deleted".to_owned(), "v".to_owned())?;
+    let deleted = live(&store) - before;
+    let before = store.stats().stale_bytes;
+    store.remove("deleted".to_owned())?;
+    assert_eq!(store.stats().stale_bytes, before);
+
+    let forecast = store.stats().reclaim_forecast;
+    assert_eq!(forecast.in_a_minute, stale + minute);
+    assert_eq!(forecast.in_an_hour, stale + minute + hour);
+    assert_eq!(forecast.in_a_day, stale + minute + hour);
+    assert!(forecast.in_a_week > stale + minute + hour + deleted);
+
+    // Once everything has lapsed, it is all reclaimable straight away.
+    clock.advance(Duration::from_secs(3 * 24 * 3600));
+    let forecast = store.stats().reclaim_forecast;
+    assert_eq!(forecast.in_a_minute, forecast.in_a_week);
+    store.compact()?;
+    assert_eq!(store.stats().keys, 1);
+    assert_eq!(store.stats().reclaim_forecast, ReclaimForecast::default());
+    Ok(())
+}
+
+// Secondary indexes should behave the same whichever hasher they are built
+// with, and the hasher should be free to change between opens.
+#[test]
+fn index_hasher() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let opts = KvOpts::new().index_hasher(IndexHasher::Fx);
+    let mut store = KvStore::open_with_opts(temp_dir.path(), opts.clone())?;
+    for i in 0..1000 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    for i in 0..1000 {
+        store.set(format!("key{}", i), format!("value{}", i + 1))?;
+    }
+    store.remove("key0".to_owned())?;
+    let live_bytes = store.stats().live_bytes;
+    drop(store);
+
+    for hasher in [IndexHasher::Fx, IndexHasher::SipHash] {
+        let opts = KvOpts::new().index_hasher(hasher);
+        let mut store = KvStore::open_with_opts(temp_dir.path(), opts)?;
+        assert_eq!(store.stats().keys, 999);
+        assert_eq!(store.stats().live_bytes, live_bytes);
+        assert_eq!(store.get("key0".to_owned())?, None);
+        store.register_index("tags", tags)?;
+        assert!(store.query_index("tags", "value1")?.is_empty());
+        assert_eq!(store.query_index("tags", "value2")?, ["key1"]);
+        store.register_token_index(kvs::tokenize)?;
+        assert_eq!(store.search("value1000")?, ["key999"]);
+        for i in 1..1000 {
+            assert_eq!(
+                store.get(format!("key{}", i))?,
+                Some(format!("value{}", i + 1))
+            );
+        }
+    }
+    Ok(())
+}
+
+// `kvs list-stores` should find every store beneath a directory, without
+// looking inside the stores it finds.
+#[test]
+fn cli_list_stores() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    for dir in ["a", "b/c", "b/c/d", "e", "f"] {
+        std::fs::create_dir_all(temp_dir.path().join(dir))?;
+    }
+    KvStore::open(temp_dir.path().join("b/c/d"))?;
+    KvStore::open(temp_dir.path().join("b/c"))?;
+    KvStore::open(temp_dir.path().join("a"))?;
+    std::fs::write(temp_dir.path().join("e/kvs.meta"), "not metadata")?;
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["list-stores"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("./a\n./b/c\n"));
+    assert_eq!(
+        KvStore::discover(temp_dir.path().join("b"))?,
+        vec![temp_dir.path().join("b/c")]
+    );
+    Ok(())
+}
+
+// `kvs copy --compact` should leave a compacted copy of a store that is not
+// open, without changing a single byte of the original.
+#[test]
+fn cli_copy() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let src = temp_dir.path().join("src");
+    std::fs::create_dir(&src)?;
+    let mut store = KvStore::open(&src)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    store.remove("key3".to_owned())?;
+
+    // An open store cannot be copied.
+    assert!(matches!(
+        KvStore::copy(&src, temp_dir.path().join("locked")),
+        Err(KvsError::StoreLocked(_))
+    ));
+    drop(store);
+    let contents = |dir: &std::path::Path| -> Vec<(std::path::PathBuf, Vec<u8>)> {
+        let mut files: Vec<_> = WalkDir::new(dir)
+            .into_iter()
+            .map(|entry| entry.unwrap().into_path())
+            .filter(|path| path.is_file())
+            .map(|path| {
+                let buf = std::fs::read(&path).unwrap();
+                (path, buf)
+            })
+            .collect();
+        files.sort();
+        files
+    };
+    let before = contents(&src);
+
+    let kvs = |args: &[&str]| {
+        let mut cmd = Command::cargo_bin("kvs").unwrap();
+        cmd.args(args).current_dir(&temp_dir);
+        cmd
+    };
+    kvs(&["copy", "src", "dst", "--compact"])
+        .assert()
+        .success()
+        .stderr(contains("Copied 2 key(s)"))
+        .stderr(contains(", 0 B stale"));
+    kvs(&["copy", "src", "dst"]).assert().failure();
+    assert_eq!(contents(&src), before);
+
+    let opts = KvOpts::new().verify_on_open(Verify::Full);
+    let mut copy = KvStore::open_with_opts(temp_dir.path().join("dst"), opts)?;
+    assert_eq!(copy.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(copy.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(copy.get("key3".to_owned())?, None);
+    assert_eq!(copy.stats().stale_bytes, 0);
+    Ok(())
+}
+
+// `kvs compact --offline DIR` should compact the store in DIR, but never
+// one that is open.
+#[test]
+fn cli_compact_offline() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup = temp_dir.path().join("backup");
+    std::fs::create_dir(&backup)?;
+    let mut store = KvStore::open(&backup)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    let kvs = |args: &[&str]| {
+        let mut cmd = Command::cargo_bin("kvs").unwrap();
+        cmd.args(args).current_dir(&temp_dir);
+        cmd
+    };
+
+    kvs(&["compact", "--offline", "backup"])
+        .assert()
+        .failure()
+        .stderr(contains("is already open"));
+    drop(store);
+    kvs(&["compact", "--offline", "backup"])
+        .assert()
+        .success()
+        .stderr(contains("Compacted 39 B live, reclaimed 39 B"));
+
+    let mut store = KvStore::open(&backup)?;
+    assert_eq!(store.stats().stale_bytes, 0);
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert!(!temp_dir.path().join("kvs.meta").exists());
+
+    // A directory without a store is refused, rather than made into one.
+    std::fs::create_dir(temp_dir.path().join("empty"))?;
+    for dir in ["missing", "empty"] {
+        kvs(&["compact", "--offline", dir])
+            .assert()
+            .failure()
+            .stderr(contains("no store at"));
+    }
+    assert!(!temp_dir.path().join("missing").exists());
+    assert_eq!(std::fs::read_dir(temp_dir.path().join("empty"))?.count(), 0);
+    Ok(())
+}
+
+// Every write should reach the write-ahead log in a single write, the log's
+// header included, until the pending commands are written to their segment.
+#[test]
+fn set_single_write() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let fs = FaultyFs::new();
+    let mut store = KvStore::open_with_opts(temp_dir.path(), KvOpts::new().fs(fs.clone()))?;
+    let writes = fs.writes();
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.remove("key0".to_owned())?;
+    assert_eq!(fs.writes() - writes, 101);
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    assert_eq!(store.get("key99".to_owned())?, Some("value99".to_owned()));
+    Ok(())
+}
+
+// Stats should account for the bytes written and read, and the syncs,
+// alongside the bytes of the keys and values behind them.
+#[test]
+fn io_stats() -> Result<()> {
+    let fs = MemFs::new();
+    let opts = KvOpts::new().fs(fs.clone()).sync(true);
+    let mut store = KvStore::open_with_opts("/store", opts.clone())?;
+    for i in 0..100 {
+        store.set(format!("key{:02}", i), "v".repeat(100))?;
+    }
+    store.remove("key00".to_owned())?;
+    let io = store.stats().io;
+    assert_eq!(io.logical_bytes_written, 100 * (5 + 100) + 5);
+    assert!(io.write_amplification().unwrap() > 1.0);
+    assert!(io.syncs >= 101);
+    assert_eq!(io.read_bytes_per_get(), None);
+
+    // Values that have not reached their segment are read from memory.
+    assert_eq!(store.get("key01".to_owned())?, Some("v".repeat(100)));
+    assert_eq!(store.stats().io.get_bytes_read, 0);
+    store.compact()?;
+    assert!(store.stats().io.bytes_read > 0);
+    drop(store);
+
+    let mut store = KvStore::open_with_opts("/store", opts)?;
+    assert_eq!(store.stats().io.logical_bytes_written, 0);
+    assert_eq!(store.get("key01".to_owned())?, Some("v".repeat(100)));
+    assert_eq!(store.get("key00".to_owned())?, None);
+    let io = store.stats().io;
+    assert_eq!(io.gets, 2);
+    assert!(io.get_bytes_read >= 100);
+    assert_eq!(
+        io.read_bytes_per_get(),
+        Some(io.get_bytes_read as f64 / 2.0)
+    );
+    Ok(())
+}
+
+// Returns every file beneath `dir`, along with its contents.
+fn dir_contents(dir: &Path) -> Vec<(PathBuf, Vec<u8>)> {
+    WalkDir::new(dir)
+        .sort_by_file_name()
+        .into_iter()
+        .map(|entry| entry.expect("unable to walk the directory").into_path())
+        .filter(|path| path.is_file())
+        .map(|path| {
+            let contents = std::fs::read(&path).expect("unable to read a file");
+            (path, contents)
+        })
+        .collect()
+}
+
+// A read-only store should open alongside the process that holds the lock,
+// refuse every write, and leave every file as it was.
+#[test]
+fn read_only() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let opts = KvOpts::new().read_only(true);
+    assert!(KvStore::open_with_opts(temp_dir.path().join("missing"), opts.clone()).is_err());
+    assert!(!temp_dir.path().join("missing").exists());
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    for i in 0..10 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key10".to_owned(), "value10".to_owned())?;
+    let before = dir_contents(temp_dir.path());
+
+    let mut reader = KvStore::open_with_opts(temp_dir.path(), opts)?;
+    assert_eq!(reader.stats().keys, 10);
+    assert_eq!(reader.get("key3".to_owned())?, Some("value3".to_owned()));
+    // Commands that are only in the write-ahead log are not seen.
+    assert_eq!(reader.get("key10".to_owned())?, None);
+    assert!(matches!(
+        reader.set("key1".to_owned(), "other".to_owned()),
+        Err(KvsError::ReadOnly(_))
+    ));
+    assert!(matches!(
+        reader.remove("key1".to_owned()),
+        Err(KvsError::ReadOnly(_))
+    ));
+    assert!(matches!(reader.compact(), Err(KvsError::ReadOnly(_))));
+    assert_eq!(reader.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(reader);
+    assert_eq!(dir_contents(temp_dir.path()), before);
+
+    store.set("key11".to_owned(), "value11".to_owned())?;
+    assert_eq!(store.get("key10".to_owned())?, Some("value10".to_owned()));
+    Ok(())
+}
+
+// A store should publish how many sets, gets and removes it served, carrying
+// on from one open to the next, only when asked to.
+#[test]
+fn publish_stats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    assert_eq!(KvStore::validate(temp_dir.path())?.ops, None);
+
+    let opts = KvOpts::new().publish_stats(Duration::ZERO);
+    let mut store = KvStore::open_with_opts(temp_dir.path(), opts.clone())?;
+    for i in 0..3 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.get("key1".to_owned())?;
+    store.get("key9".to_owned())?;
+    store.remove("key2".to_owned())?;
+    let io = store.stats().io;
+    assert_eq!((io.sets, io.gets, io.removes), (3, 2, 1));
+    let published = OpCounts {
+        sets: 3,
+        gets: 2,
+        removes: 1,
+    };
+    assert_eq!(KvStore::validate(temp_dir.path())?.ops, Some(published));
+    drop(store);
+
+    let mut store = KvStore::open_with_opts(temp_dir.path(), opts)?;
+    assert!(store.unexpected_files().is_empty());
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    assert_eq!(store.stats().io.sets, 1);
+    drop(store);
+    let ops = KvStore::validate(temp_dir.path())?.ops.unwrap();
+    assert_eq!((ops.sets, ops.gets, ops.removes), (4, 2, 1));
+    Ok(())
+}
+
+// Overwrites every key of a store, `spacing` apart, and returns the ratio of stale to live bytes just before the first
+// compaction.
+fn ratio_at_compaction<F: Fs + 'static>(
+    fs: F,
+    policy: AdaptiveCompaction,
+    spacing: Duration,
+) -> Result<Option<f64>> {
+    let clock = ManualClock::new(Duration::from_secs(1_000_000));
+    let opts = KvOpts::new()
+        .fs(fs)
+        .clock(clock.clone())
+        .compaction_policy(CompactionPolicy::Adaptive(policy));
+    let mut store = KvStore::open_with_opts("/store", opts)?;
+    for i in 0..100 {
+        store.set(format!("key{:02}", i), "v".repeat(100))?;
+    }
+    clock.advance(Duration::from_secs(3600));
+    for i in 0..100 {
+        let before = store.stats();
+        store.set(format!("key{:02}", i), "w".repeat(100))?;
+        if store.stats().stale_bytes < before.stale_bytes {
+            return Ok(Some(before.stale_bytes as f64 / before.live_bytes as f64));
+        }
+        clock.advance(spacing);
+    }
+    Ok(None)
+}
+
+// An adaptive policy should compact past its ratio of stale to live bytes,
+// later while the store is rewritten quickly, and never below its floor.
+#[test]
+fn adaptive_compaction() -> Result<()> {
+    let policy = AdaptiveCompaction::new().min_stale_bytes(0);
+    let hour = Duration::from_secs(3600);
+    let calm = ratio_at_compaction(MemFs::new(), policy, hour)?.unwrap();
+    assert!((0.49..0.55).contains(&calm), "{}", calm);
+    let churned = ratio_at_compaction(MemFs::new(), policy, Duration::ZERO)?.unwrap();
+    assert!(churned > calm + 0.2, "{} vs {}", churned, calm);
+
+    // Short on space, only the floor is left.
+    let floored = policy.min_stale_bytes(2000);
+    let full = ratio_at_compaction(FullFs(MemFs::new()), floored, hour)?.unwrap();
+    assert!(full < 0.2, "{}", full);
+    let floored = policy.min_stale_bytes(1 << 20);
+    assert_eq!(
+        ratio_at_compaction(FullFs(MemFs::new()), floored, hour)?,
+        None
+    );
+    Ok(())
+}
+
+/// A `MemFs` on a disk with no space left.
+#[derive(Debug)]
+struct FullFs(MemFs);
+
+impl Fs for FullFs {
+    fn open(&self, path: &Path, mode: OpenMode) -> std::io::Result<Box<dyn FsFile>> {
+        self.0.open(path, mode)
+    }
+
+    fn read_dir(&self, dir: &Path) -> std::io::Result<Vec<PathBuf>> {
+        self.0.read_dir(dir)
+    }
+
+    fn subdirs(&self, dir: &Path) -> std::io::Result<Vec<PathBuf>> {
+        self.0.subdirs(dir)
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.0.exists(path)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> std::io::Result<()> {
+        self.0.rename(from, to)
+    }
+
+    fn remove_file(&self, path: &Path) -> std::io::Result<()> {
+        self.0.remove_file(path)
+    }
+
+    fn create_dir_all(&self, path: &Path) -> std::io::Result<()> {
+        self.0.create_dir_all(path)
+    }
+
+    fn available_space(&self, _dir: &Path) -> Option<std::io::Result<u64>> {
+        Some(Ok(0))
+    }
+}
+
+// A snapshot should keep reading the store as it was, from another thread,
+// while the store is written to and compacted.
+#[test]
+fn snapshot_handle() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for i in 0..200 {
+        store.set(format!("key{:03}", i), format!("old{}", i))?;
+    }
+    store.compact()?;
+    // Some of the keys are still in memory when the snapshot is taken.
+    store.set("key000".to_owned(), "latest".to_owned())?;
+    store.set_with_ttl("gone".to_owned(), "soon".to_owned(), Duration::ZERO)?;
+
+    let mut snapshot = store.snapshot()?;
+    assert_eq!(snapshot.len(), 200);
+    for i in 0..200 {
+        store.set(format!("key{:03}", i), format!("new{}", i))?;
+    }
+    store.remove("key001".to_owned())?;
+    store.set("extra".to_owned(), "value".to_owned())?;
+    store.compact()?;
+
+    let pairs = std::thread::spawn(move || snapshot.scan("key"))
+        .join()
+        .unwrap()?;
+    assert_eq!(pairs.len(), 200);
+    assert_eq!(pairs[0], ("key000".to_owned(), "latest".to_owned()));
+    assert_eq!(pairs[1], ("key001".to_owned(), "old1".to_owned()));
+    assert_eq!(pairs[199], ("key199".to_owned(), "old199".to_owned()));
+
+    let mut snapshot = store.snapshot()?;
+    assert_eq!(snapshot.get("key001".to_owned())?, None);
+    assert_eq!(snapshot.get("extra".to_owned())?, Some("value".to_owned()));
+    assert_eq!(snapshot.keys("key00").len(), 9);
+    Ok(())
+}
+
+// A conditional write should only go through at the version the key was
+// last read or written at, and versions should survive compaction but not
+// a reopen.
+#[test]
+fn version_tokens() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key".to_owned(), "0".to_owned())?;
+    let (value, first) = store.get_versioned("key".to_owned())?.unwrap();
+    assert_eq!(value, "0");
+    assert_eq!(store.get_versioned("missing".to_owned())?, None);
+
+    let second = store.set_if_version("key".to_owned(), "1".to_owned(), first)?;
+    assert_ne!(second, first);
+    assert!(matches!(
+        store.set_if_version("key".to_owned(), "2".to_owned(), first),
+        Err(KvsError::VersionMismatch(_))
+    ));
+    assert_eq!(store.get("key".to_owned())?, Some("1".to_owned()));
+
+    // Versions round-trip through strings, and compaction leaves them be.
+    let token = second.to_string();
+    store.compact()?;
+    let second: Version = token.parse()?;
+    assert_eq!(store.get_versioned("key".to_owned())?.unwrap().1, second);
+    assert!("not a version".parse::<Version>().is_err());
+
+    // A key that is removed, or set again, moves on.
+    let other = store.set_versioned("other".to_owned(), "a".to_owned())?;
+    store.remove("other".to_owned())?;
+    assert!(store
+        .set_if_version("other".to_owned(), "b".to_owned(), other)
+        .is_err());
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert!(store
+        .set_if_version("key".to_owned(), "3".to_owned(), second)
+        .is_err());
+    let (_, reopened) = store.get_versioned("key".to_owned())?.unwrap();
+    store.set_if_version("key".to_owned(), "3".to_owned(), reopened)?;
+    assert_eq!(store.get("key".to_owned())?, Some("3".to_owned()));
+    Ok(())
+}
+
+// contains_key, len and is_empty should answer from the index, leaving out
+// removed and expired keys, and read nothing from disk.
+#[test]
+fn contains_key_and_len() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let clock = ManualClock::new(Duration::from_secs(1_000_000));
+    let opts = KvOpts::new()
+        .clock(clock.clone())
+        .key_codec(CaseInsensitive);
+    let mut store = KvStore::open_with_opts(temp_dir.path(), opts)?;
+    assert!(store.is_empty());
+    assert_eq!(store.len(), 0);
+
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.remove("key5".to_owned())?;
+    store.set_with_ttl(
+        "temp".to_owned(),
+        "value".to_owned(),
+        Duration::from_secs(60),
+    )?;
+    store.compact()?;
+    let bytes_read = store.stats().io.bytes_read;
+    assert!(store.contains_key("KEY7"));
+    assert!(!store.contains_key("key5"));
+    assert!(!store.contains_key("missing"));
+    assert!(store.contains_key("temp"));
+    assert_eq!(store.len(), 100);
+    assert!(!store.is_empty());
+
+    clock.advance(Duration::from_secs(60));
+    assert!(!store.contains_key("temp"));
+    assert_eq!(store.len(), 99);
+    assert_eq!(store.stats().io.bytes_read, bytes_read);
+
+    for i in 0..100 {
+        store.remove_if_exists(format!("key{}", i))?;
+    }
+    assert!(store.is_empty());
+    Ok(())
+}
+
+// compare_and_swap should only write when the key holds the expected value,
+// treating a missing or expired key as `None`, and should persist its writes.
+#[test]
+fn compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let clock = ManualClock::new(Duration::from_secs(1_000_000));
+    let opts = KvOpts::new().clock(clock.clone()).write_once("once/");
+    let mut store = KvStore::open_with_opts(temp_dir.path(), opts.clone())?;
+    let some = |value: &str| Some(value.to_owned());
+
+    assert!(store.compare_and_swap("key".to_owned(), None, some("1"))?);
+    assert!(!store.compare_and_swap("key".to_owned(), None, some("2"))?);
+    assert!(!store.compare_and_swap("key".to_owned(), some("0"), some("2"))?);
+    assert!(store.compare_and_swap("key".to_owned(), some("1"), some("2"))?);
+    assert_eq!(store.get("key".to_owned())?, some("2"));
+
+    assert!(store.compare_and_swap("gone".to_owned(), None, None)?);
+    assert!(!store.compare_and_swap("gone".to_owned(), some("1"), None)?);
+    store.set("removed".to_owned(), "1".to_owned())?;
+    assert!(store.compare_and_swap("removed".to_owned(), some("1"), None)?);
+    assert_eq!(store.get("removed".to_owned())?, None);
+
+    store.set_with_ttl("temp".to_owned(), "1".to_owned(), Duration::from_secs(60))?;
+    clock.advance(Duration::from_secs(60));
+    assert!(!store.compare_and_swap("temp".to_owned(), some("1"), some("2"))?);
+    assert!(store.compare_and_swap("temp".to_owned(), None, some("2"))?);
+
+    assert!(store.compare_and_swap("once/a".to_owned(), None, some("1"))?);
+    assert!(!store.compare_and_swap("once/a".to_owned(), None, some("2"))?);
+    assert!(matches!(
+        store.compare_and_swap("once/a".to_owned(), some("1"), some("2")),
+        Err(KvsError::WriteOnce(_))
+    ));
+    drop(store);
+
+    let mut store = KvStore::open_with_opts(temp_dir.path(), opts)?;
+    assert_eq!(store.get("key".to_owned())?, some("2"));
+    assert_eq!(store.get("removed".to_owned())?, None);
+    assert_eq!(store.get("temp".to_owned())?, some("2"));
+    assert_eq!(store.get("once/a".to_owned())?, some("1"));
+    Ok(())
+}
+
+// A scoped store should only ever see, and change, the keys in its scope.
+#[test]
+fn scoped_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("tenant1".to_owned(), "outside".to_owned())?;
+    store.set("tenant10:a".to_owned(), "neighbour".to_owned())?;
+
+    let mut tenant = store.scoped("tenant1:");
+    assert_eq!(tenant.prefix(), "tenant1:");
+    tenant.set("a".to_owned(), "1".to_owned())?;
+    tenant.set("b/x".to_owned(), "2".to_owned())?;
+    tenant.set("b/y".to_owned(), "3".to_owned())?;
+    assert_eq!(tenant.get("a".to_owned())?, Some("1".to_owned()));
+    assert_eq!(tenant.keys(), vec!["a", "b/x", "b/y"]);
+    assert_eq!(
+        tenant.scan("b/")?,
+        vec![
+            ("b/x".to_owned(), "2".to_owned()),
+            ("b/y".to_owned(), "3".to_owned())
+        ]
+    );
+    tenant.remove("a".to_owned())?;
+    assert!(!tenant.remove_if_exists("a".to_owned())?);
+    assert_eq!(tenant.clear()?, 2);
+    assert!(tenant.keys().is_empty());
+
+    assert_eq!(
+        store.scan("")?,
+        vec![
+            ("tenant1".to_owned(), "outside".to_owned()),
+            ("tenant10:a".to_owned(), "neighbour".to_owned())
+        ]
+    );
+
+    // Keys come back without the prefix, however the codec normalizes it.
+    let opts = KvOpts::new().fs(MemFs::new()).key_codec(CaseInsensitive);
+    let mut store = KvStore::open_with_opts("/store", opts)?;
+    store
+        .scoped("Tenant:")
+        .set("Key".to_owned(), "value".to_owned())?;
+    assert_eq!(store.scoped("TENANT:").keys(), vec!["key"]);
+    Ok(())
+}
+
+// A scan bounded to fewer bytes than a single value should still read every
+// value, one at a time.
+#[test]
+fn scan_batch_bytes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for i in 0..20 {
+        store.set(format!("key{:02}", i), i.to_string().repeat(1000))?;
+    }
+    store.remove("key05".to_owned())?;
+    for batch_bytes in [1, 2500, 1 << 20] {
+        let opts = ScanOpts::new().reverse(true).batch_bytes(batch_bytes);
+        let pairs = store.scan_iter(opts).collect::<Result<Vec<_>>>()?;
+        assert_eq!(pairs.len(), 19);
+        assert_eq!(pairs[0], ("key19".to_owned(), "19".repeat(1000)));
+        assert_eq!(pairs[14], ("key04".to_owned(), "4".repeat(1000)));
+    }
+    Ok(())
+}
+
+// A range scan should yield the live keys within its bounds, in key order,
+// whichever ends of it are inclusive.
+#[test]
+fn scan_range() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key in &["a", "b", "b1", "c", "d", "e"] {
+        store.set(key.to_string(), key.to_uppercase())?;
+    }
+    store.remove("d".to_owned())?;
+    let keys = |pairs: Vec<(String, String)>| -> Vec<String> {
+        pairs.into_iter().map(|(key, _)| key).collect()
+    };
+
+    let pairs = store.range("b".."d").collect::<Result<Vec<_>>>()?;
+    assert_eq!(pairs[0], ("b".to_owned(), "B".to_owned()));
+    assert_eq!(keys(pairs), ["b", "b1", "c"]);
+    let pairs = store.range("b"..="e").collect::<Result<Vec<_>>>()?;
+    assert_eq!(keys(pairs), ["b", "b1", "c", "e"]);
+    let pairs = store.range(.."b").collect::<Result<Vec<_>>>()?;
+    assert_eq!(keys(pairs), ["a"]);
+    let pairs = store.range("c"..).collect::<Result<Vec<_>>>()?;
+    assert_eq!(keys(pairs), ["c", "e"]);
+
+    let opts = ScanOpts::new()
+        .range((Bound::Excluded("b"), Bound::Unbounded))
+        .reverse(true)
+        .limit(2);
+    assert_eq!(keys(store.scan_with(opts)?), ["e", "c"]);
+
+    // Bounds that leave no keys, and a prefix narrower or wider than them.
+    let pairs = store.range("d".."b").collect::<Result<Vec<_>>>()?;
+    assert!(pairs.is_empty());
+    let opts = ScanOpts::new().range((Bound::Excluded("b"), Bound::Excluded("b")));
+    assert!(store.scan_with(opts)?.is_empty());
+    let opts = ScanOpts::new().prefix("b").range("a"..);
+    assert_eq!(keys(store.scan_with(opts)?), ["b", "b1"]);
+    let opts = ScanOpts::new().prefix("b").range("b0".."z").reverse(true);
+    assert_eq!(keys(store.scan_with(opts)?), ["b1"]);
+    let opts = ScanOpts::new().prefix("c").range(.."b");
+    assert!(store.scan_with(opts)?.is_empty());
+
+    // Keys beyond every character but the last.
+    let max = char::MAX.to_string();
+    for key in [format!("b{}", max), format!("b{}{}", max, max), max.clone()] {
+        store.set(key, String::new())?;
+    }
+    let opts = ScanOpts::new().prefix(format!("b{}", max));
+    assert_eq!(
+        keys(store.scan_with(opts)?),
+        [format!("b{}", max), format!("b{}{}", max, max)]
+    );
+    let opts = ScanOpts::new().prefix(max.clone()).reverse(true);
+    assert_eq!(keys(store.scan_with(opts)?), [max]);
+    Ok(())
+}
+
+// keys() should walk the live keys in key order without their values, and
+// iter() should read the values in key order too, both leaving out removed and expired keys.
+#[test]
+fn keys_and_iter() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let clock = ManualClock::new(Duration::from_secs(1_000_000));
+    let opts = KvOpts::new().clock(clock.clone());
+    let mut store = KvStore::open_with_opts(temp_dir.path(), opts)?;
+    for i in (0..10).rev() {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.remove("key3".to_owned())?;
+    store.set_with_ttl(
+        "key7".to_owned(),
+        "value7".to_owned(),
+        Duration::from_secs(10),
+    )?;
+    clock.advance(Duration::from_secs(10));
+
+    let keys = store.keys();
+    assert_eq!(keys.len(), 8);
+    assert_eq!(
+        keys.collect::<Vec<_>>(),
+        ["key0", "key1", "key2", "key4", "key5", "key6", "key8", "key9"]
+    );
+    let pairs = store.iter().collect::<Result<Vec<_>>>()?;
+    assert_eq!(pairs.len(), 8);
+    assert_eq!(pairs[0], ("key0".to_owned(), "value0".to_owned()));
+    assert_eq!(pairs[7], ("key9".to_owned(), "value9".to_owned()));
+    Ok(())
+}
+
+// A full scan should fetch far ahead of the commands it reads, rather than
+// reading the file system once per command.
+#[test]
+fn scan_prefetch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let fs = FaultyFs::new();
+    let opts = KvOpts::new().fs(fs.clone());
+    let mut store = KvStore::open_with_opts(temp_dir.path(), opts.clone())?;
+    for i in 0..2000 {
+        store.set(format!("key{:04}", i), format!("value{:0100}", i))?;
+    }
+    store.compact()?;
+    drop(store);
+
+    let mut store = KvStore::open_with_opts(temp_dir.path(), opts)?;
+    let reads = fs.reads();
+    let mut scanned = 0;
+    for pair in store.scan_iter(ScanOpts::new()) {
+        let (key, value) = pair?;
+        assert_eq!(
+            value,
+            format!("value{:0100}", &key[3..].parse::<u64>().unwrap())
+        );
+        scanned += 1;
+    }
+    assert_eq!(scanned, 2000);
+    assert!(fs.reads() - reads < 100, "{} reads", fs.reads() - reads);
+    Ok(())
+}
+
+// `kvs undelete` should restore a soft-deleted key, and exit like `kvs rm`
+// does for a key it cannot restore.
+#[test]
+fn cli_undelete() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let opts = KvOpts::new().soft_delete(Duration::from_secs(3600));
+    let mut store = KvStore::open_with_opts(temp_dir.path(), opts)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    let kvs = |args: &[&str]| {
+        let mut cmd = Command::cargo_bin("kvs").unwrap();
+        cmd.args(args).current_dir(&temp_dir);
+        cmd
+    };
+
+    kvs(&["rm", "key1"]).assert().success();
+    kvs(&["get", "key1"])
+        .assert()
+        .stdout(eq("Key not found").trim());
+    kvs(&["undelete", "key1"]).assert().success();
+    kvs(&["get", "key1"])
+        .assert()
+        .success()
+        .stdout(eq("value1").trim());
+    kvs(&["undelete", "key2"])
+        .assert()
+        .code(2)
+        .stderr(eq("Key not found").trim());
+    Ok(())
+}
+
+// A store on an in-memory file system should never touch the disk, and
+// should behave just like one on the disk.
+#[test]
+fn in_memory_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path().join("store");
+    let fs = MemFs::new();
+    let mut store = KvStore::open_with_opts(&path, KvOpts::new().fs(fs.clone()))?;
+    for i in 0..100 {
+        store.set(format!("key{}", i % 10), format!("value{}", i))?;
+    }
+    store.remove("key0".to_owned())?;
+    store.compact()?;
+    drop(store);
+    assert!(!path.exists());
+
+    let mut store = KvStore::open_with_opts(&path, KvOpts::new().fs(fs))?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    assert_eq!(store.get("key9".to_owned())?, Some("value99".to_owned()));
+    drop(store);
+
+    for seed in 0..4 {
+        let opts = KvOpts::new().fs(MemFs::new());
+        kvs::testing::check_model(&path, opts, seed, 400)?;
+    }
+    assert!(!path.exists());
+    Ok(())
+}
+
+// validate should describe a store without opening it, whether or not it is
+// open, and refuse a directory that holds no store.
+#[test]
+fn validate_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    assert!(matches!(
+        KvStore::validate(temp_dir.path()),
+        Err(KvsError::Io(_))
+    ));
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.compact()?;
+    let info = KvStore::validate(temp_dir.path())?;
+    assert!(info.locked);
+    assert!(info.current);
+    assert_eq!(&info.meta, store.info());
+    assert_eq!(info.segments.len(), 2);
+    assert!(info.segments.windows(2).all(|pair| pair[0] < pair[1]));
+    assert!(info.size >= store.stats().live_bytes);
+    drop(store);
+
+    let info = KvStore::validate(temp_dir.path())?;
+    assert!(!info.locked);
+    let meta_path = temp_dir.path().join("kvs.meta");
+    let mut meta: serde_json::Value = serde_json::from_slice(&std::fs::read(&meta_path)?)?;
+    meta["format_version"] = 0.into();
+    std::fs::write(&meta_path, serde_json::to_vec(&meta)?)?;
+    assert!(!KvStore::validate(temp_dir.path())?.current);
+    meta["format_version"] = 2.into();
+    std::fs::write(&meta_path, serde_json::to_vec(&meta)?)?;
+    assert!(matches!(
+        KvStore::validate(temp_dir.path()),
+        Err(KvsError::UnsupportedFormat(_))
+    ));
+    Ok(())
+}
+
+// A store in an older format should be refused on open, with a pointer to
+// `kvs upgrade`, and upgrading it should keep its keys, expiries and
+// identity.
+#[test]
+fn upgrade_format() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path().join("store");
+    let clock = ManualClock::new(Duration::from_secs(1_000_000));
+    let opts = KvOpts::new()
+        .clock(clock.clone())
+        .key_codec(CaseInsensitive);
+    std::fs::create_dir(&path)?;
+    let mut store = KvStore::open_with_opts(&path, opts.clone())?;
+    for i in 0..100 {
+        store.set(format!("Key{}", i), format!("value{}", i))?;
+    }
+    store.remove("key5".to_owned())?;
+    store.set_with_ttl(
+        "temp".to_owned(),
+        "value".to_owned(),
+        Duration::from_secs(60),
+    )?;
+    let uuid = store.info().uuid.clone();
+    drop(store);
+    assert!(!KvStore::upgrade(&path)?);
+
+    let meta_path = path.join("kvs.meta");
+    let mut meta: serde_json::Value = serde_json::from_slice(&std::fs::read(&meta_path)?)?;
+    meta["format_version"] = 0.into();
+    std::fs::write(&meta_path, serde_json::to_vec(&meta)?)?;
+    match KvStore::open_with_opts(&path, opts.clone()) {
+        Err(KvsError::UnsupportedFormat(message)) => assert!(message.contains("kvs upgrade")),
+        other => panic!(
+            "expected an unsupported format, got {:?}",
+            other.map(|_| ())
+        ),
+    }
+
+    assert!(KvStore::upgrade(&path)?);
+    assert!(!temp_dir.path().join("store.upgrade").exists());
+    assert!(!temp_dir.path().join("store.old").exists());
+    let mut store = KvStore::open_with_opts(&path, opts)?;
+    assert_eq!(store.info().uuid, uuid);
+    assert_eq!(store.info().format_version, 1);
+    assert_eq!(store.stats().keys, 100);
+    assert_eq!(store.get("KEY7".to_owned())?, Some("value7".to_owned()));
+    assert_eq!(store.get("key5".to_owned())?, None);
+    clock.advance(Duration::from_secs(60));
+    assert_eq!(store.get("temp".to_owned())?, None);
+    Ok(())
+}
+
+// The watchdog should report and count a sync that takes longer than it
+// allows, and a compaction that runs for longer, once.
+#[test]
+fn watchdog_stalls() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let fs = FaultyFs::new();
+    let stalls = Arc::new(Mutex::new(Vec::new()));
+    let reported = Arc::clone(&stalls);
+    let watchdog = Watchdog::new()
+        .max_sync(Duration::from_millis(50))
+        .max_compaction(Duration::ZERO)
+        .on_stall(move |stall| reported.lock().unwrap().push(*stall));
+    let opts = KvOpts::new().fs(fs.clone()).sync(true).watchdog(watchdog);
+    let mut store = KvStore::open_with_opts(temp_dir.path(), opts)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.stats().io.slow_syncs, 0);
+    assert!(stalls.lock().unwrap().is_empty());
+
+    fs.inject(Fault::SlowSync, 1);
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.stats().io.slow_syncs, 1);
+    {
+        let stalls = stalls.lock().unwrap();
+        assert_eq!(stalls.len(), 1);
+        assert_eq!(stalls[0].kind, StallKind::Sync);
+        assert_eq!(stalls[0].limit, Duration::from_millis(50));
+        assert!(stalls[0].elapsed >= Duration::from_millis(100));
+    }
+
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.compact()?;
+    let io = store.stats().io;
+    assert_eq!(io.slow_compactions, 1);
+    assert_eq!(io.slow_syncs, 1);
+    let stalls = stalls.lock().unwrap();
+    assert_eq!(stalls.len(), 2);
+    assert_eq!(stalls[1].kind, StallKind::Compaction);
+    drop(store);
+
+    // Without a handler, stalls are still counted.
+    let watchdog = Watchdog::new().max_compaction(Duration::ZERO);
+    let opts = KvOpts::new().fs(fs.clone()).watchdog(watchdog);
+    let mut store = KvStore::open_with_opts(temp_dir.path(), opts)?;
+    store.compact()?;
+    assert_eq!(store.stats().io.slow_compactions, 1);
+    Ok(())
+}
+
+// multi_get should return every value in the order asked for, whether it is
+// on disk, still in memory, missing or expired.
+#[test]
+fn multi_get() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for i in 0..500 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.compact()?;
+    store.set("key7".to_owned(), "latest".to_owned())?;
+    store.set_with_ttl("gone".to_owned(), "soon".to_owned(), Duration::ZERO)?;
+    store.remove("key9".to_owned())?;
+
+    let gets = store.stats().io.gets;
+    let keys: Vec<String> = [
+        "key499", "key7", "missing", "key9", "gone", "key0", "key499",
+    ]
+    .iter()
+    .map(|key| key.to_string())
+    .collect();
+    let values = store.multi_get(&keys)?;
+    assert_eq!(
+        values,
+        [
+            Some("value499".to_owned()),
+            Some("latest".to_owned()),
+            None,
+            None,
+            None,
+            Some("value0".to_owned()),
+            Some("value499".to_owned()),
+        ]
+    );
+    assert_eq!(store.stats().io.gets, gets + 7);
+    assert_eq!(store.multi_get(&[])?, Vec::<Option<String>>::new());
+    Ok(())
+}
+
+// A write batch should apply its writes in order, skip removes of missing
+// keys, and survive a crash before it reaches the data segment.
+#[test]
+fn write_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("from".to_owned(), "100".to_owned())?;
+    store.set("old".to_owned(), "value".to_owned())?;
+
+    let mut batch = WriteBatch::new();
+    batch
+        .set("from".to_owned(), "60".to_owned())
+        .set("to".to_owned(), "40".to_owned())
+        .remove("old".to_owned())
+        .remove("missing".to_owned())
+        .set("temp".to_owned(), "1".to_owned())
+        .remove("temp".to_owned());
+    assert_eq!(batch.len(), 6);
+    store.write(batch)?;
+    store.write(WriteBatch::new())?;
+    kvs::testing::crash(store, temp_dir.path(), CrashPoint::BetweenOps)?;
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("from".to_owned())?, Some("60".to_owned()));
+    assert_eq!(store.get("to".to_owned())?, Some("40".to_owned()));
+    assert_eq!(store.get("old".to_owned())?, None);
+    assert_eq!(store.get("missing".to_owned())?, None);
+    assert_eq!(store.get("temp".to_owned())?, None);
+    Ok(())
+}
+
+// A batch torn at the tail of the write-ahead log should be discarded as a
+// whole, and one that breaks a write-once key should write nothing.
+#[test]
+fn write_batch_all_or_nothing() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let opts = KvOpts::new().write_once("audit/");
+    let mut store = KvStore::open_with_opts(temp_dir.path(), opts.clone())?;
+    store.set("audit/1".to_owned(), "created".to_owned())?;
+
+    let mut batch = WriteBatch::new();
+    batch
+        .set("key1".to_owned(), "value1".to_owned())
+        .set("audit/1".to_owned(), "changed".to_owned());
+    assert!(matches!(store.write(batch), Err(KvsError::WriteOnce(_))));
+    assert_eq!(store.get("key1".to_owned())?, None);
+    kvs::testing::crash(store, temp_dir.path(), CrashPoint::BetweenOps)?;
+
+    let wal = temp_dir.path().join("kvs.wal");
+    let mut contents = std::fs::read(&wal)?;
+    contents.extend_from_slice(
+        b"[{\"Set\":{\"key\":\"key1\",\"value\":\"value1\"}},{\"Set\":{\"key\":\"key2\"",
+    );
+    std::fs::write(&wal, contents)?;
+
+    let mut store = KvStore::open_with_opts(temp_dir.path(), opts)?;
+    assert_eq!(store.get("audit/1".to_owned())?, Some("created".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, None);
+    Ok(())
+}
+
+// `kvs bench --compare` should time every engine at every value size, and
+// leave no scratch store behind in the working directory.
+#[test]
+fn cli_bench_compare() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args([
+            "bench",
+            "--compare",
+            "--keys",
+            "50",
+            "--value-size",
+            "10,100",
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(
+            contains("engine")
+                .and(is_match(r"native +10 B +50 ").unwrap())
+                .and(is_match(r"native +100 B +50 ").unwrap())
+                .and(is_match(r"memory +10 B +50 ").unwrap())
+                .and(is_match(r"memory +100 B +50 ").unwrap()),
+        );
+    assert_eq!(std::fs::read_dir(temp_dir.path()).unwrap().count(), 0);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["bench", "--compare", "--engine", "memory"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+}
+
+// `kvs upgrade --dir` should upgrade a store in an older format, and leave
+// one in the newest format alone.
+#[test]
+fn cli_upgrade() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    let meta_path = temp_dir.path().join("kvs.meta");
+    let mut meta: serde_json::Value = serde_json::from_slice(&std::fs::read(&meta_path)?)?;
+    meta["format_version"] = 0.into();
+    std::fs::write(&meta_path, serde_json::to_vec(&meta)?)?;
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("kvs upgrade"));
+    let dir = temp_dir.path().to_str().unwrap();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["upgrade", "--dir", dir])
+        .assert()
+        .success()
+        .stderr(contains("Upgraded"));
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["upgrade", "--dir", dir])
+        .assert()
+        .success()
+        .stderr(contains("Unchanged"));
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1");
+    Ok(())
+}